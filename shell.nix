@@ -16,6 +16,7 @@ pkgs.mkShell {
     sqliteman
     pkg-config
     alsa-lib
+    dbus
   ];
 
   RUST_BACKTRACE = 1;
@@ -0,0 +1,249 @@
+use derive_more::From;
+use image::{DynamicImage, ImageOutputFormat};
+use sha2::{Digest, Sha256};
+use std::ffi::OsStr;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use crate::db_operations::DBObject;
+use crate::file_operations::TagFormat;
+
+/// File names (without extension) that are used as album art when a track has none embedded
+const FOLDER_ART_NAMES: [&str; 4] = ["cover", "folder", "front", "album"];
+const FOLDER_ART_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+/// Thumbnails are clamped to this range of sizes, in pixels
+const MIN_THUMBNAIL_SIZE: u32 = 16;
+const MAX_THUMBNAIL_SIZE: u32 = 2048;
+
+/// Catch all Error for extracting, caching and serving artwork
+#[derive(From, Debug)]
+pub enum ArtworkError {
+    IoError(std::io::Error),
+    ImageError(image::ImageError),
+    RusqliteError(rusqlite::Error),
+    NotFound,
+}
+
+/// Artwork as it is stored in the database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtworkInfo {
+    pub id: i64,
+    /// The hex encoded SHA-256 of the image, which is also its name in the cache
+    pub hash: String,
+    pub mime: String,
+}
+
+/// The on-disk store of artwork, deduplicated by content hash
+///
+/// Originals are stored as `<hash>.<ext>`, resized thumbnails as `<hash>-<size>.jpg`.
+pub struct ArtworkCache {
+    dir: PathBuf,
+}
+
+impl ArtworkCache {
+    pub fn new(dir: PathBuf) -> Result<Self, std::io::Error> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(ArtworkCache { dir })
+    }
+
+    /// The cache location used when none is configured
+    pub fn default_dir() -> PathBuf {
+        dirs_next::cache_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("sousa")
+            .join("artwork")
+    }
+
+    fn original_path(&self, hash: &str, mime: &str) -> PathBuf {
+        let extension = mime.rsplit('/').next().unwrap_or("img");
+        self.dir.join(format!("{}.{}", hash, extension))
+    }
+
+    /// Store an image in the cache, returning its hash and mime type
+    ///
+    /// Storing the same image twice only writes it once.
+    pub fn store(&self, data: &[u8]) -> Result<(String, String), ArtworkError> {
+        let format = image::guess_format(data)?;
+        let mime = format.to_mime_type().to_string();
+        let hash = format!("{:x}", Sha256::digest(data));
+
+        let path = self.original_path(&hash, &mime);
+        if !path.exists() {
+            std::fs::write(path, data)?;
+        }
+        Ok((hash, mime))
+    }
+
+    /// Load an image from the cache, resized to fit a `size` by `size` square if one is given
+    ///
+    /// Returns the image data and its mime type. Thumbnails are created on first use and
+    /// kept in the cache; sizes at least as large as the original return the original.
+    pub fn load(
+        &self,
+        artwork: &ArtworkInfo,
+        size: Option<u32>,
+    ) -> Result<(Vec<u8>, String), ArtworkError> {
+        let original_path = self.original_path(&artwork.hash, &artwork.mime);
+        if !original_path.exists() {
+            return Err(ArtworkError::NotFound);
+        }
+
+        let size = match size {
+            None => return Ok((std::fs::read(original_path)?, artwork.mime.clone())),
+            Some(size) => size.clamp(MIN_THUMBNAIL_SIZE, MAX_THUMBNAIL_SIZE),
+        };
+
+        let thumbnail_path = self.dir.join(format!("{}-{}.jpg", artwork.hash, size));
+        if thumbnail_path.exists() {
+            return Ok((std::fs::read(thumbnail_path)?, "image/jpeg".to_string()));
+        }
+
+        let original_data = std::fs::read(original_path)?;
+        let original = image::load_from_memory(&original_data)?;
+        if original.width() <= size && original.height() <= size {
+            return Ok((original_data, artwork.mime.clone()));
+        }
+
+        // JPEG has no alpha channel, so flatten the image first
+        let thumbnail = DynamicImage::ImageRgb8(original.thumbnail(size, size).to_rgb8());
+        let mut thumbnail_data = Vec::<u8>::new();
+        thumbnail.write_to(
+            &mut Cursor::new(&mut thumbnail_data),
+            ImageOutputFormat::Jpeg(85),
+        )?;
+        std::fs::write(thumbnail_path, &thumbnail_data)?;
+
+        Ok((thumbnail_data, "image/jpeg".to_string()))
+    }
+}
+
+/// Read the artwork embedded in a file's tags, preferring the front cover
+///
+/// mp3 files are read for APIC frames, flac files for PICTURE blocks.
+pub fn read_embedded_artwork(path: &Path) -> Option<Vec<u8>> {
+    match TagFormat::from_path(path)? {
+        TagFormat::Id3 => {
+            let tag = id3::Tag::read_from_path(path).ok()?;
+            let pictures: Vec<&id3::frame::Picture> = tag.pictures().collect();
+            pictures
+                .iter()
+                .find(|picture| picture.picture_type == id3::frame::PictureType::CoverFront)
+                .or_else(|| pictures.first())
+                .map(|picture| picture.data.clone())
+        }
+        TagFormat::Vorbis => {
+            let tag = metaflac::Tag::read_from_path(path).ok()?;
+            let pictures: Vec<&metaflac::block::Picture> = tag.pictures().collect();
+            pictures
+                .iter()
+                .find(|picture| picture.picture_type == metaflac::block::PictureType::CoverFront)
+                .or_else(|| pictures.first())
+                .map(|picture| picture.data.clone())
+        }
+    }
+}
+
+/// Find a `cover.jpg`, `folder.png` or similar image in a directory
+///
+/// Names are matched regardless of case, and earlier names in `FOLDER_ART_NAMES` win.
+pub fn find_folder_artwork(dir: &Path) -> Option<PathBuf> {
+    let mut candidates: Vec<(usize, PathBuf)> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter_map(|path| {
+            let stem = path.file_stem().and_then(OsStr::to_str)?.to_lowercase();
+            let extension = path.extension().and_then(OsStr::to_str)?.to_lowercase();
+            if !FOLDER_ART_EXTENSIONS.contains(&extension.as_str()) {
+                return None;
+            }
+            let rank = FOLDER_ART_NAMES.iter().position(|name| *name == stem)?;
+            Some((rank, path))
+        })
+        .collect();
+
+    candidates.sort();
+    candidates.into_iter().next().map(|(_, path)| path)
+}
+
+/// Find the artwork for a track, falling back to the folder art of its directory
+pub fn find_artwork(track_path: &Path) -> Option<Vec<u8>> {
+    if let Some(data) = read_embedded_artwork(track_path) {
+        return Some(data);
+    }
+
+    let folder_art = find_folder_artwork(track_path.parent()?)?;
+    std::fs::read(folder_art).ok()
+}
+
+/// Extract the artwork of a track into the cache and link it in the database
+///
+/// Returns the id of the artwork, or `None` if the track has none.
+pub fn scan_artwork(
+    dbo: &DBObject,
+    cache: &ArtworkCache,
+    track_path: &Path,
+) -> Result<Option<i64>, ArtworkError> {
+    let data = match find_artwork(track_path) {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+
+    let (hash, mime) = cache.store(&data)?;
+    let artwork_id = dbo.save_artwork(&hash, &mime)?;
+    dbo.set_track_artwork(&track_path.to_string_lossy(), artwork_id)?;
+
+    Ok(Some(artwork_id))
+}
+
+#[test]
+fn test_artwork_cache_and_folder_art() {
+    use crate::message_types::ItemTag;
+
+    let dir = std::env::temp_dir().join(format!("sousa-artwork-{}", std::process::id()));
+    let album_dir = dir.join("album");
+    std::fs::create_dir_all(&album_dir).unwrap();
+    let cache = ArtworkCache::new(dir.join("cache")).unwrap();
+
+    let mut cover_data = Vec::<u8>::new();
+    DynamicImage::new_rgb8(400, 200)
+        .write_to(&mut Cursor::new(&mut cover_data), ImageOutputFormat::Png)
+        .unwrap();
+    std::fs::write(album_dir.join("Folder.PNG"), &cover_data).unwrap();
+    std::fs::write(album_dir.join("cover.jpg.bak"), &cover_data).unwrap();
+    assert_eq!(
+        find_folder_artwork(&album_dir),
+        Some(album_dir.join("Folder.PNG"))
+    );
+
+    let dbo = DBObject::new(&PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+    let mut artwork_ids = Vec::<i64>::new();
+    for title in ["one", "two"] {
+        let track_path = album_dir.join(format!("{}.mp3", title));
+        std::fs::write(&track_path, []).unwrap();
+        dbo.save_tag(&ItemTag {
+            path: track_path.to_string_lossy().into_owned(),
+            title: title.to_string(),
+            album: "An example album".to_string(),
+            ..ItemTag::default()
+        })
+        .unwrap();
+        artwork_ids.push(scan_artwork(&dbo, &cache, &track_path).unwrap().unwrap());
+    }
+    // Both tracks share the folder art, so it is only stored once
+    assert_eq!(artwork_ids[0], artwork_ids[1]);
+
+    let artwork = dbo.get_artwork(artwork_ids[0]).unwrap().unwrap();
+    assert_eq!(artwork.mime, "image/png".to_string());
+
+    let (thumbnail, mime) = cache.load(&artwork, Some(100)).unwrap();
+    assert_eq!(mime, "image/jpeg".to_string());
+    let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (100, 50));
+
+    let (original, _) = cache.load(&artwork, None).unwrap();
+    assert_eq!(original, cover_data);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+
+use crate::equalizer::EqualizerSettings;
+use crate::fading::CrossfadeSettings;
+use crate::http_api::ApiSettings;
+use crate::http_stream::StreamingSettings;
+use crate::loudness::LoudnessAnalysisSettings;
+use crate::mpd::MpdSettings;
+use crate::output::{DeviceSelector, OutputBackend};
+use crate::radio::RadioSettings;
+use crate::replaygain::ReplayGainSettings;
+
+/// The settings Sousa reads from its configuration file
+///
+/// Missing keys fall back to their defaults, so older configuration files keep working
+/// as settings are added.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SousaConfig {
+    /// Write ratings back to the audio files (ID3 POPM frames, Vorbis `RATING` comments) when
+    /// they are changed, so they survive a database rebuild
+    pub sync_ratings_to_files: bool,
+    /// Library directories that are scanned along with the root directory
+    pub music_roots: Vec<String>,
+    /// Where extracted cover art and thumbnails are kept, defaults to the user cache dir
+    pub artwork_cache_dir: Option<String>,
+    /// Loudness normalization during playback
+    pub replaygain: ReplayGainSettings,
+    /// Measuring the loudness of tracks that have no ReplayGain tags
+    pub loudness_analysis: LoudnessAnalysisSettings,
+    /// Crossfading between tracks, and the fades on pause and skip
+    pub crossfade: CrossfadeSettings,
+    /// The EQ presets shared by the zones, and the one they start out with
+    pub equalizer: EqualizerSettings,
+    /// Where the audio is played, the `--null-output` and `--wav-output` flags override it
+    pub output: OutputBackend,
+    /// The sound device to play to by name or index, the default device if not set
+    pub output_device: Option<DeviceSelector>,
+    /// The token clients have to send to use the websocket and the HTTP endpoints, anyone
+    /// can if it is not set
+    pub access_token: Option<String>,
+    /// Serving the library's audio files over HTTP
+    pub streaming: StreamingSettings,
+    /// Mirroring the playback as an HTTP radio stream
+    pub radio: RadioSettings,
+    /// The REST API that takes the same requests as the websocket
+    pub api: ApiSettings,
+    /// The Music Player Daemon protocol server, for MPD clients
+    pub mpd: MpdSettings,
+}
+
+/// Load the configuration from `config_file`, or from the default location if it is `None`
+///
+/// A default configuration file is written if there isn't one yet.
+pub fn load_config(config_file: Option<&String>) -> Result<SousaConfig, confy::ConfyError> {
+    match config_file {
+        Some(path) => confy::load_path(path),
+        None => confy::load("sousa", None),
+    }
+}
+
+/// Write the configuration to `config_file`, or to the default location if it is `None`
+pub fn store_config(
+    config_file: Option<&String>,
+    config: &SousaConfig,
+) -> Result<(), confy::ConfyError> {
+    match config_file {
+        Some(path) => confy::store_path(path, config),
+        None => confy::store("sousa", None, config),
+    }
+}
@@ -1,7 +1,15 @@
 use derive_more::From;
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Result, Row};
 
-use crate::message_types::{ItemTag, PartialTag};
+use crate::artwork::ArtworkInfo;
+use crate::loudness::Loudness;
+use crate::lyrics::Lyrics;
+use crate::message_types::{ItemTag, PartialTag, Playlist, PlaylistEntry};
+use crate::music_player::{PlayRecord, PlayerState};
+use crate::replaygain::ReplayGain;
+use crate::scheduler::{Schedule, SCHEDULE_GRACE_MINUTES};
+use crate::zones::{ZoneSettings, DEFAULT_ZONE};
+use crate::smart_playlists::{SmartPlaylistRules, SmartSort, TextField};
 
 /// Catch all Error for database creation errors
 #[derive(From, Debug)]
@@ -10,6 +18,152 @@ pub enum DatabaseCreationError {
     IoError(std::io::Error),
 }
 
+/// The version of the schema `DBObject::new` migrates databases to
+const SCHEMA_VERSION: u32 = 15;
+
+/// The flattened view of the library that searches run against
+///
+/// Columns are added at the end so `MUSICINFO_COLUMNS` can select a prefix of them.
+const MUSICINFO_VIEW: &str = "CREATE VIEW musicinfo AS
+    SELECT
+        tracks.path AS path,
+        tracks.title AS title,
+        COALESCE((
+            SELECT group_concat(name, '; ') FROM (
+                SELECT artists.name AS name
+                FROM track_artists
+                JOIN artists ON artists.id = track_artists.artist_id
+                WHERE track_artists.track_id = tracks.id
+                ORDER BY track_artists.position
+            )
+        ), '') AS artist,
+        COALESCE(albums.title, '') AS album,
+        COALESCE(album_artists.name, '') AS album_artist,
+        tracks.genre AS genre,
+        tracks.year AS year,
+        tracks.date_added AS date_added,
+        COALESCE(plays.play_count, 0) AS play_count,
+        COALESCE(plays.skip_count, 0) AS skip_count,
+        plays.last_played AS last_played,
+        tracks.rating AS rating,
+        tracks.favorite AS favorite,
+        COALESCE((
+            SELECT group_concat(labels.name, char(31))
+            FROM track_labels
+            JOIN labels ON labels.id = track_labels.label_id
+            WHERE track_labels.track_id = tracks.id
+        ), '') AS labels,
+        COALESCE(tracks.artwork_id, albums.artwork_id) AS artwork_id,
+        COALESCE(tracks.track_gain, -18.0 - loudness.integrated) AS track_gain,
+        COALESCE(tracks.track_peak, loudness.true_peak) AS track_peak,
+        tracks.album_gain AS album_gain,
+        tracks.album_peak AS album_peak,
+        tracks.id AS track_id
+    FROM tracks
+    LEFT JOIN albums ON albums.id = tracks.album_id
+    LEFT JOIN artists AS album_artists ON album_artists.id = albums.album_artist_id
+    LEFT JOIN loudness ON loudness.track_id = tracks.id
+    LEFT JOIN (
+        SELECT
+            path,
+            SUM(counted) AS play_count,
+            SUM(skipped) AS skip_count,
+            MAX(CASE WHEN counted THEN started_at END) AS last_played
+        FROM play_history
+        GROUP BY path
+    ) AS plays ON plays.path = tracks.path;";
+
+/// The `musicinfo` columns `item_tag_from_row` reads, in order
+pub const MUSICINFO_COLUMNS: &str =
+    "path, title, artist, album, album_artist, genre, year, play_count, skip_count, last_played, \
+    rating, favorite, labels, artwork_id, track_gain, track_peak, album_gain, album_peak, track_id";
+
+/// The separator between the labels in the `labels` column of `musicinfo`
+const LABEL_SEPARATOR: char = '\u{1f}';
+
+/// Build an `ItemTag` from a row that has `MUSICINFO_COLUMNS` starting at `first_column`
+pub fn item_tag_from_row(row: &Row, first_column: usize) -> Result<ItemTag, rusqlite::Error> {
+    Ok(ItemTag {
+        path: row.get(first_column)?,
+        title: row.get(first_column + 1)?,
+        artist: row.get(first_column + 2)?,
+        album: row.get(first_column + 3)?,
+        album_artist: row.get(first_column + 4)?,
+        genre: row.get(first_column + 5)?,
+        year: row.get(first_column + 6)?,
+        play_count: row.get(first_column + 7)?,
+        skip_count: row.get(first_column + 8)?,
+        last_played: row.get(first_column + 9)?,
+        rating: row.get(first_column + 10)?,
+        favorite: row.get(first_column + 11)?,
+        labels: row
+            .get::<_, String>(first_column + 12)?
+            .split(LABEL_SEPARATOR)
+            .filter(|label| !label.is_empty())
+            .map(|label| label.to_string())
+            .collect(),
+        artwork_id: row.get(first_column + 13)?,
+        replaygain: ReplayGain {
+            track_gain: row.get(first_column + 14)?,
+            track_peak: row.get(first_column + 15)?,
+            album_gain: row.get(first_column + 16)?,
+            album_peak: row.get(first_column + 17)?,
+        },
+        id: row.get(first_column + 18)?,
+    })
+}
+
+/// Parse a JSON column, failing like a column of the wrong type would
+fn from_json_column<T: serde::de::DeserializeOwned>(
+    column: usize,
+    json: &str,
+) -> Result<T, rusqlite::Error> {
+    serde_json::from_str(json).map_err(|err| {
+        rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, Box::new(err))
+    })
+}
+
+/// The separator used when several track artists are flattened into `ItemTag.artist`
+pub const ARTIST_SEPARATOR: &str = "; ";
+
+/// Split a flattened artist string into the individual artists
+///
+/// Featured artists ("feat.", "ft.", "featuring") are split off as their own artist so
+/// they can be browsed like any other.
+pub fn split_artists(artist: &str) -> Vec<String> {
+    let mut artists = Vec::<String>::new();
+
+    for part in artist.split(ARTIST_SEPARATOR).flat_map(|part| part.split('\0')) {
+        let mut remaining = part;
+        loop {
+            let lowercase = remaining.to_ascii_lowercase();
+            let featured = [" feat. ", " ft. ", " featuring "]
+                .iter()
+                .filter_map(|marker| lowercase.find(marker).map(|index| (index, marker.len())))
+                .min();
+
+            match featured {
+                Some((index, marker_len)) => {
+                    artists.push(remaining[..index].to_string());
+                    remaining = &remaining[index + marker_len..];
+                }
+                None => {
+                    artists.push(remaining.to_string());
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut output = Vec::<String>::new();
+    for artist in artists.iter().map(|artist| artist.trim()) {
+        if !artist.is_empty() && !output.iter().any(|existing| existing == artist) {
+            output.push(artist.to_string());
+        }
+    }
+    output
+}
+
 pub struct DatabaseRequest {
     pub search_type: SearchType,
     pub search_tag: PartialTag,
@@ -86,44 +240,609 @@ impl DBObject {
             conn = Connection::open(db_filepath)?;
         }
 
-        // CREATE TABLE IF NOT EXISTS comment (
-        //        id         INTEGER PRIMARY KEY,
-        //        email      TEXT,
-        //        author     TEXT,
-        //        text       TEXT NOT NULL,
-        //        timestamp  DATETIME DEFAULT CURRENT_TIMESTAMP,
-        //        content_id TEXT NOT NULL,
-        //        parent     INTEGER
-        //    )
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS musicinfo (
-                path         TEXT PRIMARY KEY,
-                title        TEXT NOT NULL,
-                artist       TEXT,
-                album        TEXT,
-                album_artist TEXT
+        let dbo = DBObject { conn };
+        dbo.migrate()?;
 
-            )",
+        Ok(dbo)
+    }
+
+    /// Bring the database schema up to `SCHEMA_VERSION`
+    ///
+    /// The version is tracked with sqlite's `user_version` pragma, so every step only
+    /// runs once per database file. The `musicinfo` view is recreated whenever the schema
+    /// changes so it always matches `MUSICINFO_VIEW`.
+    fn migrate(&self) -> Result<(), DatabaseCreationError> {
+        let version: u32 = self
+            .conn
+            .query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+
+        if version >= SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        if version < 1 {
+            self.create_normalized_schema()?;
+        } else {
+            self.conn.execute_batch("DROP VIEW IF EXISTS musicinfo;")?;
+        }
+        if version < 2 {
+            self.conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS playlists (
+                    id   INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL UNIQUE
+                );
+
+                CREATE TABLE IF NOT EXISTS playlist_entries (
+                    playlist_id INTEGER NOT NULL REFERENCES playlists(id) ON DELETE CASCADE,
+                    position    INTEGER NOT NULL,
+                    path        TEXT NOT NULL,
+                    PRIMARY KEY (playlist_id, position)
+                );",
+            )?;
+        }
+        if version < 3 {
+            self.conn.execute_batch(
+                "ALTER TABLE tracks ADD COLUMN genre TEXT NOT NULL DEFAULT '';
+                ALTER TABLE tracks ADD COLUMN year INTEGER;
+                ALTER TABLE tracks ADD COLUMN date_added INTEGER NOT NULL DEFAULT 0;
+                UPDATE tracks SET date_added = CAST(strftime('%s', 'now') AS INTEGER);
+
+                ALTER TABLE playlists ADD COLUMN rules TEXT;
+                ALTER TABLE playlists ADD COLUMN needs_refresh INTEGER NOT NULL DEFAULT 1;
+
+                CREATE TRIGGER IF NOT EXISTS smart_playlists_track_insert AFTER INSERT ON tracks
+                BEGIN UPDATE playlists SET needs_refresh = 1 WHERE rules IS NOT NULL; END;
+                CREATE TRIGGER IF NOT EXISTS smart_playlists_track_update AFTER UPDATE ON tracks
+                BEGIN UPDATE playlists SET needs_refresh = 1 WHERE rules IS NOT NULL; END;
+                CREATE TRIGGER IF NOT EXISTS smart_playlists_track_delete AFTER DELETE ON tracks
+                BEGIN UPDATE playlists SET needs_refresh = 1 WHERE rules IS NOT NULL; END;
+                CREATE TRIGGER IF NOT EXISTS smart_playlists_artist_insert AFTER INSERT ON track_artists
+                BEGIN UPDATE playlists SET needs_refresh = 1 WHERE rules IS NOT NULL; END;
+                CREATE TRIGGER IF NOT EXISTS smart_playlists_artist_update AFTER UPDATE ON artists
+                BEGIN UPDATE playlists SET needs_refresh = 1 WHERE rules IS NOT NULL; END;
+                CREATE TRIGGER IF NOT EXISTS smart_playlists_album_update AFTER UPDATE ON albums
+                BEGIN UPDATE playlists SET needs_refresh = 1 WHERE rules IS NOT NULL; END;",
+            )?;
+        }
+        if version < 4 {
+            self.conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS play_history (
+                    id         INTEGER PRIMARY KEY,
+                    path       TEXT NOT NULL,
+                    started_at INTEGER NOT NULL,
+                    played_ms  INTEGER NOT NULL,
+                    completed  INTEGER NOT NULL,
+                    counted    INTEGER NOT NULL,
+                    skipped    INTEGER NOT NULL
+                );
+
+                CREATE INDEX IF NOT EXISTS play_history_path ON play_history(path);
+                CREATE INDEX IF NOT EXISTS play_history_started_at ON play_history(started_at);",
+            )?;
+        }
+        if version < 5 {
+            self.conn.execute_batch(
+                "ALTER TABLE tracks ADD COLUMN rating INTEGER;
+                ALTER TABLE tracks ADD COLUMN favorite INTEGER NOT NULL DEFAULT 0;
+
+                CREATE TABLE IF NOT EXISTS labels (
+                    id   INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL UNIQUE COLLATE NOCASE
+                );
+
+                CREATE TABLE IF NOT EXISTS track_labels (
+                    track_id INTEGER NOT NULL REFERENCES tracks(id) ON DELETE CASCADE,
+                    label_id INTEGER NOT NULL REFERENCES labels(id) ON DELETE CASCADE,
+                    PRIMARY KEY (track_id, label_id)
+                );
+
+                CREATE TRIGGER IF NOT EXISTS smart_playlists_label_insert AFTER INSERT ON track_labels
+                BEGIN UPDATE playlists SET needs_refresh = 1 WHERE rules IS NOT NULL; END;
+                CREATE TRIGGER IF NOT EXISTS smart_playlists_label_delete AFTER DELETE ON track_labels
+                BEGIN UPDATE playlists SET needs_refresh = 1 WHERE rules IS NOT NULL; END;",
+            )?;
+        }
+        if version < 6 {
+            self.conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS tag_edits (
+                    id        INTEGER PRIMARY KEY,
+                    path      TEXT NOT NULL,
+                    edited_at INTEGER NOT NULL,
+                    previous  TEXT NOT NULL,
+                    changes   TEXT NOT NULL
+                );
+
+                CREATE INDEX IF NOT EXISTS tag_edits_path ON tag_edits(path);",
+            )?;
+        }
+        if version < 7 {
+            self.conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS artwork (
+                    id   INTEGER PRIMARY KEY,
+                    hash TEXT NOT NULL UNIQUE,
+                    mime TEXT NOT NULL
+                );
+
+                ALTER TABLE tracks ADD COLUMN artwork_id INTEGER REFERENCES artwork(id) ON DELETE SET NULL;
+                ALTER TABLE albums ADD COLUMN artwork_id INTEGER REFERENCES artwork(id) ON DELETE SET NULL;",
+            )?;
+        }
+        if version < 8 {
+            // `text` is the lyrics without timing, which is what searches run against
+            self.conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS lyrics (
+                    track_id INTEGER PRIMARY KEY REFERENCES tracks(id) ON DELETE CASCADE,
+                    synced   INTEGER NOT NULL,
+                    text     TEXT NOT NULL,
+                    lines    TEXT NOT NULL
+                );",
+            )?;
+        }
+        if version < 9 {
+            self.conn.execute_batch(
+                "ALTER TABLE tracks ADD COLUMN track_gain REAL;
+                ALTER TABLE tracks ADD COLUMN track_peak REAL;
+                ALTER TABLE tracks ADD COLUMN album_gain REAL;
+                ALTER TABLE tracks ADD COLUMN album_peak REAL;",
+            )?;
+        }
+        if version < 10 {
+            // A NULL loudness means the analysis failed, the track isn't tried again
+            self.conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS loudness (
+                    track_id    INTEGER PRIMARY KEY REFERENCES tracks(id) ON DELETE CASCADE,
+                    integrated  REAL,
+                    true_peak   REAL,
+                    analysed_at INTEGER NOT NULL
+                );",
+            )?;
+        }
+        if version < 11 {
+            // `days` is a bitmask with Sunday as bit 0, 0 for every day
+            self.conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS schedules (
+                    id            INTEGER PRIMARY KEY,
+                    name          TEXT NOT NULL UNIQUE,
+                    minute_of_day INTEGER NOT NULL,
+                    days          INTEGER NOT NULL,
+                    action        TEXT NOT NULL,
+                    enabled       INTEGER NOT NULL,
+                    last_run      INTEGER
+                );",
+            )?;
+        }
+        if version < 12 {
+            // A single row, the state is JSON
+            self.conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS player_state (
+                    id       INTEGER PRIMARY KEY CHECK (id = 1),
+                    state    TEXT NOT NULL,
+                    saved_at INTEGER NOT NULL
+                );",
+            )?;
+        }
+        if version < 13 {
+            // The player state is kept per zone, the state from before zones is the default one's
+            self.conn.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS zones (
+                    id      INTEGER PRIMARY KEY,
+                    name    TEXT NOT NULL UNIQUE,
+                    output  TEXT NOT NULL,
+                    device  TEXT
+                );
+                CREATE TABLE IF NOT EXISTS zone_state (
+                    zone     TEXT PRIMARY KEY,
+                    state    TEXT NOT NULL,
+                    saved_at INTEGER NOT NULL
+                );
+                INSERT OR IGNORE INTO zone_state (zone, state, saved_at)
+                    SELECT '{}', state, saved_at FROM player_state;
+                DROP TABLE player_state;",
+                DEFAULT_ZONE
+            ))?;
+        }
+        if version < 15 {
+            // Play counts come from the play history, which smart playlists can sort by
+            self.conn.execute_batch(
+                "CREATE TRIGGER IF NOT EXISTS smart_playlists_play_insert AFTER INSERT ON play_history
+                BEGIN UPDATE playlists SET needs_refresh = 1 WHERE rules IS NOT NULL; END;",
+            )?;
+        }
+
+        self.conn.execute_batch(MUSICINFO_VIEW)?;
+        self.import_legacy_musicinfo()?;
+
+        self.conn
+            .execute_batch(&format!("PRAGMA user_version = {};", SCHEMA_VERSION))?;
+        Ok(())
+    }
+
+    /// Create the artists/albums/tracks tables
+    ///
+    /// Databases created before the schema was normalized have `musicinfo` as a plain
+    /// table; it is set aside as `musicinfo_legacy` so `import_legacy_musicinfo` can move
+    /// its rows over once the rest of the schema is in place.
+    fn create_normalized_schema(&self) -> Result<(), DatabaseCreationError> {
+        let musicinfo_type: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT type FROM sqlite_master WHERE name = 'musicinfo'",
+                params![],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        if musicinfo_type.as_deref() == Some("table") {
+            self.conn
+                .execute_batch("ALTER TABLE musicinfo RENAME TO musicinfo_legacy;")?;
+        }
+
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS artists (
+                id   INTEGER PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE
+            );
+
+            CREATE TABLE IF NOT EXISTS albums (
+                id              INTEGER PRIMARY KEY,
+                title           TEXT NOT NULL,
+                album_artist_id INTEGER REFERENCES artists(id) ON DELETE SET NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS tracks (
+                id       INTEGER PRIMARY KEY,
+                path     TEXT NOT NULL UNIQUE,
+                title    TEXT NOT NULL,
+                album_id INTEGER REFERENCES albums(id) ON DELETE SET NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS track_artists (
+                track_id  INTEGER NOT NULL REFERENCES tracks(id) ON DELETE CASCADE,
+                artist_id INTEGER NOT NULL REFERENCES artists(id) ON DELETE CASCADE,
+                position  INTEGER NOT NULL,
+                PRIMARY KEY (track_id, artist_id)
+            );
+
+            CREATE INDEX IF NOT EXISTS track_artists_artist ON track_artists(artist_id);
+            CREATE INDEX IF NOT EXISTS tracks_album ON tracks(album_id);",
+        )?;
+
+        Ok(())
+    }
+
+    /// Move the rows of a pre-normalization `musicinfo` table into the library
+    fn import_legacy_musicinfo(&self) -> Result<(), DatabaseCreationError> {
+        let has_legacy_table: bool = self.conn.query_row(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'musicinfo_legacy'",
             params![],
+            |row| row.get(0),
+        )?;
+        if !has_legacy_table {
+            return Ok(());
+        }
+
+        let legacy_tags = {
+            let mut stmt = self.conn.prepare(
+                "SELECT path, title, COALESCE(artist, ''), COALESCE(album, ''), COALESCE(album_artist, '')
+                FROM musicinfo_legacy",
+            )?;
+            let rows = stmt.query_map(params![], |row| {
+                Ok(ItemTag {
+                    path: row.get(0)?,
+                    title: row.get(1)?,
+                    artist: row.get(2)?,
+                    album: row.get(3)?,
+                    album_artist: row.get(4)?,
+                    ..ItemTag::default()
+                })
+            })?;
+            rows.collect::<Result<Vec<ItemTag>, rusqlite::Error>>()?
+        };
+
+        for tag in legacy_tags.iter() {
+            self.save_tag(tag)?;
+        }
+
+        self.conn.execute_batch("DROP TABLE musicinfo_legacy;")?;
+        Ok(())
+    }
+
+    /// Returns the id of the artist with `name`, creating the artist if it doesn't exist yet
+    fn get_or_create_artist(&self, name: &str) -> Result<i64, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO artists (name) VALUES (?1)",
+            params![name],
         )?;
+        self.conn.query_row(
+            "SELECT id FROM artists WHERE name = ?1",
+            params![name],
+            |row| row.get(0),
+        )
+    }
 
-        Ok(DBObject { conn })
+    /// Returns the id of the album, creating the album if it doesn't exist yet
+    ///
+    /// Albums are identified by their title and album artist, so two albums that share a
+    /// name but not an artist (e.g. "Greatest Hits") stay separate.
+    fn get_or_create_album(
+        &self,
+        title: &str,
+        album_artist: &str,
+    ) -> Result<i64, rusqlite::Error> {
+        let album_artist_id = if album_artist.is_empty() {
+            None
+        } else {
+            Some(self.get_or_create_artist(album_artist)?)
+        };
+
+        let existing: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT id FROM albums WHERE title = ?1 AND album_artist_id IS ?2",
+                params![title, album_artist_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match existing {
+            Some(id) => Ok(id),
+            None => {
+                self.conn.execute(
+                    "INSERT INTO albums (title, album_artist_id) VALUES (?1, ?2)",
+                    params![title, album_artist_id],
+                )?;
+                Ok(self.conn.last_insert_rowid())
+            }
+        }
     }
 
+    /// Save a tag to the library
+    ///
+    /// The flattened `ItemTag` is split up into its artists, album and track rows.
+    /// Tracks are keyed by path, so saving a path that is already in the library is a no-op.
     pub fn save_tag(&self, tag: &ItemTag) -> Result<(), DatabaseCreationError> {
+        let transaction = self.conn.unchecked_transaction()?;
+
+        let album_id = if tag.album.is_empty() {
+            None
+        } else {
+            Some(self.get_or_create_album(&tag.album, &tag.album_artist)?)
+        };
+
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO tracks (path, title, album_id, genre, year, rating, date_added)
+            VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, CAST(strftime('%s', 'now') AS INTEGER) )",
+            params![tag.path, tag.title, album_id, tag.genre, tag.year, tag.rating],
+        )?;
+
+        if inserted == 0 && tag.rating.is_some() {
+            // Ratings read from the file fill in tracks that haven't been rated in the library yet
+            self.conn.execute(
+                "UPDATE tracks SET rating = ?2 WHERE path = ?1 AND rating IS NULL",
+                params![tag.path, tag.rating],
+            )?;
+        }
+
+        // ReplayGain values only come from the file, so a rescan picks up newly analysed tracks
+        self.set_replaygain(&tag.path, &tag.replaygain)?;
+
+        if inserted > 0 {
+            let track_id = self.conn.last_insert_rowid();
+            for (position, artist) in split_artists(&tag.artist).iter().enumerate() {
+                let artist_id = self.get_or_create_artist(artist)?;
+                self.conn.execute(
+                    "INSERT OR IGNORE INTO track_artists (track_id, artist_id, position) VALUES ( ?1, ?2, ?3 )",
+                    params![track_id, artist_id, position],
+                )?;
+            }
+        }
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn set_replaygain(&self, path: &str, replaygain: &ReplayGain) -> Result<(), rusqlite::Error> {
+        self.conn.execute(
+            "UPDATE tracks SET track_gain = ?2, track_peak = ?3, album_gain = ?4, album_peak = ?5
+            WHERE path = ?1",
+            params![
+                path,
+                replaygain.track_gain,
+                replaygain.track_peak,
+                replaygain.album_gain,
+                replaygain.album_peak
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Replace the metadata of a track that is already in the library with `tag`
+    ///
+    /// Only the fields read from the file's tags are changed; the rating, favorite and
+    /// labels stay as they are. Albums and artists left without tracks are removed.
+    pub fn update_tag(&self, tag: &ItemTag) -> Result<(), rusqlite::Error> {
+        let track_id = self.get_track_id(&tag.path)?;
+        let transaction = self.conn.unchecked_transaction()?;
+
+        let album_id = if tag.album.is_empty() {
+            None
+        } else {
+            Some(self.get_or_create_album(&tag.album, &tag.album_artist)?)
+        };
+
+        self.conn.execute(
+            "UPDATE tracks SET title = ?2, album_id = ?3, genre = ?4, year = ?5 WHERE id = ?1",
+            params![track_id, tag.title, album_id, tag.genre, tag.year],
+        )?;
+        self.set_replaygain(&tag.path, &tag.replaygain)?;
+
+        self.conn.execute(
+            "DELETE FROM track_artists WHERE track_id = ?1",
+            params![track_id],
+        )?;
+        for (position, artist) in split_artists(&tag.artist).iter().enumerate() {
+            let artist_id = self.get_or_create_artist(artist)?;
+            self.conn.execute(
+                "INSERT OR IGNORE INTO track_artists (track_id, artist_id, position) VALUES ( ?1, ?2, ?3 )",
+                params![track_id, artist_id, position],
+            )?;
+        }
+
+        self.conn.execute_batch(
+            "DELETE FROM albums WHERE id NOT IN (SELECT album_id FROM tracks WHERE album_id IS NOT NULL);
+            DELETE FROM artists
+            WHERE id NOT IN (SELECT artist_id FROM track_artists)
+            AND id NOT IN (SELECT album_artist_id FROM albums WHERE album_artist_id IS NOT NULL);",
+        )?;
+
+        transaction.commit()
+    }
+
+    /// Returns the library entry for `path`
+    pub fn get_tag_by_path(&self, path: &str) -> Result<Option<ItemTag>, rusqlite::Error> {
+        self.conn
+            .query_row(
+                &format!("SELECT {} FROM musicinfo WHERE path = ?1", MUSICINFO_COLUMNS),
+                params![path],
+                |row| item_tag_from_row(row, 0),
+            )
+            .optional()
+    }
+
+    /// Remember the values a tag edit overwrote, so it can be undone
+    pub fn log_tag_edit(
+        &self,
+        path: &str,
+        previous: &PartialTag,
+        changes: &PartialTag,
+    ) -> Result<(), rusqlite::Error> {
         self.conn.execute(
-            "INSERT OR IGNORE INTO musicinfo (path, title, artist, album, album_artist) VALUES ( ?1, ?2, ?3, ?4, ?5 )",
-            params![tag.path, tag.title, tag.artist, tag.album, tag.album_artist],
+            "INSERT INTO tag_edits (path, edited_at, previous, changes)
+            VALUES (?1, CAST(strftime('%s', 'now') AS INTEGER), ?2, ?3)",
+            params![
+                path,
+                serde_json::to_string(previous).unwrap(),
+                serde_json::to_string(changes).unwrap()
+            ],
         )?;
         Ok(())
     }
 
+    /// Returns the id and overwritten values of the most recent tag edit of `path`
+    pub fn last_tag_edit(&self, path: &str) -> Result<Option<(i64, PartialTag)>, rusqlite::Error> {
+        let last_edit: Option<(i64, String)> = self
+            .conn
+            .query_row(
+                "SELECT id, previous FROM tag_edits WHERE path = ?1 ORDER BY id DESC LIMIT 1",
+                params![path],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        match last_edit {
+            None => Ok(None),
+            Some((id, previous)) => {
+                let previous = serde_json::from_str(&previous).map_err(|err| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        0,
+                        rusqlite::types::Type::Text,
+                        Box::new(err),
+                    )
+                })?;
+                Ok(Some((id, previous)))
+            }
+        }
+    }
+
+    /// Remove a tag edit from the log once it has been undone
+    pub fn delete_tag_edit(&self, id: i64) -> Result<(), rusqlite::Error> {
+        self.conn
+            .execute("DELETE FROM tag_edits WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Rename an artist everywhere it is used, as a track artist or as an album artist
+    ///
+    /// When an artist named `new_name` already exists, like when fixing a misspelling, the
+    /// two are merged into it. Their albums with the same title are merged as well.
+    pub fn rename_artist(&self, old_name: &str, new_name: &str) -> Result<(), rusqlite::Error> {
+        let transaction = self.conn.unchecked_transaction()?;
+        let find_artist = |name: &str| {
+            self.conn
+                .query_row(
+                    "SELECT id FROM artists WHERE name = ?1",
+                    params![name],
+                    |row| row.get::<_, i64>(0),
+                )
+                .optional()
+        };
+
+        let (old_id, existing_id) = match (find_artist(old_name)?, find_artist(new_name)?) {
+            (Some(old_id), Some(existing_id)) if old_id != existing_id => (old_id, existing_id),
+            (Some(_), None) => {
+                self.conn.execute(
+                    "UPDATE artists SET name = ?2 WHERE name = ?1",
+                    params![old_name, new_name],
+                )?;
+                return transaction.commit();
+            }
+            _ => return Ok(()),
+        };
+
+        // A track listing both artists keeps the position of the one that already existed
+        self.conn.execute(
+            "UPDATE OR IGNORE track_artists SET artist_id = ?2 WHERE artist_id = ?1",
+            params![old_id, existing_id],
+        )?;
+        self.conn.execute(
+            "DELETE FROM track_artists WHERE artist_id = ?1",
+            params![old_id],
+        )?;
+
+        self.conn.execute(
+            "UPDATE tracks SET album_id = (
+                SELECT kept.id FROM albums AS kept JOIN albums AS merged ON kept.title = merged.title
+                WHERE merged.id = tracks.album_id AND kept.album_artist_id = ?2
+            )
+            WHERE album_id IN (
+                SELECT id FROM albums WHERE album_artist_id = ?1
+                AND title IN (SELECT title FROM albums WHERE album_artist_id = ?2)
+            )",
+            params![old_id, existing_id],
+        )?;
+        self.conn.execute(
+            "DELETE FROM albums WHERE album_artist_id = ?1
+            AND title IN (SELECT title FROM albums WHERE album_artist_id = ?2)",
+            params![old_id, existing_id],
+        )?;
+        self.conn.execute(
+            "UPDATE albums SET album_artist_id = ?2 WHERE album_artist_id = ?1",
+            params![old_id, existing_id],
+        )?;
+
+        self.conn
+            .execute("DELETE FROM artists WHERE id = ?1", params![old_id])?;
+        transaction.commit()
+    }
+
     /// Returns a vector of ItemTags that fulfil the requested query
     ///
     pub fn get(
         &self,
         request: &DatabaseRequest,
+    ) -> Result<Option<Vec<ItemTag>>, rusqlite::Error> {
+        self.get_sorted(request, None, false)
+    }
+
+    /// Returns a vector of ItemTags that fulfil the requested query, in the requested order
+    ///
+    pub fn get_sorted(
+        &self,
+        request: &DatabaseRequest,
+        sort_by: Option<SmartSort>,
+        descending: bool,
     ) -> Result<Option<Vec<ItemTag>>, rusqlite::Error> {
         assert!(!request.search_tag.is_empty(), "There must be at least one field filled in the PartialItem. Use `get_all()` if you want the full table");
 
@@ -180,6 +899,15 @@ impl DBObject {
             ));
         }
 
+        if request.search_tag.has_genre() {
+            conditions.push(format!(
+                "genre = {}{}{}",
+                does_have_wild.0,
+                request.search_tag.genre.clone().unwrap(),
+                does_have_wild.1,
+            ));
+        }
+
         let condition: String;
 
         match request.search_type {
@@ -192,21 +920,56 @@ impl DBObject {
             }
         }
 
-        let req_string: String =
-            String::from("SELECT * FROM musicinfo WHERE ") + condition.as_str();
+        // These don't get turned into LIKE comparisons, so they are added after the text fields
+        let mut exact_conditions = Vec::<String>::new();
+        if !condition.is_empty() {
+            exact_conditions.push(condition);
+        }
+
+        if let Some(year) = request.search_tag.year {
+            exact_conditions.push(format!("year = {}", year));
+        }
+
+        if let Some(favorite) = request.search_tag.favorite {
+            exact_conditions.push(format!("favorite = {}", favorite as u8));
+        }
+
+        if let Some(min_rating) = request.search_tag.min_rating {
+            exact_conditions.push(format!("rating >= {}", min_rating));
+        }
+
+        if request.search_tag.has_label() {
+            exact_conditions.push(format!(
+                "path IN (SELECT tracks.path FROM tracks
+                    JOIN track_labels ON track_labels.track_id = tracks.id
+                    JOIN labels ON labels.id = track_labels.label_id
+                    WHERE labels.name = '{}')",
+                request.search_tag.label.clone().unwrap()
+            ));
+        }
+
+        // Lyrics are always searched for as a part of the text
+        if request.search_tag.has_lyrics() {
+            exact_conditions.push(format!(
+                "path IN (SELECT tracks.path FROM tracks
+                    JOIN lyrics ON lyrics.track_id = tracks.id
+                    WHERE lyrics.text LIKE '%{}%')",
+                request.search_tag.lyrics.clone().unwrap()
+            ));
+        }
+
+        let mut req_string: String = format!("SELECT {} FROM musicinfo WHERE ", MUSICINFO_COLUMNS)
+            + exact_conditions.join(" AND ").as_str();
+
+        if let Some(sort_by) = sort_by {
+            req_string.push_str(" ORDER BY ");
+            req_string.push_str(&sort_by.order_by(descending));
+        }
 
         println!("Running sql: {}", req_string.clone());
         let mut stmt = self.conn.prepare(req_string.as_str())?;
 
-        let ret_iter = stmt.query_map([], |row| {
-            Ok(ItemTag {
-                path: row.get(0)?,
-                title: row.get(1)?,
-                artist: row.get(2)?,
-                album: row.get(3)?,
-                album_artist: row.get(4)?,
-            })
-        })?;
+        let ret_iter = stmt.query_map([], |row| item_tag_from_row(row, 0))?;
 
         let mut ret = Vec::<ItemTag>::new();
         for item in ret_iter {
@@ -219,56 +982,891 @@ impl DBObject {
             Ok(Some(ret))
         }
     }
-}
 
-#[test]
-fn test_database_get_title() {
-    let db_object =
-        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+    /// Remove a track from the library
+    ///
+    /// Playlist entries pointing at the track are kept and reported as missing.
+    pub fn remove_tag(&self, path: &str) -> Result<(), rusqlite::Error> {
+        self.conn
+            .execute("DELETE FROM tracks WHERE path = ?1", params![path])?;
+        Ok(())
+    }
 
-    let item = ItemTag {
-        path: "/path/to/music.mp3".to_string(),
-        title: "An example song title".to_string(),
-        artist: "An example artist".to_string(),
-        album: "An example album".to_string(),
-        album_artist: "An example album artist".to_string(),
-    };
+    /// Remove every track whose file no longer exists on disk
+    ///
+    /// Returns the paths that were removed
+    pub fn remove_missing_tracks(&self) -> Result<Vec<String>, rusqlite::Error> {
+        let paths = {
+            let mut stmt = self.conn.prepare("SELECT path FROM tracks")?;
+            let rows = stmt.query_map(params![], |row| row.get::<_, String>(0))?;
+            rows.collect::<Result<Vec<String>, rusqlite::Error>>()?
+        };
 
-    db_object.save_tag(&item).unwrap();
+        let mut removed = Vec::<String>::new();
+        for path in paths {
+            if !std::path::Path::new(&path).exists() {
+                self.remove_tag(&path)?;
+                removed.push(path);
+            }
+        }
+        Ok(removed)
+    }
 
-    let request = DatabaseRequest {
-        search_type: SearchType::Where,
-        search_tag: PartialTag {
-            title: Some("An example song title".to_string()),
-            ..PartialTag::default()
-        },
-    };
+    fn get_playlist_id(&self, name: &str) -> Result<i64, rusqlite::Error> {
+        self.conn.query_row(
+            "SELECT id FROM playlists WHERE name = ?1",
+            params![name],
+            |row| row.get(0),
+        )
+    }
 
-    let ret = db_object.get(&request).unwrap();
+    /// Returns the id of a playlist whose entries can be edited, which excludes smart playlists
+    fn get_static_playlist_id(&self, name: &str) -> Result<i64, rusqlite::Error> {
+        self.conn.query_row(
+            "SELECT id FROM playlists WHERE name = ?1 AND rules IS NULL",
+            params![name],
+            |row| row.get(0),
+        )
+    }
 
-    assert!(ret.is_some());
-    assert_eq!(ret.unwrap()[0].artist, "An example artist".to_string());
-}
+    /// Create a smart playlist, or replace the rules of an existing one
+    pub fn save_smart_playlist(
+        &self,
+        name: &str,
+        rules: &SmartPlaylistRules,
+    ) -> Result<(), rusqlite::Error> {
+        let rules_json = serde_json::to_string(rules).unwrap();
+        self.conn.execute(
+            "INSERT INTO playlists (name, rules, needs_refresh) VALUES (?1, ?2, 1)
+            ON CONFLICT(name) DO UPDATE SET rules = ?2, needs_refresh = 1
+            WHERE rules IS NOT NULL",
+            params![name, rules_json],
+        )?;
+        // The conflict clause leaves static playlists alone, so check the rules were stored
+        self.get_smart_playlist_rules(name)?;
+        Ok(())
+    }
 
-#[test]
-fn test_database_get_path() {
-    let db_object =
-        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+    /// Returns the rules of a smart playlist
+    pub fn get_smart_playlist_rules(
+        &self,
+        name: &str,
+    ) -> Result<SmartPlaylistRules, rusqlite::Error> {
+        let rules_json: String = self.conn.query_row(
+            "SELECT rules FROM playlists WHERE name = ?1 AND rules IS NOT NULL",
+            params![name],
+            |row| row.get(0),
+        )?;
+        serde_json::from_str(&rules_json).map_err(|err| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err))
+        })
+    }
 
-    let item = ItemTag {
-        path: "/path/to/music.mp3".to_string(),
-        title: "An example song title".to_string(),
-        artist: "An example artist".to_string(),
-        album: "An example album".to_string(),
-        album_artist: "An example album artist".to_string(),
-    };
+    /// Re-evaluate a smart playlist against the library if it is out of date
+    ///
+    /// Smart playlists are flagged by triggers whenever the library changes; ones that
+    /// depend on the current time are refreshed every time. Static playlists are left alone.
+    fn refresh_smart_playlist_if_needed(&self, playlist_id: i64) -> Result<(), rusqlite::Error> {
+        let (rules_json, needs_refresh): (Option<String>, bool) = self.conn.query_row(
+            "SELECT rules, needs_refresh FROM playlists WHERE id = ?1",
+            params![playlist_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
 
-    db_object.save_tag(&item).unwrap();
-    let request = DatabaseRequest {
-        search_type: SearchType::Where,
-        search_tag: PartialTag {
-            path: Some("/path/to/music.mp3".to_string()),
-            ..PartialTag::default()
+        let rules: SmartPlaylistRules = match rules_json {
+            None => return Ok(()),
+            Some(rules_json) => serde_json::from_str(&rules_json).map_err(|err| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    0,
+                    rusqlite::types::Type::Text,
+                    Box::new(err),
+                )
+            })?,
+        };
+        if !needs_refresh && !rules.is_time_relative() {
+            return Ok(());
+        }
+
+        let (query, parameters) = rules.to_sql();
+        let paths = {
+            let mut stmt = self.conn.prepare(&query)?;
+            let rows = stmt.query_map(params_from_iter(parameters), |row| row.get::<_, String>(0))?;
+            rows.collect::<Result<Vec<String>, rusqlite::Error>>()?
+        };
+
+        let transaction = self.conn.unchecked_transaction()?;
+        self.write_playlist_paths(playlist_id, &paths)?;
+        self.conn.execute(
+            "UPDATE playlists SET needs_refresh = 0 WHERE id = ?1",
+            params![playlist_id],
+        )?;
+        transaction.commit()
+    }
+
+    /// Re-evaluate every smart playlist that is out of date
+    pub fn refresh_smart_playlists(&self) -> Result<(), rusqlite::Error> {
+        let playlist_ids = {
+            let mut stmt = self
+                .conn
+                .prepare("SELECT id FROM playlists WHERE rules IS NOT NULL")?;
+            let rows = stmt.query_map(params![], |row| row.get::<_, i64>(0))?;
+            rows.collect::<Result<Vec<i64>, rusqlite::Error>>()?
+        };
+
+        for playlist_id in playlist_ids {
+            self.refresh_smart_playlist_if_needed(playlist_id)?;
+        }
+        Ok(())
+    }
+
+    pub fn create_playlist(&self, name: &str) -> Result<(), rusqlite::Error> {
+        self.conn
+            .execute("INSERT INTO playlists (name) VALUES (?1)", params![name])?;
+        Ok(())
+    }
+
+    pub fn rename_playlist(&self, name: &str, new_name: &str) -> Result<(), rusqlite::Error> {
+        let playlist_id = self.get_playlist_id(name)?;
+        self.conn.execute(
+            "UPDATE playlists SET name = ?2 WHERE id = ?1",
+            params![playlist_id, new_name],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_playlist(&self, name: &str) -> Result<(), rusqlite::Error> {
+        let playlist_id = self.get_playlist_id(name)?;
+        self.conn
+            .execute("DELETE FROM playlists WHERE id = ?1", params![playlist_id])?;
+        Ok(())
+    }
+
+    /// Returns every stored playlist, sorted by name
+    pub fn list_playlists(&self) -> Result<Vec<Playlist>, rusqlite::Error> {
+        self.refresh_smart_playlists()?;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT playlists.name,
+                COUNT(playlist_entries.path),
+                COUNT(playlist_entries.path) - COUNT(tracks.id),
+                playlists.rules IS NOT NULL
+            FROM playlists
+            LEFT JOIN playlist_entries ON playlist_entries.playlist_id = playlists.id
+            LEFT JOIN tracks ON tracks.path = playlist_entries.path
+            GROUP BY playlists.id
+            ORDER BY playlists.name",
+        )?;
+
+        let rows = stmt.query_map(params![], |row| {
+            Ok(Playlist {
+                name: row.get(0)?,
+                track_count: row.get(1)?,
+                missing_count: row.get(2)?,
+                smart: row.get(3)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Returns the entries of a playlist in order
+    pub fn get_playlist_entries(&self, name: &str) -> Result<Vec<PlaylistEntry>, rusqlite::Error> {
+        let playlist_id = self.get_playlist_id(name)?;
+        self.refresh_smart_playlist_if_needed(playlist_id)?;
+
+        let columns: Vec<String> = MUSICINFO_COLUMNS
+            .split(", ")
+            .map(|column| format!("musicinfo.{}", column))
+            .collect();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT playlist_entries.position, playlist_entries.path, musicinfo.path IS NULL, {}
+            FROM playlist_entries
+            LEFT JOIN musicinfo ON musicinfo.path = playlist_entries.path
+            WHERE playlist_entries.playlist_id = ?1
+            ORDER BY playlist_entries.position",
+            columns.join(", ")
+        ))?;
+
+        let rows = stmt.query_map(params![playlist_id], |row| {
+            let missing: bool = row.get(2)?;
+            Ok(PlaylistEntry {
+                position: row.get(0)?,
+                missing,
+                tag: if missing {
+                    ItemTag {
+                        path: row.get(1)?,
+                        ..ItemTag::default()
+                    }
+                } else {
+                    item_tag_from_row(row, 3)?
+                },
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Returns the tags of a playlist that are still in the library, in playlist order
+    pub fn get_playlist_tracks(&self, name: &str) -> Result<Vec<ItemTag>, rusqlite::Error> {
+        Ok(self
+            .get_playlist_entries(name)?
+            .into_iter()
+            .filter(|entry| !entry.missing)
+            .map(|entry| entry.tag)
+            .collect())
+    }
+
+    /// Append tracks to the end of a playlist
+    pub fn add_to_playlist(&self, name: &str, tags: &[ItemTag]) -> Result<(), rusqlite::Error> {
+        let playlist_id = self.get_static_playlist_id(name)?;
+        let transaction = self.conn.unchecked_transaction()?;
+
+        let first_position: usize = self.conn.query_row(
+            "SELECT COALESCE(MAX(position) + 1, 0) FROM playlist_entries WHERE playlist_id = ?1",
+            params![playlist_id],
+            |row| row.get(0),
+        )?;
+
+        for (offset, tag) in tags.iter().enumerate() {
+            self.conn.execute(
+                "INSERT INTO playlist_entries (playlist_id, position, path) VALUES (?1, ?2, ?3)",
+                params![playlist_id, first_position + offset, tag.path],
+            )?;
+        }
+
+        transaction.commit()
+    }
+
+    /// Rewrite a playlist's entries with the paths in order
+    fn write_playlist_paths(&self, playlist_id: i64, paths: &[String]) -> Result<(), rusqlite::Error> {
+        self.conn.execute(
+            "DELETE FROM playlist_entries WHERE playlist_id = ?1",
+            params![playlist_id],
+        )?;
+        for (position, path) in paths.iter().enumerate() {
+            self.conn.execute(
+                "INSERT INTO playlist_entries (playlist_id, position, path) VALUES (?1, ?2, ?3)",
+                params![playlist_id, position, path],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Remove the entry at `position`, shifting the following entries up
+    pub fn remove_from_playlist(&self, name: &str, position: usize) -> Result<(), rusqlite::Error> {
+        let playlist_id = self.get_static_playlist_id(name)?;
+        let mut paths: Vec<String> = self
+            .get_playlist_entries(name)?
+            .into_iter()
+            .map(|entry| entry.tag.path)
+            .collect();
+
+        if position >= paths.len() {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        paths.remove(position);
+
+        let transaction = self.conn.unchecked_transaction()?;
+        self.write_playlist_paths(playlist_id, &paths)?;
+        transaction.commit()
+    }
+
+    /// Move the entry at `from` so that it ends up at position `to`
+    pub fn move_playlist_entry(
+        &self,
+        name: &str,
+        from: usize,
+        to: usize,
+    ) -> Result<(), rusqlite::Error> {
+        let playlist_id = self.get_static_playlist_id(name)?;
+        let mut paths: Vec<String> = self
+            .get_playlist_entries(name)?
+            .into_iter()
+            .map(|entry| entry.tag.path)
+            .collect();
+
+        if from >= paths.len() || to >= paths.len() {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        let path = paths.remove(from);
+        paths.insert(to, path);
+
+        let transaction = self.conn.unchecked_transaction()?;
+        self.write_playlist_paths(playlist_id, &paths)?;
+        transaction.commit()
+    }
+
+    /// Drop the entries of a playlist whose tracks are no longer in the library
+    ///
+    /// Returns the number of entries that were dropped
+    pub fn prune_missing_from_playlist(&self, name: &str) -> Result<usize, rusqlite::Error> {
+        let playlist_id = self.get_static_playlist_id(name)?;
+        let entries = self.get_playlist_entries(name)?;
+        let paths: Vec<String> = entries
+            .iter()
+            .filter(|entry| !entry.missing)
+            .map(|entry| entry.tag.path.clone())
+            .collect();
+
+        let transaction = self.conn.unchecked_transaction()?;
+        self.write_playlist_paths(playlist_id, &paths)?;
+        transaction.commit()?;
+        Ok(entries.len() - paths.len())
+    }
+
+    /// Returns the library path matching `path`, falling back to a case insensitive match
+    pub fn find_track_path(&self, path: &str) -> Result<Option<String>, rusqlite::Error> {
+        let exact: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT path FROM tracks WHERE path = ?1",
+                params![path],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if exact.is_some() {
+            return Ok(exact);
+        }
+
+        self.conn
+            .query_row(
+                "SELECT path FROM tracks WHERE path = ?1 COLLATE NOCASE",
+                params![path],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Returns the path of a track with the given title, and artist if one is given
+    ///
+    /// Used to resolve playlist entries by their metadata when the path doesn't match.
+    pub fn find_track_by_title(
+        &self,
+        artist: Option<&str>,
+        title: &str,
+    ) -> Result<Option<String>, rusqlite::Error> {
+        self.conn
+            .query_row(
+                "SELECT path FROM musicinfo
+                WHERE title = ?1 COLLATE NOCASE AND (?2 IS NULL OR artist LIKE '%' || ?2 || '%')
+                ORDER BY path LIMIT 1",
+                params![title, artist],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Replace the contents of a playlist, creating the playlist if it doesn't exist yet
+    pub fn set_playlist_paths(&self, name: &str, paths: &[String]) -> Result<(), rusqlite::Error> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO playlists (name) VALUES (?1)",
+            params![name],
+        )?;
+        let playlist_id = self.get_static_playlist_id(name)?;
+
+        let transaction = self.conn.unchecked_transaction()?;
+        self.write_playlist_paths(playlist_id, paths)?;
+        transaction.commit()
+    }
+
+    /// Add a finished listen to the play history
+    pub fn record_play(&self, record: &PlayRecord) -> Result<(), rusqlite::Error> {
+        let started_at = record
+            .started_at
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs() as i64)
+            .unwrap_or(0);
+
+        self.conn.execute(
+            "INSERT INTO play_history (path, started_at, played_ms, completed, counted, skipped)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                record.path,
+                started_at,
+                record.played.as_millis() as i64,
+                record.completed,
+                record.counts_as_play(),
+                record.is_skip()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the most recently played tracks, most recent first
+    pub fn recently_played(&self, limit: usize) -> Result<Vec<ItemTag>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM musicinfo WHERE last_played IS NOT NULL
+            ORDER BY last_played DESC LIMIT ?1",
+            MUSICINFO_COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![limit], |row| item_tag_from_row(row, 0))?;
+        rows.collect()
+    }
+
+    /// Returns the tracks with the highest play counts, most played first
+    pub fn most_played(&self, limit: usize) -> Result<Vec<ItemTag>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM musicinfo WHERE play_count > 0
+            ORDER BY play_count DESC, last_played DESC LIMIT ?1",
+            MUSICINFO_COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![limit], |row| item_tag_from_row(row, 0))?;
+        rows.collect()
+    }
+
+    /// Returns every value of a text tag in the library once, in alphabetical order
+    ///
+    /// Tracks that don't have the tag are left out.
+    pub fn list_tag_values(&self, field: TextField) -> Result<Vec<String>, rusqlite::Error> {
+        let column = field.column();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT DISTINCT {column} FROM musicinfo WHERE {column} <> ''
+            ORDER BY {column} COLLATE NOCASE"
+        ))?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Returns the tracks in a directory and the directories under it, ordered by path
+    pub fn tracks_in_directory(&self, directory: &str) -> Result<Vec<ItemTag>, rusqlite::Error> {
+        let prefix = format!("{}/", directory.trim_end_matches('/'));
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM musicinfo WHERE substr(path, 1, length(?1)) = ?1 ORDER BY path",
+            MUSICINFO_COLUMNS
+        ))?;
+        let rows = stmt.query_map(params![prefix], |row| item_tag_from_row(row, 0))?;
+        rows.collect()
+    }
+
+    /// Returns the id of the track at `path`
+    fn get_track_id(&self, path: &str) -> Result<i64, rusqlite::Error> {
+        self.conn.query_row(
+            "SELECT id FROM tracks WHERE path = ?1",
+            params![path],
+            |row| row.get(0),
+        )
+    }
+
+    /// Returns the path of a track by its id, `None` if there is no such track
+    pub fn get_track_path(&self, track_id: i64) -> Result<Option<String>, rusqlite::Error> {
+        self.conn
+            .query_row(
+                "SELECT path FROM tracks WHERE id = ?1",
+                params![track_id],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Rate a track from 0 to 5 stars, `None` clears the rating
+    pub fn set_rating(&self, path: &str, rating: Option<u8>) -> Result<(), rusqlite::Error> {
+        let track_id = self.get_track_id(path)?;
+        self.conn.execute(
+            "UPDATE tracks SET rating = ?2 WHERE id = ?1",
+            params![track_id, rating.map(|rating| rating.min(5))],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_favorite(&self, path: &str, favorite: bool) -> Result<(), rusqlite::Error> {
+        let track_id = self.get_track_id(path)?;
+        self.conn.execute(
+            "UPDATE tracks SET favorite = ?2 WHERE id = ?1",
+            params![track_id, favorite],
+        )?;
+        Ok(())
+    }
+
+    /// Attach a label to a track, labels are created the first time they are used
+    pub fn add_label(&self, path: &str, label: &str) -> Result<(), rusqlite::Error> {
+        let track_id = self.get_track_id(path)?;
+        self.conn.execute(
+            "INSERT OR IGNORE INTO labels (name) VALUES (?1)",
+            params![label],
+        )?;
+        self.conn.execute(
+            "INSERT OR IGNORE INTO track_labels (track_id, label_id)
+            SELECT ?1, id FROM labels WHERE name = ?2",
+            params![track_id, label],
+        )?;
+        Ok(())
+    }
+
+    /// Detach a label from a track, labels that are no longer used are removed
+    pub fn remove_label(&self, path: &str, label: &str) -> Result<(), rusqlite::Error> {
+        let track_id = self.get_track_id(path)?;
+        self.conn.execute(
+            "DELETE FROM track_labels
+            WHERE track_id = ?1 AND label_id = (SELECT id FROM labels WHERE name = ?2)",
+            params![track_id, label],
+        )?;
+        self.conn.execute(
+            "DELETE FROM labels WHERE id NOT IN (SELECT label_id FROM track_labels)",
+            params![],
+        )?;
+        Ok(())
+    }
+
+    /// Returns every label in use, sorted by name
+    pub fn list_labels(&self) -> Result<Vec<String>, rusqlite::Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name FROM labels ORDER BY name COLLATE NOCASE")?;
+        let rows = stmt.query_map(params![], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    /// Returns the id of the artwork with `hash`, adding it if it isn't known yet
+    pub fn save_artwork(&self, hash: &str, mime: &str) -> Result<i64, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO artwork (hash, mime) VALUES (?1, ?2)",
+            params![hash, mime],
+        )?;
+        self.conn.query_row(
+            "SELECT id FROM artwork WHERE hash = ?1",
+            params![hash],
+            |row| row.get(0),
+        )
+    }
+
+    /// Link artwork to a track, the track's album gets it too if it has none yet
+    pub fn set_track_artwork(&self, path: &str, artwork_id: i64) -> Result<(), rusqlite::Error> {
+        let track_id = self.get_track_id(path)?;
+        self.conn.execute(
+            "UPDATE tracks SET artwork_id = ?2 WHERE id = ?1",
+            params![track_id, artwork_id],
+        )?;
+        self.conn.execute(
+            "UPDATE albums SET artwork_id = ?2
+            WHERE id = (SELECT album_id FROM tracks WHERE id = ?1) AND artwork_id IS NULL",
+            params![track_id, artwork_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_artwork(&self, artwork_id: i64) -> Result<Option<ArtworkInfo>, rusqlite::Error> {
+        self.conn
+            .query_row(
+                "SELECT id, hash, mime FROM artwork WHERE id = ?1",
+                params![artwork_id],
+                |row| {
+                    Ok(ArtworkInfo {
+                        id: row.get(0)?,
+                        hash: row.get(1)?,
+                        mime: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    /// Store the lyrics of a track, replacing any it had
+    pub fn save_lyrics(&self, path: &str, lyrics: &Lyrics) -> Result<(), rusqlite::Error> {
+        let track_id = self.get_track_id(path)?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO lyrics (track_id, synced, text, lines) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                track_id,
+                lyrics.synced,
+                lyrics.text(),
+                serde_json::to_string(&lyrics.lines).unwrap()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_lyrics(&self, path: &str) -> Result<(), rusqlite::Error> {
+        self.conn.execute(
+            "DELETE FROM lyrics WHERE track_id = (SELECT id FROM tracks WHERE path = ?1)",
+            params![path],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_lyrics(&self, path: &str) -> Result<Option<Lyrics>, rusqlite::Error> {
+        self.conn
+            .query_row(
+                "SELECT lyrics.synced, lyrics.lines FROM lyrics
+                JOIN tracks ON tracks.id = lyrics.track_id
+                WHERE tracks.path = ?1",
+                params![path],
+                |row| {
+                    let lines: String = row.get(1)?;
+                    Ok(Lyrics {
+                        synced: row.get(0)?,
+                        lines: serde_json::from_str(&lines).unwrap_or_default(),
+                    })
+                },
+            )
+            .optional()
+    }
+
+    /// The next track without ReplayGain tags that hasn't been analysed yet
+    pub fn next_track_to_analyse(&self) -> Result<Option<String>, rusqlite::Error> {
+        self.conn
+            .query_row(
+                "SELECT path FROM tracks
+                WHERE track_gain IS NULL AND id NOT IN (SELECT track_id FROM loudness)
+                ORDER BY id LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Store the analysed loudness of a track, `None` if the analysis failed
+    pub fn save_loudness(
+        &self,
+        path: &str,
+        loudness: Option<&Loudness>,
+    ) -> Result<(), rusqlite::Error> {
+        let track_id = self.get_track_id(path)?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO loudness (track_id, integrated, true_peak, analysed_at)
+            VALUES (?1, ?2, ?3, CAST(strftime('%s', 'now') AS INTEGER))",
+            params![
+                track_id,
+                loudness.map(|loudness| loudness.integrated),
+                loudness.map(|loudness| loudness.true_peak)
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The number of tracks without ReplayGain tags that were analysed, and how many there are
+    pub fn loudness_analysis_counts(&self) -> Result<(usize, usize), rusqlite::Error> {
+        self.conn.query_row(
+            "SELECT COUNT(loudness.track_id), COUNT(*) FROM tracks
+            LEFT JOIN loudness ON loudness.track_id = tracks.id
+            WHERE tracks.track_gain IS NULL",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+
+    /// Store a schedule, or replace the one with the same name
+    pub fn save_schedule(&self, schedule: &Schedule) -> Result<(), rusqlite::Error> {
+        let action_json = serde_json::to_string(&schedule.action).unwrap();
+        self.conn.execute(
+            "INSERT INTO schedules (name, minute_of_day, days, action, enabled)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT(name) DO UPDATE SET
+                minute_of_day = ?2, days = ?3, action = ?4, enabled = ?5, last_run = NULL",
+            params![
+                schedule.name,
+                schedule.minute_of_day(),
+                schedule.days_mask(),
+                action_json,
+                schedule.enabled
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_schedule(&self, name: &str) -> Result<(), rusqlite::Error> {
+        let deleted = self
+            .conn
+            .execute("DELETE FROM schedules WHERE name = ?1", params![name])?;
+        if deleted == 0 {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        Ok(())
+    }
+
+    pub fn set_schedule_enabled(&self, name: &str, enabled: bool) -> Result<(), rusqlite::Error> {
+        let updated = self.conn.execute(
+            "UPDATE schedules SET enabled = ?2 WHERE name = ?1",
+            params![name, enabled],
+        )?;
+        if updated == 0 {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        Ok(())
+    }
+
+    /// Returns every schedule, sorted by time of day
+    pub fn list_schedules(&self) -> Result<Vec<Schedule>, rusqlite::Error> {
+        self.query_schedules("SELECT name, minute_of_day, days, action, enabled FROM schedules
+            ORDER BY minute_of_day, name", params![])
+    }
+
+    /// Returns the enabled schedules that should run at the unix time `now`
+    ///
+    /// A schedule is due from its time until `SCHEDULE_GRACE_MINUTES` later in local time,
+    /// on its days, unless it already ran in that time.
+    pub fn due_schedules(&self, now: i64) -> Result<Vec<Schedule>, rusqlite::Error> {
+        self.query_schedules(
+            "SELECT name, minute_of_day, days, action, enabled FROM schedules
+            WHERE enabled
+                AND (days = 0 OR (days >> CAST(strftime('%w', ?1, 'unixepoch', 'localtime') AS INTEGER)) & 1)
+                AND (CAST(strftime('%H', ?1, 'unixepoch', 'localtime') AS INTEGER) * 60
+                    + CAST(strftime('%M', ?1, 'unixepoch', 'localtime') AS INTEGER)
+                    - minute_of_day + 1440) % 1440 < ?2
+                AND (last_run IS NULL OR last_run <= ?1 - ?2 * 60)
+            ORDER BY minute_of_day, name",
+            params![now, SCHEDULE_GRACE_MINUTES],
+        )
+    }
+
+    /// Remember that a schedule ran at the unix time `now`, so it doesn't run again right away
+    pub fn mark_schedule_run(&self, name: &str, now: i64) -> Result<(), rusqlite::Error> {
+        self.conn.execute(
+            "UPDATE schedules SET last_run = ?2 WHERE name = ?1",
+            params![name, now],
+        )?;
+        Ok(())
+    }
+
+    /// Store the player state of a zone, replacing its last one
+    pub fn save_player_state(&self, zone: &str, state: &PlayerState) -> Result<(), rusqlite::Error> {
+        let state_json = serde_json::to_string(state).unwrap();
+        self.conn.execute(
+            "INSERT OR REPLACE INTO zone_state (zone, state, saved_at)
+            VALUES (?1, ?2, CAST(strftime('%s', 'now') AS INTEGER))",
+            params![zone, state_json],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the last saved player state of a zone, if there is one
+    pub fn get_player_state(&self, zone: &str) -> Result<Option<PlayerState>, rusqlite::Error> {
+        let state_json: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT state FROM zone_state WHERE zone = ?1",
+                params![zone],
+                |row| row.get(0),
+            )
+            .optional()?;
+        state_json
+            .map(|state_json| from_json_column(0, &state_json))
+            .transpose()
+    }
+
+    /// Store a zone, or replace the output of the one with the same name
+    pub fn save_zone(&self, zone: &ZoneSettings) -> Result<(), rusqlite::Error> {
+        self.conn.execute(
+            "INSERT INTO zones (name, output, device) VALUES (?1, ?2, ?3)
+            ON CONFLICT(name) DO UPDATE SET output = ?2, device = ?3",
+            params![
+                zone.name,
+                serde_json::to_string(&zone.output).unwrap(),
+                zone.device
+                    .as_ref()
+                    .map(|device| serde_json::to_string(device).unwrap())
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Forget a zone along with its player state
+    pub fn delete_zone(&self, name: &str) -> Result<(), rusqlite::Error> {
+        let deleted = self
+            .conn
+            .execute("DELETE FROM zones WHERE name = ?1", params![name])?;
+        if deleted == 0 {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        self.conn
+            .execute("DELETE FROM zone_state WHERE zone = ?1", params![name])?;
+        Ok(())
+    }
+
+    /// Returns the zones that were created, in the order they were created in
+    pub fn list_zones(&self) -> Result<Vec<ZoneSettings>, rusqlite::Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, output, device FROM zones ORDER BY id")?;
+        let rows = stmt.query_map([], |row| {
+            let output: String = row.get(1)?;
+            let device: Option<String> = row.get(2)?;
+            Ok(ZoneSettings {
+                name: row.get(0)?,
+                output: from_json_column(1, &output)?,
+                device: device
+                    .map(|device| from_json_column(2, &device))
+                    .transpose()?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    fn query_schedules(
+        &self,
+        query: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<Schedule>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(query)?;
+        let rows = stmt.query_map(params, |row| {
+            let minute_of_day: u32 = row.get(1)?;
+            let action_json: String = row.get(3)?;
+            Ok(Schedule {
+                name: row.get(0)?,
+                hour: (minute_of_day / 60) as u8,
+                minute: (minute_of_day % 60) as u8,
+                days: Schedule::days_from_mask(row.get(2)?),
+                action: serde_json::from_str(&action_json).map_err(|err| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        3,
+                        rusqlite::types::Type::Text,
+                        Box::new(err),
+                    )
+                })?,
+                enabled: row.get(4)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+#[test]
+fn test_database_get_title() {
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+
+    let item = ItemTag {
+        path: "/path/to/music.mp3".to_string(),
+        title: "An example song title".to_string(),
+        artist: "An example artist".to_string(),
+        album: "An example album".to_string(),
+        album_artist: "An example album artist".to_string(),
+        ..ItemTag::default()
+    };
+
+    db_object.save_tag(&item).unwrap();
+
+    let request = DatabaseRequest {
+        search_type: SearchType::Where,
+        search_tag: PartialTag {
+            title: Some("An example song title".to_string()),
+            ..PartialTag::default()
+        },
+    };
+
+    let ret = db_object.get(&request).unwrap();
+
+    assert!(ret.is_some());
+    assert_eq!(ret.unwrap()[0].artist, "An example artist".to_string());
+}
+
+#[test]
+fn test_database_get_path() {
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+
+    let item = ItemTag {
+        path: "/path/to/music.mp3".to_string(),
+        title: "An example song title".to_string(),
+        artist: "An example artist".to_string(),
+        album: "An example album".to_string(),
+        album_artist: "An example album artist".to_string(),
+        ..ItemTag::default()
+    };
+
+    db_object.save_tag(&item).unwrap();
+    let request = DatabaseRequest {
+        search_type: SearchType::Where,
+        search_tag: PartialTag {
+            path: Some("/path/to/music.mp3".to_string()),
+            ..PartialTag::default()
         },
     };
 
@@ -289,6 +1887,7 @@ fn test_database_get_artist() {
         artist: "An example artist".to_string(),
         album: "An example album".to_string(),
         album_artist: "An example album artist".to_string(),
+        ..ItemTag::default()
     };
 
     db_object.save_tag(&item).unwrap();
@@ -317,6 +1916,7 @@ fn test_database_get_album() {
         artist: "An example artist".to_string(),
         album: "An example album".to_string(),
         album_artist: "An example album artist".to_string(),
+        ..ItemTag::default()
     };
 
     db_object.save_tag(&item).unwrap();
@@ -345,6 +1945,7 @@ fn test_database_get_album_artist() {
         artist: "An example artist".to_string(),
         album: "An example album".to_string(),
         album_artist: "An example album artist".to_string(),
+        ..ItemTag::default()
     };
 
     db_object.save_tag(&item).unwrap();
@@ -361,3 +1962,716 @@ fn test_database_get_album_artist() {
     assert!(ret.is_some());
     assert_eq!(ret.unwrap()[0].artist, "An example artist".to_string());
 }
+
+#[test]
+fn test_split_artists() {
+    assert_eq!(
+        split_artists("An example artist feat. A guest; Another artist"),
+        vec![
+            "An example artist".to_string(),
+            "A guest".to_string(),
+            "Another artist".to_string()
+        ]
+    );
+    assert_eq!(split_artists("AC/DC"), vec!["AC/DC".to_string()]);
+    assert!(split_artists("").is_empty());
+}
+
+#[test]
+fn test_database_multiple_artists() {
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+
+    let item = ItemTag {
+        path: "/path/to/music.mp3".to_string(),
+        title: "An example song title".to_string(),
+        artist: "An example artist ft. A guest".to_string(),
+        album: "An example album".to_string(),
+        album_artist: "An example artist".to_string(),
+        ..ItemTag::default()
+    };
+
+    db_object.save_tag(&item).unwrap();
+
+    let request = DatabaseRequest {
+        search_type: SearchType::Like,
+        search_tag: PartialTag {
+            artist: Some("A guest".to_string()),
+            ..PartialTag::default()
+        },
+    };
+
+    let ret = db_object.get(&request).unwrap().unwrap();
+    assert_eq!(ret[0].artist, "An example artist; A guest".to_string());
+
+    let artist_count: i64 = db_object
+        .conn
+        .query_row("SELECT COUNT(*) FROM artists", params![], |row| row.get(0))
+        .unwrap();
+    assert_eq!(artist_count, 2);
+}
+
+#[test]
+fn test_database_rename_artist() {
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+
+    for (path, title) in [("/path/to/one.mp3", "One"), ("/path/to/two.mp3", "Two")] {
+        db_object
+            .save_tag(&ItemTag {
+                path: path.to_string(),
+                title: title.to_string(),
+                artist: "An example artist".to_string(),
+                album: "An example album".to_string(),
+                album_artist: "An example artist".to_string(),
+                ..ItemTag::default()
+            })
+            .unwrap();
+    }
+
+    db_object
+        .rename_artist("An example artist", "A renamed artist")
+        .unwrap();
+
+    let request = DatabaseRequest {
+        search_type: SearchType::Where,
+        search_tag: PartialTag {
+            artist: Some("A renamed artist".to_string()),
+            ..PartialTag::default()
+        },
+    };
+
+    let ret = db_object.get(&request).unwrap().unwrap();
+    assert_eq!(ret.len(), 2);
+    assert!(ret.iter().all(|tag| tag.album_artist == "A renamed artist"));
+}
+
+#[test]
+fn test_database_rename_artist_to_existing() {
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+
+    for (path, artist) in [
+        ("/path/to/one.mp3", "The Beatels"),
+        ("/path/to/two.mp3", "The Beatles"),
+        ("/path/to/three.mp3", "The Beatels; The Beatles"),
+    ] {
+        db_object
+            .save_tag(&ItemTag {
+                path: path.to_string(),
+                title: path.to_string(),
+                artist: artist.to_string(),
+                album: "Abbey Road".to_string(),
+                album_artist: artist.split(';').next().unwrap().to_string(),
+                ..ItemTag::default()
+            })
+            .unwrap();
+    }
+
+    db_object.rename_artist("The Beatels", "The Beatles").unwrap();
+
+    for path in ["/path/to/one.mp3", "/path/to/two.mp3", "/path/to/three.mp3"] {
+        let tag = db_object.get_tag_by_path(path).unwrap().unwrap();
+        assert_eq!(tag.artist, "The Beatles");
+        assert_eq!(tag.album_artist, "The Beatles");
+    }
+    let albums: i64 = db_object
+        .conn
+        .query_row("SELECT COUNT(*) FROM albums", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(albums, 1);
+    let misspelled: i64 = db_object
+        .conn
+        .query_row(
+            "SELECT COUNT(*) FROM artists WHERE name = 'The Beatels'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(misspelled, 0);
+}
+
+#[test]
+fn test_database_migrates_legacy_musicinfo() {
+    let db_path = std::env::temp_dir().join(format!("sousa-legacy-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&db_path);
+
+    {
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE musicinfo (
+                path         TEXT PRIMARY KEY,
+                title        TEXT NOT NULL,
+                artist       TEXT,
+                album        TEXT,
+                album_artist TEXT
+            );
+            INSERT INTO musicinfo VALUES ('/path/to/music.mp3', 'An example song title', 'An example artist', 'An example album', NULL);",
+        )
+        .unwrap();
+    }
+
+    let db_object = DBObject::new(&db_path, false).unwrap();
+    let request = DatabaseRequest {
+        search_type: SearchType::Where,
+        search_tag: PartialTag {
+            path: Some("/path/to/music.mp3".to_string()),
+            ..PartialTag::default()
+        },
+    };
+
+    let ret = db_object.get(&request).unwrap().unwrap();
+    assert_eq!(ret[0].artist, "An example artist".to_string());
+    assert_eq!(ret[0].album_artist, String::new());
+
+    drop(db_object);
+    std::fs::remove_file(&db_path).unwrap();
+}
+
+#[test]
+fn test_database_playlists() {
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+
+    let tags: Vec<ItemTag> = ["one", "two", "three"]
+        .iter()
+        .map(|title| ItemTag {
+            path: format!("/path/to/{}.mp3", title),
+            title: title.to_string(),
+            ..ItemTag::default()
+        })
+        .collect();
+    for tag in tags.iter() {
+        db_object.save_tag(tag).unwrap();
+    }
+
+    db_object.create_playlist("An example playlist").unwrap();
+    db_object.add_to_playlist("An example playlist", &tags).unwrap();
+    db_object.move_playlist_entry("An example playlist", 2, 0).unwrap();
+    db_object.remove_from_playlist("An example playlist", 1).unwrap();
+    db_object
+        .rename_playlist("An example playlist", "A renamed playlist")
+        .unwrap();
+
+    let titles: Vec<String> = db_object
+        .get_playlist_tracks("A renamed playlist")
+        .unwrap()
+        .into_iter()
+        .map(|tag| tag.title)
+        .collect();
+    assert_eq!(titles, vec!["three".to_string(), "two".to_string()]);
+
+    // Removing a track from the library keeps its entry around as missing
+    db_object.remove_tag("/path/to/three.mp3").unwrap();
+    let entries = db_object.get_playlist_entries("A renamed playlist").unwrap();
+    assert!(entries[0].missing);
+    assert_eq!(entries[0].tag.path, "/path/to/three.mp3".to_string());
+
+    // Rescanning the file makes the entry resolve again
+    db_object.save_tag(&tags[2]).unwrap();
+    assert!(!db_object.get_playlist_entries("A renamed playlist").unwrap()[0].missing);
+
+    db_object.remove_tag("/path/to/three.mp3").unwrap();
+    assert_eq!(
+        db_object.prune_missing_from_playlist("A renamed playlist").unwrap(),
+        1
+    );
+    let playlists = db_object.list_playlists().unwrap();
+    assert_eq!(playlists[0].track_count, 1);
+    assert_eq!(playlists[0].missing_count, 0);
+
+    db_object.delete_playlist("A renamed playlist").unwrap();
+    assert!(db_object.list_playlists().unwrap().is_empty());
+}
+
+#[test]
+fn test_database_smart_playlists() {
+    use crate::smart_playlists::{NumberOperator, SmartRule, SmartSort, TextField, TextOperator};
+
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+
+    let tags = [("one", "Jazz", 1959), ("two", "jazz", 1975), ("three", "Rock", 1965)];
+    for (title, genre, year) in tags {
+        db_object
+            .save_tag(&ItemTag {
+                path: format!("/path/to/{}.mp3", title),
+                title: title.to_string(),
+                genre: genre.to_string(),
+                year: Some(year),
+                ..ItemTag::default()
+            })
+            .unwrap();
+    }
+
+    let rules = SmartPlaylistRules {
+        match_all: true,
+        rules: vec![
+            SmartRule::Text {
+                field: TextField::Genre,
+                operator: TextOperator::Is,
+                value: "jazz".to_string(),
+            },
+            SmartRule::Year {
+                operator: NumberOperator::LessThan,
+                value: 1970,
+            },
+        ],
+        sort_by: SmartSort::Random,
+        descending: false,
+        limit: Some(100),
+    };
+    db_object.save_smart_playlist("Old jazz", &rules).unwrap();
+
+    let titles = |db_object: &DBObject| -> Vec<String> {
+        db_object
+            .get_playlist_tracks("Old jazz")
+            .unwrap()
+            .into_iter()
+            .map(|tag| tag.title)
+            .collect()
+    };
+    assert_eq!(titles(&db_object), vec!["one".to_string()]);
+
+    // The playlist follows changes to the library
+    db_object
+        .save_tag(&ItemTag {
+            path: "/path/to/four.mp3".to_string(),
+            title: "four".to_string(),
+            genre: "Jazz".to_string(),
+            year: Some(1940),
+            ..ItemTag::default()
+        })
+        .unwrap();
+    assert_eq!(titles(&db_object).len(), 2);
+    db_object.remove_tag("/path/to/one.mp3").unwrap();
+    assert_eq!(titles(&db_object), vec!["four".to_string()]);
+
+    // Smart playlists can't be edited by hand
+    assert!(db_object
+        .remove_from_playlist("Old jazz", 0)
+        .is_err());
+
+    let recent = SmartPlaylistRules {
+        match_all: true,
+        rules: vec![SmartRule::AddedInLast { days: 30 }],
+        sort_by: SmartSort::Title,
+        descending: false,
+        limit: None,
+    };
+    db_object.save_smart_playlist("Recently added", &recent).unwrap();
+    assert_eq!(
+        db_object.get_playlist_tracks("Recently added").unwrap().len(),
+        3
+    );
+    assert!(db_object.list_playlists().unwrap().iter().all(|playlist| playlist.smart));
+}
+
+#[test]
+fn test_database_play_history() {
+    use std::time::{Duration, SystemTime};
+
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+
+    for title in ["one", "two"] {
+        db_object
+            .save_tag(&ItemTag {
+                path: format!("/path/to/{}.mp3", title),
+                title: title.to_string(),
+                ..ItemTag::default()
+            })
+            .unwrap();
+    }
+
+    let record = |path: &str, played_secs: u64, completed: bool, seconds_ago: u64| PlayRecord {
+        path: path.to_string(),
+        started_at: SystemTime::now() - Duration::from_secs(seconds_ago),
+        played: Duration::from_secs(played_secs),
+        completed,
+        track_length: Duration::from_secs(200),
+    };
+
+    db_object
+        .record_play(&record("/path/to/one.mp3", 200, true, 600))
+        .unwrap();
+    db_object
+        .record_play(&record("/path/to/one.mp3", 120, false, 300))
+        .unwrap();
+    db_object
+        .record_play(&record("/path/to/one.mp3", 10, false, 200))
+        .unwrap();
+    db_object
+        .record_play(&record("/path/to/two.mp3", 200, true, 100))
+        .unwrap();
+
+    let most_played = db_object.most_played(10).unwrap();
+    assert_eq!(most_played[0].title, "one".to_string());
+    assert_eq!(most_played[0].play_count, 2);
+    assert_eq!(most_played[0].skip_count, 1);
+
+    let recently_played = db_object.recently_played(1).unwrap();
+    assert_eq!(recently_played.len(), 1);
+    assert_eq!(recently_played[0].title, "two".to_string());
+    assert!(recently_played[0].last_played.is_some());
+
+    // Smart playlists sorted by play count follow new plays
+    let most_played = SmartPlaylistRules {
+        match_all: true,
+        rules: vec![],
+        sort_by: SmartSort::PlayCount,
+        descending: true,
+        limit: Some(1),
+    };
+    db_object.save_smart_playlist("Most played", &most_played).unwrap();
+    let top = |db_object: &DBObject| {
+        db_object.get_playlist_tracks("Most played").unwrap()[0]
+            .title
+            .clone()
+    };
+    assert_eq!(top(&db_object), "one".to_string());
+    for seconds_ago in [50, 20] {
+        db_object
+            .record_play(&record("/path/to/two.mp3", 200, true, seconds_ago))
+            .unwrap();
+    }
+    assert_eq!(top(&db_object), "two".to_string());
+}
+
+#[test]
+fn test_database_ratings_favorites_and_labels() {
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+
+    for title in ["one", "two", "three"] {
+        db_object
+            .save_tag(&ItemTag {
+                path: format!("/path/to/{}.mp3", title),
+                title: title.to_string(),
+                ..ItemTag::default()
+            })
+            .unwrap();
+    }
+
+    db_object.set_rating("/path/to/one.mp3", Some(3)).unwrap();
+    db_object.set_rating("/path/to/two.mp3", Some(5)).unwrap();
+    db_object.set_favorite("/path/to/two.mp3", true).unwrap();
+    db_object.add_label("/path/to/one.mp3", "workout").unwrap();
+    db_object.add_label("/path/to/three.mp3", "Workout").unwrap();
+    assert!(db_object.set_rating("/path/to/missing.mp3", Some(1)).is_err());
+
+    let request = DatabaseRequest {
+        search_type: SearchType::Like,
+        search_tag: PartialTag {
+            min_rating: Some(3),
+            ..PartialTag::default()
+        },
+    };
+    let rated = db_object
+        .get_sorted(&request, Some(SmartSort::Rating), true)
+        .unwrap()
+        .unwrap();
+    let titles: Vec<String> = rated.iter().map(|tag| tag.title.clone()).collect();
+    assert_eq!(titles, vec!["two".to_string(), "one".to_string()]);
+    assert!(rated[0].favorite);
+    assert_eq!(rated[1].labels, vec!["workout".to_string()]);
+
+    let request = DatabaseRequest {
+        search_type: SearchType::Like,
+        search_tag: PartialTag {
+            title: Some("e".to_string()),
+            label: Some("workout".to_string()),
+            ..PartialTag::default()
+        },
+    };
+    assert_eq!(db_object.get(&request).unwrap().unwrap().len(), 2);
+
+    db_object.remove_label("/path/to/one.mp3", "workout").unwrap();
+    db_object.remove_label("/path/to/three.mp3", "workout").unwrap();
+    assert!(db_object.list_labels().unwrap().is_empty());
+
+    // A rating read from the file doesn't overwrite one set in the library
+    db_object
+        .save_tag(&ItemTag {
+            path: "/path/to/one.mp3".to_string(),
+            title: "one".to_string(),
+            rating: Some(1),
+            ..ItemTag::default()
+        })
+        .unwrap();
+    db_object
+        .save_tag(&ItemTag {
+            path: "/path/to/three.mp3".to_string(),
+            title: "three".to_string(),
+            rating: Some(4),
+            ..ItemTag::default()
+        })
+        .unwrap();
+    let request = DatabaseRequest {
+        search_type: SearchType::Where,
+        search_tag: PartialTag {
+            min_rating: Some(0),
+            ..PartialTag::default()
+        },
+    };
+    let ratings: Vec<Option<u8>> = db_object
+        .get_sorted(&request, Some(SmartSort::Title), false)
+        .unwrap()
+        .unwrap()
+        .iter()
+        .map(|tag| tag.rating)
+        .collect();
+    assert_eq!(ratings, vec![Some(3), Some(4), Some(5)]);
+}
+
+#[test]
+fn test_database_lyrics() {
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+
+    for title in ["one", "two"] {
+        db_object
+            .save_tag(&ItemTag {
+                path: format!("/path/to/{}.mp3", title),
+                title: title.to_string(),
+                ..ItemTag::default()
+            })
+            .unwrap();
+    }
+
+    let lyrics = crate::lyrics::parse_lrc("[00:01.00]Hello there\n[00:03.00]General Kenobi");
+    db_object.save_lyrics("/path/to/one.mp3", &lyrics).unwrap();
+    db_object
+        .save_lyrics("/path/to/two.mp3", &Lyrics::from_text("Nothing to see"))
+        .unwrap();
+    assert_eq!(db_object.get_lyrics("/path/to/one.mp3").unwrap(), Some(lyrics));
+    assert!(db_object.save_lyrics("/path/to/missing.mp3", &Lyrics::from_text("")).is_err());
+
+    let request = DatabaseRequest {
+        search_type: SearchType::Where,
+        search_tag: PartialTag {
+            lyrics: Some("kenobi".to_string()),
+            ..PartialTag::default()
+        },
+    };
+    let found = db_object.get(&request).unwrap().unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].title, "one".to_string());
+
+    // Lyrics go with their track
+    db_object.remove_tag("/path/to/one.mp3").unwrap();
+    assert!(db_object.get(&request).unwrap().is_none());
+    db_object.remove_lyrics("/path/to/two.mp3").unwrap();
+    assert_eq!(db_object.get_lyrics("/path/to/two.mp3").unwrap(), None);
+}
+
+#[test]
+fn test_database_replaygain() {
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+
+    let mut tag = ItemTag {
+        path: "/path/to/one.mp3".to_string(),
+        title: "one".to_string(),
+        replaygain: ReplayGain {
+            track_gain: Some(-7.5),
+            track_peak: Some(0.75),
+            ..ReplayGain::default()
+        },
+        ..ItemTag::default()
+    };
+    db_object.save_tag(&tag).unwrap();
+    assert_eq!(
+        db_object.get_tag_by_path(&tag.path).unwrap().unwrap().replaygain,
+        tag.replaygain
+    );
+
+    // A rescan after the album was analysed picks up the new values
+    tag.replaygain.album_gain = Some(-6.5);
+    tag.replaygain.album_peak = Some(0.875);
+    db_object.save_tag(&tag).unwrap();
+    assert_eq!(
+        db_object.get_tag_by_path(&tag.path).unwrap().unwrap().replaygain,
+        tag.replaygain
+    );
+}
+
+#[test]
+fn test_database_loudness_analysis() {
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+
+    for (title, track_gain) in [("one", None), ("two", Some(-3.0)), ("three", None)] {
+        db_object
+            .save_tag(&ItemTag {
+                path: format!("/path/to/{}.mp3", title),
+                title: title.to_string(),
+                replaygain: ReplayGain {
+                    track_gain,
+                    ..ReplayGain::default()
+                },
+                ..ItemTag::default()
+            })
+            .unwrap();
+    }
+
+    assert_eq!(db_object.loudness_analysis_counts().unwrap(), (0, 2));
+    assert_eq!(
+        db_object.next_track_to_analyse().unwrap(),
+        Some("/path/to/one.mp3".to_string())
+    );
+
+    let loudness = Loudness {
+        integrated: -12.0,
+        true_peak: 0.5,
+    };
+    db_object
+        .save_loudness("/path/to/one.mp3", Some(&loudness))
+        .unwrap();
+    db_object.save_loudness("/path/to/three.mp3", None).unwrap();
+    assert_eq!(db_object.loudness_analysis_counts().unwrap(), (2, 2));
+    assert_eq!(db_object.next_track_to_analyse().unwrap(), None);
+
+    let replaygain = db_object
+        .get_tag_by_path("/path/to/one.mp3")
+        .unwrap()
+        .unwrap()
+        .replaygain;
+    assert_eq!(replaygain.track_gain, Some(-6.0));
+    assert_eq!(replaygain.track_peak, Some(0.5));
+}
+
+#[test]
+fn test_database_schedules() {
+    use crate::scheduler::{ScheduledAction, SleepTimer, Weekday};
+
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+
+    // Schedules run in local time, so work out what that is for `now`
+    let now = 1_700_000_000;
+    let (weekday, minute_of_day): (u8, u32) = db_object
+        .conn
+        .query_row(
+            "SELECT CAST(strftime('%w', ?1, 'unixepoch', 'localtime') AS INTEGER),
+                CAST(strftime('%H', ?1, 'unixepoch', 'localtime') AS INTEGER) * 60
+                + CAST(strftime('%M', ?1, 'unixepoch', 'localtime') AS INTEGER)",
+            params![now],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    let today = Weekday::ALL[weekday as usize];
+    let tomorrow = Weekday::ALL[(weekday as usize + 1) % 7];
+
+    let schedule = |name: &str, days: Vec<Weekday>, action| Schedule {
+        name: name.to_string(),
+        hour: (minute_of_day / 60) as u8,
+        minute: (minute_of_day % 60) as u8,
+        days,
+        action,
+        enabled: true,
+    };
+    let today_schedule = schedule(
+        "today",
+        vec![today],
+        ScheduledAction::LoadPlaylist("Morning".to_string()),
+    );
+    db_object.save_schedule(&today_schedule).unwrap();
+    db_object
+        .save_schedule(&schedule("tomorrow", vec![tomorrow], ScheduledAction::Play))
+        .unwrap();
+    db_object
+        .save_schedule(&schedule(
+            "every day",
+            vec![],
+            ScheduledAction::SleepTimer(SleepTimer::After(60)),
+        ))
+        .unwrap();
+    assert_eq!(db_object.list_schedules().unwrap().len(), 3);
+
+    let due_names = |now| -> Vec<String> {
+        db_object
+            .due_schedules(now)
+            .unwrap()
+            .into_iter()
+            .map(|schedule| schedule.name)
+            .collect()
+    };
+    assert_eq!(due_names(now), vec!["every day", "today"]);
+    assert_eq!(due_names(now - 60), Vec::<String>::new());
+    assert_eq!(due_names(now + 5 * 60), vec!["every day", "today"]);
+    assert_eq!(due_names(now + 11 * 60), Vec::<String>::new());
+    assert_eq!(db_object.due_schedules(now).unwrap()[1], today_schedule);
+
+    db_object.mark_schedule_run("every day", now).unwrap();
+    db_object.set_schedule_enabled("today", false).unwrap();
+    assert_eq!(due_names(now + 60), Vec::<String>::new());
+    // A day later it runs again, along with the one for that day
+    assert_eq!(
+        due_names(now + 24 * 60 * 60),
+        vec!["every day", "tomorrow"]
+    );
+
+    db_object.delete_schedule("tomorrow").unwrap();
+    assert!(db_object.delete_schedule("tomorrow").is_err());
+    assert_eq!(db_object.list_schedules().unwrap().len(), 2);
+}
+
+#[test]
+fn test_database_player_state() {
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+    assert_eq!(db_object.get_player_state(DEFAULT_ZONE).unwrap(), None);
+
+    let mut state = PlayerState {
+        queue: vec!["/path/to/one.mp3".to_string(), "/path/to/two.mp3".to_string()],
+        queue_position: 1,
+        position_ms: 12_345,
+        speed: crate::speed::SpeedSettings::default(),
+        volume: 0.5,
+        eq_preset: Some("Bass".to_string()),
+    };
+    db_object.save_player_state(DEFAULT_ZONE, &state).unwrap();
+    state.position_ms = 20_000;
+    db_object.save_player_state(DEFAULT_ZONE, &state).unwrap();
+    assert_eq!(
+        db_object.get_player_state(DEFAULT_ZONE).unwrap(),
+        Some(state.clone())
+    );
+    assert_eq!(db_object.get_player_state("Kitchen").unwrap(), None);
+}
+
+#[test]
+fn test_database_zones() {
+    use crate::output::{DeviceSelector, OutputBackend};
+
+    let db_object =
+        DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+    let kitchen = ZoneSettings {
+        name: "Kitchen".to_string(),
+        output: OutputBackend::Device,
+        device: Some(DeviceSelector::Name("USB DAC".to_string())),
+    };
+    let garden = ZoneSettings {
+        name: "Garden".to_string(),
+        output: OutputBackend::Null,
+        device: None,
+    };
+    db_object.save_zone(&kitchen).unwrap();
+    db_object.save_zone(&garden).unwrap();
+    assert_eq!(db_object.list_zones().unwrap(), vec![kitchen.clone(), garden]);
+
+    let state = PlayerState {
+        queue: vec!["/path/to/one.mp3".to_string()],
+        queue_position: 0,
+        position_ms: 0,
+        speed: crate::speed::SpeedSettings::default(),
+        volume: 1.0,
+        eq_preset: None,
+    };
+    db_object.save_player_state("Garden", &state).unwrap();
+    db_object.delete_zone("Garden").unwrap();
+    assert!(db_object.delete_zone("Garden").is_err());
+    assert_eq!(db_object.get_player_state("Garden").unwrap(), None);
+    assert_eq!(db_object.list_zones().unwrap(), vec![kitchen]);
+}
@@ -0,0 +1,597 @@
+use rodio::cpal::Sample as CpalSample;
+use rodio::{Sample, Source};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::f64::consts::PI;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The centre frequencies of the bands of `EqPreset::graphic`, in Hz
+pub const GRAPHIC_EQ_FREQUENCIES: [f32; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+
+/// The Q of the bands of `EqPreset::graphic`, about an octave wide
+const GRAPHIC_EQ_Q: f32 = 1.41;
+
+/// The shape of a filter band, as in the RBJ audio EQ cookbook
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum FilterKind {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+/// A single biquad filter of an EQ preset
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, JsonSchema)]
+pub struct EqBand {
+    pub kind: FilterKind,
+    /// The centre, corner or shelf frequency in Hz
+    pub frequency: f32,
+    /// Ignored by the low and high pass filters
+    pub gain_db: f32,
+    pub q: f32,
+}
+
+/// A named set of EQ bands along with a preamp and balance, saved in the configuration file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default, JsonSchema)]
+#[serde(default)]
+pub struct EqPreset {
+    pub name: String,
+    pub preamp_db: f32,
+    /// From -1 (left only) through 0 (centred) to 1 (right only)
+    pub balance: f32,
+    pub bands: Vec<EqBand>,
+}
+
+impl EqPreset {
+    /// A 10 band graphic EQ, with one gain per `GRAPHIC_EQ_FREQUENCIES` band
+    pub fn graphic(name: &str, gains_db: [f32; 10]) -> Self {
+        EqPreset {
+            name: name.to_string(),
+            bands: GRAPHIC_EQ_FREQUENCIES
+                .iter()
+                .zip(gains_db)
+                .map(|(frequency, gain_db)| EqBand {
+                    kind: FilterKind::Peaking,
+                    frequency: *frequency,
+                    gain_db,
+                    q: GRAPHIC_EQ_Q,
+                })
+                .collect(),
+            ..EqPreset::default()
+        }
+    }
+}
+
+/// The EQ presets and which one is in use, part of the configuration file
+///
+/// The presets are shared by every zone, while each zone picks its own preset. In the
+/// configuration file `active_preset` is the one zones start out with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default, JsonSchema)]
+#[serde(default)]
+pub struct EqualizerSettings {
+    /// The name of the preset in use, the output isn't touched when this is `None`
+    pub active_preset: Option<String>,
+    pub presets: Vec<EqPreset>,
+}
+
+/// The EQ presets of the server, shared between the players of every zone
+pub type EqPresets = Rc<RefCell<Vec<EqPreset>>>;
+
+/// Normalized biquad coefficients, `a0` is divided out
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Coefficients {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+impl Coefficients {
+    fn new(band: &EqBand, sample_rate: u32) -> Self {
+        // Bands above Nyquist are pulled just under it
+        let frequency = (band.frequency as f64).clamp(1.0, sample_rate as f64 * 0.49);
+        let w0 = 2.0 * PI * frequency / sample_rate as f64;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * (band.q as f64).max(0.01));
+        let a = 10f64.powf(band.gain_db as f64 / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match band.kind {
+            FilterKind::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            FilterKind::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ),
+            FilterKind::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ),
+            FilterKind::LowPass => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterKind::HighPass => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+        };
+
+        Coefficients {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+}
+
+/// The memory of a biquad for one channel, transposed direct form II
+#[derive(Debug, Clone, Copy, Default)]
+struct FilterState {
+    z1: f64,
+    z2: f64,
+}
+
+impl FilterState {
+    fn process(&mut self, coefficients: &Coefficients, input: f64) -> f64 {
+        let output = coefficients.b0 * input + self.z1;
+        self.z1 = coefficients.b1 * input - coefficients.a1 * output + self.z2;
+        self.z2 = coefficients.b2 * input - coefficients.a2 * output;
+        output
+    }
+}
+
+/// The EQ preset every `Equalizer` and `Balance` of a player follows
+///
+/// Changing it takes effect on the playing sources at their next frame.
+pub struct Dsp {
+    preset: Mutex<Option<EqPreset>>,
+    /// Bumped for every change so the sources notice it
+    generation: AtomicU64,
+}
+
+impl Dsp {
+    pub fn new(preset: Option<EqPreset>) -> Arc<Self> {
+        Arc::new(Dsp {
+            preset: Mutex::new(preset),
+            generation: AtomicU64::new(0),
+        })
+    }
+
+    pub fn set_preset(&self, preset: Option<EqPreset>) {
+        *self.preset.lock().unwrap() = preset;
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// The preset if it changed since `seen_generation`, without blocking the audio thread
+    fn changed_preset(&self, seen_generation: &mut u64) -> Option<Option<EqPreset>> {
+        let generation = self.generation.load(Ordering::Acquire);
+        if generation == *seen_generation {
+            return None;
+        }
+        let preset = self.preset.try_lock().ok()?.clone();
+        *seen_generation = generation;
+        Some(preset)
+    }
+}
+
+/// Applies the preamp and the EQ bands of a `Dsp`
+pub struct Equalizer<S> {
+    inner: S,
+    dsp: Arc<Dsp>,
+    seen_generation: u64,
+    preamp: f64,
+    coefficients: Vec<Coefficients>,
+    /// One state per band per channel, band major
+    states: Vec<FilterState>,
+    channel: usize,
+}
+
+impl<S> Equalizer<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    pub fn new(inner: S, dsp: &Arc<Dsp>) -> Self {
+        let mut equalizer = Equalizer {
+            inner,
+            dsp: Arc::clone(dsp),
+            // Picks up the current preset on the first sample
+            seen_generation: u64::MAX,
+            preamp: 1.0,
+            coefficients: vec![],
+            states: vec![],
+            channel: 0,
+        };
+        equalizer.update();
+        equalizer
+    }
+
+    fn update(&mut self) {
+        let preset = match self.dsp.changed_preset(&mut self.seen_generation) {
+            Some(preset) => preset,
+            None => return,
+        };
+
+        let sample_rate = self.inner.sample_rate();
+        let (preamp_db, coefficients) = match &preset {
+            Some(preset) => (
+                preset.preamp_db,
+                preset
+                    .bands
+                    .iter()
+                    .map(|band| Coefficients::new(band, sample_rate))
+                    .collect(),
+            ),
+            None => (0.0, vec![]),
+        };
+        self.preamp = 10f64.powf(preamp_db as f64 / 20.0);
+
+        // The filters keep their memory when only their settings change, so there's no click
+        let state_count = coefficients.len() * self.inner.channels() as usize;
+        if state_count != self.states.len() {
+            self.states = vec![FilterState::default(); state_count];
+        }
+        self.coefficients = coefficients;
+    }
+}
+
+impl<S> Iterator for Equalizer<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        if self.channel == 0 {
+            self.update();
+        }
+        let channels = self.inner.channels() as usize;
+        let sample = self.inner.next()?;
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % channels.max(1);
+
+        if self.coefficients.is_empty() && self.preamp == 1.0 {
+            return Some(sample);
+        }
+
+        let mut value = sample.to_f32() as f64 * self.preamp;
+        for (band, coefficients) in self.coefficients.iter().enumerate() {
+            if let Some(state) = self.states.get_mut(band * channels + channel) {
+                value = state.process(coefficients, value);
+            }
+        }
+        Some(<S::Item as CpalSample>::from(
+            &(value.clamp(-1.0, 1.0) as f32),
+        ))
+    }
+}
+
+impl<S> Source for Equalizer<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+/// Turns down the left or the right channel following the balance of a `Dsp`
+///
+/// Only the first two channels are touched.
+pub struct Balance<S> {
+    inner: S,
+    dsp: Arc<Dsp>,
+    seen_generation: u64,
+    gains: [f32; 2],
+    channel: usize,
+}
+
+impl<S> Balance<S> {
+    pub fn new(inner: S, dsp: &Arc<Dsp>) -> Self {
+        let mut balance = Balance {
+            inner,
+            dsp: Arc::clone(dsp),
+            seen_generation: u64::MAX,
+            gains: [1.0, 1.0],
+            channel: 0,
+        };
+        balance.update();
+        balance
+    }
+
+    fn update(&mut self) {
+        if let Some(preset) = self.dsp.changed_preset(&mut self.seen_generation) {
+            let balance = preset.map_or(0.0, |preset| preset.balance.clamp(-1.0, 1.0));
+            self.gains = [(1.0 - balance).min(1.0), (1.0 + balance).min(1.0)];
+        }
+    }
+}
+
+impl<S> Iterator for Balance<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        if self.channel == 0 {
+            self.update();
+        }
+        let channels = self.inner.channels() as usize;
+        let sample = self.inner.next()?;
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % channels.max(1);
+
+        match self.gains.get(channel) {
+            Some(gain) if channels >= 2 && *gain != 1.0 => Some(sample.amplify(*gain)),
+            _ => Some(sample),
+        }
+    }
+}
+
+impl<S> Source for Balance<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+/// Measure the gain in dB of `dsp` at each frequency, by playing a sine through it
+#[cfg(test)]
+fn measure_response(dsp: &Arc<Dsp>, frequencies: &[f32]) -> Vec<f32> {
+    let sample_rate = 48000;
+    frequencies
+        .iter()
+        .map(|frequency| {
+            let sine: Vec<f32> = (0..sample_rate)
+                .map(|n| {
+                    let phase = 2.0 * PI * *frequency as f64 * n as f64 / sample_rate as f64;
+                    (0.25 * phase.sin()) as f32
+                })
+                .collect();
+            let output: Vec<f32> = Equalizer::new(
+                rodio::buffer::SamplesBuffer::new(1, sample_rate, sine.clone()),
+                dsp,
+            )
+            .collect();
+
+            // Skip the first half while the filters settle
+            let rms = |samples: &[f32]| {
+                (samples.iter().map(|s| (*s as f64).powi(2)).sum::<f64>() / samples.len() as f64)
+                    .sqrt()
+            };
+            let half = sample_rate as usize / 2;
+            (20.0 * (rms(&output[half..]) / rms(&sine[half..])).log10()) as f32
+        })
+        .collect()
+}
+
+/// The gain in dB `preset` should have at `frequency`, worked out from the filter coefficients
+#[cfg(test)]
+fn expected_response(preset: &EqPreset, frequency: f32, sample_rate: u32) -> f32 {
+    let w = 2.0 * PI * frequency as f64 / sample_rate as f64;
+    let (sin, cos) = w.sin_cos();
+    let (sin2, cos2) = (2.0 * w).sin_cos();
+    let bands: f64 = preset
+        .bands
+        .iter()
+        .map(|band| {
+            let c = Coefficients::new(band, sample_rate);
+            // |H(e^jw)| with z^-1 = cos(w) - j sin(w)
+            let numerator = (c.b0 + c.b1 * cos + c.b2 * cos2).hypot(c.b1 * sin + c.b2 * sin2);
+            let denominator = (1.0 + c.a1 * cos + c.a2 * cos2).hypot(c.a1 * sin + c.a2 * sin2);
+            20.0 * (numerator / denominator).log10()
+        })
+        .sum();
+    preset.preamp_db + bands as f32
+}
+
+#[test]
+fn test_equalizer_frequency_response() {
+    let close = |measured: &[f32], expected: &[f32]| {
+        measured
+            .iter()
+            .zip(expected)
+            .all(|(measured, expected)| (measured - expected).abs() < 0.5)
+    };
+    let spots = [50.0, 200.0, 1000.0, 5000.0, 15000.0];
+
+    let dsp = Dsp::new(None);
+    let flat = measure_response(&dsp, &spots);
+    assert!(close(&flat, &[0.0; 5]), "{:?}", flat);
+
+    dsp.set_preset(Some(EqPreset {
+        name: "Mid boost".to_string(),
+        preamp_db: -3.0,
+        bands: vec![EqBand {
+            kind: FilterKind::Peaking,
+            frequency: 1000.0,
+            gain_db: 6.0,
+            q: 2.0,
+        }],
+        ..EqPreset::default()
+    }));
+    let peaking = measure_response(&dsp, &spots);
+    assert!(
+        close(&peaking, &[-3.0, -3.0, 3.0, -3.0, -3.0]),
+        "{:?}",
+        peaking
+    );
+
+    dsp.set_preset(Some(EqPreset {
+        name: "Shelves".to_string(),
+        bands: vec![
+            EqBand {
+                kind: FilterKind::LowShelf,
+                frequency: 100.0,
+                gain_db: 6.0,
+                q: 0.707,
+            },
+            EqBand {
+                kind: FilterKind::HighShelf,
+                frequency: 8000.0,
+                gain_db: -6.0,
+                q: 0.707,
+            },
+        ],
+        ..EqPreset::default()
+    }));
+    let shelves = measure_response(&dsp, &[20.0, 1000.0, 20000.0]);
+    assert!(close(&shelves, &[6.0, 0.0, -6.0]), "{:?}", shelves);
+
+    let graphic = EqPreset::graphic(
+        "Smile",
+        [6.0, 4.0, 2.0, 0.0, -2.0, -2.0, 0.0, 2.0, 4.0, 6.0],
+    );
+    assert_eq!(graphic.bands.len(), 10);
+    dsp.set_preset(Some(graphic));
+    let smile = measure_response(&dsp, &[31.0, 1000.0, 16000.0]);
+    assert!(
+        smile[0] > 4.0 && smile[1] < -1.0 && smile[2] > 4.0,
+        "{:?}",
+        smile
+    );
+}
+
+#[test]
+fn test_equalizer_sweep() {
+    // Third octaves from 20 Hz to 20 kHz
+    let sweep: Vec<f32> = (0..=30)
+        .map(|step| 20.0 * 1000f32.powf(step as f32 / 30.0))
+        .collect();
+    let presets = [
+        EqPreset {
+            name: "Everything".to_string(),
+            preamp_db: -4.0,
+            bands: vec![
+                EqBand {
+                    kind: FilterKind::HighPass,
+                    frequency: 40.0,
+                    gain_db: 0.0,
+                    q: 0.707,
+                },
+                EqBand {
+                    kind: FilterKind::LowShelf,
+                    frequency: 150.0,
+                    gain_db: 4.0,
+                    q: 0.707,
+                },
+                EqBand {
+                    kind: FilterKind::Peaking,
+                    frequency: 2500.0,
+                    gain_db: -5.0,
+                    q: 1.0,
+                },
+                EqBand {
+                    kind: FilterKind::HighShelf,
+                    frequency: 6000.0,
+                    gain_db: 3.0,
+                    q: 0.707,
+                },
+                EqBand {
+                    kind: FilterKind::LowPass,
+                    frequency: 16000.0,
+                    gain_db: 0.0,
+                    q: 0.707,
+                },
+            ],
+            ..EqPreset::default()
+        },
+        EqPreset::graphic(
+            "Smile",
+            [6.0, 4.0, 2.0, 0.0, -2.0, -2.0, 0.0, 2.0, 4.0, 6.0],
+        ),
+    ];
+
+    for preset in presets {
+        let dsp = Dsp::new(Some(preset.clone()));
+        let measured = measure_response(&dsp, &sweep);
+        for (frequency, measured) in sweep.iter().zip(measured) {
+            let expected = expected_response(&preset, *frequency, 48000);
+            assert!(
+                (measured - expected).abs() < 0.5,
+                "{} at {} Hz: measured {} dB, expected {} dB",
+                preset.name,
+                frequency,
+                measured,
+                expected
+            );
+        }
+    }
+}
+
+#[test]
+fn test_balance() {
+    let dsp = Dsp::new(Some(EqPreset {
+        balance: 0.5,
+        ..EqPreset::default()
+    }));
+    let stereo = rodio::buffer::SamplesBuffer::new(2, 44100, vec![1000i16; 4]);
+    assert_eq!(
+        Balance::new(stereo, &dsp).collect::<Vec<i16>>(),
+        vec![500, 1000, 500, 1000]
+    );
+}
@@ -0,0 +1,327 @@
+use rodio::{Sample, Source};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long fades and crossfades are, part of the configuration file
+///
+/// A duration of 0 turns that fade off.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(default)]
+pub struct CrossfadeSettings {
+    /// Overlap between the end of a track and the start of the next one
+    ///
+    /// Consecutive tracks from the same album are played gapless instead.
+    pub crossfade_ms: u64,
+    /// Fade out before pausing, and back in when resuming
+    pub pause_fade_ms: u64,
+    /// Fade between the tracks when skipping or switching by hand
+    pub skip_fade_ms: u64,
+}
+
+impl Default for CrossfadeSettings {
+    fn default() -> Self {
+        CrossfadeSettings {
+            crossfade_ms: 0,
+            pause_fade_ms: 150,
+            skip_fade_ms: 300,
+        }
+    }
+}
+
+/// The number of samples (for all channels) in `milliseconds` of audio
+fn samples_in(milliseconds: u64, sample_rate: u32, channels: u16) -> usize {
+    (milliseconds * sample_rate as u64 / 1000) as usize * channels as usize
+}
+
+/// Controls the volume of a `Faded` source from another thread
+pub struct Fader {
+    /// The volume to fade to, stored as `f32` bits
+    target: AtomicU32,
+    duration_ms: AtomicU64,
+    /// Bumped for every fade so the source notices a new one
+    generation: AtomicU64,
+}
+
+impl Fader {
+    pub fn new(volume: f32) -> Arc<Self> {
+        Arc::new(Fader {
+            target: AtomicU32::new(volume.to_bits()),
+            duration_ms: AtomicU64::new(0),
+            generation: AtomicU64::new(0),
+        })
+    }
+
+    /// Linearly fade from the current volume to `target` over `duration`
+    pub fn fade_to(&self, target: f32, duration: Duration) {
+        self.target.store(target.to_bits(), Ordering::Relaxed);
+        self.duration_ms
+            .store(duration.as_millis() as u64, Ordering::Relaxed);
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// A source whose volume follows a `Fader`
+///
+/// The fade moves along with the samples that are pulled, so it is exact to the sample and
+/// holds still while the sink is paused.
+pub struct Faded<S> {
+    inner: S,
+    fader: Arc<Fader>,
+    seen_generation: u64,
+    volume: f32,
+    target: f32,
+    step: f32,
+}
+
+impl<S> Faded<S> {
+    pub fn new(inner: S, fader: &Arc<Fader>) -> Self {
+        let volume = f32::from_bits(fader.target.load(Ordering::Relaxed));
+        Faded {
+            inner,
+            fader: Arc::clone(fader),
+            seen_generation: fader.generation.load(Ordering::Acquire),
+            volume,
+            target: volume,
+            step: 0.0,
+        }
+    }
+}
+
+impl<S> Iterator for Faded<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        let generation = self.fader.generation.load(Ordering::Acquire);
+        if generation != self.seen_generation {
+            self.seen_generation = generation;
+            self.target = f32::from_bits(self.fader.target.load(Ordering::Relaxed));
+            let length = samples_in(
+                self.fader.duration_ms.load(Ordering::Relaxed),
+                self.inner.sample_rate(),
+                self.inner.channels(),
+            );
+            self.step = (self.target - self.volume).abs() / length.max(1) as f32;
+        }
+
+        if self.volume < self.target {
+            self.volume = (self.volume + self.step).min(self.target);
+        } else if self.volume > self.target {
+            self.volume = (self.volume - self.step).max(self.target);
+        }
+
+        self.inner.next().map(|sample| sample.amplify(self.volume))
+    }
+}
+
+impl<S> Source for Faded<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+/// Fades out the last part of a source, and signals when that part starts
+///
+/// The tail is found by holding back as many samples as it is long. Its length can be
+/// changed while the source plays, up until the inner source runs out.
+pub struct CrossfadeTail<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    inner: S,
+    tail_ms: Arc<AtomicU64>,
+    tail_started: Arc<AtomicBool>,
+    held_back: VecDeque<S::Item>,
+    /// The length of the tail in samples, once the inner source has run out
+    tail_length: Option<usize>,
+}
+
+impl<S> CrossfadeTail<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    pub fn new(inner: S, tail_ms: &Arc<AtomicU64>, tail_started: &Arc<AtomicBool>) -> Self {
+        CrossfadeTail {
+            inner,
+            tail_ms: Arc::clone(tail_ms),
+            tail_started: Arc::clone(tail_started),
+            held_back: VecDeque::new(),
+            tail_length: None,
+        }
+    }
+}
+
+impl<S> Iterator for CrossfadeTail<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        if self.tail_length.is_none() {
+            let wanted = samples_in(
+                self.tail_ms.load(Ordering::Relaxed),
+                self.inner.sample_rate(),
+                self.inner.channels(),
+            );
+            while self.held_back.len() <= wanted {
+                match self.inner.next() {
+                    Some(sample) => self.held_back.push_back(sample),
+                    None => {
+                        self.tail_length = Some(self.held_back.len());
+                        self.tail_started.store(true, Ordering::Release);
+                        break;
+                    }
+                }
+            }
+        }
+
+        let remaining = self.held_back.len();
+        let sample = self.held_back.pop_front()?;
+        match self.tail_length {
+            Some(tail_length) => Some(sample.amplify(remaining as f32 / (tail_length + 1) as f32)),
+            None => Some(sample),
+        }
+    }
+}
+
+impl<S> Source for CrossfadeTail<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+/// Plays silence until `open` is set, then the inner source
+///
+/// Used to start the incoming track of a crossfade right as the outgoing one's tail starts.
+pub struct Gate<S> {
+    inner: S,
+    open: Arc<AtomicBool>,
+    opened: bool,
+    /// Counts the silent samples so the gate only opens at the start of a frame
+    silent_samples: usize,
+}
+
+impl<S> Gate<S> {
+    pub fn new(inner: S, open: &Arc<AtomicBool>) -> Self {
+        Gate {
+            inner,
+            open: Arc::clone(open),
+            opened: false,
+            silent_samples: 0,
+        }
+    }
+}
+
+impl<S> Iterator for Gate<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        if !self.opened {
+            let frame_start = self.silent_samples.is_multiple_of(self.inner.channels() as usize);
+            if frame_start && self.open.load(Ordering::Acquire) {
+                self.opened = true;
+            } else {
+                self.silent_samples += 1;
+                return Some(S::Item::zero_value());
+            }
+        }
+        self.inner.next()
+    }
+}
+
+impl<S> Source for Gate<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[test]
+fn test_fades() {
+    // 1 channel at 1000 Hz, so every sample is a millisecond
+    let ones = || rodio::buffer::SamplesBuffer::new(1, 1000, vec![1000i16; 10]);
+
+    let fader = Fader::new(0.0);
+    let faded = Faded::new(ones(), &fader);
+    fader.fade_to(1.0, Duration::from_millis(4));
+    assert_eq!(
+        faded.collect::<Vec<i16>>(),
+        vec![250, 500, 750, 1000, 1000, 1000, 1000, 1000, 1000, 1000]
+    );
+
+    let tail_ms = Arc::new(AtomicU64::new(4));
+    let tail_started = Arc::new(AtomicBool::new(false));
+    let mut tail = CrossfadeTail::new(ones(), &tail_ms, &tail_started);
+    assert_eq!(tail.by_ref().take(6).collect::<Vec<i16>>(), vec![1000; 6]);
+    assert!(!tail_started.load(Ordering::Acquire));
+    assert_eq!(tail.collect::<Vec<i16>>(), vec![800, 600, 400, 200]);
+    assert!(tail_started.load(Ordering::Acquire));
+
+    let open = Arc::new(AtomicBool::new(false));
+    let mut gate = Gate::new(ones(), &open);
+    assert_eq!(gate.by_ref().take(3).collect::<Vec<i16>>(), vec![0; 3]);
+    open.store(true, Ordering::Release);
+    assert_eq!(gate.count(), 10);
+}
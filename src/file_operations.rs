@@ -1,11 +1,44 @@
+use derive_more::From;
 use id3::{Tag, TagLike};
 use scan_dir::ScanDir;
 use std::{path::{PathBuf, Path}, ffi::OsStr};
 use log::warn;
 
-use crate::message_types::ItemTag;
+use crate::db_operations::{split_artists, ARTIST_SEPARATOR};
+use crate::message_types::{ItemTag, PartialTag};
+use crate::playlist_files::SUPPORTED_PLAYLIST_FILETYPES;
+use crate::loudness::Loudness;
+use crate::replaygain;
 
-const SUPPORTED_FILETYPES: [&str; 1] = ["mp3"];
+const SUPPORTED_FILETYPES: [&str; 2] = ["mp3", "flac"];
+
+/// Catch all Error for reading and writing the tags of audio files
+#[derive(From, Debug)]
+pub enum TagError {
+    Id3Error(id3::Error),
+    FlacError(metaflac::Error),
+    UnsupportedFormat,
+}
+
+/// The kinds of tags Sousa can read and write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagFormat {
+    /// ID3v2.3 and ID3v2.4, used by mp3 files
+    Id3,
+    /// Vorbis comments, used by flac files
+    Vorbis,
+}
+
+impl TagFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension().and_then(OsStr::to_str)?.to_lowercase();
+        match extension.as_str() {
+            "mp3" => Some(TagFormat::Id3),
+            "flac" => Some(TagFormat::Vorbis),
+            _ => None,
+        }
+    }
+}
 
 /// The object that iteratively and recursively scans the directories
 ///
@@ -73,7 +106,10 @@ impl Iterator for MusicScanner {
                             // Check if the file has a valid extension
                             match Path::new(path).extension().and_then(OsStr::to_str) {
                                 Some(extension) => {
-                                    if SUPPORTED_FILETYPES.contains(&extension){
+                                    let extension = extension.to_lowercase();
+                                    if SUPPORTED_FILETYPES.contains(&extension.as_str())
+                                        || SUPPORTED_PLAYLIST_FILETYPES.contains(&extension.as_str())
+                                    {
                                         files.push(entry.path());
                                     }
                                 },
@@ -83,7 +119,7 @@ impl Iterator for MusicScanner {
                         },
                         None => {
                             warn!("Error encountered in directory: {:?}", target);
-                            warn!("There was an error getting the path to {:?}", path);
+                            warn!("There was an error getting the path to {:?}", entry.path());
                         }
                     }
                 }
@@ -95,8 +131,38 @@ impl Iterator for MusicScanner {
     }
 }
 
+/// Check that a path is inside one of the music roots
+///
+/// Symlinks and `..` are resolved first. The path itself doesn't have to exist yet, as
+/// long as its parent directory does.
+pub fn is_in_music_roots(path: &Path, music_roots: &[PathBuf]) -> bool {
+    let resolved = match path.canonicalize() {
+        Ok(resolved) => resolved,
+        Err(_) => match (path.parent(), path.file_name()) {
+            (Some(parent), Some(file_name)) => match parent.canonicalize() {
+                Ok(parent) => parent.join(file_name),
+                Err(_) => return false,
+            },
+            _ => return false,
+        },
+    };
+
+    music_roots.iter().any(|root| match root.canonicalize() {
+        Ok(root) => resolved.starts_with(root),
+        Err(_) => false,
+    })
+}
+
 /// Returns the music information from a filepath
-pub fn get_tag(filepath: &PathBuf) -> Result<ItemTag, id3::Error> {
+pub fn get_tag(filepath: &Path) -> Result<ItemTag, TagError> {
+    match TagFormat::from_path(filepath) {
+        Some(TagFormat::Id3) => Ok(get_id3_tag(filepath)?),
+        Some(TagFormat::Vorbis) => Ok(get_vorbis_tag(filepath)?),
+        None => Err(TagError::UnsupportedFormat),
+    }
+}
+
+fn get_id3_tag(filepath: &Path) -> Result<ItemTag, id3::Error> {
     let tag = Tag::read_from_path(filepath)?;
 
     let mut output_tag = ItemTag {
@@ -105,8 +171,8 @@ pub fn get_tag(filepath: &PathBuf) -> Result<ItemTag, id3::Error> {
     output_tag.path = filepath.to_string_lossy().into_owned();
 
     // Get a bunch of frames...
-    if let Some(artist) = tag.artist() {
-        output_tag.artist = artist.to_string();
+    if let Some(artists) = tag.artists() {
+        output_tag.artist = artists.join(ARTIST_SEPARATOR);
     }
     if let Some(title) = tag.title() {
         output_tag.title = title.to_string();
@@ -114,6 +180,332 @@ pub fn get_tag(filepath: &PathBuf) -> Result<ItemTag, id3::Error> {
     if let Some(album) = tag.album() {
         output_tag.album = album.to_string();
     }
+    if let Some(album_artist) = tag.album_artist() {
+        output_tag.album_artist = album_artist.to_string();
+    }
+    if let Some(genre) = tag.genre_parsed() {
+        output_tag.genre = genre.into_owned();
+    }
+    output_tag.year = tag
+        .year()
+        .or_else(|| tag.date_recorded().map(|date| date.year));
+    output_tag.rating = read_popm_rating(&tag);
+    output_tag.replaygain = replaygain::from_fields(|field| {
+        tag.extended_texts()
+            .find(|text| text.description.eq_ignore_ascii_case(field))
+            .map(|text| text.value.as_str())
+    });
+
+    Ok(output_tag)
+}
+
+fn get_vorbis_tag(filepath: &Path) -> Result<ItemTag, metaflac::Error> {
+    let tag = metaflac::Tag::read_from_path(filepath)?;
+
+    let mut output_tag = ItemTag {
+        ..ItemTag::default()
+    };
+    output_tag.path = filepath.to_string_lossy().into_owned();
+
+    if let Some(comments) = tag.vorbis_comments() {
+        if let Some(artists) = comments.artist() {
+            output_tag.artist = artists.join(ARTIST_SEPARATOR);
+        }
+        if let Some(title) = comments.title().and_then(|values| values.first()) {
+            output_tag.title = title.clone();
+        }
+        if let Some(album) = comments.album().and_then(|values| values.first()) {
+            output_tag.album = album.clone();
+        }
+        if let Some(album_artist) = comments.album_artist().and_then(|values| values.first()) {
+            output_tag.album_artist = album_artist.clone();
+        }
+        if let Some(genre) = comments.genre().and_then(|values| values.first()) {
+            output_tag.genre = genre.clone();
+        }
+        // DATE is usually a full date, the year is its first part
+        output_tag.year = comments
+            .get("DATE")
+            .and_then(|values| values.first())
+            .and_then(|date| date.get(..4))
+            .and_then(|year| year.parse().ok());
+        output_tag.rating = comments
+            .get("RATING")
+            .and_then(|values| values.first())
+            .and_then(|rating| stars_from_vorbis(rating));
+        output_tag.replaygain = replaygain::from_fields(|field| {
+            comments
+                .comments
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(field))
+                .and_then(|(_, values)| values.first())
+                .map(|value| value.as_str())
+        });
+    }
 
     Ok(output_tag)
 }
+
+/// Write the fields that are set in `changes` to the file's tags
+///
+/// Fields that are set to an empty string (or year 0) are removed from the tags. Fields
+/// that aren't tag fields, like `path` or `favorite`, are ignored.
+pub fn write_tags(filepath: &Path, changes: &PartialTag) -> Result<(), TagError> {
+    match TagFormat::from_path(filepath) {
+        Some(TagFormat::Id3) => Ok(write_id3_tags(filepath, changes)?),
+        Some(TagFormat::Vorbis) => Ok(write_vorbis_tags(filepath, changes)?),
+        None => Err(TagError::UnsupportedFormat),
+    }
+}
+
+/// Read the file's ID3 tag, or start a new ID3v2.4 tag if it has none
+fn read_or_new_id3_tag(filepath: &Path) -> Result<Tag, id3::Error> {
+    match Tag::read_from_path(filepath) {
+        Ok(tag) => Ok(tag),
+        Err(id3::Error {
+            kind: id3::ErrorKind::NoTag,
+            ..
+        }) => Ok(Tag::new()),
+        Err(err) => Err(err),
+    }
+}
+
+fn write_id3_tags(filepath: &Path, changes: &PartialTag) -> Result<(), id3::Error> {
+    let mut tag = read_or_new_id3_tag(filepath)?;
+
+    if let Some(title) = &changes.title {
+        match title.is_empty() {
+            true => tag.remove_title(),
+            false => tag.set_title(title.clone()),
+        }
+    }
+    if let Some(artist) = &changes.artist {
+        let artists = split_artists(artist);
+        if artists.is_empty() {
+            tag.remove_artist();
+        } else if tag.version() == id3::Version::Id3v24 {
+            // ID3v2.4 supports multiple values natively
+            tag.set_text_values("TPE1", artists);
+        } else {
+            tag.set_artist(artists.join(ARTIST_SEPARATOR));
+        }
+    }
+    if let Some(album) = &changes.album {
+        match album.is_empty() {
+            true => tag.remove_album(),
+            false => tag.set_album(album.clone()),
+        }
+    }
+    if let Some(album_artist) = &changes.album_artist {
+        match album_artist.is_empty() {
+            true => tag.remove_album_artist(),
+            false => tag.set_album_artist(album_artist.clone()),
+        }
+    }
+    if let Some(genre) = &changes.genre {
+        match genre.is_empty() {
+            true => tag.remove_genre(),
+            false => tag.set_genre(genre.clone()),
+        }
+    }
+    if let Some(year) = changes.year {
+        tag.remove_date_recorded();
+        match year {
+            0 => tag.remove_year(),
+            year => tag.set_year(year),
+        }
+    }
+
+    tag.write_to_path(filepath, tag.version())
+}
+
+fn write_vorbis_tags(filepath: &Path, changes: &PartialTag) -> Result<(), metaflac::Error> {
+    let mut tag = metaflac::Tag::read_from_path(filepath)?;
+    let comments = tag.vorbis_comments_mut();
+
+    let mut set_or_remove = |key: &str, values: Vec<String>| {
+        if values.iter().all(|value| value.is_empty()) {
+            comments.remove(key);
+        } else {
+            comments.set(key, values);
+        }
+    };
+
+    if let Some(title) = &changes.title {
+        set_or_remove("TITLE", vec![title.clone()]);
+    }
+    if let Some(artist) = &changes.artist {
+        set_or_remove("ARTIST", split_artists(artist));
+    }
+    if let Some(album) = &changes.album {
+        set_or_remove("ALBUM", vec![album.clone()]);
+    }
+    if let Some(album_artist) = &changes.album_artist {
+        set_or_remove("ALBUMARTIST", vec![album_artist.clone()]);
+    }
+    if let Some(genre) = &changes.genre {
+        set_or_remove("GENRE", vec![genre.clone()]);
+    }
+    if let Some(year) = changes.year {
+        let year = if year == 0 { String::new() } else { year.to_string() };
+        set_or_remove("DATE", vec![year]);
+    }
+
+    tag.save()
+}
+
+/// The POPM "email" Sousa writes its ratings under
+const POPM_USER: &str = "sousa";
+
+/// Convert a POPM rating (1-255) to stars, using the same ranges as most players
+pub fn stars_from_popm(popm_rating: u8) -> Option<u8> {
+    match popm_rating {
+        0 => None,
+        1..=31 => Some(1),
+        32..=95 => Some(2),
+        96..=159 => Some(3),
+        160..=223 => Some(4),
+        224..=255 => Some(5),
+    }
+}
+
+/// Convert stars to a POPM rating, 0 stars has no POPM equivalent and is stored as unrated
+pub fn popm_from_stars(stars: u8) -> u8 {
+    match stars {
+        0 => 0,
+        1 => 1,
+        2 => 64,
+        3 => 128,
+        4 => 196,
+        _ => 255,
+    }
+}
+
+/// Convert a Vorbis `RATING` comment to stars
+///
+/// The comment has no fixed scale; values up to 5 are read as stars, anything larger as
+/// a percentage.
+pub fn stars_from_vorbis(rating: &str) -> Option<u8> {
+    let value: f64 = rating.trim().parse().ok()?;
+    if !(0.0..=100.0).contains(&value) {
+        return None;
+    }
+    if value <= 5.0 {
+        Some(value.round() as u8)
+    } else {
+        Some((value / 20.0).round() as u8)
+    }
+}
+
+/// Convert stars to a Vorbis `RATING` comment, written as a percentage
+pub fn vorbis_from_stars(stars: u8) -> String {
+    (stars.min(5) as u32 * 20).to_string()
+}
+
+/// Read the rating from the POPM frames, preferring the one Sousa wrote
+fn read_popm_rating(tag: &Tag) -> Option<u8> {
+    let popularimeters: Vec<&id3::frame::Popularimeter> = tag
+        .frames()
+        .filter_map(|frame| frame.content().popularimeter())
+        .collect();
+
+    popularimeters
+        .iter()
+        .find(|popm| popm.user == POPM_USER)
+        .or_else(|| popularimeters.first())
+        .and_then(|popm| stars_from_popm(popm.rating))
+}
+
+/// Write a rating to the file's tags, `None` removes it
+///
+/// mp3 files get a POPM frame, flac files a `RATING` Vorbis comment.
+pub fn write_rating(filepath: &Path, rating: Option<u8>) -> Result<(), TagError> {
+    match TagFormat::from_path(filepath) {
+        Some(TagFormat::Id3) => Ok(write_popm_rating(filepath, rating)?),
+        Some(TagFormat::Vorbis) => {
+            let mut tag = metaflac::Tag::read_from_path(filepath)?;
+            match rating {
+                Some(stars) => tag.set_vorbis("RATING", vec![vorbis_from_stars(stars)]),
+                None => tag.remove_vorbis("RATING"),
+            }
+            Ok(tag.save()?)
+        }
+        None => Err(TagError::UnsupportedFormat),
+    }
+}
+
+/// Write the analysed loudness of a file as ReplayGain track gain and peak tags
+pub fn write_replaygain(filepath: &Path, loudness: &Loudness) -> Result<(), TagError> {
+    let gain = format!("{:.2} dB", loudness.replaygain());
+    let peak = format!("{:.6}", loudness.true_peak);
+    match TagFormat::from_path(filepath) {
+        Some(TagFormat::Id3) => {
+            let mut tag = read_or_new_id3_tag(filepath)?;
+            // Replaces the TXXX frames with the same description
+            tag.add_frame(id3::frame::ExtendedText {
+                description: "REPLAYGAIN_TRACK_GAIN".to_string(),
+                value: gain,
+            });
+            tag.add_frame(id3::frame::ExtendedText {
+                description: "REPLAYGAIN_TRACK_PEAK".to_string(),
+                value: peak,
+            });
+            Ok(tag.write_to_path(filepath, tag.version())?)
+        }
+        Some(TagFormat::Vorbis) => {
+            let mut tag = metaflac::Tag::read_from_path(filepath)?;
+            tag.set_vorbis("REPLAYGAIN_TRACK_GAIN", vec![gain]);
+            tag.set_vorbis("REPLAYGAIN_TRACK_PEAK", vec![peak]);
+            Ok(tag.save()?)
+        }
+        None => Err(TagError::UnsupportedFormat),
+    }
+}
+
+fn write_popm_rating(filepath: &Path, rating: Option<u8>) -> Result<(), id3::Error> {
+    let mut tag = read_or_new_id3_tag(filepath)?;
+
+    let is_sousa_popm = |frame: &id3::Frame| {
+        frame
+            .content()
+            .popularimeter()
+            .is_some_and(|popm| popm.user == POPM_USER)
+    };
+    let counter = tag
+        .frames()
+        .filter_map(|frame| frame.content().popularimeter())
+        .find(|popm| popm.user == POPM_USER)
+        .map(|popm| popm.counter)
+        .unwrap_or(0);
+
+    match rating.filter(|stars| *stars > 0) {
+        // Replaces the frame Sousa wrote before, other players' frames are kept
+        Some(stars) => {
+            tag.add_frame(id3::frame::Popularimeter {
+                user: POPM_USER.to_string(),
+                rating: popm_from_stars(stars),
+                counter,
+            });
+        }
+        None => {
+            let mut cleared_tag = Tag::with_version(tag.version());
+            for frame in tag.frames().filter(|frame| !is_sousa_popm(frame)) {
+                cleared_tag.add_frame(frame.clone());
+            }
+            tag = cleared_tag;
+        }
+    }
+
+    tag.write_to_path(filepath, tag.version())
+}
+
+#[test]
+fn test_rating_conversions() {
+    for stars in 1..=5 {
+        assert_eq!(stars_from_popm(popm_from_stars(stars)), Some(stars));
+        assert_eq!(stars_from_vorbis(&vorbis_from_stars(stars)), Some(stars));
+    }
+    assert_eq!(stars_from_popm(0), None);
+    assert_eq!(stars_from_vorbis("4"), Some(4));
+    assert_eq!(stars_from_vorbis("not a rating"), None);
+}
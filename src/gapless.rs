@@ -0,0 +1,308 @@
+use rodio::{Sample, Source};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::file_operations::TagFormat;
+
+/// The delay of the MP3 decoder itself, added on top of the encoder delay LAME reports
+const MP3_DECODER_DELAY: u64 = 529;
+
+/// How much of the start of an mp3 is read looking for the LAME header
+const LAME_HEADER_SEARCH_LENGTH: u64 = 64 * 1024;
+
+/// The silence an encoder added around the audio, in frames (one sample per channel)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GaplessInfo {
+    /// Frames to drop from the start of the decoded audio
+    pub skip_frames: u64,
+    /// Frames to drop from the end of the decoded audio
+    pub trailing_frames: u64,
+}
+
+/// Parse the iTunes `iTunSMPB` comment, " 00000000 <delay> <padding> <length> ..." in hex
+pub fn parse_itunsmpb(value: &str) -> Option<GaplessInfo> {
+    let fields: Vec<u64> = value
+        .split_whitespace()
+        .take(3)
+        .map(|field| u64::from_str_radix(field, 16))
+        .collect::<Result<_, _>>()
+        .ok()?;
+
+    match fields[..] {
+        [_, skip_frames, trailing_frames] => Some(GaplessInfo {
+            skip_frames,
+            trailing_frames,
+        }),
+        _ => None,
+    }
+}
+
+/// Find the Xing/Info frame with a LAME extension at the start of the mp3 data
+///
+/// `data` starts at the first MPEG frame, after any ID3v2 tag. The Info frame itself
+/// carries no audio but decodes to a frame of silence, so it is skipped as well.
+pub fn parse_lame_header(data: &[u8]) -> Option<GaplessInfo> {
+    let header = data.get(..4)?;
+    if header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+
+    let mpeg1 = (header[1] >> 3) & 0b11 == 0b11;
+    let mono = header[3] >> 6 == 0b11;
+    let (side_info_length, frame_samples) = match (mpeg1, mono) {
+        (true, true) => (17, 1152),
+        (true, false) => (32, 1152),
+        (false, true) => (9, 576),
+        (false, false) => (17, 576),
+    };
+
+    let mut position = 4 + side_info_length;
+    let tag = data.get(position..position + 4)?;
+    if tag != b"Xing" && tag != b"Info" {
+        return None;
+    }
+    let flags = u32::from_be_bytes(data.get(position + 4..position + 8)?.try_into().ok()?);
+    position += 8;
+    // Frame count, byte count, seek table and quality, whichever are present
+    for (flag, length) in [(0x1, 4), (0x2, 4), (0x4, 100), (0x8, 4)] {
+        if flags & flag != 0 {
+            position += length;
+        }
+    }
+
+    // The delay and padding are 12 bits each, 21 bytes into the LAME extension
+    if data.get(position..position + 4)? != b"LAME" {
+        return None;
+    }
+    let delay_padding = data.get(position + 21..position + 24)?;
+    let encoder_delay = ((delay_padding[0] as u64) << 4) | (delay_padding[1] as u64 >> 4);
+    let padding = (((delay_padding[1] & 0x0F) as u64) << 8) | delay_padding[2] as u64;
+
+    Some(GaplessInfo {
+        skip_frames: frame_samples + encoder_delay + MP3_DECODER_DELAY,
+        trailing_frames: padding.saturating_sub(MP3_DECODER_DELAY),
+    })
+}
+
+/// The length of the ID3v2 tag at the start of `data`, 0 if there is none
+fn id3v2_length(data: &[u8]) -> usize {
+    match data.get(..10) {
+        Some(header) if &header[..3] == b"ID3" => {
+            let size = header[6..10]
+                .iter()
+                .fold(0usize, |size, byte| (size << 7) | (*byte & 0x7F) as usize);
+            let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+            10 + size + footer
+        }
+        _ => 0,
+    }
+}
+
+/// Read the encoder delay and padding of a file
+///
+/// Only mp3 files have any; an `iTunSMPB` comment is preferred over the LAME header.
+pub fn read_gapless_info(path: &Path) -> Option<GaplessInfo> {
+    if TagFormat::from_path(path)? != TagFormat::Id3 {
+        return None;
+    }
+
+    if let Ok(tag) = id3::Tag::read_from_path(path) {
+        let itunsmpb = tag
+            .comments()
+            .find(|comment| comment.description == "iTunSMPB")
+            .and_then(|comment| parse_itunsmpb(&comment.text));
+        if itunsmpb.is_some() {
+            return itunsmpb;
+        }
+    }
+
+    let mut data = Vec::<u8>::new();
+    File::open(path)
+        .ok()?
+        .take(LAME_HEADER_SEARCH_LENGTH)
+        .read_to_end(&mut data)
+        .ok()?;
+    parse_lame_header(data.get(id3v2_length(&data)..)?)
+}
+
+/// A source with the encoder delay and padding cut off
+///
+/// The end is trimmed by holding back as many samples as there is padding, so the length
+/// of the track doesn't need to be known up front.
+pub struct Trimmed<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    inner: S,
+    skip_samples: u64,
+    trailing_samples: usize,
+    held_back: VecDeque<S::Item>,
+}
+
+impl<S> Trimmed<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    pub fn new(inner: S, gapless_info: GaplessInfo) -> Self {
+        let channels = inner.channels() as u64;
+        Trimmed {
+            skip_samples: gapless_info.skip_frames * channels,
+            trailing_samples: (gapless_info.trailing_frames * channels) as usize,
+            held_back: VecDeque::with_capacity((gapless_info.trailing_frames * channels) as usize),
+            inner,
+        }
+    }
+}
+
+impl<S> Iterator for Trimmed<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        while self.skip_samples > 0 {
+            self.inner.next()?;
+            self.skip_samples -= 1;
+        }
+
+        while self.held_back.len() <= self.trailing_samples {
+            // Once the inner source ends, whatever is held back is padding
+            self.held_back.push_back(self.inner.next()?);
+        }
+        self.held_back.pop_front()
+    }
+}
+
+impl<S> Source for Trimmed<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// When a track ran out of samples, shared between the audio thread and the player
+pub type TrackEnd = Arc<Mutex<Option<Instant>>>;
+
+/// A source that records the moment it runs out in a `TrackEnd`
+pub struct EndSignal<S> {
+    inner: S,
+    end: TrackEnd,
+}
+
+impl<S> EndSignal<S> {
+    pub fn new(inner: S, end: &TrackEnd) -> Self {
+        EndSignal {
+            inner,
+            end: Arc::clone(end),
+        }
+    }
+}
+
+impl<S> Iterator for EndSignal<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        let sample = self.inner.next();
+        if sample.is_none() {
+            let mut end = self.end.lock().unwrap();
+            if end.is_none() {
+                *end = Some(Instant::now());
+            }
+        }
+        sample
+    }
+}
+
+impl<S> Source for EndSignal<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+#[test]
+fn test_gapless_info_and_trimming() {
+    assert_eq!(
+        parse_itunsmpb(" 00000000 00000840 000001CA 00000000003F31F6 00000000"),
+        Some(GaplessInfo {
+            skip_frames: 0x840,
+            trailing_frames: 0x1CA,
+        })
+    );
+    assert_eq!(parse_itunsmpb("not a number"), None);
+
+    // An MPEG1 stereo Info frame with only the frame count, then LAME with delay 576, padding 1260
+    let mut frame = vec![0xFF, 0xFB, 0x90, 0x00];
+    frame.extend([0; 32]);
+    frame.extend(b"Info");
+    frame.extend(1u32.to_be_bytes());
+    frame.extend(100u32.to_be_bytes());
+    frame.extend(b"LAME3.100");
+    frame.extend([0; 12]);
+    frame.extend([0x24, 0x04, 0xEC]);
+    assert_eq!(
+        parse_lame_header(&frame),
+        Some(GaplessInfo {
+            skip_frames: 1152 + 576 + 529,
+            trailing_frames: 1260 - 529,
+        })
+    );
+    assert_eq!(parse_lame_header(&frame[..40]), None);
+
+    let source = rodio::buffer::SamplesBuffer::new(2, 44100, (0..20).collect::<Vec<i16>>());
+    let end: TrackEnd = Arc::new(Mutex::new(None));
+    let trimmed = EndSignal::new(
+        Trimmed::new(
+            source,
+            GaplessInfo {
+                skip_frames: 2,
+                trailing_frames: 3,
+            },
+        ),
+        &end,
+    );
+    assert_eq!(trimmed.collect::<Vec<i16>>(), (4..14).collect::<Vec<i16>>());
+    assert!(end.lock().unwrap().is_some());
+}
@@ -0,0 +1,740 @@
+use log::{info, warn};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+use crate::http_stream::{read_request_head, write_status};
+use crate::message_types::{PartialTag, ServerResponse, SkipDirection, UIRequest};
+use crate::server_handling::{is_authorized, query_parameter, ClientConnection};
+
+/// How long a connection has to send its request before it is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a connection waits for the server loop to handle its request
+const HANDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// The largest request body that is accepted
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const MAX_HEADERS: usize = 32;
+/// How many tracks the recently and most played lists have when the request doesn't say
+const DEFAULT_LIST_LIMIT: usize = 20;
+
+/// The HTTP API, part of the configuration file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ApiSettings {
+    pub enabled: bool,
+    /// The address the HTTP API listens on
+    pub address: String,
+}
+
+impl Default for ApiSettings {
+    fn default() -> Self {
+        ApiSettings {
+            enabled: true,
+            address: "127.0.0.1:9004".to_string(),
+        }
+    }
+}
+
+/// An endpoint of the API, for both routing and the OpenAPI description
+///
+/// Path segments in braces are parameters, like `/api/playlists/{name}`.
+struct Route {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    /// The query parameters along with their JSON type, beside `zone` which every route takes
+    query: &'static [(&'static str, &'static str)],
+    /// The schema of the JSON body, for routes that take one
+    body: Option<fn(&mut SchemaGenerator) -> Schema>,
+}
+
+const TAG_QUERY: &[(&str, &str)] = &[
+    ("title", "string"),
+    ("artist", "string"),
+    ("album", "string"),
+    ("album_artist", "string"),
+    ("genre", "string"),
+    ("year", "integer"),
+    ("label", "string"),
+    ("lyrics", "string"),
+    ("favorite", "boolean"),
+    ("min_rating", "integer"),
+];
+
+const ROUTES: &[Route] = &[
+    Route {
+        method: "GET",
+        path: "/api/status",
+        summary: "What the player is doing",
+        query: &[],
+        body: None,
+    },
+    Route {
+        method: "GET",
+        path: "/api/queue",
+        summary: "The play queue and the position of the current track in it",
+        query: &[],
+        body: None,
+    },
+    Route {
+        method: "POST",
+        path: "/api/play",
+        summary: "Resume playing",
+        query: &[],
+        body: None,
+    },
+    Route {
+        method: "POST",
+        path: "/api/pause",
+        summary: "Pause",
+        query: &[],
+        body: None,
+    },
+    Route {
+        method: "POST",
+        path: "/api/next",
+        summary: "Skip to the next track",
+        query: &[],
+        body: None,
+    },
+    Route {
+        method: "POST",
+        path: "/api/previous",
+        summary: "Go back to the previous track",
+        query: &[],
+        body: None,
+    },
+    Route {
+        method: "PUT",
+        path: "/api/volume",
+        summary: "Set the volume, from 0 to 1",
+        query: &[],
+        body: Some(SchemaGenerator::subschema_for::<f32>),
+    },
+    Route {
+        method: "GET",
+        path: "/api/search",
+        summary: "Search the library, every parameter given has to match",
+        query: TAG_QUERY,
+        body: None,
+    },
+    Route {
+        method: "POST",
+        path: "/api/switch-to",
+        summary: "Queue the tracks matching a tag and play them",
+        query: &[],
+        body: Some(SchemaGenerator::subschema_for::<PartialTag>),
+    },
+    Route {
+        method: "GET",
+        path: "/api/recently-played",
+        summary: "The most recently played tracks",
+        query: &[("limit", "integer")],
+        body: None,
+    },
+    Route {
+        method: "GET",
+        path: "/api/most-played",
+        summary: "The tracks with the highest play counts",
+        query: &[("limit", "integer")],
+        body: None,
+    },
+    Route {
+        method: "GET",
+        path: "/api/labels",
+        summary: "Every label in the library",
+        query: &[],
+        body: None,
+    },
+    Route {
+        method: "GET",
+        path: "/api/playlists",
+        summary: "List the playlists",
+        query: &[],
+        body: None,
+    },
+    Route {
+        method: "GET",
+        path: "/api/playlists/{name}",
+        summary: "The entries of a playlist",
+        query: &[],
+        body: None,
+    },
+    Route {
+        method: "PUT",
+        path: "/api/playlists/{name}",
+        summary: "Create a playlist",
+        query: &[],
+        body: None,
+    },
+    Route {
+        method: "DELETE",
+        path: "/api/playlists/{name}",
+        summary: "Delete a playlist",
+        query: &[],
+        body: None,
+    },
+    Route {
+        method: "POST",
+        path: "/api/playlists/{name}/tracks",
+        summary: "Append every track matching a tag to a playlist",
+        query: &[],
+        body: Some(SchemaGenerator::subschema_for::<PartialTag>),
+    },
+    Route {
+        method: "POST",
+        path: "/api/playlists/{name}/play",
+        summary: "Replace the play queue with a playlist and play it",
+        query: &[],
+        body: None,
+    },
+    Route {
+        method: "GET",
+        path: "/api/zones",
+        summary: "List the zones",
+        query: &[],
+        body: None,
+    },
+    Route {
+        method: "POST",
+        path: "/api/request",
+        summary: "Handle any request the websocket takes",
+        query: &[],
+        body: Some(SchemaGenerator::subschema_for::<UIRequest>),
+    },
+    Route {
+        method: "GET",
+        path: "/api/openapi.json",
+        summary: "This description of the API",
+        query: &[],
+        body: None,
+    },
+];
+
+/// Collects what the request handler sends, to answer an HTTP request with
+#[derive(Default)]
+pub struct ResponseCollector {
+    pub messages: Vec<String>,
+}
+
+impl ClientConnection for ResponseCollector {
+    fn send_message(&mut self, message: String) -> Result<(), tungstenite::Error> {
+        self.messages.push(message);
+        Ok(())
+    }
+}
+
+/// A request waiting for the server loop to handle it
+struct ApiCall {
+    request: UIRequest,
+    reply: Sender<Vec<String>>,
+}
+
+/// Answers the HTTP API, the same requests the websocket takes as REST endpoints
+///
+/// Every connection is served from a thread of its own that turns the HTTP request into
+/// a `UIRequest`; the server loop handles it in `poll` like one from the websocket.
+pub struct ApiServer {
+    listener: TcpListener,
+    access_token: Option<String>,
+    calls: Receiver<ApiCall>,
+    call_sender: Sender<ApiCall>,
+}
+
+impl ApiServer {
+    pub fn bind(settings: &ApiSettings, access_token: Option<String>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(&settings.address)?;
+        listener.set_nonblocking(true)?;
+        info!("HTTP API listening on: {}", listener.local_addr()?);
+
+        let (call_sender, calls) = channel();
+        Ok(ApiServer {
+            listener,
+            access_token,
+            calls,
+            call_sender,
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Take the connections that are waiting and run the requests of the ones being served
+    /// through `handle`, which returns the messages it sent
+    pub fn poll(&self, mut handle: impl FnMut(UIRequest) -> Vec<String>) {
+        while let Ok((stream, addr)) = self.listener.accept() {
+            info!("New API connection from: {}", addr);
+            let access_token = self.access_token.clone();
+            let call_sender = self.call_sender.clone();
+            std::thread::spawn(move || {
+                if let Err(err) = serve_connection(stream, access_token.as_deref(), &call_sender) {
+                    warn!("Could not serve an API request: {}", err);
+                }
+            });
+        }
+
+        while let Ok(call) = self.calls.try_recv() {
+            // The connection may have timed out waiting
+            let _ = call.reply.send(handle(call.request));
+        }
+    }
+}
+
+/// The parts of a request the API looks at
+#[derive(Debug)]
+struct ApiRequest {
+    method: String,
+    path: String,
+    query: String,
+    authorization: Option<String>,
+    content_length: usize,
+    /// The part of the body that was read along with the head
+    body: Vec<u8>,
+}
+
+/// Parse a request head, `Err` holds the status line to answer with
+fn parse_request(head: &[u8]) -> Result<ApiRequest, &'static str> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    let head_length = match request.parse(head) {
+        Ok(httparse::Status::Complete(length)) => length,
+        _ => return Err("400 Bad Request"),
+    };
+
+    let header = |name: &str| {
+        request
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| String::from_utf8_lossy(header.value).into_owned())
+    };
+    let content_length = match header("Content-Length") {
+        Some(length) => length.trim().parse().map_err(|_| "400 Bad Request")?,
+        None => 0,
+    };
+    let target = request.path.unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Ok(ApiRequest {
+        method: request.method.unwrap_or_default().to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        authorization: header("Authorization"),
+        content_length,
+        body: head[head_length..].to_vec(),
+    })
+}
+
+/// The route a request is for along with its path parameters, `Err` holds the status line
+/// to answer with
+fn find_route(method: &str, path: &str) -> Result<(&'static Route, Vec<String>), &'static str> {
+    let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+    let mut path_found = false;
+    for route in ROUTES {
+        let template: Vec<&str> = route.path.split('/').collect();
+        if template.len() != segments.len() {
+            continue;
+        }
+        let mut parameters = vec![];
+        let matches = template.iter().zip(&segments).all(|(expected, segment)| {
+            if expected.starts_with('{') {
+                parameters.push(
+                    percent_encoding::percent_decode_str(segment)
+                        .decode_utf8_lossy()
+                        .into_owned(),
+                );
+                !segment.is_empty()
+            } else {
+                expected == segment
+            }
+        });
+        if !matches {
+            continue;
+        }
+        if route.method == method {
+            return Ok((route, parameters));
+        }
+        path_found = true;
+    }
+    Err(if path_found {
+        "405 Method Not Allowed"
+    } else {
+        "404 Not Found"
+    })
+}
+
+/// A tag to search with from the query parameters
+fn tag_from_query(query: &str) -> Result<PartialTag, String> {
+    let text = |name: &str| query_parameter(query, name);
+    fn parse<T: std::str::FromStr>(query: &str, name: &str) -> Result<Option<T>, String> {
+        query_parameter(query, name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("'{}' isn't a valid {}", value, name))
+            })
+            .transpose()
+    }
+    Ok(PartialTag {
+        title: text("title"),
+        artist: text("artist"),
+        album: text("album"),
+        album_artist: text("album_artist"),
+        genre: text("genre"),
+        year: parse(query, "year")?,
+        label: text("label"),
+        lyrics: text("lyrics"),
+        favorite: parse(query, "favorite")?,
+        min_rating: parse(query, "min_rating")?,
+        ..PartialTag::default()
+    })
+}
+
+fn json_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, String> {
+    serde_json::from_slice(body).map_err(|err| err.to_string())
+}
+
+/// The request a route stands for, `Err` holds why the request doesn't make sense
+fn build_request(
+    route: &Route,
+    parameters: Vec<String>,
+    query: &str,
+    body: &[u8],
+) -> Result<UIRequest, String> {
+    let limit = || -> Result<usize, String> {
+        query_parameter(query, "limit")
+            .map(|limit| limit.parse().map_err(|_| "The limit has to be a number"))
+            .transpose()
+            .map(|limit| limit.unwrap_or(DEFAULT_LIST_LIMIT))
+            .map_err(str::to_string)
+    };
+    let name = || parameters[0].clone();
+
+    let request = match (route.method, route.path) {
+        ("GET", "/api/status") => UIRequest::GetStatus,
+        ("GET", "/api/queue") => UIRequest::GetQueue,
+        ("POST", "/api/play") => UIRequest::Play,
+        ("POST", "/api/pause") => UIRequest::Pause,
+        ("POST", "/api/next") => UIRequest::Skip(SkipDirection::Forward),
+        ("POST", "/api/previous") => UIRequest::Skip(SkipDirection::Backward),
+        ("PUT", "/api/volume") => UIRequest::SetVolume(json_body(body)?),
+        ("GET", "/api/search") => UIRequest::Search(tag_from_query(query)?),
+        ("POST", "/api/switch-to") => UIRequest::SwitchTo(json_body(body)?),
+        ("GET", "/api/recently-played") => UIRequest::RecentlyPlayed(limit()?),
+        ("GET", "/api/most-played") => UIRequest::MostPlayed(limit()?),
+        ("GET", "/api/labels") => UIRequest::ListLabels,
+        ("GET", "/api/playlists") => UIRequest::ListPlaylists,
+        ("GET", "/api/playlists/{name}") => UIRequest::GetPlaylist(name()),
+        ("PUT", "/api/playlists/{name}") => UIRequest::CreatePlaylist(name()),
+        ("DELETE", "/api/playlists/{name}") => UIRequest::DeletePlaylist(name()),
+        ("POST", "/api/playlists/{name}/tracks") => {
+            UIRequest::AddToPlaylist(name(), json_body(body)?)
+        }
+        ("POST", "/api/playlists/{name}/play") => UIRequest::LoadPlaylist(name()),
+        ("GET", "/api/zones") => UIRequest::ListZones,
+        ("POST", "/api/request") => json_body(body)?,
+        _ => unreachable!("{} {} has no request", route.method, route.path),
+    };
+    Ok(match query_parameter(query, "zone") {
+        Some(zone) => UIRequest::InZone(zone, Box::new(request)),
+        None => request,
+    })
+}
+
+/// The OpenAPI description of every route, with the schemas generated from the request and
+/// response types
+pub fn openapi_document() -> Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    let response_schema = generator.subschema_for::<ServerResponse>();
+
+    let mut paths = serde_json::Map::new();
+    for route in ROUTES {
+        let mut parameters: Vec<Value> = route
+            .path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+            .map(|name| {
+                json!({"name": name, "in": "path", "required": true, "schema": {"type": "string"}})
+            })
+            .collect();
+        let query = route.query.iter().chain(&[("zone", "string")]);
+        parameters.extend(query.map(|(name, kind)| {
+            json!({"name": name, "in": "query", "required": false, "schema": {"type": kind}})
+        }));
+
+        let mut operation = json!({
+            "summary": route.summary,
+            "parameters": parameters,
+            "responses": {
+                "200": {
+                    "description": "The server's answer, the message says whether it went through",
+                    "content": {"application/json": {"schema": response_schema}},
+                },
+                "401": {"description": "The access token is missing or wrong"},
+            },
+        });
+        if let Some(body) = route.body {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": {"application/json": {"schema": body(&mut generator)}},
+            });
+        }
+        let methods = paths
+            .entry(route.path)
+            .or_insert_with(|| Value::Object(Default::default()));
+        methods[route.method.to_lowercase()] = operation;
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Sousa",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Control the Sousa music server, the same requests the websocket takes.",
+        },
+        "paths": paths,
+        "components": {
+            "schemas": generator.take_definitions(),
+            "securitySchemes": {"token": {"type": "http", "scheme": "bearer"}},
+        },
+        "security": [{"token": []}],
+    })
+}
+
+fn write_json(stream: &mut TcpStream, status: &str, body: &Value) -> std::io::Result<()> {
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// A message that isn't a request's answer in JSON, like an error
+fn message_response(message: String) -> Value {
+    serde_json::to_value(ServerResponse {
+        message,
+        search_results: vec![],
+        payload: None,
+    })
+    .unwrap()
+}
+
+fn serve_connection(
+    mut stream: TcpStream,
+    access_token: Option<&str>,
+    call_sender: &Sender<ApiCall>,
+) -> std::io::Result<()> {
+    // Accepted sockets take on the listener's non-blocking mode on some platforms
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let head = read_request_head(&mut stream)?;
+    let mut request = match parse_request(&head) {
+        Ok(request) => request,
+        Err(status) => return write_status(&mut stream, status, ""),
+    };
+    if !is_authorized(
+        access_token,
+        request.authorization.as_deref(),
+        Some(&request.query),
+    ) {
+        return write_status(
+            &mut stream,
+            "401 Unauthorized",
+            "WWW-Authenticate: Bearer\r\n",
+        );
+    }
+    let (route, parameters) = match find_route(&request.method, &request.path) {
+        Ok(found) => found,
+        Err(status) => return write_status(&mut stream, status, ""),
+    };
+    if route.path == "/api/openapi.json" {
+        return write_json(&mut stream, "200 OK", &openapi_document());
+    }
+
+    if request.content_length > MAX_BODY_SIZE {
+        return write_status(&mut stream, "413 Payload Too Large", "");
+    }
+    let missing = request.content_length.saturating_sub(request.body.len());
+    (&mut stream)
+        .take(missing as u64)
+        .read_to_end(&mut request.body)?;
+    request.body.truncate(request.content_length);
+
+    let ui_request = match build_request(route, parameters, &request.query, &request.body) {
+        Ok(ui_request) => ui_request,
+        Err(err) => return write_json(&mut stream, "400 Bad Request", &message_response(err)),
+    };
+    let (reply, messages) = channel();
+    let call = ApiCall {
+        request: ui_request,
+        reply,
+    };
+    let messages = match call_sender.send(call) {
+        Ok(()) => messages.recv_timeout(HANDLE_TIMEOUT).ok(),
+        Err(_) => None,
+    };
+    // Every request is answered with one message, anything after it is pushed to every
+    // client and is left out
+    match messages.and_then(|messages| messages.into_iter().next()) {
+        Some(message) => {
+            let response =
+                serde_json::from_str(&message).unwrap_or_else(|_| message_response(message));
+            write_json(&mut stream, "200 OK", &response)
+        }
+        None => write_status(&mut stream, "503 Service Unavailable", ""),
+    }
+}
+
+#[test]
+fn test_api_routes() {
+    let request = |method: &str, target: &str, body: &str| {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let (route, parameters) = find_route(method, path)?;
+        build_request(route, parameters, query, body.as_bytes()).map_err(|_| "400 Bad Request")
+    };
+
+    assert!(matches!(
+        request("GET", "/api/status", ""),
+        Ok(UIRequest::GetStatus)
+    ));
+    assert!(matches!(
+        request("POST", "/api/next/", ""),
+        Ok(UIRequest::Skip(SkipDirection::Forward))
+    ));
+    assert!(matches!(
+        request("GET", "/api/play", ""),
+        Err("405 Method Not Allowed")
+    ));
+    assert!(matches!(
+        request("GET", "/api/nothing", ""),
+        Err("404 Not Found")
+    ));
+
+    match request(
+        "GET",
+        "/api/search?artist=Sonic+Youth&year=1988&zone=Kitchen",
+        "",
+    ) {
+        Ok(UIRequest::InZone(zone, search)) => {
+            assert_eq!(zone, "Kitchen");
+            match *search {
+                UIRequest::Search(tag) => {
+                    assert_eq!(tag.artist.as_deref(), Some("Sonic Youth"));
+                    assert_eq!(tag.year, Some(1988));
+                    assert_eq!(tag.title, None);
+                }
+                _ => panic!("not a search"),
+            }
+        }
+        _ => panic!("not a search in a zone"),
+    }
+    assert!(request("GET", "/api/search?year=soon", "").is_err());
+
+    match request(
+        "POST",
+        "/api/playlists/Road%20trip/tracks",
+        r#"{"album": "Daydream Nation"}"#,
+    ) {
+        Ok(UIRequest::AddToPlaylist(name, tag)) => {
+            assert_eq!(name, "Road trip");
+            assert_eq!(tag.album.as_deref(), Some("Daydream Nation"));
+        }
+        _ => panic!("not an addition to a playlist"),
+    }
+    assert!(matches!(
+        request("GET", "/api/most-played?limit=5", ""),
+        Ok(UIRequest::MostPlayed(5))
+    ));
+    assert!(matches!(
+        request("POST", "/api/request", r#"{"SetVolume": 0.25}"#),
+        Ok(UIRequest::SetVolume(_))
+    ));
+
+    let document = openapi_document();
+    for route in ROUTES {
+        assert!(
+            document["paths"][route.path][route.method.to_lowercase()].is_object(),
+            "{} {} is not described",
+            route.method,
+            route.path
+        );
+    }
+    let schemas = &document["components"]["schemas"];
+    assert!(schemas["UIRequest"].is_object());
+    assert!(schemas["ServerResponse"].is_object());
+    assert!(schemas["PlayerStatus"].is_object());
+}
+
+#[test]
+fn test_api_round_trip() {
+    let settings = ApiSettings {
+        address: "127.0.0.1:0".to_string(),
+        ..ApiSettings::default()
+    };
+    let server = ApiServer::bind(&settings, Some("secret".to_string())).unwrap();
+    let address = server.local_addr().unwrap();
+
+    // A client on a thread of its own, while this one runs the server loop
+    let client = std::thread::spawn(move || {
+        let fetch = |request: &str| -> (String, String) {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            (head.to_string(), body.to_string())
+        };
+
+        let (head, _) = fetch("POST /api/pause HTTP/1.1\r\nHost: sousa\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 401"), "{}", head);
+
+        let (head, body) = fetch("POST /api/pause?token=secret HTTP/1.1\r\nHost: sousa\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        let response: ServerResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(response.message, "Player Paused");
+
+        let playlist = r#"{"artist": "Low"}"#;
+        let (head, body) = fetch(&format!(
+            "POST /api/playlists/Slowcore/tracks HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: {}\r\n\r\n{}",
+            playlist.len(),
+            playlist
+        ));
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert!(body.contains("Added to Slowcore"), "{}", body);
+
+        let (head, body) = fetch("GET /api/openapi.json?token=secret HTTP/1.1\r\n\r\n");
+        assert!(head.contains("Content-Type: application/json"), "{}", head);
+        let document: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(document["openapi"], "3.0.3");
+    });
+
+    let mut handled = vec![];
+    while !client.is_finished() {
+        server.poll(|request| {
+            let message = match &request {
+                UIRequest::Pause => "Player Paused".to_string(),
+                UIRequest::AddToPlaylist(name, tag) if tag.artist.is_some() => {
+                    format!("Added to {}", name)
+                }
+                _ => "Unexpected request".to_string(),
+            };
+            handled.push(message.clone());
+            let mut collector = ResponseCollector::default();
+            crate::server_handling::write_to_socket(&mut collector, message, vec![]).unwrap();
+            collector.messages
+        });
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    client.join().unwrap();
+    assert_eq!(handled, vec!["Player Paused", "Added to Slowcore"]);
+}
@@ -0,0 +1,530 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+use crate::db_operations::DBObject;
+use crate::server_handling::{is_authorized, query_parameter};
+
+/// How long a connection has to send its request before it is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a connection waits for the server loop to look up its track
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// The largest request head that is read, requests are only ever a few headers
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 32;
+/// The bitrate of transcoded streams when the request doesn't give one, in kbit/s
+const DEFAULT_TRANSCODE_BITRATE: u32 = 128;
+
+/// Serving the library's audio files over HTTP, part of the configuration file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct StreamingSettings {
+    pub enabled: bool,
+    /// The address the HTTP server listens on
+    pub address: String,
+    /// The ffmpeg binary that transcodes streams, transcoding fails if it isn't installed
+    pub ffmpeg: String,
+}
+
+impl Default for StreamingSettings {
+    fn default() -> Self {
+        StreamingSettings {
+            enabled: true,
+            address: "127.0.0.1:9002".to_string(),
+            ffmpeg: "ffmpeg".to_string(),
+        }
+    }
+}
+
+/// A format tracks can be transcoded to on the fly, for clients on slow connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscodeFormat {
+    Mp3,
+    Opus,
+}
+
+impl TranscodeFormat {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "mp3" => Some(TranscodeFormat::Mp3),
+            "opus" => Some(TranscodeFormat::Opus),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            TranscodeFormat::Mp3 => "audio/mpeg",
+            TranscodeFormat::Opus => "audio/ogg",
+        }
+    }
+
+    /// The ffmpeg encoder and container
+    fn ffmpeg_codec(self) -> (&'static str, &'static str) {
+        match self {
+            TranscodeFormat::Mp3 => ("libmp3lame", "mp3"),
+            TranscodeFormat::Opus => ("libopus", "ogg"),
+        }
+    }
+}
+
+/// A connection waiting for the server loop to look up the path of a track
+struct TrackLookup {
+    track_id: i64,
+    reply: Sender<Option<String>>,
+}
+
+/// Streams the tracks of the library to HTTP clients
+///
+/// `GET /tracks/<id>/stream` sends the file as it is, with `Range` requests for seeking.
+/// `?format=mp3` or `?format=opus`, with an optional `&bitrate=<kbit/s>`, transcodes it
+/// instead. Every connection is served from a thread of its own; the tracks are looked up
+/// by the server loop in `poll`, as that is where the database is.
+pub struct StreamServer {
+    listener: TcpListener,
+    settings: StreamingSettings,
+    access_token: Option<String>,
+    lookups: Receiver<TrackLookup>,
+    lookup_sender: Sender<TrackLookup>,
+}
+
+impl StreamServer {
+    pub fn bind(
+        settings: &StreamingSettings,
+        access_token: Option<String>,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(&settings.address)?;
+        listener.set_nonblocking(true)?;
+        info!("Streaming over HTTP on: {}", listener.local_addr()?);
+
+        let (lookup_sender, lookups) = channel();
+        Ok(StreamServer {
+            listener,
+            settings: settings.clone(),
+            access_token,
+            lookups,
+            lookup_sender,
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Take the connections that are waiting and answer the track lookups of the ones
+    /// being served
+    pub fn poll(&self, dbo: &DBObject) {
+        while let Ok((stream, addr)) = self.listener.accept() {
+            info!("New HTTP connection from: {}", addr);
+            let settings = self.settings.clone();
+            let access_token = self.access_token.clone();
+            let lookup_sender = self.lookup_sender.clone();
+            std::thread::spawn(move || {
+                if let Err(err) =
+                    serve_connection(stream, &settings, access_token.as_deref(), &lookup_sender)
+                {
+                    warn!("Could not serve an HTTP request: {}", err);
+                }
+            });
+        }
+
+        while let Ok(lookup) = self.lookups.try_recv() {
+            let path = match dbo.get_track_path(lookup.track_id) {
+                Ok(path) => path,
+                Err(err) => {
+                    warn!("Could not look up track {}: {}", lookup.track_id, err);
+                    None
+                }
+            };
+            // The connection may have timed out waiting
+            let _ = lookup.reply.send(path);
+        }
+    }
+}
+
+/// The parts of a request the stream endpoint looks at
+#[derive(Debug, PartialEq, Eq)]
+struct StreamRequest {
+    head_only: bool,
+    track_id: i64,
+    query: String,
+    authorization: Option<String>,
+    range: Option<String>,
+}
+
+/// Parse a request head, `Err` holds the status line to answer with
+fn parse_request(head: &[u8]) -> Result<StreamRequest, &'static str> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        _ => return Err("400 Bad Request"),
+    }
+
+    let head_only = match request.method {
+        Some("GET") => false,
+        Some("HEAD") => true,
+        _ => return Err("405 Method Not Allowed"),
+    };
+    let target = request.path.unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let track_id = path
+        .strip_prefix("/tracks/")
+        .and_then(|rest| rest.strip_suffix("/stream"))
+        .and_then(|id| id.parse().ok())
+        .ok_or("404 Not Found")?;
+
+    let header = |name: &str| {
+        request
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| String::from_utf8_lossy(header.value).into_owned())
+    };
+    Ok(StreamRequest {
+        head_only,
+        track_id,
+        query: query.to_string(),
+        authorization: header("Authorization"),
+        range: header("Range"),
+    })
+}
+
+/// The byte range to send of a file `length` bytes long, as (first byte, last byte)
+///
+/// `None` if the whole file should be sent, which is also the answer to range requests this
+/// doesn't handle, like several ranges at once. `Some(Err(()))` if the range is past the end.
+fn parse_range(range: &str, length: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // The last `end` bytes
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || length == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok((length.saturating_sub(suffix), length - 1)));
+    }
+
+    let start: u64 = start.parse().ok()?;
+    let end: u64 = match end {
+        "" => u64::MAX,
+        end => end.parse().ok()?,
+    };
+    if start >= length || end < start {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end.min(length - 1))))
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg") | Some("oga") | Some("opus") => "audio/ogg",
+        Some("m4a") | Some("mp4") | Some("aac") => "audio/mp4",
+        Some("wav") => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Read a request head, up to the blank line that ends it
+pub(crate) fn read_request_head(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut head = vec![];
+    let mut buffer = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_SIZE {
+            break;
+        }
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    Ok(head)
+}
+
+pub(crate) fn write_status(
+    stream: &mut TcpStream,
+    status: &str,
+    extra_headers: &str,
+) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+        status, extra_headers
+    )
+}
+
+fn serve_connection(
+    mut stream: TcpStream,
+    settings: &StreamingSettings,
+    access_token: Option<&str>,
+    lookup_sender: &Sender<TrackLookup>,
+) -> std::io::Result<()> {
+    // Accepted sockets take on the listener's non-blocking mode on some platforms
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let head = read_request_head(&mut stream)?;
+    let request = match parse_request(&head) {
+        Ok(request) => request,
+        Err(status) => return write_status(&mut stream, status, ""),
+    };
+    if !is_authorized(
+        access_token,
+        request.authorization.as_deref(),
+        Some(&request.query),
+    ) {
+        return write_status(
+            &mut stream,
+            "401 Unauthorized",
+            "WWW-Authenticate: Bearer\r\n",
+        );
+    }
+
+    let (reply, path) = channel();
+    let lookup = TrackLookup {
+        track_id: request.track_id,
+        reply,
+    };
+    let path = match lookup_sender.send(lookup) {
+        Ok(()) => path.recv_timeout(LOOKUP_TIMEOUT).ok().flatten(),
+        Err(_) => None,
+    };
+    let path = match path {
+        Some(path) if Path::new(&path).is_file() => path,
+        _ => return write_status(&mut stream, "404 Not Found", ""),
+    };
+
+    match query_parameter(&request.query, "format") {
+        None => serve_file(&mut stream, Path::new(&path), &request),
+        Some(format) => match TranscodeFormat::from_name(&format) {
+            Some(format) => {
+                let bitrate = query_parameter(&request.query, "bitrate")
+                    .and_then(|bitrate| bitrate.parse().ok())
+                    .unwrap_or(DEFAULT_TRANSCODE_BITRATE)
+                    .clamp(32, 320);
+                serve_transcoded(&mut stream, settings, &path, format, bitrate, &request)
+            }
+            None => write_status(&mut stream, "400 Bad Request", ""),
+        },
+    }
+}
+
+/// Send the file as it is, or the part of it the `Range` header asks for
+fn serve_file(stream: &mut TcpStream, path: &Path, request: &StreamRequest) -> std::io::Result<()> {
+    let mut file = File::open(path)?;
+    let length = file.metadata()?.len();
+
+    let range = request
+        .range
+        .as_deref()
+        .and_then(|range| parse_range(range, length));
+    let (status, first, last) = match range {
+        None => ("200 OK", 0, length.saturating_sub(1)),
+        Some(Ok((first, last))) => ("206 Partial Content", first, last),
+        Some(Err(())) => {
+            return write_status(
+                stream,
+                "416 Range Not Satisfiable",
+                &format!("Content-Range: bytes */{}\r\n", length),
+            )
+        }
+    };
+    let content_length = if length == 0 { 0 } else { last - first + 1 };
+
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\n",
+        status,
+        content_type(path),
+        content_length
+    );
+    if range.is_some() {
+        head.push_str(&format!(
+            "Content-Range: bytes {}-{}/{}\r\n",
+            first, last, length
+        ));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    stream.write_all(head.as_bytes())?;
+    if request.head_only {
+        return Ok(());
+    }
+
+    file.seek(SeekFrom::Start(first))?;
+    std::io::copy(&mut file.take(content_length), stream)?;
+    Ok(())
+}
+
+/// Send the track transcoded by ffmpeg as it is encoded
+///
+/// The length isn't known ahead of time, so the stream can't be seeked in and ends when
+/// the connection is closed.
+fn serve_transcoded(
+    stream: &mut TcpStream,
+    settings: &StreamingSettings,
+    path: &str,
+    format: TranscodeFormat,
+    bitrate: u32,
+    request: &StreamRequest,
+) -> std::io::Result<()> {
+    let (codec, container) = format.ffmpeg_codec();
+    let child = Command::new(&settings.ffmpeg)
+        .args(["-v", "error", "-nostdin", "-i"])
+        .arg(path)
+        .args(["-vn", "-map", "0:a:0", "-c:a", codec])
+        .args(["-b:a", &format!("{}k", bitrate), "-f", container, "pipe:1"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            warn!("Could not start {} to transcode: {}", settings.ffmpeg, err);
+            return write_status(stream, "503 Service Unavailable", "");
+        }
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nAccept-Ranges: none\r\nConnection: close\r\n\r\n",
+        format.content_type()
+    )?;
+    let result = if request.head_only {
+        Ok(())
+    } else {
+        let mut output = child.stdout.take().unwrap();
+        std::io::copy(&mut output, stream).map(|_| ())
+    };
+    // The client may have gone away before the end, ffmpeg is not needed any more either way
+    let _ = child.kill();
+    let _ = child.wait();
+    result
+}
+
+#[test]
+fn test_parse_range() {
+    assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 99))));
+    assert_eq!(parse_range("bytes=900-", 1000), Some(Ok((900, 999))));
+    assert_eq!(parse_range("bytes=900-2000", 1000), Some(Ok((900, 999))));
+    assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 999))));
+    assert_eq!(parse_range("bytes=-5000", 1000), Some(Ok((0, 999))));
+    assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+    assert_eq!(parse_range("bytes=50-10", 1000), Some(Err(())));
+    assert_eq!(parse_range("bytes=0-1,5-9", 1000), None);
+    assert_eq!(parse_range("lines=0-1", 1000), None);
+    assert_eq!(parse_range("bytes=abc-", 1000), None);
+}
+
+#[test]
+fn test_stream_byte_ranges() {
+    use crate::message_types::ItemTag;
+
+    let path = std::env::temp_dir().join(format!("sousa-stream-{}.mp3", std::process::id()));
+    let contents: Vec<u8> = (0..5000u32).map(|n| (n % 251) as u8).collect();
+    std::fs::write(&path, &contents).unwrap();
+
+    let dbo = DBObject::new(&std::path::PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+    let item = ItemTag {
+        path: path.to_string_lossy().into_owned(),
+        title: "Streamed".to_string(),
+        ..ItemTag::default()
+    };
+    dbo.save_tag(&item).unwrap();
+    let track_id = dbo
+        .get_tag_by_path(&item.path)
+        .unwrap()
+        .unwrap()
+        .id
+        .unwrap();
+
+    let settings = StreamingSettings {
+        address: "127.0.0.1:0".to_string(),
+        ..StreamingSettings::default()
+    };
+    let server = StreamServer::bind(&settings, Some("secret".to_string())).unwrap();
+    let address = server.local_addr().unwrap();
+
+    // A client on a thread of its own, while this one runs the server loop
+    let client = std::thread::spawn(move || {
+        let fetch = |target: String, headers: &str| -> (String, Vec<u8>) {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(
+                stream,
+                "GET {} HTTP/1.1\r\nHost: sousa\r\n{}\r\n",
+                target, headers
+            )
+            .unwrap();
+            let mut response = vec![];
+            stream.read_to_end(&mut response).unwrap();
+            let split = response
+                .windows(4)
+                .position(|window| window == b"\r\n\r\n")
+                .unwrap();
+            let head = String::from_utf8_lossy(&response[..split]).into_owned();
+            (head, response[split + 4..].to_vec())
+        };
+        let stream_url = format!("/tracks/{}/stream", track_id);
+        let bearer = "Authorization: Bearer secret\r\n";
+
+        let (head, _) = fetch(stream_url.clone(), "");
+        assert!(head.starts_with("HTTP/1.1 401"), "{}", head);
+
+        let (head, body) = fetch(format!("{}?token=secret", stream_url), "");
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert!(head.contains("Content-Type: audio/mpeg"));
+        assert_eq!(body.len(), 5000);
+
+        let (head, body) = fetch(
+            stream_url.clone(),
+            &format!("{}Range: bytes=100-199\r\n", bearer),
+        );
+        assert!(head.starts_with("HTTP/1.1 206"), "{}", head);
+        assert!(head.contains("Content-Range: bytes 100-199/5000"));
+        assert_eq!(
+            body,
+            (100..200u32).map(|n| (n % 251) as u8).collect::<Vec<_>>()
+        );
+
+        let (head, body) = fetch(
+            stream_url.clone(),
+            &format!("{}Range: bytes=-10\r\n", bearer),
+        );
+        assert!(
+            head.contains("Content-Range: bytes 4990-4999/5000"),
+            "{}",
+            head
+        );
+        assert_eq!(body.len(), 10);
+
+        let (head, _) = fetch(stream_url, &format!("{}Range: bytes=6000-\r\n", bearer));
+        assert!(head.starts_with("HTTP/1.1 416"), "{}", head);
+
+        let (head, _) = fetch(format!("/tracks/{}/stream", track_id + 1), bearer);
+        assert!(head.starts_with("HTTP/1.1 404"), "{}", head);
+    });
+
+    while !client.is_finished() {
+        server.poll(&dbo);
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    std::fs::remove_file(&path).unwrap();
+    client.join().unwrap();
+}
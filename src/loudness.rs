@@ -0,0 +1,256 @@
+use derive_more::From;
+use ebur128::{EbuR128, Mode};
+use log::{info, warn};
+use rodio::{Decoder, Source};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::db_operations::DBObject;
+use crate::file_operations::write_replaygain;
+
+/// The loudness ReplayGain 2.0 normalizes tracks to, in LUFS
+pub const REPLAYGAIN_REFERENCE_LUFS: f64 = -18.0;
+
+/// Samples are handed to the loudness meter in chunks of this many frames
+const FRAMES_PER_CHUNK: usize = 4096;
+
+/// Catch all Error for analysing the loudness of a file
+#[derive(From, Debug)]
+pub enum LoudnessError {
+    IoError(std::io::Error),
+    DecoderError(rodio::decoder::DecoderError),
+    EbuR128Error(ebur128::Error),
+    /// The file has no audio above the absolute gate of -70 LUFS
+    Silent,
+}
+
+/// The loudness of a track as measured per EBU R128
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS
+    pub integrated: f64,
+    /// The highest true peak of all channels, as a linear sample value
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    /// The ReplayGain 2.0 track gain that brings the track to the reference loudness, in dB
+    pub fn replaygain(&self) -> f64 {
+        REPLAYGAIN_REFERENCE_LUFS - self.integrated
+    }
+}
+
+/// Settings for the background loudness analysis, part of the configuration file
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct LoudnessAnalysisSettings {
+    /// Analyse the tracks that don't have ReplayGain tags in the background
+    pub enabled: bool,
+    /// How long to wait between tracks, to keep the analysis from hogging the CPU
+    pub pause_between_tracks_ms: u64,
+    /// Write the results back to the files as ReplayGain tags
+    pub write_tags: bool,
+}
+
+impl Default for LoudnessAnalysisSettings {
+    fn default() -> Self {
+        LoudnessAnalysisSettings {
+            enabled: true,
+            pause_between_tracks_ms: 500,
+            write_tags: false,
+        }
+    }
+}
+
+/// How far along the analysis of the library is
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct AnalysisProgress {
+    /// Tracks that have been analysed, including those that failed
+    pub analysed: usize,
+    /// Tracks without ReplayGain tags
+    pub total: usize,
+    pub paused: bool,
+    /// The track that is being analysed right now
+    pub current: Option<String>,
+}
+
+/// Decode a whole file and measure its loudness
+pub fn analyse_file(path: &Path) -> Result<Loudness, LoudnessError> {
+    let mut source = Decoder::new(BufReader::new(File::open(path)?))?;
+    let channels = source.channels() as usize;
+    let mut meter = EbuR128::new(
+        channels as u32,
+        source.sample_rate(),
+        Mode::I | Mode::TRUE_PEAK,
+    )?;
+
+    let mut chunk = Vec::<i16>::with_capacity(FRAMES_PER_CHUNK * channels);
+    loop {
+        chunk.clear();
+        chunk.extend(source.by_ref().take(FRAMES_PER_CHUNK * channels));
+        // A truncated file can end half way through a frame
+        chunk.truncate(chunk.len() - chunk.len() % channels);
+        if chunk.is_empty() {
+            break;
+        }
+        meter.add_frames_i16(&chunk)?;
+    }
+
+    let integrated = meter.loudness_global()?;
+    if !integrated.is_finite() {
+        return Err(LoudnessError::Silent);
+    }
+
+    let mut true_peak: f64 = 0.0;
+    for channel in 0..channels as u32 {
+        true_peak = true_peak.max(meter.true_peak(channel)?);
+    }
+
+    Ok(Loudness {
+        integrated,
+        true_peak,
+    })
+}
+
+/// Runs the loudness analysis of the library on a worker thread, one track at a time
+///
+/// The tracks still to do are taken from the database, so the analysis picks up where it
+/// left off after a restart. `poll` has to be called regularly to hand out work and store
+/// the results.
+pub struct LoudnessAnalyser {
+    settings: LoudnessAnalysisSettings,
+    paused: bool,
+    current: Option<String>,
+    requests: Sender<PathBuf>,
+    results: Receiver<(String, Result<Loudness, LoudnessError>)>,
+    _worker: JoinHandle<()>,
+}
+
+impl LoudnessAnalyser {
+    pub fn new(settings: LoudnessAnalysisSettings) -> Self {
+        let (requests, worker_requests) = channel::<PathBuf>();
+        let (worker_results, results) = channel();
+        let pause = Duration::from_millis(settings.pause_between_tracks_ms);
+
+        let worker = std::thread::spawn(move || {
+            for path in worker_requests {
+                let result = analyse_file(&path);
+                if worker_results
+                    .send((path.to_string_lossy().into_owned(), result))
+                    .is_err()
+                {
+                    break;
+                }
+                std::thread::sleep(pause);
+            }
+        });
+
+        LoudnessAnalyser {
+            settings,
+            paused: !settings.enabled,
+            current: None,
+            requests,
+            results,
+            _worker: worker,
+        }
+    }
+
+    /// Pause or resume the analysis, a track that is being analysed is still finished
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn progress(&self, dbo: &DBObject) -> Result<AnalysisProgress, rusqlite::Error> {
+        let (analysed, total) = dbo.loudness_analysis_counts()?;
+        Ok(AnalysisProgress {
+            analysed,
+            total,
+            paused: self.paused,
+            current: self.current.clone(),
+        })
+    }
+
+    /// Store a finished analysis and start on the next track
+    ///
+    /// Returns the progress whenever a track was finished.
+    pub fn poll(&mut self, dbo: &DBObject) -> Result<Option<AnalysisProgress>, rusqlite::Error> {
+        let mut finished = false;
+        match self.results.try_recv() {
+            Ok((path, result)) => {
+                // The worker is free either way, a failed write mustn't keep it waiting
+                self.current = None;
+                self.store_result(dbo, &path, result)?;
+                finished = true;
+            }
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => return Ok(None),
+        }
+
+        if self.current.is_none() && !self.paused {
+            if let Some(path) = dbo.next_track_to_analyse()? {
+                if self.requests.send(PathBuf::from(&path)).is_ok() {
+                    self.current = Some(path);
+                }
+            }
+        }
+
+        if finished {
+            Ok(Some(self.progress(dbo)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn store_result(
+        &self,
+        dbo: &DBObject,
+        path: &str,
+        result: Result<Loudness, LoudnessError>,
+    ) -> Result<(), rusqlite::Error> {
+        match result {
+            Ok(loudness) => {
+                info!(
+                    "Analysed {}: {:.2} LUFS, true peak {:.4}",
+                    path, loudness.integrated, loudness.true_peak
+                );
+                dbo.save_loudness(path, Some(&loudness))?;
+                if self.settings.write_tags {
+                    if let Err(err) = write_replaygain(Path::new(path), &loudness) {
+                        warn!("Could not write the ReplayGain tags of {}: {:?}", path, err);
+                    }
+                }
+            }
+            Err(err) => {
+                warn!("Could not analyse the loudness of {}: {:?}", path, err);
+                dbo.save_loudness(path, None)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_analyse_file() {
+    // A 997 Hz sine peaking at -20 dBFS on both channels measures -20 LUFS
+    let sample_rate: u32 = 48000;
+    let samples = (0..sample_rate * 3).flat_map(|frame| {
+        let phase = 2.0 * std::f64::consts::PI * 997.0 * frame as f64 / sample_rate as f64;
+        let sample = ((0.1 * phase.sin()) * i16::MAX as f64) as i16;
+        [sample, sample]
+    });
+
+    let path = std::env::temp_dir().join(format!("sousa-loudness-{}.wav", std::process::id()));
+    crate::output::write_wav(&path, 2, sample_rate, samples);
+    let loudness = analyse_file(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!((loudness.integrated + 20.0).abs() < 0.1);
+    assert!((loudness.replaygain() - 2.0).abs() < 0.1);
+    assert!((loudness.true_peak - 0.1).abs() < 0.01);
+}
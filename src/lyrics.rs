@@ -0,0 +1,366 @@
+use derive_more::From;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::db_operations::DBObject;
+use crate::file_operations::TagFormat;
+
+/// Vorbis comments that hold lyrics, in order of preference
+const VORBIS_LYRICS_FIELDS: [&str; 2] = ["LYRICS", "UNSYNCEDLYRICS"];
+
+/// Catch all Error for reading and storing lyrics
+#[derive(From, Debug)]
+pub enum LyricsError {
+    IoError(std::io::Error),
+    RusqliteError(rusqlite::Error),
+}
+
+/// A single line of lyrics, synced lyrics have the time the line starts at
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct LyricLine {
+    pub time_ms: Option<u64>,
+    pub text: String,
+}
+
+/// The lyrics of a track
+///
+/// Synced lyrics have a time on every line, and their lines are sorted by it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct Lyrics {
+    pub synced: bool,
+    pub lines: Vec<LyricLine>,
+}
+
+impl Lyrics {
+    /// Unsynced lyrics from plain text, one line per line of text
+    pub fn from_text(text: &str) -> Self {
+        Lyrics {
+            synced: false,
+            lines: text
+                .lines()
+                .map(|line| LyricLine {
+                    time_ms: None,
+                    text: line.trim().to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// The lyrics as plain text, without any timing
+    pub fn text(&self) -> String {
+        self.lines
+            .iter()
+            .map(|line| line.text.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.iter().all(|line| line.text.is_empty())
+    }
+
+    /// The index of the line being sung after `played` of the track
+    ///
+    /// Always `None` for unsynced lyrics, and before the first line starts.
+    pub fn line_at(&self, played: Duration) -> Option<usize> {
+        if !self.synced {
+            return None;
+        }
+        let played_ms = played.as_millis() as u64;
+        self.lines
+            .iter()
+            .rposition(|line| line.time_ms.is_some_and(|time| time <= played_ms))
+    }
+}
+
+/// Parse a `[mm:ss.xx]` timestamp into milliseconds
+fn parse_lrc_timestamp(timestamp: &str) -> Option<u64> {
+    let (minutes, seconds) = timestamp.split_once(':')?;
+    let minutes: u64 = minutes.trim().parse().ok()?;
+
+    // Some files separate the fraction with a colon instead of a dot
+    let (seconds, fraction) = match seconds.split_once(['.', ':']) {
+        Some((seconds, fraction)) => (seconds, fraction),
+        None => (seconds, ""),
+    };
+    let seconds: u64 = seconds.trim().parse().ok()?;
+    let fraction_ms = match fraction.len() {
+        0 => 0,
+        1 => fraction.parse::<u64>().ok()? * 100,
+        2 => fraction.parse::<u64>().ok()? * 10,
+        _ => fraction.get(..3)?.parse::<u64>().ok()?,
+    };
+
+    Some(minutes * 60_000 + seconds * 1000 + fraction_ms)
+}
+
+/// Remove the `<mm:ss.xx>` word timings of enhanced LRC from a line
+fn strip_word_timings(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) if parse_lrc_timestamp(&rest[start + 1..start + end]).is_some() => {
+                output.push_str(&rest[..start]);
+                rest = &rest[start + end + 1..];
+            }
+            _ => {
+                output.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output.trim().to_string()
+}
+
+/// Parse the contents of a `.lrc` file
+///
+/// Lines may have several timestamps, and `[offset:]` shifts every line. Other tags like
+/// `[ar:]` are ignored. Text without any timestamps is read as unsynced lyrics.
+pub fn parse_lrc(contents: &str) -> Lyrics {
+    let mut offset_ms: i64 = 0;
+    let mut timed_lines = Vec::<(u64, String)>::new();
+    let mut untimed_lines = Vec::<String>::new();
+
+    for line in contents.lines() {
+        let mut rest = line.trim();
+        let mut timestamps = Vec::<u64>::new();
+        let mut has_tags = false;
+
+        while rest.starts_with('[') {
+            let end = match rest.find(']') {
+                Some(end) => end,
+                None => break,
+            };
+            let tag = &rest[1..end];
+            rest = rest[end + 1..].trim_start();
+            has_tags = true;
+
+            if let Some(timestamp) = parse_lrc_timestamp(tag) {
+                timestamps.push(timestamp);
+            } else if let Some((key, value)) = tag.split_once(':') {
+                if key.trim().eq_ignore_ascii_case("offset") {
+                    offset_ms = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+
+        let text = strip_word_timings(rest);
+        if !timestamps.is_empty() {
+            timed_lines.extend(timestamps.into_iter().map(|time| (time, text.clone())));
+        } else if !has_tags {
+            untimed_lines.push(text);
+        }
+    }
+
+    if timed_lines.is_empty() {
+        return Lyrics::from_text(&untimed_lines.join("\n"));
+    }
+
+    // A positive offset makes the lyrics show up sooner
+    timed_lines.sort_by_key(|(time, _)| *time);
+    Lyrics {
+        synced: true,
+        lines: timed_lines
+            .into_iter()
+            .map(|(time, text)| LyricLine {
+                time_ms: Some((time as i64 - offset_ms).max(0) as u64),
+                text,
+            })
+            .collect(),
+    }
+}
+
+/// Find the `.lrc` file next to a track, with the same name as it
+pub fn find_sidecar_lrc(track_path: &Path) -> Option<PathBuf> {
+    let stem = track_path.file_stem()?;
+    std::fs::read_dir(track_path.parent()?)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| {
+            path.file_stem() == Some(stem)
+                && path
+                    .extension()
+                    .and_then(OsStr::to_str)
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("lrc"))
+        })
+}
+
+/// Read the lyrics embedded in a file's tags
+///
+/// mp3 files are read for SYLT and USLT frames, synced lyrics are preferred. flac files
+/// are read for a LYRICS comment, which may be in LRC format.
+pub fn read_embedded_lyrics(path: &Path) -> Option<Lyrics> {
+    match TagFormat::from_path(path)? {
+        TagFormat::Id3 => {
+            let tag = id3::Tag::read_from_path(path).ok()?;
+            let synced = tag
+                .synchronised_lyrics()
+                .find(|lyrics| lyrics.timestamp_format == id3::frame::TimestampFormat::Ms)
+                .map(|lyrics| Lyrics {
+                    synced: true,
+                    lines: lyrics
+                        .content
+                        .iter()
+                        .map(|(time, text)| LyricLine {
+                            time_ms: Some(*time as u64),
+                            text: text.trim().to_string(),
+                        })
+                        .collect(),
+                });
+            synced
+                .or_else(|| {
+                    tag.lyrics()
+                        .next()
+                        .map(|lyrics| Lyrics::from_text(&lyrics.text))
+                })
+                .filter(|lyrics| !lyrics.is_empty())
+        }
+        TagFormat::Vorbis => {
+            let tag = metaflac::Tag::read_from_path(path).ok()?;
+            let comments = tag.vorbis_comments()?;
+            VORBIS_LYRICS_FIELDS
+                .iter()
+                .filter_map(|field| comments.get(field).and_then(|values| values.first()))
+                .map(|text| parse_lrc(text))
+                .find(|lyrics| !lyrics.is_empty())
+        }
+    }
+}
+
+/// Find the lyrics of a track, from its tags or a `.lrc` file next to it
+///
+/// Synced lyrics are preferred over unsynced ones, and a `.lrc` file over the tags.
+pub fn find_lyrics(track_path: &Path) -> Option<Lyrics> {
+    let sidecar = find_sidecar_lrc(track_path)
+        .and_then(|lrc_path| std::fs::read(lrc_path).ok())
+        .map(|contents| parse_lrc(&String::from_utf8_lossy(&contents)))
+        .filter(|lyrics| !lyrics.is_empty());
+
+    match sidecar {
+        Some(lyrics) if lyrics.synced => Some(lyrics),
+        sidecar => match read_embedded_lyrics(track_path) {
+            Some(embedded) if embedded.synced || sidecar.is_none() => Some(embedded),
+            _ => sidecar,
+        },
+    }
+}
+
+/// Read the lyrics of a track into the database, replacing any it had before
+///
+/// Returns whether the track has lyrics.
+pub fn scan_lyrics(dbo: &DBObject, track_path: &Path) -> Result<bool, LyricsError> {
+    let path = track_path.to_string_lossy();
+    match find_lyrics(track_path) {
+        Some(lyrics) => {
+            dbo.save_lyrics(&path, &lyrics)?;
+            Ok(true)
+        }
+        None => {
+            dbo.remove_lyrics(&path)?;
+            Ok(false)
+        }
+    }
+}
+
+/// A line of synced lyrics that just started, as pushed to subscribed clients
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct CurrentLyricLine {
+    pub path: String,
+    pub index: usize,
+    pub line: LyricLine,
+}
+
+/// Follows the synced lyrics of the playing track
+pub struct LyricsFollower {
+    path: Option<String>,
+    lyrics: Option<Lyrics>,
+    line: Option<usize>,
+}
+
+impl Default for LyricsFollower {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LyricsFollower {
+    pub fn new() -> Self {
+        LyricsFollower {
+            path: None,
+            lyrics: None,
+            line: None,
+        }
+    }
+
+    /// Returns the line that started since the last update, if any
+    ///
+    /// The lyrics are loaded from the database when the playing track changes.
+    pub fn update(
+        &mut self,
+        dbo: &DBObject,
+        path: &str,
+        played: Duration,
+    ) -> Option<CurrentLyricLine> {
+        if self.path.as_deref() != Some(path) {
+            self.path = Some(path.to_string());
+            self.lyrics = dbo.get_lyrics(path).ok().flatten();
+            self.line = None;
+        }
+
+        let lyrics = self.lyrics.as_ref()?;
+        let line = lyrics.line_at(played);
+        if line == self.line {
+            return None;
+        }
+        self.line = line;
+
+        Some(CurrentLyricLine {
+            path: path.to_string(),
+            index: line?,
+            line: lyrics.lines[line?].clone(),
+        })
+    }
+}
+
+#[test]
+fn test_parse_lrc() {
+    let lyrics = parse_lrc(
+        "[ar:Someone]\n\
+        [ti:A song]\n\
+        [offset:+500]\n\
+        [00:12.00]First line\n\
+        [00:17.20][01:02.5]<00:17.20>Repeated <00:18.00>line\n\
+        [00:21.100]\n\
+        [00:25:00]Last line",
+    );
+
+    assert!(lyrics.synced);
+    let times: Vec<Option<u64>> = lyrics.lines.iter().map(|line| line.time_ms).collect();
+    assert_eq!(
+        times,
+        vec![
+            Some(11500),
+            Some(16700),
+            Some(20600),
+            Some(24500),
+            Some(62000)
+        ]
+    );
+    assert_eq!(lyrics.lines[1].text, "Repeated line".to_string());
+    assert_eq!(lyrics.lines[4].text, "Repeated line".to_string());
+
+    assert_eq!(lyrics.line_at(Duration::from_secs(5)), None);
+    assert_eq!(lyrics.line_at(Duration::from_secs(12)), Some(0));
+    assert_eq!(lyrics.line_at(Duration::from_secs(120)), Some(4));
+
+    let unsynced = parse_lrc("Just some\nplain lyrics");
+    assert!(!unsynced.synced);
+    assert_eq!(unsynced.text(), "Just some\nplain lyrics".to_string());
+    assert_eq!(unsynced.line_at(Duration::from_secs(1)), None);
+}
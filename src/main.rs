@@ -1,24 +1,68 @@
 use log::{debug, error, info, trace, warn, LevelFilter};
 use simplelog::*;
+use std::cell::RefCell;
 use std::fs::File;
 use std::net::TcpListener;
-use std::net::TcpStream;
-use std::path::PathBuf;
-use tungstenite::accept;
-use tungstenite::protocol::WebSocket;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tungstenite::accept_hdr;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
 
 use clap::Parser;
 
+pub mod artwork;
+pub mod config;
 pub mod db_operations;
+pub mod equalizer;
+pub mod fading;
 pub mod file_operations;
+pub mod gapless;
+pub mod http_api;
+pub mod http_stream;
+pub mod loudness;
+pub mod lyrics;
 pub mod message_types;
+pub mod mpd;
+#[cfg(feature = "mpris")]
+pub mod mpris;
 pub mod music_player;
+pub mod output;
+pub mod playlist_files;
+pub mod radio;
+pub mod replaygain;
+pub mod scheduler;
 pub mod server_handling;
+pub mod smart_playlists;
+pub mod speed;
+pub mod tag_editing;
+pub mod zones;
 
+use crate::artwork::ArtworkCache;
+use crate::config::SousaConfig;
 use crate::db_operations::{DBObject, DatabaseRequest};
-use crate::message_types::{PartialTag, UIRequest};
-use crate::music_player::MusicPlayer;
-use crate::server_handling::{write_to_socket, sanitize_partialtag};
+use crate::equalizer::EqualizerSettings;
+use crate::http_api::{ApiServer, ResponseCollector};
+use crate::http_stream::StreamServer;
+use crate::loudness::LoudnessAnalyser;
+use crate::message_types::{ItemTag, PartialTag, PlayerStatus, ResponsePayload, UIRequest};
+use crate::mpd::MpdServer;
+#[cfg(feature = "mpris")]
+use crate::mpris::Mpris;
+use crate::output::{AudioOutput, DeviceSelector, DeviceWatcher, OutputBackend};
+use crate::playlist_files::PlaylistFormat;
+use crate::radio::Radio;
+use crate::server_handling::{
+    is_authorized, sanitize_partialtag, write_payload_to_socket, write_to_socket, Client,
+    ClientConnection,
+};
+use crate::zones::{Zone, ZoneSettings, Zones, DEFAULT_ZONE};
+
+/// How often the player state is saved, so a restart picks up close to where it was
+const PLAYER_STATE_SAVE_PERIOD: Duration = Duration::from_secs(10);
+/// How often the sound devices are listed, to check the ones played to are still there
+const OUTPUT_CHECK_PERIOD: Duration = Duration::from_secs(5);
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about=None)]
@@ -42,6 +86,22 @@ struct Cli {
     #[arg(long, default_value = "false")]
     no_save: bool,
 
+    /// Play to nowhere, for servers without a sound card
+    #[arg(long)]
+    null_output: bool,
+
+    /// Record what is played to a WAV file instead of playing it
+    #[arg(long, conflicts_with = "null_output")]
+    wav_output: Option<PathBuf>,
+
+    /// The sound device to play to, by name or by its index in --list-output-devices
+    #[arg(long)]
+    output_device: Option<DeviceSelector>,
+
+    /// Print the sound devices that can be played to and exit
+    #[arg(long)]
+    list_output_devices: bool,
+
     /// Delete an existing database file (wherever it looks on startup)
     /// TODO: actually make this a thing
     #[arg(long)]
@@ -50,6 +110,19 @@ struct Cli {
 
 fn main() {
     let cli = Cli::parse();
+
+    if cli.list_output_devices {
+        match output::list_output_devices() {
+            Ok(devices) => {
+                for device in devices {
+                    let default = if device.is_default { " (default)" } else { "" };
+                    println!("{}: {}{}", device.index, device.name, default);
+                }
+            }
+            Err(err) => eprintln!("Could not list the sound devices: {:?}", err),
+        }
+        return;
+    }
     // settings = confy settings
 
     let log_file: String;
@@ -58,6 +131,14 @@ fn main() {
 
     init_logger(log_file);
 
+    let config = match config::load_config(cli.configuration_file.as_ref()) {
+        Ok(config) => config,
+        Err(err) => {
+            warn!("Could not load the configuration, using the defaults: {}", err);
+            SousaConfig::default()
+        }
+    };
+
     let music_dir: String;
     if cli.root_directory.is_some() {
         music_dir = cli.root_directory.clone().unwrap();
@@ -65,7 +146,8 @@ fn main() {
         music_dir = String::from(dirs_next::audio_dir().unwrap().to_str().unwrap());
     }
 
-    let music_scanner = file_operations::MusicScanner::new(music_dir.clone());
+    let mut music_roots = vec![PathBuf::from(&music_dir)];
+    music_roots.extend(config.music_roots.iter().map(PathBuf::from));
 
     let db_path: PathBuf = ["/", "home", "nixolas", "RustedBeats.db"].iter().collect();
 
@@ -73,19 +155,84 @@ fn main() {
     info!("Database file path is: {}", &db_path.to_string_lossy());
     let dbo = db_operations::DBObject::new(&db_path, cli.no_save).unwrap();
 
-    info!("Starting file scan with root set to: {}", music_dir);
-    for file_batch in music_scanner {
-        for filepath in file_batch {
+    let artwork_cache = ArtworkCache::new(
+        config
+            .artwork_cache_dir
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(ArtworkCache::default_dir),
+    )
+    .unwrap();
+
+    let mut playlist_paths = Vec::<PathBuf>::new();
+    for music_root in music_roots.iter() {
+        info!("Starting file scan with root set to: {}", music_root.to_string_lossy());
+        let music_scanner =
+            file_operations::MusicScanner::new(music_root.to_string_lossy().into_owned());
+        for filepath in music_scanner.flatten() {
             debug!("checking file: {}", filepath.to_string_lossy());
-            if filepath.to_string_lossy().ends_with(".wav") {
+            if PlaylistFormat::from_path(&filepath).is_some() {
+                // Playlists are imported once every track they could reference is in the database
+                playlist_paths.push(filepath);
+            } else if filepath.to_string_lossy().ends_with(".wav") {
                 continue;
             } else {
-                let tag = file_operations::get_tag(&filepath).unwrap();
-                dbo.save_tag(&tag).unwrap();
+                match file_operations::get_tag(&filepath) {
+                    Ok(tag) => {
+                        dbo.save_tag(&tag).unwrap();
+                        if let Err(err) = artwork::scan_artwork(&dbo, &artwork_cache, &filepath) {
+                            warn!(
+                                "Could not extract the artwork of {}: {:?}",
+                                filepath.to_string_lossy(),
+                                err
+                            );
+                        }
+                        if let Err(err) = lyrics::scan_lyrics(&dbo, &filepath) {
+                            warn!(
+                                "Could not read the lyrics of {}: {:?}",
+                                filepath.to_string_lossy(),
+                                err
+                            );
+                        }
+                    }
+                    Err(err) => warn!(
+                        "Could not read the tags of {}: {:?}",
+                        filepath.to_string_lossy(),
+                        err
+                    ),
+                }
             }
         }
     }
 
+    for removed in dbo.remove_missing_tracks().unwrap() {
+        info!("Removed missing file from the library: {}", removed);
+    }
+
+    for playlist_path in playlist_paths {
+        match playlist_files::import_playlist(&dbo, &playlist_path) {
+            Ok(import) => {
+                info!(
+                    "Imported playlist '{}' with {} tracks",
+                    import.name,
+                    import.resolved.len()
+                );
+                for location in import.unresolved {
+                    warn!(
+                        "Could not resolve '{}' in playlist {}",
+                        location,
+                        playlist_path.to_string_lossy()
+                    );
+                }
+            }
+            Err(err) => warn!(
+                "Could not import playlist {}: {:?}",
+                playlist_path.to_string_lossy(),
+                err
+            ),
+        }
+    }
+
     let test_tag = PartialTag {
         title: Some("bees".to_string()),
         ..PartialTag::default()
@@ -100,38 +247,206 @@ fn main() {
         .unwrap();
 
     info!("Creating music player");
-    let (_stream, stream_handle) = rodio::OutputStream::try_default().unwrap();
-    let mut music_player = MusicPlayer::new(test_file[0].clone(), &stream_handle);
+    let output_backend = match (cli.null_output, &cli.wav_output) {
+        (true, _) => OutputBackend::Null,
+        (_, Some(path)) => OutputBackend::Wav(path.clone()),
+        _ => config.output.clone(),
+    };
+    let output_device = cli.output_device.as_ref().or(config.output_device.as_ref());
+    let output = AudioOutput::open(&output_backend, output_device).unwrap_or_else(|err| {
+        warn!("Could not open the {:?} output: {:?}", output_backend, err);
+        open_fallback_output()
+    });
+    let default_zone = ZoneSettings {
+        name: DEFAULT_ZONE.to_string(),
+        output: output_backend,
+        device: output_device.cloned(),
+    };
+    let eq_presets = Rc::new(RefCell::new(config.equalizer.presets.clone()));
+    let mut zones = Zones::new(Zone::new(
+        default_zone,
+        output,
+        test_file[0].clone(),
+        &config,
+        &eq_presets,
+    ));
+    match dbo.list_zones() {
+        Ok(saved_zones) => {
+            for settings in saved_zones {
+                // Saved zones were made by clients, and are held to the same outputs
+                let output = settings
+                    .check_client_output()
+                    .and_then(|()| {
+                        Ok(AudioOutput::open(&settings.output, settings.device.as_ref())?)
+                    })
+                    .unwrap_or_else(|err| {
+                        warn!(
+                            "Could not open the output of zone '{}', playing to nowhere: {:?}",
+                            settings.name, err
+                        );
+                        AudioOutput::null()
+                    });
+                let starting_item = zones.default_zone().player.get_currently_playing().clone();
+                let zone = Zone::new(settings, output, starting_item, &config, &eq_presets);
+                if let Err(err) = zones.add(zone) {
+                    warn!("Could not add a saved zone: {:?}", err);
+                }
+            }
+        }
+        Err(err) => warn!("Could not load the zones: {}", err),
+    }
+    for zone in zones.iter_mut() {
+        restore_zone_state(&dbo, zone);
+    }
+    let device_watcher = DeviceWatcher::spawn(OUTPUT_CHECK_PERIOD);
+
+    let radio = if config.radio.enabled {
+        Radio::start(
+            &config.radio,
+            &config.streaming.ffmpeg,
+            config.access_token.clone(),
+            &zones.default_zone().output,
+        )
+        .map_err(|err| warn!("Could not start the radio: {}", err))
+        .ok()
+    } else {
+        None
+    };
 
     info!("Opening Tcp Listener");
     let tcp_listener = TcpListener::bind("127.0.0.1:9001").unwrap();
     tcp_listener.set_nonblocking(true).unwrap();
 
-    let mut sockets = Vec::<WebSocket<TcpStream>>::new();
+    let stream_server = if config.streaming.enabled {
+        StreamServer::bind(&config.streaming, config.access_token.clone())
+            .map_err(|err| warn!("Could not start streaming over HTTP: {}", err))
+            .ok()
+    } else {
+        None
+    };
+
+    let api_server = if config.api.enabled {
+        ApiServer::bind(&config.api, config.access_token.clone())
+            .map_err(|err| warn!("Could not start the HTTP API: {}", err))
+            .ok()
+    } else {
+        None
+    };
+
+    let mut mpd_server = if config.mpd.enabled {
+        MpdServer::bind(&config.mpd, config.access_token.clone())
+            .map_err(|err| warn!("Could not start the MPD server: {}", err))
+            .ok()
+    } else {
+        None
+    };
+
+    #[cfg(feature = "mpris")]
+    let mut mpris = Mpris::connect_session(&zones.default_zone().player)
+        .map_err(|err| warn!("Could not start the MPRIS interface: {}", err))
+        .ok();
+
+    let context = ServerContext {
+        dbo: &dbo,
+        music_roots: &music_roots,
+        config: &config,
+        config_file: cli.configuration_file.as_ref(),
+        artwork_cache: &artwork_cache,
+    };
+
+    let mut clients = Vec::<Client>::new();
+    let mut loudness_analyser = LoudnessAnalyser::new(config.loudness_analysis);
     info!(
         "Socket listening on: {}",
         tcp_listener.local_addr().unwrap()
     );
 
     loop {
+        for zone in zones.iter_mut() {
+            poll_zone(zone, &dbo, &mut clients, &device_watcher);
+        }
+        if let Some(radio) = &radio {
+            radio.set_now_playing(zones.default_zone().player.get_currently_playing());
+        }
+
+        if let Some(stream_server) = &stream_server {
+            stream_server.poll(&dbo);
+        }
+        if let Some(mpd_server) = &mut mpd_server {
+            let player = &mut zones.get_mut(None).unwrap().player;
+            mpd_server.poll(player, &dbo, &music_roots);
+        }
+        #[cfg(feature = "mpris")]
+        if let Some(mpris) = &mut mpris {
+            mpris.poll(&mut zones.get_mut(None).unwrap().player);
+        }
+        if let Some(api_server) = &api_server {
+            api_server.poll(|request| {
+                let mut client = Client::new(ResponseCollector::default());
+                if let Err(err) = handle_uirequest(
+                    request,
+                    &mut client,
+                    &mut zones,
+                    None,
+                    &mut loudness_analyser,
+                    &context,
+                ) {
+                    error!("Could not handle an API request: {}", err);
+                }
+                client.socket.messages
+            });
+        }
+
+        match loudness_analyser.poll(&dbo) {
+            Ok(Some(progress)) => {
+                for client in clients.iter_mut().filter(|client| client.analysis_subscribed) {
+                    if let Err(err) = write_payload_to_socket(
+                        &mut client.socket,
+                        "Loudness analysis progress:".to_string(),
+                        ResponsePayload::AnalysisProgress(progress.clone()),
+                    ) {
+                        warn!("Could not send the analysis progress to a socket: {}", err);
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(err) => error!("Could not save the loudness analysis: {}", err),
+        }
+
         if let Ok((stream, addr)) = tcp_listener.accept() {
             stream.set_nonblocking(true).unwrap();
 
             info!("New socket connected from: {}", addr);
 
-            match accept(stream) {
-                Ok(sck) => sockets.push(sck),
+            let access_token = config.access_token.as_deref();
+            // The error type is the one tungstenite's handshake callbacks return
+            #[allow(clippy::result_large_err)]
+            let check_token = |request: &Request, response: Response| {
+                let authorization = request
+                    .headers()
+                    .get("Authorization")
+                    .and_then(|value| value.to_str().ok());
+                if is_authorized(access_token, authorization, request.uri().query()) {
+                    Ok(response)
+                } else {
+                    let mut refusal = ErrorResponse::new(Some("Wrong access token".to_string()));
+                    *refusal.status_mut() = StatusCode::UNAUTHORIZED;
+                    Err(refusal)
+                }
+            };
+            match accept_hdr(stream, check_token) {
+                Ok(sck) => clients.push(Client::new(sck)),
                 Err(_) => continue,
             }
         }
 
-        if sockets.len() == 0 {
+        if clients.is_empty() {
             std::thread::sleep(std::time::Duration::from_millis(200));
         }
 
         // Need to get an asynchronous socket reader like tokio
-        for i in 0..sockets.len() {
-            match sockets[i].read_message() {
+        for i in 0..clients.len() {
+            match clients[i].socket.read_message() {
                 Ok(mess) => {
                     if mess.is_text() {
                         match server_handling::handle_request(mess.into_text().unwrap()) {
@@ -140,10 +455,11 @@ fn main() {
                             }
                             Ok(req) => handle_uirequest(
                                 req,
-                                &mut sockets[i],
-                                &mut music_player,
-                                &dbo,
-                                &stream_handle,
+                                &mut clients[i],
+                                &mut zones,
+                                None,
+                                &mut loudness_analyser,
+                                &context,
                             )
                             .unwrap(),
                         }
@@ -152,26 +468,26 @@ fn main() {
                 Err(error) => match error {
                     tungstenite::Error::ConnectionClosed => {
                         info!("dropping socket: {}", i);
-                        let tmp = sockets.remove(i);
+                        let tmp = clients.remove(i);
                         drop(tmp);
                     }
                     tungstenite::Error::Io(_) => {
                         if error.to_string().ends_with("(os error 32)") {
-                            sockets.remove(i);
+                            clients.remove(i);
                         } else if error.to_string().ends_with("(os error 11)") {
                             continue;
                         } else if error
                             .to_string()
                             .ends_with("Trying to work with closed connection")
                         {
-                            sockets.remove(i);
+                            clients.remove(i);
                         } else {
                             error!("There was an IO error: {}", error.to_string());
                         }
                     }
                     _ => {
                         warn!("A socket errored: {}", error.to_string());
-                        sockets.remove(i);
+                        clients.remove(i);
                     }
                 },
             }
@@ -179,13 +495,188 @@ fn main() {
     }
 }
 
-fn handle_uirequest(
+/// The long-lived server state that requests are handled against
+struct ServerContext<'a> {
+    dbo: &'a DBObject,
+    music_roots: &'a [PathBuf],
+    config: &'a SousaConfig,
+    config_file: Option<&'a String>,
+    artwork_cache: &'a ArtworkCache,
+}
+
+/// Take the saved player state of a zone back up, paused
+fn restore_zone_state(dbo: &DBObject, zone: &mut Zone) {
+    match dbo.get_player_state(zone.name()) {
+        Ok(Some(state)) => {
+            let lookup = |path: &str| {
+                if !Path::new(path).exists() {
+                    return None;
+                }
+                dbo.get_tag_by_path(path).ok().flatten()
+            };
+            match zone.player.restore_state(&state, lookup) {
+                Ok(()) => info!("Restored the queue of zone '{}' from the last run", zone.name()),
+                Err(err) => warn!(
+                    "Could not restore the player state of zone '{}': {:?}",
+                    zone.name(),
+                    err
+                ),
+            }
+        }
+        Ok(None) => {}
+        Err(err) => warn!("Could not load the player state of zone '{}': {}", zone.name(), err),
+    }
+    zone.saved_state = zone.player.state();
+}
+
+/// The work the server loop does for every zone: moving on to the next track, saving the
+/// player state, the sleep timer and schedules, and the lyrics
+///
+/// The zone's sound device is checked to still be among the `devices`.
+fn poll_zone(zone: &mut Zone, dbo: &DBObject, clients: &mut [Client], devices: &DeviceWatcher) {
+    let track_changed = zone.player.advance_if_finished();
+    if track_changed {
+        info!(
+            "Now playing in zone '{}': '{}'",
+            zone.name(),
+            zone.player.get_currently_playing().title
+        );
+    }
+
+    if zone.state_saved_at.elapsed() >= PLAYER_STATE_SAVE_PERIOD {
+        zone.state_saved_at = Instant::now();
+        let state = zone.player.state();
+        if state != zone.saved_state {
+            match dbo.save_player_state(zone.name(), &state) {
+                Ok(()) => zone.saved_state = state,
+                Err(err) => error!("Could not save the player state: {}", err),
+            }
+        }
+    }
+
+    if !zone.output.is_available(devices) {
+        let lost = zone.output.device_name().unwrap_or_default();
+        warn!("The sound device '{}' of zone '{}' is gone", lost, zone.name());
+        zone.player.pause();
+        if zone.output.switch(&OutputBackend::Device, None).is_err() {
+            zone.output.switch(&OutputBackend::Null, None).unwrap();
+        }
+        if let Err(err) = zone.player.move_to_new_output() {
+            error!("Could not move the playback to the new output: {:?}", err);
+        }
+
+        let message = format!(
+            "The sound device '{}' of zone '{}' is gone, paused and switched to '{}'",
+            lost,
+            zone.name(),
+            zone.output.device_name().unwrap_or_else(|| "nowhere".to_string())
+        );
+        for client in clients.iter_mut() {
+            if let Err(err) = write_to_socket(&mut client.socket, message.clone(), vec![]) {
+                warn!("Could not send the output change to a socket: {}", err);
+            }
+        }
+    }
+
+    for event in zone.scheduler.poll(dbo, &mut zone.player, track_changed) {
+        for client in clients.iter_mut() {
+            if let Err(err) = write_payload_to_socket(
+                &mut client.socket,
+                format!("Scheduler ({}):", zone.name()),
+                ResponsePayload::Scheduler(event.clone()),
+            ) {
+                warn!("Could not send a scheduler event to a socket: {}", err);
+            }
+        }
+    }
+
+    for record in zone.player.take_play_records() {
+        if let Err(err) = dbo.record_play(&record) {
+            error!("Could not save to the play history: {}", err);
+        }
+    }
+
+    let name = zone.name().to_string();
+    let follows_zone = |client: &Client| client.lyrics_zone.as_ref() == Some(&name);
+    if clients.iter().any(follows_zone) {
+        let current_line = zone.lyrics_follower.update(
+            dbo,
+            &zone.player.get_currently_playing().path,
+            zone.player.get_played_time(),
+        );
+        if let Some(current_line) = current_line {
+            for client in clients.iter_mut().filter(|client| follows_zone(client)) {
+                if let Err(err) = write_payload_to_socket(
+                    &mut client.socket,
+                    "Now singing:".to_string(),
+                    ResponsePayload::LyricLine(current_line.clone()),
+                ) {
+                    warn!("Could not send the lyrics to a socket: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Handle a request against the zone named `zone_name`, the default zone for `None`
+fn handle_uirequest<C: ClientConnection>(
     request: UIRequest,
-    socket: &mut WebSocket<TcpStream>,
-    music_player: &mut MusicPlayer,
-    dbo: &DBObject,
-    stream_handle: &rodio::OutputStreamHandle,
+    client: &mut Client<C>,
+    zones: &mut Zones,
+    zone_name: Option<&str>,
+    loudness_analyser: &mut LoudnessAnalyser,
+    context: &ServerContext,
 ) -> Result<(), String> {
+    let request = match request {
+        UIRequest::InZone(zone_name, request) => {
+            return handle_uirequest(
+                *request,
+                client,
+                zones,
+                Some(&zone_name),
+                loudness_analyser,
+                context,
+            );
+        }
+        UIRequest::ListZones
+        | UIRequest::CreateZone(_)
+        | UIRequest::DestroyZone(_)
+        | UIRequest::MoveQueue(..)
+        | UIRequest::SaveEqPreset(_)
+        | UIRequest::DeleteEqPreset(_) => {
+            handle_zone_request(request, &mut client.socket, zones, context);
+            return Ok(());
+        }
+        request => request,
+    };
+
+    let Client {
+        socket,
+        lyrics_zone,
+        analysis_subscribed,
+    } = client;
+    let ServerContext {
+        dbo,
+        music_roots,
+        config,
+        artwork_cache,
+        ..
+    } = *context;
+    let zone = match zones.get_mut(zone_name) {
+        Some(zone) => zone,
+        None => {
+            write_to_socket(socket, "No such zone".to_string(), vec![]).unwrap();
+            return Ok(());
+        }
+    };
+    let Zone {
+        settings: zone_settings,
+        output,
+        player: music_player,
+        scheduler,
+        ..
+    } = zone;
+
     match request {
         UIRequest::Play => {
             music_player.play();
@@ -195,7 +686,20 @@ fn handle_uirequest(
             music_player.pause();
             write_to_socket(socket, "Player Paused".to_string(), vec![]).unwrap();
         }
-        UIRequest::Skip(skip_direction) => todo!(),
+        UIRequest::Skip(skip_direction) => match music_player.skip(skip_direction) {
+            Ok(()) => {
+                music_player.play();
+                write_to_socket(
+                    socket,
+                    "Skipped to".to_string(),
+                    vec![music_player.get_currently_playing().clone()],
+                )
+                .unwrap();
+            }
+            Err(err) => {
+                write_to_socket(socket, format!("Could not skip: {:?}", err), vec![]).unwrap();
+            }
+        },
         UIRequest::Search(unsanitary_req) => {
             let request = sanitize_partialtag(unsanitary_req);
             // TODO: switch this to a debug
@@ -208,7 +712,7 @@ fn handle_uirequest(
                 .unwrap();
 
             match items {
-                None => socket.write_message("None".into()).unwrap(),
+                None => socket.send_message("None".to_string()).unwrap(),
                 Some(items) => {
                     write_to_socket(socket, "Here are the results:".to_string(), items).unwrap();
                 }
@@ -243,7 +747,7 @@ fn handle_uirequest(
                         );
 
                         music_player
-                            .change_now_playing(items.get(0).unwrap().clone())
+                            .set_queue(vec![items.get(0).unwrap().clone()])
                             .unwrap();
                         info!("{}", items.get(0).unwrap().path.clone());
 
@@ -267,11 +771,614 @@ fn handle_uirequest(
                 vec![])
             .unwrap();
         },
+        UIRequest::GetStatus => {
+            let (queue, queue_position) = music_player.get_queue();
+            let status = PlayerStatus {
+                zone: zone_settings.name.clone(),
+                currently_playing: music_player.get_currently_playing().clone(),
+                paused: music_player.is_paused(),
+                position_ms: music_player.get_played_time().as_millis() as u64,
+                length_ms: music_player.get_track_length().as_millis() as u64,
+                queue_position,
+                queue_length: queue.len(),
+                volume: music_player.volume(),
+                speed: music_player.speed_settings().speed,
+            };
+            write_payload_to_socket(
+                socket,
+                "Player status:".to_string(),
+                ResponsePayload::Status(Box::new(status)),
+            )
+            .unwrap();
+        }
+        UIRequest::GetQueue => {
+            let (queue, position) = music_player.get_queue();
+            write_payload_to_socket(
+                socket,
+                "Here is the queue:".to_string(),
+                ResponsePayload::Queue {
+                    items: queue.to_vec(),
+                    position,
+                },
+            )
+            .unwrap();
+        }
+        UIRequest::ListPlaylists => match dbo.list_playlists() {
+            Ok(playlists) => write_payload_to_socket(
+                socket,
+                "Here are the playlists:".to_string(),
+                ResponsePayload::Playlists(playlists),
+            )
+            .unwrap(),
+            Err(err) => report_result(socket, Err(err), ""),
+        },
+        UIRequest::CreatePlaylist(name) => {
+            report_result(socket, dbo.create_playlist(&name), "Playlist created")
+        }
+        UIRequest::RenamePlaylist(name, new_name) => report_result(
+            socket,
+            dbo.rename_playlist(&name, &new_name),
+            "Playlist renamed",
+        ),
+        UIRequest::DeletePlaylist(name) => {
+            report_result(socket, dbo.delete_playlist(&name), "Playlist deleted")
+        }
+        UIRequest::GetPlaylist(name) => match dbo.get_playlist_entries(&name) {
+            Ok(entries) => write_payload_to_socket(
+                socket,
+                format!("Here is the playlist '{}':", name),
+                ResponsePayload::PlaylistEntries(entries),
+            )
+            .unwrap(),
+            Err(err) => report_result(socket, Err(err), ""),
+        },
+        UIRequest::AddToPlaylist(name, in_partial_tag) => {
+            let partial_tag = sanitize_partialtag(in_partial_tag);
+            let items = dbo
+                .get(&DatabaseRequest {
+                    search_type: db_operations::SearchType::Like,
+                    search_tag: partial_tag,
+                })
+                .unwrap();
+
+            match items {
+                None => {
+                    write_to_socket(socket, "No song found with that field!".to_string(), vec![])
+                        .unwrap();
+                }
+                Some(items) => match dbo.add_to_playlist(&name, &items) {
+                    Ok(()) => write_to_socket(socket, "Added to playlist".to_string(), items)
+                        .unwrap(),
+                    Err(err) => report_result(socket, Err(err), ""),
+                },
+            }
+        }
+        UIRequest::RemoveFromPlaylist(name, position) => report_result(
+            socket,
+            dbo.remove_from_playlist(&name, position),
+            "Removed from playlist",
+        ),
+        UIRequest::MovePlaylistEntry(name, from, to) => report_result(
+            socket,
+            dbo.move_playlist_entry(&name, from, to),
+            "Playlist entry moved",
+        ),
+        UIRequest::PruneMissingFromPlaylist(name) => {
+            match dbo.prune_missing_from_playlist(&name) {
+                Ok(count) => write_to_socket(
+                    socket,
+                    format!("Removed {} missing entries", count),
+                    vec![],
+                )
+                .unwrap(),
+                Err(err) => report_result(socket, Err(err), ""),
+            }
+        }
+        UIRequest::LoadPlaylist(name) => match dbo.get_playlist_tracks(&name) {
+            Ok(tracks) => match music_player.set_queue(tracks.clone()) {
+                Ok(()) => {
+                    music_player.play();
+                    write_to_socket(socket, "Playing playlist".to_string(), tracks).unwrap();
+                }
+                Err(err) => {
+                    write_to_socket(
+                        socket,
+                        format!("Could not play the playlist: {:?}", err),
+                        vec![],
+                    )
+                    .unwrap();
+                }
+            },
+            Err(err) => report_result(socket, Err(err), ""),
+        },
+        UIRequest::SaveSmartPlaylist(name, rules) => report_result(
+            socket,
+            dbo.save_smart_playlist(&name, &rules),
+            "Smart playlist saved",
+        ),
+        UIRequest::GetSmartPlaylistRules(name) => match dbo.get_smart_playlist_rules(&name) {
+            Ok(rules) => write_payload_to_socket(
+                socket,
+                format!("Here are the rules of '{}':", name),
+                ResponsePayload::SmartPlaylistRules(rules),
+            )
+            .unwrap(),
+            Err(err) => report_result(socket, Err(err), ""),
+        },
+        UIRequest::RecentlyPlayed(limit) => match dbo.recently_played(limit) {
+            Ok(items) => {
+                write_to_socket(socket, "Recently played:".to_string(), items).unwrap();
+            }
+            Err(err) => report_result(socket, Err(err), ""),
+        },
+        UIRequest::MostPlayed(limit) => match dbo.most_played(limit) {
+            Ok(items) => write_to_socket(socket, "Most played:".to_string(), items).unwrap(),
+            Err(err) => report_result(socket, Err(err), ""),
+        },
+        UIRequest::SortedSearch(unsanitary_req, sort_by, descending) => {
+            let request = sanitize_partialtag(unsanitary_req);
+            let items = dbo
+                .get_sorted(
+                    &DatabaseRequest {
+                        search_type: db_operations::SearchType::Like,
+                        search_tag: request,
+                    },
+                    Some(sort_by),
+                    descending,
+                )
+                .unwrap();
+
+            match items {
+                None => write_to_socket(socket, "None".to_string(), vec![]).unwrap(),
+                Some(items) => {
+                    write_to_socket(socket, "Here are the results:".to_string(), items).unwrap();
+                }
+            }
+        }
+        UIRequest::SetRating(path, rating) => {
+            if rating.is_some_and(|rating| rating > 5) {
+                write_to_socket(socket, "Ratings go from 0 to 5".to_string(), vec![]).unwrap();
+                return Ok(());
+            }
+
+            let result = dbo.set_rating(&path, rating);
+            if result.is_ok() && config.sync_ratings_to_files {
+                if let Err(err) = file_operations::write_rating(Path::new(&path), rating) {
+                    warn!("Could not write the rating to {}: {:?}", path, err);
+                }
+            }
+            report_result(socket, result, "Rating saved");
+        }
+        UIRequest::SetFavorite(path, favorite) => {
+            report_result(socket, dbo.set_favorite(&path, favorite), "Favorite saved")
+        }
+        UIRequest::AddLabel(path, label) => {
+            report_result(socket, dbo.add_label(&path, &label), "Label added")
+        }
+        UIRequest::RemoveLabel(path, label) => {
+            report_result(socket, dbo.remove_label(&path, &label), "Label removed")
+        }
+        UIRequest::ListLabels => match dbo.list_labels() {
+            Ok(labels) => write_payload_to_socket(
+                socket,
+                "Here are the labels:".to_string(),
+                ResponsePayload::Labels(labels),
+            )
+            .unwrap(),
+            Err(err) => report_result(socket, Err(err), ""),
+        },
+        UIRequest::EditTags(path, changes) => {
+            match tag_editing::edit_tags(dbo, &path, &changes, music_roots) {
+                Ok(updated) => {
+                    write_to_socket(socket, "Tags saved".to_string(), vec![updated]).unwrap();
+                }
+                Err(err) => {
+                    write_to_socket(socket, format!("Could not edit the tags: {:?}", err), vec![])
+                        .unwrap();
+                }
+            }
+        }
+        UIRequest::EditTagsBatch(paths, changes) => {
+            let mut updated = Vec::<ItemTag>::new();
+            let mut failures = Vec::<String>::new();
+            let results = tag_editing::edit_tags_batch(dbo, &paths, &changes, music_roots);
+            for (path, result) in paths.iter().zip(results) {
+                match result {
+                    Ok(tag) => updated.push(tag),
+                    Err(err) => failures.push(format!("{}: {:?}", path, err)),
+                }
+            }
+
+            let mut message = format!("Edited the tags of {} files", updated.len());
+            if !failures.is_empty() {
+                message.push_str(&format!("\n{} failed:\n{}", failures.len(), failures.join("\n")));
+            }
+            write_to_socket(socket, message, updated).unwrap();
+        }
+        UIRequest::UndoTagEdit(path) => match tag_editing::undo_last_edit(dbo, &path, music_roots) {
+            Ok(restored) => {
+                write_to_socket(socket, "Tag edit undone".to_string(), vec![restored]).unwrap();
+            }
+            Err(err) => {
+                write_to_socket(socket, format!("Could not undo: {:?}", err), vec![]).unwrap();
+            }
+        },
+        UIRequest::GetArtwork(artwork_id, size) => {
+            let artwork = match dbo.get_artwork(artwork_id) {
+                Ok(Some(artwork)) => artwork,
+                Ok(None) => {
+                    write_to_socket(socket, "No such artwork".to_string(), vec![]).unwrap();
+                    return Ok(());
+                }
+                Err(err) => {
+                    report_result(socket, Err(err), "");
+                    return Ok(());
+                }
+            };
+
+            match artwork_cache.load(&artwork, size) {
+                Ok((data, mime)) => write_payload_to_socket(
+                    socket,
+                    "Here is the artwork:".to_string(),
+                    ResponsePayload::Artwork {
+                        id: artwork.id,
+                        mime,
+                        data: base64::encode(data),
+                    },
+                )
+                .unwrap(),
+                Err(err) => {
+                    write_to_socket(socket, format!("Could not load the artwork: {:?}", err), vec![])
+                        .unwrap();
+                }
+            }
+        }
+        UIRequest::SetCrossfade(settings) => {
+            music_player.set_crossfade_settings(settings);
+            write_to_socket(socket, "Crossfade settings changed".to_string(), vec![]).unwrap();
+        }
+        UIRequest::SetReplayGain(settings) => {
+            music_player.set_replaygain_settings(settings);
+            write_to_socket(socket, "ReplayGain settings changed".to_string(), vec![]).unwrap();
+        }
+        UIRequest::SetVolume(volume) => {
+            let message = match music_player.set_volume(volume) {
+                Ok(()) => format!("Volume set to {}", volume),
+                Err(_) => "The volume has to be between 0 and 1".to_string(),
+            };
+            write_to_socket(socket, message, vec![]).unwrap();
+        }
+        UIRequest::SetSpeed(settings) => {
+            let message = match music_player.set_speed(settings) {
+                Ok(()) => format!("Playing at {}x speed", settings.speed),
+                Err(_) => format!(
+                    "The speed has to be between {}x and {}x",
+                    speed::MIN_SPEED,
+                    speed::MAX_SPEED
+                ),
+            };
+            write_to_socket(socket, message, vec![]).unwrap();
+        }
+        UIRequest::GetAnalysisProgress => match loudness_analyser.progress(dbo) {
+            Ok(progress) => write_payload_to_socket(
+                socket,
+                "Loudness analysis progress:".to_string(),
+                ResponsePayload::AnalysisProgress(progress),
+            )
+            .unwrap(),
+            Err(err) => report_result(socket, Err(err), ""),
+        },
+        UIRequest::SubscribeAnalysisProgress(subscribe) => {
+            *analysis_subscribed = subscribe;
+            let message = if subscribe {
+                "Subscribed to the loudness analysis progress"
+            } else {
+                "Unsubscribed from the loudness analysis progress"
+            };
+            write_to_socket(socket, message.to_string(), vec![]).unwrap();
+        }
+        UIRequest::PauseAnalysis(paused) => {
+            loudness_analyser.set_paused(paused);
+            let message = if paused {
+                "Loudness analysis paused"
+            } else {
+                "Loudness analysis resumed"
+            };
+            write_to_socket(socket, message.to_string(), vec![]).unwrap();
+        }
+        UIRequest::GetLyrics(path) => match dbo.get_lyrics(&path) {
+            Ok(lyrics) => write_payload_to_socket(
+                socket,
+                "Here are the lyrics:".to_string(),
+                ResponsePayload::Lyrics { path, lyrics },
+            )
+            .unwrap(),
+            Err(err) => report_result(socket, Err(err), ""),
+        },
+        UIRequest::SubscribeLyrics(subscribe) => {
+            *lyrics_zone = subscribe.then(|| zone_settings.name.clone());
+            let message = if subscribe {
+                "Subscribed to lyrics"
+            } else {
+                "Unsubscribed from lyrics"
+            };
+            write_to_socket(socket, message.to_string(), vec![]).unwrap();
+        }
+        UIRequest::ImportPlaylist(path) => {
+            let path = PathBuf::from(path);
+            if !file_operations::is_in_music_roots(&path, music_roots) {
+                write_to_socket(
+                    socket,
+                    "Playlists can only be imported from the music directory".to_string(),
+                    vec![],
+                )
+                .unwrap();
+                return Ok(());
+            }
+
+            match playlist_files::import_playlist(dbo, &path) {
+                Ok(import) => write_payload_to_socket(
+                    socket,
+                    format!("Imported {} tracks", import.resolved.len()),
+                    ResponsePayload::PlaylistImport {
+                        playlist: import.name,
+                        unresolved: import.unresolved,
+                    },
+                )
+                .unwrap(),
+                Err(err) => {
+                    write_to_socket(socket, format!("Could not import: {:?}", err), vec![])
+                        .unwrap();
+                }
+            }
+        }
+        UIRequest::ExportPlaylist(name, path) => {
+            let path = PathBuf::from(path);
+            if !file_operations::is_in_music_roots(&path, music_roots) {
+                write_to_socket(
+                    socket,
+                    "Playlists can only be exported to the music directory".to_string(),
+                    vec![],
+                )
+                .unwrap();
+                return Ok(());
+            }
+
+            let message = match playlist_files::export_playlist(dbo, &name, &path) {
+                Ok(()) => "Playlist exported".to_string(),
+                Err(err) => format!("Could not export: {:?}", err),
+            };
+            write_to_socket(socket, message, vec![]).unwrap();
+        }
+        UIRequest::ListEqPresets => write_payload_to_socket(
+            socket,
+            "Here are the EQ presets:".to_string(),
+            ResponsePayload::Equalizer(music_player.equalizer_settings()),
+        )
+        .unwrap(),
+        UIRequest::SelectEqPreset(name) => {
+            // Saved along with the rest of the zone's player state
+            let message = match music_player.select_eq_preset(name) {
+                Ok(()) => "EQ preset changed".to_string(),
+                Err(err) => format!("Could not change the EQ preset: {:?}", err),
+            };
+            write_to_socket(socket, message, vec![]).unwrap();
+        }
+        UIRequest::SleepTimer(timer) => {
+            // Every client hears about it from the scheduler
+            scheduler.set_sleep_timer(timer);
+            let message = match timer {
+                Some(timer) => format!("Sleep timer set: {:?}", timer),
+                None => "Sleep timer cancelled".to_string(),
+            };
+            write_to_socket(socket, message, vec![]).unwrap();
+        }
+        UIRequest::ListOutputDevices => match output::list_output_devices() {
+            Ok(devices) => write_payload_to_socket(
+                socket,
+                "Here are the sound devices:".to_string(),
+                ResponsePayload::OutputDevices {
+                    devices,
+                    current: output.device_name(),
+                },
+            )
+            .unwrap(),
+            Err(err) => write_to_socket(
+                socket,
+                format!("Could not list the sound devices: {:?}", err),
+                vec![],
+            )
+            .unwrap(),
+        },
+        UIRequest::SetOutputDevice(device) => {
+            let message = match output.switch(&OutputBackend::Device, device.as_ref()) {
+                Ok(()) => match music_player.move_to_new_output() {
+                    // The default zone's output comes from the configuration instead
+                    Ok(()) if zone_settings.name != DEFAULT_ZONE => {
+                        zone_settings.output = OutputBackend::Device;
+                        zone_settings.device = device;
+                        if let Err(err) = dbo.save_zone(zone_settings) {
+                            error!("Could not save the zone: {}", err);
+                        }
+                        format!(
+                            "Playing to '{}'",
+                            output.device_name().unwrap_or_default()
+                        )
+                    }
+                    Ok(()) => format!(
+                        "Playing to '{}'",
+                        output.device_name().unwrap_or_default()
+                    ),
+                    Err(err) => format!("Could not move the playback: {:?}", err),
+                },
+                Err(err) => format!("Could not switch the sound device: {:?}", err),
+            };
+            write_to_socket(socket, message, vec![]).unwrap();
+        }
+        UIRequest::ListSchedules => match dbo.list_schedules() {
+            Ok(schedules) => write_payload_to_socket(
+                socket,
+                "Here are the schedules:".to_string(),
+                ResponsePayload::Schedules(schedules),
+            )
+            .unwrap(),
+            Err(err) => report_result(socket, Err(err), ""),
+        },
+        UIRequest::SaveSchedule(schedule) => {
+            if !schedule.is_valid() {
+                write_to_socket(
+                    socket,
+                    "Schedules need a name and a time between 00:00 and 23:59".to_string(),
+                    vec![],
+                )
+                .unwrap();
+                return Ok(());
+            }
+            report_result(socket, dbo.save_schedule(&schedule), "Schedule saved")
+        }
+        UIRequest::DeleteSchedule(name) => {
+            report_result(socket, dbo.delete_schedule(&name), "Schedule deleted")
+        }
+        UIRequest::SetScheduleEnabled(name, enabled) => report_result(
+            socket,
+            dbo.set_schedule_enabled(&name, enabled),
+            if enabled {
+                "Schedule enabled"
+            } else {
+                "Schedule disabled"
+            },
+        ),
+        UIRequest::InZone(..)
+        | UIRequest::ListZones
+        | UIRequest::CreateZone(_)
+        | UIRequest::DestroyZone(_)
+        | UIRequest::MoveQueue(..)
+        | UIRequest::SaveEqPreset(_)
+        | UIRequest::DeleteEqPreset(_) => unreachable!("handled before the zone is picked"),
     }
 
     Ok(())
 }
 
+/// Handle the requests that list, create and destroy zones, move queues between them or
+/// change the EQ presets they share
+fn handle_zone_request(
+    request: UIRequest,
+    socket: &mut impl ClientConnection,
+    zones: &mut Zones,
+    context: &ServerContext,
+) {
+    let dbo = context.dbo;
+    let message = match request {
+        UIRequest::ListZones => {
+            write_payload_to_socket(
+                socket,
+                "Here are the zones:".to_string(),
+                ResponsePayload::Zones(zones.list()),
+            )
+            .unwrap();
+            return;
+        }
+        UIRequest::CreateZone(settings) => {
+            let created = zones.check_name(&settings.name).and_then(|()| {
+                settings.check_client_output()?;
+                let output = AudioOutput::open(&settings.output, settings.device.as_ref())?;
+                let starting_item = zones.default_zone().player.get_currently_playing().clone();
+                zones.add(Zone::new(
+                    settings.clone(),
+                    output,
+                    starting_item,
+                    context.config,
+                    zones.eq_presets(),
+                ))?;
+                Ok(())
+            });
+            match created {
+                Ok(()) => {
+                    if let Err(err) = dbo.save_zone(&settings) {
+                        error!("Could not save the zone: {}", err);
+                    }
+                    format!("Zone '{}' created", settings.name)
+                }
+                Err(err) => format!("Could not create the zone: {:?}", err),
+            }
+        }
+        UIRequest::DestroyZone(name) => match zones.remove(&name) {
+            Ok(_) => {
+                if let Err(err) = dbo.delete_zone(&name) {
+                    error!("Could not delete the zone: {}", err);
+                }
+                format!("Zone '{}' destroyed", name)
+            }
+            Err(err) => format!("Could not destroy the zone: {:?}", err),
+        },
+        UIRequest::MoveQueue(from, to) => match zones.move_queue(&from, &to) {
+            Ok(()) => format!("Moved the queue from '{}' to '{}'", from, to),
+            Err(err) => format!("Could not move the queue: {:?}", err),
+        },
+        UIRequest::SaveEqPreset(preset) => {
+            if preset.name.is_empty() {
+                "EQ presets need a name".to_string()
+            } else {
+                zones.save_eq_preset(preset);
+                store_equalizer_settings(context.config, context.config_file, zones);
+                "EQ preset saved".to_string()
+            }
+        }
+        UIRequest::DeleteEqPreset(name) => match zones.delete_eq_preset(&name) {
+            Ok(()) => {
+                store_equalizer_settings(context.config, context.config_file, zones);
+                "EQ preset deleted".to_string()
+            }
+            Err(err) => format!("Could not delete the EQ preset: {:?}", err),
+        },
+        _ => unreachable!("not a zone request"),
+    };
+    write_to_socket(socket, message, vec![]).unwrap();
+}
+
+/// The default sound device, or nowhere if it can't be opened either
+fn open_fallback_output() -> AudioOutput {
+    AudioOutput::open(&OutputBackend::Device, None).unwrap_or_else(|err| {
+        warn!("Could not open the default sound device, playing to nowhere: {:?}", err);
+        AudioOutput::null()
+    })
+}
+
+/// Write the zones' EQ presets to the configuration file, so they are kept across restarts
+///
+/// The preset zones start out with stays as configured, each zone saves its own choice
+/// along with its player state.
+fn store_equalizer_settings(config: &SousaConfig, config_file: Option<&String>, zones: &Zones) {
+    let config = SousaConfig {
+        equalizer: EqualizerSettings {
+            active_preset: config.equalizer.active_preset.clone(),
+            presets: zones.eq_presets().borrow().clone(),
+        },
+        ..config.clone()
+    };
+    if let Err(err) = config::store_config(config_file, &config) {
+        warn!("Could not save the EQ presets to the configuration: {}", err);
+    }
+}
+
+/// Tell the client whether a database operation went through
+fn report_result(
+    socket: &mut impl ClientConnection,
+    result: Result<(), rusqlite::Error>,
+    success_message: &str,
+) {
+    let message = match result {
+        Ok(()) => success_message.to_string(),
+        Err(rusqlite::Error::QueryReturnedNoRows) => "No such playlist, entry or schedule".to_string(),
+        Err(err) => {
+            error!("Database request failed: {}", err);
+            format!("The request failed: {}", err)
+        }
+    };
+    write_to_socket(socket, message, vec![]).unwrap();
+}
+
 
 pub fn init_logger(output_file: String) {
     // TODO: configure the log levels to something appropriate
@@ -1,13 +1,54 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use crate::equalizer::{EqPreset, EqualizerSettings};
+use crate::fading::CrossfadeSettings;
+use crate::loudness::AnalysisProgress;
+use crate::lyrics::{CurrentLyricLine, Lyrics};
+use crate::output::{DeviceSelector, OutputDevice};
+use crate::replaygain::{ReplayGain, ReplayGainSettings};
+use crate::scheduler::{Schedule, SchedulerEvent, SleepTimer};
+use crate::smart_playlists::{SmartPlaylistRules, SmartSort};
+use crate::speed::SpeedSettings;
+use crate::zones::{ZoneInfo, ZoneSettings};
+
 /// A struct that defines all the music tags supported by Sousa
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 pub struct ItemTag {
     pub path: String,
     pub title: String,
     pub artist: String,
     pub album: String,
     pub album_artist: String,
+    #[serde(default)]
+    pub genre: String,
+    #[serde(default)]
+    pub year: Option<i32>,
+    /// How often the track was listened to past the scrobble threshold
+    #[serde(default)]
+    pub play_count: u32,
+    /// How often the track was skipped before reaching the scrobble threshold
+    #[serde(default)]
+    pub skip_count: u32,
+    /// Unix timestamp of the last counted play
+    #[serde(default)]
+    pub last_played: Option<i64>,
+    /// Star rating from 0 to 5, `None` if the track hasn't been rated
+    #[serde(default)]
+    pub rating: Option<u8>,
+    #[serde(default)]
+    pub favorite: bool,
+    /// Free-form labels like "workout" attached to the track
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// The id to request the cover art with, from the track itself or from its album
+    #[serde(default)]
+    pub artwork_id: Option<i64>,
+    #[serde(default)]
+    pub replaygain: ReplayGain,
+    /// The id to stream the track over HTTP with, `None` for tracks that aren't in the library
+    #[serde(default)]
+    pub id: Option<i64>,
 }
 
 impl Default for ItemTag {
@@ -18,17 +59,43 @@ impl Default for ItemTag {
             artist: String::new(),
             album: String::new(),
             album_artist: String::new(),
+            genre: String::new(),
+            year: None,
+            play_count: 0,
+            skip_count: 0,
+            last_played: None,
+            rating: None,
+            favorite: false,
+            labels: vec![],
+            artwork_id: None,
+            replaygain: ReplayGain::default(),
+            id: None,
         }
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, JsonSchema)]
 pub struct PartialTag {
     pub path: Option<String>,
     pub title: Option<String>,
     pub artist: Option<String>,
     pub album: Option<String>,
     pub album_artist: Option<String>,
+    #[serde(default)]
+    pub genre: Option<String>,
+    #[serde(default)]
+    pub year: Option<i32>,
+    #[serde(default)]
+    pub favorite: Option<bool>,
+    /// Only match tracks rated at least this many stars
+    #[serde(default)]
+    pub min_rating: Option<u8>,
+    /// Only match tracks with this label
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Only match tracks whose lyrics contain this text
+    #[serde(default)]
+    pub lyrics: Option<String>,
 }
 
 impl Default for PartialTag {
@@ -39,6 +106,12 @@ impl Default for PartialTag {
             artist: None,
             album: None,
             album_artist: None,
+            genre: None,
+            year: None,
+            favorite: None,
+            min_rating: None,
+            label: None,
+            lyrics: None,
         }
     }
 }
@@ -64,28 +137,142 @@ impl PartialTag {
         self.album_artist.is_some()
     }
 
+    pub fn has_genre(&self) -> bool {
+        self.genre.is_some()
+    }
+
+    pub fn has_year(&self) -> bool {
+        self.year.is_some()
+    }
+
+    pub fn has_favorite(&self) -> bool {
+        self.favorite.is_some()
+    }
+
+    pub fn has_min_rating(&self) -> bool {
+        self.min_rating.is_some()
+    }
+
+    pub fn has_label(&self) -> bool {
+        self.label.is_some()
+    }
+
+    pub fn has_lyrics(&self) -> bool {
+        self.lyrics.is_some()
+    }
+
     pub fn is_empty(&self) -> bool {
         return self.path.is_none()
             && self.title.is_none()
             && self.artist.is_none()
             && self.album.is_none()
-            && self.album_artist.is_none();
+            && self.album_artist.is_none()
+            && self.genre.is_none()
+            && self.year.is_none()
+            && self.favorite.is_none()
+            && self.min_rating.is_none()
+            && self.label.is_none()
+            && self.lyrics.is_none();
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// A stored playlist and a summary of its contents
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct Playlist {
+    pub name: String,
+    pub track_count: usize,
+    pub missing_count: usize,
+    /// Smart playlists are generated from rules, and their entries can't be edited
+    pub smart: bool,
+}
+
+/// A single entry of a stored playlist
+///
+/// Entries reference tracks by path. When the track is no longer in the library the entry
+/// is kept but flagged as `missing`, and `tag` only has its `path` filled in.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct PlaylistEntry {
+    pub position: usize,
+    pub missing: bool,
+    pub tag: ItemTag,
+}
+
+/// Structured data sent along with a `ServerResponse` for requests that don't return tags
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub enum ResponsePayload {
+    Playlists(Vec<Playlist>),
+    PlaylistEntries(Vec<PlaylistEntry>),
+    SmartPlaylistRules(SmartPlaylistRules),
+    Labels(Vec<String>),
+    /// Cover art, `data` is the base64 encoded image
+    Artwork {
+        id: i64,
+        mime: String,
+        data: String,
+    },
+    /// The lyrics of a track, `None` if it has none
+    Lyrics {
+        path: String,
+        lyrics: Option<Lyrics>,
+    },
+    /// Pushed to clients subscribed to lyrics whenever a line of synced lyrics starts
+    LyricLine(CurrentLyricLine),
+    /// How far the loudness analysis is, pushed to subscribed clients after every track
+    AnalysisProgress(AnalysisProgress),
+    /// The saved EQ presets and the one in use
+    Equalizer(EqualizerSettings),
+    Schedules(Vec<Schedule>),
+    Zones(Vec<ZoneInfo>),
+    Status(Box<PlayerStatus>),
+    Queue {
+        items: Vec<ItemTag>,
+        position: usize,
+    },
+    /// The sound devices, and the name of the one being played to
+    OutputDevices {
+        devices: Vec<OutputDevice>,
+        current: Option<String>,
+    },
+    /// Pushed to every client when the sleep timer or a schedule does something
+    Scheduler(SchedulerEvent),
+    /// The playlist a file was imported as, and the entries that weren't found in the library
+    PlaylistImport {
+        playlist: String,
+        unresolved: Vec<String>,
+    },
+}
+
+/// What the player of a zone is doing
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct PlayerStatus {
+    pub zone: String,
+    pub currently_playing: ItemTag,
+    pub paused: bool,
+    /// How far into the track, in milliseconds
+    pub position_ms: u64,
+    /// The length of the track in milliseconds, 0 if it isn't known
+    pub length_ms: u64,
+    pub queue_position: usize,
+    pub queue_length: usize,
+    pub volume: f32,
+    pub speed: f32,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct ServerResponse {
     pub message: String,
     pub search_results: Vec<ItemTag>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<ResponsePayload>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 pub enum SkipDirection {
     Forward,
     Backward,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub enum UIRequest {
     Play,
     Pause,
@@ -93,4 +280,104 @@ pub enum UIRequest {
     Search(PartialTag),
     SwitchTo(PartialTag),
     GetTime,
+    GetStatus,
+    /// The play queue and the position of the current track in it
+    GetQueue,
+    ListPlaylists,
+    CreatePlaylist(String),
+    /// Rename a playlist, (current name, new name)
+    RenamePlaylist(String, String),
+    DeletePlaylist(String),
+    GetPlaylist(String),
+    /// Append every track matching the tag to the end of the playlist
+    AddToPlaylist(String, PartialTag),
+    /// Remove the entry at the position from the playlist
+    RemoveFromPlaylist(String, usize),
+    /// Move a playlist entry, (playlist, from position, to position)
+    MovePlaylistEntry(String, usize, usize),
+    /// Drop every entry whose track is no longer in the library
+    PruneMissingFromPlaylist(String),
+    /// Replace the play queue with the playlist and start playing it
+    LoadPlaylist(String),
+    /// Create a smart playlist, or replace the rules of an existing one
+    SaveSmartPlaylist(String, SmartPlaylistRules),
+    GetSmartPlaylistRules(String),
+    /// The most recently played tracks, (maximum number of tracks)
+    RecentlyPlayed(usize),
+    /// The tracks with the highest play counts, (maximum number of tracks)
+    MostPlayed(usize),
+    /// Search with the results sorted, (search, sort by, descending)
+    SortedSearch(PartialTag, SmartSort, bool),
+    /// Rate a track from 0 to 5 stars, or clear its rating with `None`, (path, rating)
+    SetRating(String, Option<u8>),
+    /// (path, favorite)
+    SetFavorite(String, bool),
+    /// (path, label)
+    AddLabel(String, String),
+    /// (path, label)
+    RemoveLabel(String, String),
+    ListLabels,
+    /// Change the tags of a file and update the library to match, (path, changes)
+    ///
+    /// Only the text fields and `year` are written; an empty string (or year 0) removes the field.
+    EditTags(String, PartialTag),
+    /// Apply the same changes to several files, (paths, changes)
+    EditTagsBatch(Vec<String>, PartialTag),
+    /// Restore the tags of a file to what they were before its last edit
+    UndoTagEdit(String),
+    /// Cover art by id, optionally resized to fit a square of the given size in pixels
+    GetArtwork(i64, Option<u32>),
+    /// Change the crossfade and fade lengths until the server restarts
+    SetCrossfade(CrossfadeSettings),
+    /// Change how loudness normalization is applied until the server restarts
+    SetReplayGain(ReplayGainSettings),
+    /// Play faster or slower, from 0.5 to 3 times normal speed
+    SetSpeed(SpeedSettings),
+    GetAnalysisProgress,
+    /// Start or stop receiving the loudness analysis progress after every analysed track
+    SubscribeAnalysisProgress(bool),
+    /// Pause (true) or resume (false) the background loudness analysis
+    PauseAnalysis(bool),
+    /// The lyrics of a track, by path
+    GetLyrics(String),
+    /// Start or stop receiving the current line of synced lyrics as the track plays
+    SubscribeLyrics(bool),
+    /// Import a M3U/M3U8/PLS/XSPF file from the music directory as a playlist
+    ImportPlaylist(String),
+    /// Write a playlist to a file, (playlist, file path); the extension picks the format
+    ExportPlaylist(String, String),
+    ListEqPresets,
+    /// Switch the zone to an EQ preset by name, or turn its EQ off with `None`
+    SelectEqPreset(Option<String>),
+    /// Save an EQ preset to the configuration, replacing the one with the same name
+    ///
+    /// The presets are shared between the zones, whichever zone the request is sent to.
+    SaveEqPreset(EqPreset),
+    DeleteEqPreset(String),
+    /// Pause with a fade out after a while or at the end of the track or queue, or cancel the
+    /// sleep timer with `None`
+    SleepTimer(Option<SleepTimer>),
+    ListSchedules,
+    /// Store a schedule, replacing the one with the same name
+    SaveSchedule(Schedule),
+    DeleteSchedule(String),
+    /// (schedule, enabled)
+    SetScheduleEnabled(String, bool),
+    ListOutputDevices,
+    /// Move the playback to a sound device by name or index, or to the default one with `None`
+    SetOutputDevice(Option<DeviceSelector>),
+    /// From 0 to 1
+    SetVolume(f32),
+    /// Send a request to the player of a zone, (zone, request)
+    ///
+    /// Requests that aren't wrapped in this go to the default zone.
+    InZone(String, Box<UIRequest>),
+    ListZones,
+    /// Add a zone playing to its own output, paused on what the default zone is playing
+    ///
+    /// The output is a sound device or nowhere, WAV outputs are refused.
+    CreateZone(ZoneSettings),
+    DestroyZone(String),
+    /// Hand the queue over to another zone, (from zone, to zone)
+    MoveQueue(String, String),
 }
@@ -0,0 +1,1185 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+use crate::db_operations::{DBObject, DatabaseRequest, SearchType};
+use crate::message_types::{ItemTag, PartialTag, SkipDirection};
+use crate::music_player::MusicPlayer;
+use crate::server_handling::sanitize_partialtag;
+use crate::smart_playlists::TextField;
+
+/// The version of the protocol clients are greeted with, the commands are a subset of it
+const PROTOCOL_VERSION: &str = "0.21.0";
+/// How long a connection waits for the server loop to run its command
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+/// The commands answered by the connection itself rather than the server loop
+const CONNECTION_COMMANDS: &[&str] = &["close", "idle", "noidle", "password", "ping"];
+/// The commands run against the player and the library
+const PLAYER_COMMANDS: &[&str] = &[
+    "add",
+    "clear",
+    "commands",
+    "currentsong",
+    "find",
+    "list",
+    "next",
+    "pause",
+    "play",
+    "playlistinfo",
+    "previous",
+    "search",
+    "setvol",
+    "status",
+    "stop",
+];
+
+/// The Music Player Daemon protocol server, part of the configuration file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct MpdSettings {
+    pub enabled: bool,
+    /// The address MPD clients connect to
+    pub address: String,
+}
+
+impl Default for MpdSettings {
+    fn default() -> Self {
+        MpdSettings {
+            enabled: true,
+            address: "127.0.0.1:6600".to_string(),
+        }
+    }
+}
+
+/// The MPD error codes Sousa answers with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AckCode {
+    Argument = 2,
+    Password = 3,
+    Permission = 4,
+    Unknown = 5,
+    NoExist = 50,
+    System = 52,
+}
+
+/// A command that failed, sent to the client as an `ACK` line
+#[derive(Debug, PartialEq, Eq)]
+struct Ack {
+    code: AckCode,
+    command: String,
+    message: String,
+}
+
+impl Ack {
+    fn new(code: AckCode, command: &str, message: impl Into<String>) -> Self {
+        Ack {
+            code,
+            command: command.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// The `ACK` line, `index` is the position of the command in its command list
+    fn line(&self, index: usize) -> String {
+        format!(
+            "ACK [{}@{}] {{{}}} {}\n",
+            self.code as u32, index, self.command, self.message
+        )
+    }
+}
+
+/// What the thread reading from a client passes on to the one answering it
+enum Input {
+    Line(String),
+    Closed,
+    /// A subsystem that changed, for `idle`
+    Changed(&'static str),
+}
+
+/// A command waiting for the server loop to run it
+struct MpdCall {
+    command: Vec<String>,
+    reply: Sender<Result<String, Ack>>,
+}
+
+/// The parts of the player clients are told about when they change
+#[derive(Clone, PartialEq)]
+struct Snapshot {
+    queue: Vec<String>,
+    queue_position: usize,
+    current: String,
+    paused: bool,
+    volume: f32,
+}
+
+impl Snapshot {
+    fn of(player: &MusicPlayer) -> Self {
+        let (queue, queue_position) = player.get_queue();
+        Snapshot {
+            queue: queue.iter().map(|item| item.path.clone()).collect(),
+            queue_position,
+            current: player.get_currently_playing().path.clone(),
+            paused: player.is_paused(),
+            volume: player.volume(),
+        }
+    }
+}
+
+/// Lets MPD clients like mpc, ncmpcpp and MALP control the default zone
+///
+/// This is a subset of the MPD text protocol: playback, the queue, searching and listing
+/// the library, and `idle`. Every connection is served from a thread of its own; the
+/// commands are run by the server loop in `poll`, as that is where the player is. The
+/// songs of the queue are identified by their position in it.
+pub struct MpdServer {
+    listener: TcpListener,
+    access_token: Option<String>,
+    calls: Receiver<MpdCall>,
+    call_sender: Sender<MpdCall>,
+    /// Where the connections are told about changes
+    connections: Vec<Sender<Input>>,
+    snapshot: Option<Snapshot>,
+    /// Goes up every time the queue changes, so clients know to fetch it again
+    playlist_version: u32,
+}
+
+impl MpdServer {
+    pub fn bind(settings: &MpdSettings, access_token: Option<String>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(&settings.address)?;
+        listener.set_nonblocking(true)?;
+        info!("MPD protocol listening on: {}", listener.local_addr()?);
+
+        let (call_sender, calls) = channel();
+        Ok(MpdServer {
+            listener,
+            access_token,
+            calls,
+            call_sender,
+            connections: vec![],
+            snapshot: None,
+            playlist_version: 1,
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Take the connections that are waiting, run the commands of the ones being served
+    /// and tell the idle ones what changed
+    pub fn poll(&mut self, player: &mut MusicPlayer, dbo: &DBObject, music_roots: &[PathBuf]) {
+        while let Ok((stream, addr)) = self.listener.accept() {
+            info!("New MPD connection from: {}", addr);
+            let (input_sender, input) = channel();
+            self.connections.push(input_sender.clone());
+            let connection = Connection {
+                authorized: self.access_token.is_none(),
+                access_token: self.access_token.clone(),
+                input,
+                call_sender: self.call_sender.clone(),
+                changed: vec![],
+            };
+            std::thread::spawn(move || {
+                if let Err(err) = connection.serve(stream, input_sender) {
+                    warn!("Could not serve an MPD client: {}", err);
+                }
+            });
+        }
+
+        self.notify_changes(player);
+        while let Ok(call) = self.calls.try_recv() {
+            let mut session = Session {
+                player: &mut *player,
+                dbo,
+                music_roots,
+                playlist_version: self.playlist_version,
+            };
+            let result = session.execute(&call.command);
+            self.notify_changes(player);
+            // The connection may have timed out waiting
+            let _ = call.reply.send(result);
+        }
+    }
+
+    fn notify_changes(&mut self, player: &MusicPlayer) {
+        let snapshot = Snapshot::of(player);
+        let previous = match self.snapshot.replace(snapshot.clone()) {
+            Some(previous) if previous != snapshot => previous,
+            _ => return,
+        };
+
+        let mut changed = vec![];
+        if previous.queue != snapshot.queue {
+            self.playlist_version += 1;
+            changed.push("playlist");
+        }
+        if previous.queue_position != snapshot.queue_position
+            || previous.current != snapshot.current
+            || previous.paused != snapshot.paused
+        {
+            changed.push("player");
+        }
+        if previous.volume != snapshot.volume {
+            changed.push("mixer");
+        }
+        for subsystem in changed {
+            self.connections
+                .retain(|connection| connection.send(Input::Changed(subsystem)).is_ok());
+        }
+    }
+}
+
+/// Split a command line into its arguments, which may be quoted with backslash escapes
+fn parse_arguments(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut arguments = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(arguments);
+        }
+
+        let mut argument = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c) => argument.push(c),
+                        None => return Err("Missing closing '\"'"),
+                    },
+                    Some(c) => argument.push(c),
+                    None => return Err("Missing closing '\"'"),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                argument.push(c);
+            }
+        }
+        arguments.push(argument);
+    }
+}
+
+/// The thread answering a client
+struct Connection {
+    authorized: bool,
+    access_token: Option<String>,
+    input: Receiver<Input>,
+    call_sender: Sender<MpdCall>,
+    /// The subsystems that changed since the last `idle`
+    changed: Vec<&'static str>,
+}
+
+impl Connection {
+    fn serve(self, mut stream: TcpStream, input_sender: Sender<Input>) -> std::io::Result<()> {
+        // Accepted sockets take on the listener's non-blocking mode on some platforms
+        stream.set_nonblocking(false)?;
+        let result = self.converse(&mut stream, input_sender);
+        // The reading thread has a handle on the socket too, this closes it for both
+        let _ = stream.shutdown(Shutdown::Both);
+        result
+    }
+
+    fn converse(
+        mut self,
+        stream: &mut TcpStream,
+        input_sender: Sender<Input>,
+    ) -> std::io::Result<()> {
+        let reader = BufReader::new(stream.try_clone()?);
+        std::thread::spawn(move || {
+            for line in reader.lines().map_while(Result::ok) {
+                if input_sender.send(Input::Line(line)).is_err() {
+                    return;
+                }
+            }
+            let _ = input_sender.send(Input::Closed);
+        });
+
+        writeln!(stream, "OK MPD {}", PROTOCOL_VERSION)?;
+        while let Some(line) = self.next_line() {
+            let arguments = match parse_arguments(&line) {
+                Ok(arguments) if !arguments.is_empty() => arguments,
+                Ok(_) => continue,
+                Err(message) => {
+                    stream
+                        .write_all(Ack::new(AckCode::Argument, "", message).line(0).as_bytes())?;
+                    continue;
+                }
+            };
+            let response = match arguments[0].as_str() {
+                "close" => return Ok(()),
+                // Only meaningful while idle
+                "noidle" => continue,
+                "idle" => match self.idle(&arguments[1..]) {
+                    Some(response) => response,
+                    None => return Ok(()),
+                },
+                "command_list_begin" | "command_list_ok_begin" => {
+                    let list_ok = arguments[0] == "command_list_ok_begin";
+                    match self.command_list(list_ok) {
+                        Some(response) => response,
+                        None => return Ok(()),
+                    }
+                }
+                _ => match self.run(&arguments) {
+                    Ok(response) => response + "OK\n",
+                    Err(ack) => ack.line(0),
+                },
+            };
+            stream.write_all(response.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// The next line from the client, `None` once it is gone
+    fn next_line(&mut self) -> Option<String> {
+        loop {
+            match self.input.recv() {
+                Ok(Input::Line(line)) => return Some(line),
+                Ok(Input::Changed(subsystem)) => self.note_change(subsystem),
+                Ok(Input::Closed) | Err(_) => return None,
+            }
+        }
+    }
+
+    fn note_change(&mut self, subsystem: &'static str) {
+        if !self.changed.contains(&subsystem) {
+            self.changed.push(subsystem);
+        }
+    }
+
+    /// Wait for a change to one of `subsystems`, or any of them if none are given
+    ///
+    /// `None` if the client went away or sent something other than `noidle` meanwhile.
+    fn idle(&mut self, subsystems: &[String]) -> Option<String> {
+        loop {
+            let (wanted, others): (Vec<_>, Vec<_>) =
+                self.changed.iter().copied().partition(|changed| {
+                    subsystems.is_empty() || subsystems.iter().any(|name| name == *changed)
+                });
+            if !wanted.is_empty() {
+                self.changed = others;
+                let mut response: String = wanted
+                    .iter()
+                    .map(|subsystem| format!("changed: {}\n", subsystem))
+                    .collect();
+                response.push_str("OK\n");
+                return Some(response);
+            }
+
+            match self.input.recv() {
+                Ok(Input::Changed(subsystem)) => self.note_change(subsystem),
+                Ok(Input::Line(line)) if line.trim() == "noidle" => return Some("OK\n".into()),
+                _ => return None,
+            }
+        }
+    }
+
+    /// Run the commands up to `command_list_end`, stopping at the first that fails
+    ///
+    /// With `list_ok` every command that went through is followed by `list_OK`.
+    fn command_list(&mut self, list_ok: bool) -> Option<String> {
+        let mut commands = vec![];
+        loop {
+            let line = self.next_line()?;
+            if line.trim() == "command_list_end" {
+                break;
+            }
+            commands.push(line);
+        }
+
+        let mut response = String::new();
+        for (index, line) in commands.iter().enumerate() {
+            let result = parse_arguments(line)
+                .map_err(|message| Ack::new(AckCode::Argument, "", message))
+                .and_then(|arguments| match arguments.first() {
+                    Some(_) => self.run(&arguments),
+                    None => Err(Ack::new(AckCode::Unknown, "", "No command given")),
+                });
+            match result {
+                Ok(output) => {
+                    response.push_str(&output);
+                    if list_ok {
+                        response.push_str("list_OK\n");
+                    }
+                }
+                Err(ack) => {
+                    response.push_str(&ack.line(index));
+                    return Some(response);
+                }
+            }
+        }
+        response.push_str("OK\n");
+        Some(response)
+    }
+
+    /// Run a command, on the server loop unless the connection answers it itself
+    fn run(&mut self, arguments: &[String]) -> Result<String, Ack> {
+        let command = arguments[0].as_str();
+        if command == "password" {
+            let password = arguments.get(1).map(String::as_str);
+            if self.access_token.is_some() && self.access_token.as_deref() != password {
+                return Err(Ack::new(AckCode::Password, command, "incorrect password"));
+            }
+            self.authorized = true;
+            return Ok(String::new());
+        }
+        if command == "ping" {
+            return Ok(String::new());
+        }
+        if !self.authorized {
+            return Err(Ack::new(
+                AckCode::Permission,
+                command,
+                format!("you don't have permission for \"{}\"", command),
+            ));
+        }
+        if CONNECTION_COMMANDS.contains(&command) {
+            return Err(Ack::new(
+                AckCode::Argument,
+                command,
+                "not allowed in a command list",
+            ));
+        }
+
+        let (reply, result) = channel();
+        let call = MpdCall {
+            command: arguments.to_vec(),
+            reply,
+        };
+        let result = match self.call_sender.send(call) {
+            Ok(()) => result.recv_timeout(COMMAND_TIMEOUT).ok(),
+            Err(_) => None,
+        };
+        result.unwrap_or_else(|| Err(Ack::new(AckCode::System, command, "The server is busy")))
+    }
+}
+
+/// What a command is run against
+struct Session<'a> {
+    player: &'a mut MusicPlayer,
+    dbo: &'a DBObject,
+    music_roots: &'a [PathBuf],
+    playlist_version: u32,
+}
+
+fn push_field(output: &mut String, key: &str, value: impl Display) {
+    output.push_str(&format!("{}: {}\n", key, value));
+}
+
+/// The library field of an MPD tag, for the tags `list` takes
+fn tag_field(tag: &str) -> Option<TextField> {
+    match tag.to_lowercase().as_str() {
+        "artist" => Some(TextField::Artist),
+        "albumartist" => Some(TextField::AlbumArtist),
+        "album" => Some(TextField::Album),
+        "title" => Some(TextField::Title),
+        "genre" => Some(TextField::Genre),
+        _ => None,
+    }
+}
+
+/// The name MPD gives a tag in its responses
+fn tag_name(field: TextField) -> &'static str {
+    match field {
+        TextField::Path => "file",
+        TextField::Title => "Title",
+        TextField::Artist => "Artist",
+        TextField::Album => "Album",
+        TextField::AlbumArtist => "AlbumArtist",
+        TextField::Genre => "Genre",
+    }
+}
+
+fn field_value(item: &ItemTag, field: TextField) -> &str {
+    match field {
+        TextField::Path => &item.path,
+        TextField::Title => &item.title,
+        TextField::Artist => &item.artist,
+        TextField::Album => &item.album,
+        TextField::AlbumArtist => &item.album_artist,
+        TextField::Genre => &item.genre,
+    }
+}
+
+/// A tag to search with from pairs of tag names and values
+fn filter_tag(command: &str, arguments: &[String]) -> Result<PartialTag, Ack> {
+    if arguments.is_empty() || !arguments.len().is_multiple_of(2) {
+        return Err(Ack::new(AckCode::Argument, command, "incorrect arguments"));
+    }
+    let mut tag = PartialTag::default();
+    for pair in arguments.chunks(2) {
+        let value = Some(pair[1].clone());
+        match pair[0].to_lowercase().as_str() {
+            "file" => tag.path = value,
+            "title" => tag.title = value,
+            "artist" => tag.artist = value,
+            "album" => tag.album = value,
+            "albumartist" => tag.album_artist = value,
+            "genre" => tag.genre = value,
+            "date" => {
+                let year = pair[1]
+                    .parse()
+                    .map_err(|_| Ack::new(AckCode::Argument, command, "Date has to be a year"))?;
+                tag.year = Some(year);
+            }
+            _ => {
+                return Err(Ack::new(
+                    AckCode::Argument,
+                    command,
+                    format!("Unsupported tag type \"{}\"", pair[0]),
+                ))
+            }
+        }
+    }
+    Ok(sanitize_partialtag(tag))
+}
+
+fn parse_number<T: std::str::FromStr>(command: &str, argument: &str) -> Result<T, Ack> {
+    argument.parse().map_err(|_| {
+        Ack::new(
+            AckCode::Argument,
+            command,
+            format!("Integer expected: {}", argument),
+        )
+    })
+}
+
+impl Session<'_> {
+    fn execute(&mut self, command: &[String]) -> Result<String, Ack> {
+        let (name, arguments) = command.split_first().unwrap();
+        let name = name.as_str();
+        let mut output = String::new();
+        match name {
+            "status" => self.status(&mut output),
+            "currentsong" => {
+                let (queue, position) = self.player.get_queue();
+                // There is no current song with an empty queue
+                if !queue.is_empty() {
+                    let item = self.player.get_currently_playing().clone();
+                    self.song(&mut output, &item, Some(position));
+                }
+            }
+            "playlistinfo" => {
+                let (queue, _) = self.player.get_queue();
+                let range = match arguments.first() {
+                    None => 0..queue.len(),
+                    Some(range) => match range.split_once(':') {
+                        Some((start, end)) => parse_number(name, start)?..parse_number(name, end)?,
+                        None => {
+                            let position = parse_number(name, range)?;
+                            position..position + 1
+                        }
+                    },
+                };
+                if range.start >= queue.len() && !queue.is_empty() {
+                    return Err(Ack::new(AckCode::Argument, name, "Bad song index"));
+                }
+                let items: Vec<(usize, ItemTag)> = queue
+                    .iter()
+                    .cloned()
+                    .enumerate()
+                    .skip(range.start)
+                    .take(range.end.saturating_sub(range.start))
+                    .collect();
+                for (position, item) in items {
+                    self.song(&mut output, &item, Some(position));
+                }
+            }
+            "play" => {
+                if let Some(position) = arguments.first() {
+                    let position = parse_number(name, position)?;
+                    self.player
+                        .skip_to(position)
+                        .map_err(|_| Ack::new(AckCode::Argument, name, "Bad song index"))?;
+                }
+                self.player.play();
+            }
+            "pause" => {
+                let pause = match arguments.first().map(String::as_str) {
+                    Some("1") => true,
+                    Some("0") => false,
+                    Some(_) => return Err(Ack::new(AckCode::Argument, name, "Boolean expected")),
+                    None => !self.player.is_paused(),
+                };
+                if pause {
+                    self.player.pause();
+                } else {
+                    self.player.play();
+                }
+            }
+            // There is no stopped state, the track stays where it was
+            "stop" => self.player.pause(),
+            "clear" => self.player.clear_queue(),
+            "next" | "previous" => {
+                let direction = match name {
+                    "next" => SkipDirection::Forward,
+                    _ => SkipDirection::Backward,
+                };
+                if self.player.skip(direction).is_ok() {
+                    self.player.play();
+                }
+            }
+            "setvol" => {
+                let volume: u32 = parse_number(name, arguments.first().map_or("", String::as_str))?;
+                self.player
+                    .set_volume(volume as f32 / 100.0)
+                    .map_err(|_| Ack::new(AckCode::Argument, name, "Invalid volume value"))?;
+            }
+            "find" | "search" => {
+                let search_type = match name {
+                    "find" => SearchType::Where,
+                    _ => SearchType::Like,
+                };
+                let request = DatabaseRequest {
+                    search_type,
+                    search_tag: filter_tag(name, arguments)?,
+                };
+                for item in self.lookup(name, &request)? {
+                    self.song(&mut output, &item, None);
+                }
+            }
+            "list" => self.list(&mut output, arguments)?,
+            "add" => {
+                let uri = arguments
+                    .first()
+                    .ok_or_else(|| Ack::new(AckCode::Argument, name, "incorrect arguments"))?;
+                let items = self.find_uri(uri)?;
+                if items.is_empty() {
+                    return Err(Ack::new(AckCode::NoExist, name, "Not found"));
+                }
+                self.player.append_to_queue(items);
+            }
+            "commands" => {
+                let mut commands: Vec<&str> = CONNECTION_COMMANDS
+                    .iter()
+                    .chain(PLAYER_COMMANDS)
+                    .copied()
+                    .collect();
+                commands.sort();
+                for command in commands {
+                    push_field(&mut output, "command", command);
+                }
+            }
+            _ => {
+                return Err(Ack::new(
+                    AckCode::Unknown,
+                    name,
+                    format!("unknown command \"{}\"", name),
+                ))
+            }
+        }
+        Ok(output)
+    }
+
+    fn status(&self, output: &mut String) {
+        let (queue, position) = self.player.get_queue();
+        push_field(output, "volume", (self.player.volume() * 100.0).round());
+        for mode in ["repeat", "random", "single", "consume"] {
+            push_field(output, mode, 0);
+        }
+        push_field(output, "playlist", self.playlist_version);
+        push_field(output, "playlistlength", queue.len());
+        let state = if queue.is_empty() {
+            "stop"
+        } else if self.player.is_paused() {
+            "pause"
+        } else {
+            "play"
+        };
+        push_field(output, "state", state);
+        // The rest is about the current song, there is none with an empty queue
+        if queue.is_empty() {
+            return;
+        }
+        push_field(output, "song", position);
+        push_field(output, "songid", position);
+
+        let elapsed = self.player.get_played_time().as_secs_f64();
+        let duration = self.player.get_track_length().as_secs_f64();
+        push_field(
+            output,
+            "time",
+            format!("{}:{}", elapsed.round(), duration.round()),
+        );
+        push_field(output, "elapsed", format!("{:.3}", elapsed));
+        if duration > 0.0 {
+            push_field(output, "duration", format!("{:.3}", duration));
+        }
+        if position + 1 < queue.len() {
+            push_field(output, "nextsong", position + 1);
+            push_field(output, "nextsongid", position + 1);
+        }
+    }
+
+    /// The tags of a song, with its place in the queue if it is in it
+    ///
+    /// Only the length of the current track is known.
+    fn song(&self, output: &mut String, item: &ItemTag, position: Option<usize>) {
+        push_field(output, "file", &item.path);
+        for field in [
+            TextField::Artist,
+            TextField::AlbumArtist,
+            TextField::Title,
+            TextField::Album,
+            TextField::Genre,
+        ] {
+            let value = field_value(item, field);
+            if !value.is_empty() {
+                push_field(output, tag_name(field), value);
+            }
+        }
+        if let Some(year) = item.year {
+            push_field(output, "Date", year);
+        }
+        let (_, current_position) = self.player.get_queue();
+        let length = self.player.get_track_length();
+        if position == Some(current_position) && !length.is_zero() {
+            push_field(output, "Time", length.as_secs_f64().round());
+            push_field(output, "duration", format!("{:.3}", length.as_secs_f64()));
+        }
+        if let Some(position) = position {
+            push_field(output, "Pos", position);
+            push_field(output, "Id", position);
+        }
+    }
+
+    fn lookup(&self, command: &str, request: &DatabaseRequest) -> Result<Vec<ItemTag>, Ack> {
+        self.dbo
+            .get(request)
+            .map(Option::unwrap_or_default)
+            .map_err(|err| Ack::new(AckCode::System, command, err.to_string()))
+    }
+
+    /// `list TYPE [FILTER...]`, every value of a tag among the tracks matching the filter
+    ///
+    /// A single filter argument is the artist, as in older versions of the protocol.
+    fn list(&self, output: &mut String, arguments: &[String]) -> Result<(), Ack> {
+        let command = "list";
+        let (tag, filter) = arguments
+            .split_first()
+            .ok_or_else(|| Ack::new(AckCode::Argument, command, "incorrect arguments"))?;
+        let field = tag_field(tag).ok_or_else(|| {
+            Ack::new(
+                AckCode::Argument,
+                command,
+                format!("Unsupported tag type \"{}\"", tag),
+            )
+        })?;
+
+        let values = match filter {
+            [] => self
+                .dbo
+                .list_tag_values(field)
+                .map_err(|err| Ack::new(AckCode::System, command, err.to_string()))?,
+            filter => {
+                let filter = match filter {
+                    [artist] => vec!["artist".to_string(), artist.clone()],
+                    filter => filter.to_vec(),
+                };
+                let request = DatabaseRequest {
+                    search_type: SearchType::Where,
+                    search_tag: filter_tag(command, &filter)?,
+                };
+                let mut values: Vec<String> = self
+                    .lookup(command, &request)?
+                    .iter()
+                    .map(|item| field_value(item, field).to_string())
+                    .filter(|value| !value.is_empty())
+                    .collect();
+                values.sort_by_key(|value| value.to_lowercase());
+                values.dedup();
+                values
+            }
+        };
+        for value in values {
+            push_field(output, tag_name(field), value);
+        }
+        Ok(())
+    }
+
+    /// The tracks a song or directory URI stands for, as a path or relative to a music root
+    fn find_uri(&self, uri: &str) -> Result<Vec<ItemTag>, Ack> {
+        let candidates = std::iter::once(PathBuf::from(uri))
+            .chain(self.music_roots.iter().map(|root| root.join(uri)));
+        for candidate in candidates {
+            let path = candidate.to_string_lossy();
+            let found = self
+                .dbo
+                .get_tag_by_path(&path)
+                .and_then(|track| match track {
+                    Some(track) => Ok(vec![track]),
+                    None => self.dbo.tracks_in_directory(&path),
+                });
+            match found {
+                Ok(tracks) if !tracks.is_empty() => return Ok(tracks),
+                Ok(_) => {}
+                Err(err) => return Err(Ack::new(AckCode::System, "add", err.to_string())),
+            }
+        }
+        Ok(vec![])
+    }
+}
+
+#[test]
+fn test_parse_arguments() {
+    assert_eq!(parse_arguments("status"), Ok(vec!["status".to_string()]));
+    assert_eq!(
+        parse_arguments(r#"find  artist "The \"Band\"" album Second"#),
+        Ok(vec![
+            "find".to_string(),
+            "artist".to_string(),
+            r#"The "Band""#.to_string(),
+            "album".to_string(),
+            "Second".to_string(),
+        ])
+    );
+    assert_eq!(
+        parse_arguments(r#"add "C:\\Music\\a b""#),
+        Ok(vec!["add".to_string(), r"C:\Music\a b".to_string()])
+    );
+    assert_eq!(parse_arguments("   "), Ok(vec![]));
+    assert!(parse_arguments(r#"find artist "unfinished"#).is_err());
+}
+
+/// Conversations with the server as recorded from a client, `C:` lines are sent and `S:`
+/// lines are expected back; a `*` at the end of an expected line matches anything
+#[cfg(test)]
+const TRANSCRIPTS: &[&str] = &[
+    r#"
+C: status
+S: ACK [4@0] {status} you don't have permission for "status"
+C: password wrong
+S: ACK [3@0] {password} incorrect password
+C: password secret
+S: OK
+C: ping
+S: OK
+"#,
+    r#"
+C: password secret
+S: OK
+C: status
+S: volume: 100
+S: repeat: 0
+S: random: 0
+S: single: 0
+S: consume: 0
+S: playlist: 1
+S: playlistlength: 1
+S: state: pause
+S: song: 0
+S: songid: 0
+S: time: 0:2
+S: elapsed: 0.000
+S: duration: 2.000
+S: OK
+C: currentsong
+S: file: {music}/The Band/First/01.wav
+S: Artist: The Band
+S: AlbumArtist: The Band
+S: Title: Opening
+S: Album: First
+S: Genre: Rock
+S: Date: 1990
+S: Time: 2
+S: duration: 2.000
+S: Pos: 0
+S: Id: 0
+S: OK
+C: add "{music}/The Band/Second"
+S: OK
+C: playlistinfo 1:3
+S: file: {music}/The Band/Second/01.wav
+S: Artist: The Band
+S: Title: Second Wind
+S: Album: Second
+S: Pos: 1
+S: Id: 1
+S: file: {music}/The Band/Second/02.wav
+S: Artist: The Band
+S: Title: Closing
+S: Album: Second
+S: Pos: 2
+S: Id: 2
+S: OK
+C: play 2
+S: OK
+C: status
+S: volume: 100
+S: repeat: 0
+S: random: 0
+S: single: 0
+S: consume: 0
+S: playlist: 2
+S: playlistlength: 3
+S: state: play
+S: song: 2
+S: songid: 2
+S: time: *
+S: elapsed: *
+S: duration: 2.000
+S: OK
+C: previous
+S: OK
+C: pause
+S: OK
+C: setvol 50
+S: OK
+C: status
+S: volume: 50
+S: repeat: 0
+S: random: 0
+S: single: 0
+S: consume: 0
+S: playlist: 2
+S: playlistlength: 3
+S: state: pause
+S: song: 1
+S: songid: 1
+S: time: *
+S: elapsed: *
+S: duration: 2.000
+S: nextsong: 2
+S: nextsongid: 2
+S: OK
+C: play 7
+S: ACK [2@0] {play} Bad song index
+C: clear
+S: OK
+C: status
+S: volume: 50
+S: repeat: 0
+S: random: 0
+S: single: 0
+S: consume: 0
+S: playlist: 3
+S: playlistlength: 0
+S: state: stop
+S: OK
+C: currentsong
+S: OK
+C: play
+S: OK
+C: add "{music}/The Band/First"
+S: OK
+C: currentsong
+S: file: {music}/The Band/First/01.wav
+S: Artist: The Band
+S: AlbumArtist: The Band
+S: Title: Opening
+S: Album: First
+S: Genre: Rock
+S: Date: 1990
+S: Time: 2
+S: duration: 2.000
+S: Pos: 0
+S: Id: 0
+S: OK
+"#,
+    r#"
+C: password secret
+S: OK
+C: find artist "The Band" album Second
+S: file: {music}/The Band/Second/01.wav
+S: Artist: The Band
+S: Title: Second Wind
+S: Album: Second
+S: file: {music}/The Band/Second/02.wav
+S: Artist: The Band
+S: Title: Closing
+S: Album: Second
+S: OK
+C: find artist "the band"
+S: OK
+C: search title SECOND
+S: file: {music}/The Band/Second/01.wav
+S: Artist: The Band
+S: Title: Second Wind
+S: Album: Second
+S: OK
+C: search any wind
+S: ACK [2@0] {search} Unsupported tag type "any"
+C: list artist
+S: Artist: Someone Else
+S: Artist: The Band
+S: OK
+C: list album artist "The Band"
+S: Album: First
+S: Album: Second
+S: OK
+C: list album "Someone Else"
+S: Album: Far
+S: OK
+C: list genre
+S: Genre: Jazz
+S: Genre: Rock
+S: OK
+C: add "{music}/Nowhere"
+S: ACK [50@0] {add} Not found
+C: frobnicate
+S: ACK [5@0] {frobnicate} unknown command "frobnicate"
+C: command_list_begin
+C: ping
+C: frobnicate
+C: ping
+C: command_list_end
+S: ACK [5@1] {frobnicate} unknown command "frobnicate"
+C: command_list_ok_begin
+C: ping
+C: ping
+C: command_list_end
+S: list_OK
+S: list_OK
+S: OK
+"#,
+];
+
+#[test]
+fn test_mpd_transcripts() {
+    use std::io::Read;
+
+    let music = std::env::temp_dir().join(format!("sousa-mpd-{}", std::process::id()));
+    let dbo = DBObject::new(&PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+    let tracks = [
+        (
+            "The Band/First/01.wav",
+            "Opening",
+            "The Band",
+            "First",
+            "The Band",
+            "Rock",
+            Some(1990),
+        ),
+        (
+            "The Band/Second/01.wav",
+            "Second Wind",
+            "The Band",
+            "Second",
+            "",
+            "",
+            None,
+        ),
+        (
+            "The Band/Second/02.wav",
+            "Closing",
+            "The Band",
+            "Second",
+            "",
+            "",
+            None,
+        ),
+        (
+            "Other/01.wav",
+            "Elsewhere",
+            "Someone Else",
+            "Far",
+            "",
+            "Jazz",
+            None,
+        ),
+    ];
+    for (file, title, artist, album, album_artist, genre, year) in tracks {
+        let path = music.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        crate::output::write_test_wav(&path, 2.0);
+        dbo.save_tag(&ItemTag {
+            path: path.to_string_lossy().into_owned(),
+            title: title.to_string(),
+            artist: artist.to_string(),
+            album: album.to_string(),
+            album_artist: album_artist.to_string(),
+            genre: genre.to_string(),
+            year,
+            ..ItemTag::default()
+        })
+        .unwrap();
+    }
+
+    let first = dbo
+        .get_tag_by_path(&music.join(tracks[0].0).to_string_lossy())
+        .unwrap()
+        .unwrap();
+    let output = std::rc::Rc::new(crate::output::AudioOutput::null());
+    let mut player = MusicPlayer::new(first, output);
+    let settings = MpdSettings {
+        address: "127.0.0.1:0".to_string(),
+        ..MpdSettings::default()
+    };
+    let mut server = MpdServer::bind(&settings, Some("secret".to_string())).unwrap();
+    let address = server.local_addr().unwrap();
+
+    let music_dir = music.to_string_lossy().into_owned();
+    // The clients on a thread of their own, while this one runs the server loop
+    let client = std::thread::spawn(move || {
+        let connect = || {
+            let stream = TcpStream::connect(address).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut greeting = String::new();
+            reader.read_line(&mut greeting).unwrap();
+            assert_eq!(greeting, format!("OK MPD {}\n", PROTOCOL_VERSION));
+            (stream, reader)
+        };
+        let read_line = |reader: &mut BufReader<TcpStream>| {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line.trim_end_matches('\n').to_string()
+        };
+
+        for transcript in TRANSCRIPTS {
+            let (mut stream, mut reader) = connect();
+            for line in transcript.replace("{music}", &music_dir).lines() {
+                if let Some(command) = line.strip_prefix("C: ") {
+                    writeln!(stream, "{}", command).unwrap();
+                } else if let Some(expected) = line.strip_prefix("S: ") {
+                    let received = read_line(&mut reader);
+                    match expected.strip_suffix('*') {
+                        Some(prefix) => assert!(received.starts_with(prefix), "{}", received),
+                        None => assert_eq!(received, expected),
+                    }
+                }
+            }
+        }
+
+        // One client waits for changes while another makes them
+        let (mut idle, mut idle_reader) = connect();
+        let (mut other, mut other_reader) = connect();
+        for stream in [&mut idle, &mut other] {
+            writeln!(stream, "password secret").unwrap();
+        }
+        assert_eq!(read_line(&mut idle_reader), "OK");
+        assert_eq!(read_line(&mut other_reader), "OK");
+
+        writeln!(idle, "idle mixer").unwrap();
+        other.write_all(b"play\nsetvol 80\n").unwrap();
+        assert_eq!(read_line(&mut other_reader), "OK");
+        assert_eq!(read_line(&mut other_reader), "OK");
+        assert_eq!(read_line(&mut idle_reader), "changed: mixer");
+        assert_eq!(read_line(&mut idle_reader), "OK");
+        // The change to the player happened while the client wasn't idle
+        writeln!(idle, "idle").unwrap();
+        assert_eq!(read_line(&mut idle_reader), "changed: player");
+        assert_eq!(read_line(&mut idle_reader), "OK");
+        idle.write_all(b"idle playlist\nnoidle\n").unwrap();
+        assert_eq!(read_line(&mut idle_reader), "OK");
+
+        writeln!(idle, "close").unwrap();
+        let mut rest = String::new();
+        idle_reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "");
+    });
+
+    while !client.is_finished() {
+        server.poll(&mut player, &dbo, std::slice::from_ref(&music));
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    let result = client.join();
+    std::fs::remove_dir_all(&music).unwrap();
+    result.unwrap();
+}
@@ -0,0 +1,544 @@
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::{
+    PropertiesPropertiesChanged, RequestNameReply,
+};
+use dbus::blocking::Connection;
+use dbus::channel::Channel;
+use dbus::message::{MessageType, SignalArgs};
+use dbus::MethodErr;
+use dbus_crossroads::{Crossroads, IfaceBuilder};
+use log::{info, warn};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::time::Duration;
+
+use crate::message_types::{ItemTag, SkipDirection};
+use crate::music_player::MusicPlayer;
+use crate::speed::{SpeedSettings, MAX_SPEED, MIN_SPEED};
+
+/// The bus name desktops look for media players under
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.sousa";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const ROOT_INTERFACE: &str = "org.mpris.MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+/// The characters escaped in the `file://` URL of a track
+const URL_ESCAPES: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'?');
+
+/// What a method call or property change asks of the player
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MprisCommand {
+    Play,
+    Pause,
+    PlayPause,
+    Next,
+    Previous,
+    SetVolume(f64),
+    SetRate(f64),
+}
+
+/// The player as it was at the last poll, which the properties are read from
+#[derive(Clone)]
+struct PlayerSnapshot {
+    track: ItemTag,
+    queue_position: usize,
+    paused: bool,
+    length: Duration,
+    position: Duration,
+    volume: f64,
+    rate: f64,
+    can_go_next: bool,
+    can_go_previous: bool,
+}
+
+impl PlayerSnapshot {
+    fn of(player: &MusicPlayer) -> Self {
+        let (queue, queue_position) = player.get_queue();
+        PlayerSnapshot {
+            track: player.get_currently_playing().clone(),
+            queue_position,
+            paused: player.is_paused(),
+            length: player.get_track_length(),
+            position: player.get_played_time(),
+            volume: player.volume() as f64,
+            rate: player.speed_settings().speed as f64,
+            can_go_next: queue_position + 1 < queue.len(),
+            can_go_previous: queue_position > 0,
+        }
+    }
+
+    fn playback_status(&self) -> String {
+        match self.paused {
+            true => "Paused".to_string(),
+            false => "Playing".to_string(),
+        }
+    }
+
+    fn metadata(&self) -> PropMap {
+        let mut metadata = PropMap::new();
+        let mut insert = |key: &str, value: Box<dyn RefArg>| {
+            metadata.insert(key.to_string(), Variant(value));
+        };
+        let track_id = format!("/org/sousa/queue/{}", self.queue_position);
+        insert("mpris:trackid", Box::new(dbus::Path::from(track_id)));
+        if !self.length.is_zero() {
+            insert("mpris:length", Box::new(self.length.as_micros() as i64));
+        }
+        let url = format!(
+            "file://{}",
+            utf8_percent_encode(&self.track.path, URL_ESCAPES)
+        );
+        insert("xesam:url", Box::new(url));
+
+        let track = &self.track;
+        for (key, value) in [("xesam:title", &track.title), ("xesam:album", &track.album)] {
+            if !value.is_empty() {
+                insert(key, Box::new(value.clone()));
+            }
+        }
+        for (key, value) in [
+            ("xesam:artist", &track.artist),
+            ("xesam:albumArtist", &track.album_artist),
+            ("xesam:genre", &track.genre),
+        ] {
+            if !value.is_empty() {
+                insert(key, Box::new(vec![value.clone()]));
+            }
+        }
+        if let Some(rating) = track.rating {
+            insert("xesam:userRating", Box::new(rating as f64 / 5.0));
+        }
+        insert("xesam:useCount", Box::new(track.play_count as i32));
+        metadata
+    }
+}
+
+/// What the D-Bus object holds, the properties are read from it and the calls queued in it
+struct MprisObject {
+    snapshot: PlayerSnapshot,
+    commands: Vec<MprisCommand>,
+}
+
+/// Lets the desktop control the default zone over D-Bus, with the media keys, the
+/// GNOME and KDE media widgets or `playerctl`
+///
+/// This implements the `org.mpris.MediaPlayer2` and `org.mpris.MediaPlayer2.Player`
+/// interfaces of MPRIS2. The server loop reads and answers the bus in `poll`; tracks can't
+/// be seeked in and there is no track list.
+pub struct Mpris {
+    connection: Connection,
+    crossroads: Crossroads,
+}
+
+impl Mpris {
+    /// Connect to the session bus of the desktop
+    pub fn connect_session(player: &MusicPlayer) -> Result<Self, dbus::Error> {
+        Mpris::new(Connection::new_session()?, player)
+    }
+
+    /// Connect to the bus at `address`, like a private one started with `dbus-daemon`
+    pub fn connect_address(address: &str, player: &MusicPlayer) -> Result<Self, dbus::Error> {
+        let mut channel = Channel::open_private(address)?;
+        channel.register()?;
+        Mpris::new(Connection::from(channel), player)
+    }
+
+    fn new(connection: Connection, player: &MusicPlayer) -> Result<Self, dbus::Error> {
+        // A second instance takes a name of its own, as the specification asks
+        let name = match connection.request_name(BUS_NAME, false, false, true)? {
+            RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => BUS_NAME.to_string(),
+            _ => {
+                let name = format!("{}.instance{}", BUS_NAME, std::process::id());
+                connection.request_name(name.as_str(), false, false, true)?;
+                name
+            }
+        };
+        info!("MPRIS interface on the bus as: {}", name);
+
+        let mut crossroads = Crossroads::new();
+        let root = crossroads.register(ROOT_INTERFACE, register_root);
+        let player_interface = crossroads.register(PLAYER_INTERFACE, register_player);
+        let object = MprisObject {
+            snapshot: PlayerSnapshot::of(player),
+            commands: vec![],
+        };
+        crossroads.insert(OBJECT_PATH, &[root, player_interface], object);
+        Ok(Mpris {
+            connection,
+            crossroads,
+        })
+    }
+
+    /// Answer the calls waiting on the bus, carry out what they asked of the player and
+    /// announce what changed about it
+    pub fn poll(&mut self, player: &mut MusicPlayer) {
+        self.update(player);
+
+        let channel = self.connection.channel();
+        if channel.read_write(Some(Duration::ZERO)).is_err() {
+            warn!("The D-Bus connection for MPRIS was lost");
+            return;
+        }
+        while let Some(message) = channel.pop_message() {
+            if message.msg_type() == MessageType::MethodCall {
+                let _ = self.crossroads.handle_message(message, channel);
+            }
+        }
+
+        let commands = std::mem::take(&mut self.object().commands);
+        for command in commands {
+            let result = match command {
+                MprisCommand::Play => {
+                    player.play();
+                    Ok(())
+                }
+                MprisCommand::Pause => {
+                    player.pause();
+                    Ok(())
+                }
+                MprisCommand::PlayPause => {
+                    match player.is_paused() {
+                        true => player.play(),
+                        false => player.pause(),
+                    }
+                    Ok(())
+                }
+                MprisCommand::Next => player.skip(SkipDirection::Forward).map(|()| player.play()),
+                MprisCommand::Previous => {
+                    player.skip(SkipDirection::Backward).map(|()| player.play())
+                }
+                MprisCommand::SetVolume(volume) => player.set_volume(volume.clamp(0.0, 1.0) as f32),
+                MprisCommand::SetRate(rate) => player.set_speed(SpeedSettings {
+                    speed: rate as f32,
+                    ..player.speed_settings()
+                }),
+            };
+            if let Err(err) = result {
+                warn!("Could not carry out the MPRIS {:?}: {:?}", command, err);
+            }
+        }
+        self.update(player);
+    }
+
+    fn object(&mut self) -> &mut MprisObject {
+        self.crossroads
+            .data_mut(&dbus::Path::from(OBJECT_PATH))
+            .unwrap()
+    }
+
+    /// Take a new snapshot of the player, with a `PropertiesChanged` signal for what changed
+    fn update(&mut self, player: &MusicPlayer) {
+        let snapshot = PlayerSnapshot::of(player);
+        let previous = std::mem::replace(&mut self.object().snapshot, snapshot.clone());
+
+        let mut changed = PropMap::new();
+        let mut insert = |name: &str, value: Box<dyn RefArg>| {
+            changed.insert(name.to_string(), Variant(value));
+        };
+        // The position moves on all the time, clients work it out from the rate
+        if previous.paused != snapshot.paused {
+            insert("PlaybackStatus", Box::new(snapshot.playback_status()));
+        }
+        if previous.track.path != snapshot.track.path
+            || previous.queue_position != snapshot.queue_position
+            || previous.length != snapshot.length
+        {
+            insert("Metadata", Box::new(snapshot.metadata()));
+        }
+        if previous.volume != snapshot.volume {
+            insert("Volume", Box::new(snapshot.volume));
+        }
+        if previous.rate != snapshot.rate {
+            insert("Rate", Box::new(snapshot.rate));
+        }
+        if previous.can_go_next != snapshot.can_go_next {
+            insert("CanGoNext", Box::new(snapshot.can_go_next));
+        }
+        if previous.can_go_previous != snapshot.can_go_previous {
+            insert("CanGoPrevious", Box::new(snapshot.can_go_previous));
+        }
+        if changed.is_empty() {
+            return;
+        }
+
+        let signal = PropertiesPropertiesChanged {
+            interface_name: PLAYER_INTERFACE.to_string(),
+            changed_properties: changed,
+            invalidated_properties: vec![],
+        };
+        let message = signal.to_emit_message(&dbus::Path::from(OBJECT_PATH));
+        if self.connection.channel().send(message).is_err() {
+            warn!("Could not announce the MPRIS property changes");
+        }
+    }
+}
+
+fn register_root(builder: &mut IfaceBuilder<MprisObject>) {
+    builder.method("Raise", (), (), |_, _, ()| Ok(()));
+    builder.method("Quit", (), (), |_, _, ()| Ok(()));
+    builder
+        .property("Identity")
+        .get(|_, _| Ok("Sousa".to_string()))
+        .emits_changed_const();
+    builder
+        .property("CanQuit")
+        .get(|_, _| Ok(false))
+        .emits_changed_const();
+    builder
+        .property("CanRaise")
+        .get(|_, _| Ok(false))
+        .emits_changed_const();
+    builder
+        .property("HasTrackList")
+        .get(|_, _| Ok(false))
+        .emits_changed_const();
+    builder
+        .property("SupportedUriSchemes")
+        .get(|_, _| Ok(Vec::<String>::new()))
+        .emits_changed_const();
+    builder
+        .property("SupportedMimeTypes")
+        .get(|_, _| Ok(Vec::<String>::new()))
+        .emits_changed_const();
+}
+
+fn register_player(builder: &mut IfaceBuilder<MprisObject>) {
+    let queue = |command| {
+        move |_: &mut _, object: &mut MprisObject, (): ()| {
+            object.commands.push(command);
+            Ok(())
+        }
+    };
+    builder.method("Play", (), (), queue(MprisCommand::Play));
+    builder.method("Pause", (), (), queue(MprisCommand::Pause));
+    builder.method("PlayPause", (), (), queue(MprisCommand::PlayPause));
+    // There is no stopped state, the track stays where it was
+    builder.method("Stop", (), (), queue(MprisCommand::Pause));
+    builder.method("Next", (), (), queue(MprisCommand::Next));
+    builder.method("Previous", (), (), queue(MprisCommand::Previous));
+    // CanSeek is false, so these do nothing
+    builder.method("Seek", ("Offset",), (), |_, _, (_,): (i64,)| Ok(()));
+    builder.method(
+        "SetPosition",
+        ("TrackId", "Position"),
+        (),
+        |_, _, (_, _): (dbus::Path<'static>, i64)| Ok(()),
+    );
+    builder.method("OpenUri", ("Uri",), (), |_, _, (_,): (String,)| {
+        Err::<(), _>(MethodErr::from((
+            "org.freedesktop.DBus.Error.NotSupported",
+            "Opening URIs is not supported",
+        )))
+    });
+    builder.signal::<(i64,), _>("Seeked", ("Position",));
+
+    builder
+        .property("PlaybackStatus")
+        .get(|_, object| Ok(object.snapshot.playback_status()));
+    builder
+        .property("Metadata")
+        .get(|_, object| Ok(object.snapshot.metadata()));
+    builder
+        .property("Volume")
+        .get(|_, object| Ok(object.snapshot.volume))
+        .set(|_, object, volume: f64| {
+            object.commands.push(MprisCommand::SetVolume(volume));
+            // Announced once the player took it
+            Ok(None)
+        });
+    builder
+        .property("Rate")
+        .get(|_, object| Ok(object.snapshot.rate))
+        .set(|_, object, rate: f64| {
+            if !(MIN_SPEED as f64..=MAX_SPEED as f64).contains(&rate) {
+                return Err(MethodErr::invalid_arg(&rate));
+            }
+            object.commands.push(MprisCommand::SetRate(rate));
+            Ok(None)
+        });
+    builder
+        .property("Position")
+        .get(|_, object| Ok(object.snapshot.position.as_micros() as i64))
+        .emits_changed_false();
+    builder
+        .property("MinimumRate")
+        .get(|_, _| Ok(MIN_SPEED as f64))
+        .emits_changed_const();
+    builder
+        .property("MaximumRate")
+        .get(|_, _| Ok(MAX_SPEED as f64))
+        .emits_changed_const();
+    builder
+        .property("CanGoNext")
+        .get(|_, object| Ok(object.snapshot.can_go_next));
+    builder
+        .property("CanGoPrevious")
+        .get(|_, object| Ok(object.snapshot.can_go_previous));
+    for name in ["CanPlay", "CanPause", "CanControl"] {
+        builder
+            .property(name)
+            .get(|_, _| Ok(true))
+            .emits_changed_const();
+    }
+    builder
+        .property("CanSeek")
+        .get(|_, _| Ok(false))
+        .emits_changed_const();
+}
+
+#[test]
+fn test_mpris_private_bus() {
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Command, Stdio};
+    use std::sync::{Arc, Mutex};
+
+    // A session bus of its own, so the test doesn't show up on the desktop it runs on
+    let daemon = Command::new("dbus-daemon")
+        .args(["--session", "--nofork", "--print-address"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut daemon = match daemon {
+        Ok(daemon) => daemon,
+        // Only skipped when asked to, so a missing dbus-daemon can't pass unnoticed
+        Err(err) if std::env::var_os("SOUSA_SKIP_DBUS_TESTS").is_some() => {
+            eprintln!(
+                "Skipping the MPRIS test, dbus-daemon could not be started: {}",
+                err
+            );
+            return;
+        }
+        Err(err) => panic!(
+            "dbus-daemon could not be started, set SOUSA_SKIP_DBUS_TESTS to skip this test: {}",
+            err
+        ),
+    };
+    let mut address = String::new();
+    BufReader::new(daemon.stdout.take().unwrap())
+        .read_line(&mut address)
+        .unwrap();
+    let address = address.trim().to_string();
+
+    let directory = std::env::temp_dir().join(format!("sousa-mpris-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let tracks: Vec<ItemTag> = ["First light", "Second sight"]
+        .iter()
+        .enumerate()
+        .map(|(n, title)| {
+            let path = directory.join(format!("{} track.wav", n));
+            crate::output::write_test_wav(&path, 5.0);
+            ItemTag {
+                path: path.to_string_lossy().into_owned(),
+                title: title.to_string(),
+                artist: "The Testers".to_string(),
+                rating: Some(4),
+                ..ItemTag::default()
+            }
+        })
+        .collect();
+
+    let output = std::rc::Rc::new(crate::output::AudioOutput::null());
+    let mut player = MusicPlayer::new(tracks[0].clone(), output);
+    player.set_queue(tracks.clone()).unwrap();
+    player.pause();
+    let mut mpris = Mpris::connect_address(&address, &player).unwrap();
+
+    let client = std::thread::spawn(move || {
+        let mut channel = Channel::open_private(&address).unwrap();
+        channel.register().unwrap();
+        let connection = Connection::from(channel);
+        let proxy = connection.with_proxy(BUS_NAME, OBJECT_PATH, Duration::from_secs(5));
+
+        let announced = Arc::new(Mutex::new(Vec::<String>::new()));
+        let recorder = Arc::clone(&announced);
+        // Signals come from the unique name of the server, so they are matched on the path
+        let path = dbus::Path::from(OBJECT_PATH);
+        let rule = PropertiesPropertiesChanged::match_rule(None, Some(&path));
+        connection
+            .add_match(
+                rule.static_clone(),
+                move |signal: PropertiesPropertiesChanged, _: &Connection, _: &dbus::Message| {
+                    let names = signal.changed_properties.into_keys();
+                    recorder.lock().unwrap().extend(names);
+                    true
+                },
+            )
+            .unwrap();
+        // Property reads go through the server loop, so changes show up a poll later
+        fn wait_for<T: for<'a> dbus::arg::Get<'a> + 'static>(
+            proxy: &dbus::blocking::Proxy<&Connection>,
+            name: &str,
+            expected: impl Fn(&T) -> bool,
+        ) {
+            for _ in 0..200 {
+                if expected(&proxy.get::<T>(PLAYER_INTERFACE, name).unwrap()) {
+                    return;
+                }
+                proxy.connection.process(Duration::from_millis(10)).unwrap();
+            }
+            panic!("{} never changed", name);
+        }
+
+        let identity: String = proxy.get(ROOT_INTERFACE, "Identity").unwrap();
+        assert_eq!(identity, "Sousa");
+        let status: String = proxy.get(PLAYER_INTERFACE, "PlaybackStatus").unwrap();
+        assert_eq!(status, "Paused");
+        let metadata: PropMap = proxy.get(PLAYER_INTERFACE, "Metadata").unwrap();
+        assert_eq!(
+            dbus::arg::prop_cast::<String>(&metadata, "xesam:title").unwrap(),
+            "First light"
+        );
+        assert_eq!(
+            dbus::arg::prop_cast::<Vec<String>>(&metadata, "xesam:artist").unwrap(),
+            &vec!["The Testers".to_string()]
+        );
+        assert_eq!(
+            dbus::arg::prop_cast::<i64>(&metadata, "mpris:length"),
+            Some(&5_000_000)
+        );
+        assert!(dbus::arg::prop_cast::<String>(&metadata, "xesam:url")
+            .unwrap()
+            .ends_with("/0%20track.wav"));
+        let can_go_next: bool = proxy.get(PLAYER_INTERFACE, "CanGoNext").unwrap();
+        assert!(can_go_next);
+
+        let _: () = proxy
+            .method_call(PLAYER_INTERFACE, "PlayPause", ())
+            .unwrap();
+        wait_for(&proxy, "PlaybackStatus", |status: &String| {
+            status == "Playing"
+        });
+        let _: () = proxy.method_call(PLAYER_INTERFACE, "Next", ()).unwrap();
+        wait_for(&proxy, "Metadata", |metadata: &PropMap| {
+            dbus::arg::prop_cast::<String>(metadata, "xesam:title").unwrap() == "Second sight"
+        });
+        proxy.set(PLAYER_INTERFACE, "Volume", 0.25f64).unwrap();
+        wait_for(&proxy, "Volume", |volume: &f64| *volume == 0.25);
+        assert!(proxy.set(PLAYER_INTERFACE, "Rate", 10.0f64).is_err());
+        let _: () = proxy.method_call(PLAYER_INTERFACE, "Pause", ()).unwrap();
+        wait_for(&proxy, "PlaybackStatus", |status: &String| {
+            status == "Paused"
+        });
+
+        while connection.process(Duration::from_millis(100)).unwrap() {}
+        let announced = announced.lock().unwrap();
+        for name in ["PlaybackStatus", "Metadata", "Volume", "CanGoNext"] {
+            assert!(
+                announced.iter().any(|announced| announced == name),
+                "{}",
+                name
+            );
+        }
+    });
+
+    while !client.is_finished() {
+        mpris.poll(&mut player);
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    let result = client.join();
+    assert!(player.is_paused());
+    assert_eq!(player.volume(), 0.25);
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+    std::fs::remove_dir_all(&directory).unwrap();
+    result.unwrap();
+}
@@ -1,121 +1,1062 @@
 //use rodio::decoder::DecoderError;
-use rodio::{Decoder, OutputStreamHandle, Sink, Source};
+use rodio::{Decoder, Sink, Source};
+use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::BufReader;
-use std::time::{Duration, Instant};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use log::warn;
 
-use crate::message_types::ItemTag;
+use crate::equalizer::{Balance, Dsp, EqPresets, Equalizer, EqualizerSettings};
+use crate::fading::{CrossfadeSettings, CrossfadeTail, Faded, Fader, Gate};
+use crate::gapless::{read_gapless_info, EndSignal, TrackEnd, Trimmed};
+use crate::message_types::{ItemTag, SkipDirection};
+use crate::output::{AudioOutput, OutputError};
+use crate::replaygain::ReplayGainSettings;
+use crate::speed::{SpeedChanged, SpeedControl, SpeedSettings};
+
+/// How often the gain stage picks up changes to the ReplayGain factor
+const GAIN_UPDATE_PERIOD: Duration = Duration::from_millis(50);
 
 #[derive(Debug)]
 pub enum MusicPlayerError {
     DecoderError,
     IOError,
+    /// There is no track in the queue in the requested direction
+    QueueEnd,
+    /// There is no EQ preset by that name
+    NoSuchPreset,
+    /// The playback speed is outside of `MIN_SPEED` to `MAX_SPEED`
+    InvalidSpeed,
+    /// There is no audio output to play to
+    OutputError,
+    /// The volume is outside of 0 to 1
+    InvalidVolume,
+}
+
+/// Tracks shorter than this are never counted as played
+const MINIMUM_SCROBBLE_LENGTH: Duration = Duration::from_secs(30);
+/// A track counts as played after this long, even if it is less than half way through
+const SCROBBLE_PLAYED_TIME: Duration = Duration::from_secs(4 * 60);
+
+/// What the player was playing, saved every so often so it can carry on after a restart
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlayerState {
+    /// The paths of the tracks in the queue
+    pub queue: Vec<String>,
+    pub queue_position: usize,
+    /// How far into the current track, in milliseconds of media time
+    pub position_ms: u64,
+    #[serde(default)]
+    pub speed: SpeedSettings,
+    #[serde(default = "full_volume")]
+    pub volume: f32,
+    /// The name of the EQ preset in use
+    #[serde(default)]
+    pub eq_preset: Option<String>,
+}
+
+fn full_volume() -> f32 {
+    1.0
+}
+
+/// Look up the queue of a saved state, leaving out the tracks `lookup` can't find
+///
+/// Returns the queue, the position in it and where in that track to start. If the current
+/// track is gone the one after it starts from the beginning.
+fn resolve_queue(
+    state: &PlayerState,
+    lookup: impl Fn(&str) -> Option<ItemTag>,
+) -> Option<(Vec<ItemTag>, usize, Duration)> {
+    let mut items = Vec::<ItemTag>::new();
+    let mut current = None;
+    for (index, path) in state.queue.iter().enumerate() {
+        let item = match lookup(path) {
+            Some(item) => item,
+            None => {
+                warn!("Leaving '{}' out of the restored queue, it is gone", path);
+                continue;
+            }
+        };
+        if index >= state.queue_position && current.is_none() {
+            current = Some((items.len(), index == state.queue_position));
+        }
+        items.push(item);
+    }
+
+    let (position, same_track) = current.unwrap_or((items.len().checked_sub(1)?, false));
+    let start_at = match same_track {
+        true => Duration::from_millis(state.position_ms),
+        false => Duration::ZERO,
+    };
+    Some((items, position, start_at))
 }
 
-pub struct MusicPlayer<'a> {
-    output_stream_handle: &'a OutputStreamHandle,
+/// A finished listen of a track, ready to be saved to the play history
+#[derive(Debug, Clone)]
+pub struct PlayRecord {
+    pub path: String,
+    pub started_at: SystemTime,
+    pub played: Duration,
+    /// The track played through to its end
+    pub completed: bool,
+    pub track_length: Duration,
+}
+
+impl PlayRecord {
+    /// Whether the listen counts towards the play count
+    ///
+    /// Follows the usual scrobbling rule: the track is longer than 30 seconds, and it was
+    /// played for half its length or for 4 minutes, whichever comes first.
+    pub fn counts_as_play(&self) -> bool {
+        if self.track_length.is_zero() {
+            // The decoder couldn't tell how long the track is
+            return self.completed || self.played >= SCROBBLE_PLAYED_TIME;
+        }
+
+        self.track_length >= MINIMUM_SCROBBLE_LENGTH
+            && (self.completed
+                || self.played >= self.track_length / 2
+                || self.played >= SCROBBLE_PLAYED_TIME)
+    }
+
+    /// Whether the track was moved away from before it counted as played
+    pub fn is_skip(&self) -> bool {
+        !self.completed && !self.counts_as_play()
+    }
+}
+
+pub struct MusicPlayer {
+    output: Rc<AudioOutput>,
     playing_sink: rodio::Sink,
+    /// The volume of every sink, from 0 to 1
+    volume: f32,
     currently_playing: ItemTag,
 
+    queue: Vec<ItemTag>,
+    queue_position: usize,
+
     current_track_length: Duration,
     started_playing: Instant,
+    /// How much of the track was played before `started_playing`, in media time
     paused_length: Duration,
+
+    /// When the current track was started, taken once its listen has been recorded
+    current_play_started: Option<SystemTime>,
+    play_records: Vec<PlayRecord>,
+
+    replaygain_settings: ReplayGainSettings,
+    crossfade_settings: CrossfadeSettings,
+    /// The EQ presets, shared with the players of the other zones
+    eq_presets: EqPresets,
+    /// The name of the EQ preset this player uses
+    eq_preset: Option<String>,
+    /// The EQ preset the sources of every track follow
+    dsp: Arc<Dsp>,
+    speed_settings: SpeedSettings,
+    speed: Arc<SpeedControl>,
+    /// The handles to the source of the current track
+    current: TrackControls,
+
+    /// The next track in the queue, already waiting to play behind the current one
+    preloaded: Option<PreloadedTrack>,
+    /// Set once preloading was tried for the current track, so failures aren't retried
+    preload_attempted: bool,
+
+    /// Sinks of tracks that are fading out, dropped once they are done or their time is up
+    fading_out: Vec<(Sink, Option<Instant>)>,
+    /// When a fade out for a pause ends and the sink is actually paused
+    pending_pause: Option<Instant>,
 }
 
-impl<'a> MusicPlayer<'a> {
-    pub fn new(starting_item: ItemTag, output_stream_handle: &'a OutputStreamHandle) -> Self {
-        let sink = Sink::try_new(&output_stream_handle).unwrap();
+/// The handles to a track's source while it plays on the audio thread
+struct TrackControls {
+    /// The ReplayGain factor, stored as `f32` bits
+    gain_factor: Arc<AtomicU32>,
+    fader: Arc<Fader>,
+    /// Set once the track has run out
+    end: TrackEnd,
+    /// How long the crossfade at the end of the track is, in milliseconds
+    tail_ms: Arc<AtomicU64>,
+    /// Set once the crossfade at the end of the track has started
+    tail_started: Arc<AtomicBool>,
+}
 
-        let file = BufReader::new(File::open(starting_item.path.clone()).unwrap());
+/// A track that was opened ahead of time, so it starts without a gap
+///
+/// For gapless playback it is appended to the sink of the current track. For a crossfade it
+/// has its own sink, which stays silent until the current track's tail starts.
+struct PreloadedTrack {
+    queue_position: usize,
+    item: ItemTag,
+    track_length: Duration,
+    controls: TrackControls,
+    sink: Option<Sink>,
+}
 
-        let source = Decoder::new(file).unwrap();
+/// A track opened for playback, along with the handles to control it while it plays
+struct OpenedTrack {
+    source: Box<dyn Source<Item = i16> + Send>,
+    track_length: Duration,
+    controls: TrackControls,
+}
 
-        println!("{:?}", source.total_duration());
+/// Decode a file, trim its encoder delay and padding, and add the gain, speed, fade and EQ
+/// stages
+///
+/// Playback starts `start_at` into the track. With a `gate` the track plays silence until it
+/// is set.
+fn open_track(
+    item: &ItemTag,
+    start_at: Duration,
+    gain: f32,
+    volume: f32,
+    gate: Option<&Arc<AtomicBool>>,
+    dsp: &Arc<Dsp>,
+    speed: &Arc<SpeedControl>,
+) -> Result<OpenedTrack, MusicPlayerError> {
+    let file = File::open(&item.path).map_err(|_| MusicPlayerError::IOError)?;
+    let source =
+        Decoder::new(BufReader::new(file)).map_err(|_| MusicPlayerError::DecoderError)?;
+    let track_length = source.total_duration().unwrap_or(Duration::from_millis(0));
 
-        let tmp_length;
-        match source.total_duration() {
-            None => tmp_length = Duration::from_millis(0),
-            Some(length) => tmp_length = length,
+    let controls = TrackControls {
+        gain_factor: Arc::new(AtomicU32::new(gain.to_bits())),
+        fader: Fader::new(volume),
+        end: Arc::new(Mutex::new(None)),
+        tail_ms: Arc::new(AtomicU64::new(0)),
+        tail_started: Arc::new(AtomicBool::new(false)),
+    };
+
+    let source: Box<dyn Source<Item = i16> + Send> =
+        match read_gapless_info(std::path::Path::new(&item.path)) {
+            Some(gapless_info) => Box::new(Trimmed::new(source, gapless_info)),
+            None => Box::new(source),
         };
+    let source = with_gain_stage(source.skip_duration(start_at), &controls.gain_factor);
+    // Ahead of the tail, so the crossfade length is in wall time
+    let source = SpeedChanged::new(source, speed);
+    let source = Faded::new(
+        CrossfadeTail::new(source, &controls.tail_ms, &controls.tail_started),
+        &controls.fader,
+    );
+    let source = Balance::new(Equalizer::new(source, dsp), dsp);
+    let source: Box<dyn Source<Item = i16> + Send> = match gate {
+        Some(gate) => Box::new(Gate::new(source, gate)),
+        None => Box::new(source),
+    };
+
+    Ok(OpenedTrack {
+        source: Box::new(EndSignal::new(source, &controls.end)),
+        track_length,
+        controls,
+    })
+}
 
-        sink.append(source);
+impl MusicPlayer {
+    pub fn new(starting_item: ItemTag, output: Rc<AudioOutput>) -> Self {
+        let sink = output.new_sink().unwrap();
+        // Paused straight away, so the start of the track isn't heard
+        sink.pause();
+
+        let dsp = Dsp::new(None);
+        let speed = SpeedControl::new(SpeedSettings::default());
+        let track = open_track(&starting_item, Duration::ZERO, 1.0, 1.0, None, &dsp, &speed).unwrap();
+        sink.append(track.source);
 
         let mut mp = MusicPlayer {
-            output_stream_handle,
+            output,
             playing_sink: sink,
-            currently_playing: starting_item,
+            volume: 1.0,
+            currently_playing: starting_item.clone(),
+
+            queue: vec![starting_item],
+            queue_position: 0,
 
-            current_track_length: Duration::from_millis(0),
+            current_track_length: track.track_length,
             started_playing: Instant::now(),
             paused_length: Duration::from_millis(0),
-        };
 
+            current_play_started: Some(SystemTime::now()),
+            play_records: vec![],
 
-        mp.current_track_length = tmp_length;
+            replaygain_settings: ReplayGainSettings::default(),
+            crossfade_settings: CrossfadeSettings::default(),
+            eq_presets: EqPresets::default(),
+            eq_preset: None,
+            dsp,
+            speed_settings: SpeedSettings::default(),
+            speed,
+            current: track.controls,
+
+            preloaded: None,
+            preload_attempted: false,
+
+            fading_out: vec![],
+            pending_pause: None,
+        };
 
-        mp.started_playing = Instant::now();
-        mp.pause();
+        mp.update_gain();
         return mp;
     }
 
     /// Check if `MediaPlayer` is paused
+    ///
+    /// The player counts as paused as soon as the fade out before a pause starts.
     pub fn is_paused(&self) -> bool {
-        return self.playing_sink.is_paused();
+        self.pending_pause.is_some() || self.playing_sink.is_paused()
     }
 
     /// Pause the playback of what is currently playing
     pub fn pause(&mut self) {
-        self.paused_length = self.started_playing.elapsed();
-        self.playing_sink.pause();
+        self.fade_out_and_pause(Duration::from_millis(self.crossfade_settings.pause_fade_ms));
+    }
+
+    /// Pause once the playback has faded out over `fade`
+    pub fn fade_out_and_pause(&mut self, fade: Duration) {
+        if self.is_paused() {
+            return;
+        }
+        self.paused_length += self.played_since_started();
+
+        if fade.is_zero() {
+            self.pause_sinks();
+        } else {
+            self.fade_current_to(0.0, fade);
+            self.pending_pause = Some(Instant::now() + fade);
+        }
     }
 
     /// Resume playing what is in the `MediaPlayer`
     pub fn play(&mut self) {
-        self.playing_sink.play();
+        // Nothing is queued after `clear_queue`
+        if !self.is_paused() || self.queue.is_empty() {
+            return;
+        }
+
+        // Resuming during the fade out just fades back in
+        if self.pending_pause.take().is_none() {
+            self.playing_sink.play();
+            if let Some(sink) = self.preloaded.as_ref().and_then(|next| next.sink.as_ref()) {
+                sink.play();
+            }
+        }
+        self.fade_current_to(
+            1.0,
+            Duration::from_millis(self.crossfade_settings.pause_fade_ms),
+        );
         self.started_playing = Instant::now();
         println!("playing");
     }
 
+    /// Fade the current track, and the next one if it shares its sink
+    fn fade_current_to(&self, volume: f32, fade: Duration) {
+        self.current.fader.fade_to(volume, fade);
+        if let Some(next) = self.preloaded.as_ref().filter(|next| next.sink.is_none()) {
+            next.controls.fader.fade_to(volume, fade);
+        }
+    }
+
+    fn pause_sinks(&mut self) {
+        self.playing_sink.pause();
+        if let Some(sink) = self.preloaded.as_ref().and_then(|next| next.sink.as_ref()) {
+            sink.pause();
+        }
+        // Whatever was fading out has no business coming back on resume
+        self.fading_out.clear();
+    }
+
+    /// Finish fades that are done: pause once the fade out is over, drop faded out tracks
+    fn update_fades(&mut self) {
+        if let Some(pause_at) = self.pending_pause {
+            if Instant::now() >= pause_at {
+                self.pending_pause = None;
+                self.pause_sinks();
+            }
+        }
+
+        let now = Instant::now();
+        self.fading_out
+            .retain(|(sink, until)| !sink.empty() && until.is_none_or(|until| now < until));
+    }
+
     // TODO: set these to return results
     pub fn change_now_playing(&mut self, item: ItemTag) -> Result<(), MusicPlayerError> {
+        self.switch_to(item, Duration::from_millis(self.crossfade_settings.skip_fade_ms))
+    }
+
+    /// Start playing `item` on a new sink, fading between the tracks for `fade`
+    fn switch_to(&mut self, item: ItemTag, fade: Duration) -> Result<(), MusicPlayerError> {
         println!("\n\n switching now playing to: {}", item.path.clone());
-        let file = File::open(item.path.clone());
+        let fade_in = !fade.is_zero() && !self.is_paused();
+        let volume = if fade_in { 0.0 } else { 1.0 };
+        let track = open_track(
+            &item,
+            Duration::ZERO,
+            self.gain_for(self.queue_position, &item),
+            volume,
+            None,
+            &self.dsp,
+            &self.speed,
+        )?;
+
+        self.record_play(false);
+
+        let new_sink = self.new_sink().unwrap();
+        let old_sink = std::mem::replace(&mut self.playing_sink, new_sink);
+        if fade_in && !old_sink.empty() {
+            self.current.fader.fade_to(0.0, fade);
+            self.fading_out.push((old_sink, Some(Instant::now() + fade)));
+        } else {
+            old_sink.stop();
+        }
+        self.pending_pause = None;
+
+        self.playing_sink.append(track.source);
+        if fade_in {
+            track.controls.fader.fade_to(1.0, fade);
+        }
+
+        self.current_track_length = track.track_length;
+        self.current = track.controls;
+        self.preloaded = None;
+        self.preload_attempted = false;
+
+        self.started_playing = Instant::now();
+        self.paused_length = Duration::from_millis(0);
+        self.currently_playing = item;
+        self.current_play_started = Some(SystemTime::now());
+        Ok(())
+    }
+
+    /// Take up the queue and position of a saved state, paused
+    ///
+    /// Tracks `lookup` can't find are left out of the queue.
+    pub fn restore_state(
+        &mut self,
+        state: &PlayerState,
+        lookup: impl Fn(&str) -> Option<ItemTag>,
+    ) -> Result<(), MusicPlayerError> {
+        if let Err(err) = self.set_speed(state.speed) {
+            warn!("Not restoring the playback speed: {:?}", err);
+        }
+        if let Err(err) = self.set_volume(state.volume) {
+            warn!("Not restoring the volume: {:?}", err);
+        }
+        if let Err(err) = self.select_eq_preset(state.eq_preset.clone()) {
+            warn!("Not restoring the EQ preset: {:?}", err);
+        }
+        let (items, position, start_at) =
+            resolve_queue(state, lookup).ok_or(MusicPlayerError::QueueEnd)?;
 
-        if file.is_err() {
-            return Err(MusicPlayerError::IOError);
+        self.record_play(false);
+        let (old_queue, old_position) = (
+            std::mem::replace(&mut self.queue, items),
+            std::mem::replace(&mut self.queue_position, position),
+        );
+        let old_item =
+            std::mem::replace(&mut self.currently_playing, self.queue[position].clone());
+
+        if let Err(err) = self.reopen_current(start_at, true) {
+            self.queue = old_queue;
+            self.queue_position = old_position;
+            self.currently_playing = old_item;
+            return Err(err);
         }
+        self.current_play_started = Some(SystemTime::now());
+        Ok(())
+    }
 
-        let reader = BufReader::new(file.unwrap());
+    /// Carry on playing the current track on the output, after it was switched to another
+    /// device
+    pub fn move_to_new_output(&mut self) -> Result<(), MusicPlayerError> {
+        self.reopen_current(self.get_played_time(), self.is_paused())
+    }
 
-        let source = Decoder::new(reader);
+    /// Open the current track again on a new sink, `start_at` into it
+    fn reopen_current(&mut self, start_at: Duration, paused: bool) -> Result<(), MusicPlayerError> {
+        let item = self.currently_playing.clone();
+        let track = open_track(
+            &item,
+            start_at,
+            self.gain_for(self.queue_position, &item),
+            1.0,
+            None,
+            &self.dsp,
+            &self.speed,
+        )?;
 
-        match source {
-            Err(_err) => return Err(MusicPlayerError::DecoderError),
-            Ok(src) => {
-                match src.total_duration() {
-                    None => self.current_track_length = Duration::from_millis(0),
-                    Some(length) => self.current_track_length = length,
-                };
+        let sink = self.new_sink().map_err(|_| MusicPlayerError::OutputError)?;
+        // Paused before anything is added, so none of it is heard
+        if paused {
+            sink.pause();
+        }
+        sink.append(track.source);
+        std::mem::replace(&mut self.playing_sink, sink).stop();
+        self.fading_out.clear();
+        self.pending_pause = None;
 
-                self.playing_sink.stop();
-                self.playing_sink = Sink::try_new(self.output_stream_handle).unwrap();
-                self.playing_sink.append(src);
+        self.current_track_length = track.track_length;
+        self.current = track.controls;
+        self.preloaded = None;
+        self.preload_attempted = false;
+
+        self.started_playing = Instant::now();
+        self.paused_length = start_at;
+        self.update_gain();
+        Ok(())
+    }
 
-                self.started_playing = Instant::now();
-                return Ok(())
+    /// What to save so `restore_state` can carry on from here
+    pub fn state(&self) -> PlayerState {
+        PlayerState {
+            queue: self.queue.iter().map(|item| item.path.clone()).collect(),
+            queue_position: self.queue_position,
+            position_ms: self.get_played_time().as_millis() as u64,
+            speed: self.speed_settings,
+            volume: self.volume,
+            eq_preset: self.eq_preset.clone(),
+        }
+    }
+
+    /// Replace the play queue and switch to its first track
+    ///
+    /// Tracks that can't be opened are skipped; if none of them can, the queue is left as it was.
+    pub fn set_queue(&mut self, items: Vec<ItemTag>) -> Result<(), MusicPlayerError> {
+        let fade = Duration::from_millis(self.crossfade_settings.skip_fade_ms);
+        let mut last_error = MusicPlayerError::QueueEnd;
+        for (position, item) in items.iter().enumerate() {
+            match self.switch_to(item.clone(), fade) {
+                Ok(()) => {
+                    self.queue = items;
+                    self.queue_position = position;
+                    self.update_gain();
+                    return Ok(());
+                }
+                Err(err) => {
+                    warn!("Skipping unplayable queue entry '{}': {:?}", item.path, err);
+                    last_error = err;
+                }
             }
         }
+        Err(last_error)
+    }
+
+    /// Move to the next or previous track in the queue
+    ///
+    /// Tracks that can't be opened are skipped over.
+    pub fn skip(&mut self, direction: SkipDirection) -> Result<(), MusicPlayerError> {
+        self.skip_with_fade(
+            direction,
+            Duration::from_millis(self.crossfade_settings.skip_fade_ms),
+        )
+    }
+
+    /// Jump to the track at `position` in the queue
+    pub fn skip_to(&mut self, position: usize) -> Result<(), MusicPlayerError> {
+        let item = self
+            .queue
+            .get(position)
+            .cloned()
+            .ok_or(MusicPlayerError::QueueEnd)?;
+        self.switch_to(item, Duration::from_millis(self.crossfade_settings.skip_fade_ms))?;
+        self.queue_position = position;
+        self.update_gain();
+        Ok(())
+    }
+
+    /// Add tracks to the end of the queue, the current track keeps playing
+    ///
+    /// Added to an empty queue, the first of them becomes the current track, paused.
+    pub fn append_to_queue(&mut self, items: Vec<ItemTag>) {
+        if self.queue.is_empty() {
+            match self.set_queue(items) {
+                Ok(()) => self.fade_out_and_pause(Duration::ZERO),
+                Err(err) => warn!("Could not queue up the tracks: {:?}", err),
+            }
+            return;
+        }
+        self.queue.extend(items);
+        // The current track may have been the last one when the next was to be preloaded
+        if self.preloaded.is_none() {
+            self.preload_attempted = false;
+        }
+        self.update_gain();
+    }
+
+    /// Empty the queue, the current track is paused and stays loaded until tracks are added
+    pub fn clear_queue(&mut self) {
+        self.pause();
+        self.record_play(false);
+        self.queue.clear();
+        self.queue_position = 0;
+        self.preloaded = None;
+    }
+
+    fn skip_with_fade(
+        &mut self,
+        direction: SkipDirection,
+        fade: Duration,
+    ) -> Result<(), MusicPlayerError> {
+        let mut position = self.queue_position;
+        loop {
+            position = match direction {
+                SkipDirection::Forward if position + 1 < self.queue.len() => position + 1,
+                SkipDirection::Backward if position > 0 => position - 1,
+                _ => return Err(MusicPlayerError::QueueEnd),
+            };
+
+            match self.switch_to(self.queue[position].clone(), fade) {
+                Ok(()) => {
+                    self.queue_position = position;
+                    self.update_gain();
+                    return Ok(());
+                }
+                Err(err) => warn!(
+                    "Skipping unplayable queue entry '{}': {:?}",
+                    self.queue[position].path, err
+                ),
+            }
+        }
+    }
+
+    /// Start the next track in the queue once the current one has finished playing
+    ///
+    /// When the next track was preloaded it is already playing, and only the bookkeeping
+    /// switches over: at the moment the previous track ran out for gapless playback, or
+    /// when its tail started for a crossfade.
+    /// Returns true if the player moved on to a new track
+    pub fn advance_if_finished(&mut self) -> bool {
+        self.update_fades();
+        if self.is_paused() {
+            return false;
+        }
+
+        let switch_at = match &self.preloaded {
+            Some(PreloadedTrack { sink: None, .. }) => *self.current.end.lock().unwrap(),
+            Some(PreloadedTrack { sink: Some(_), .. }) => self
+                .current
+                .tail_started
+                .load(Ordering::Acquire)
+                .then(Instant::now),
+            None => None,
+        };
+        if let Some(switch_at) = switch_at {
+            self.record_play(true);
+            let next = self.preloaded.take().unwrap();
+
+            if let Some(sink) = next.sink {
+                // The outgoing track plays out its tail on the old sink
+                let old_sink = std::mem::replace(&mut self.playing_sink, sink);
+                self.fading_out.push((old_sink, None));
+            }
+            self.currently_playing = next.item;
+            self.queue_position = next.queue_position;
+            self.current_track_length = next.track_length;
+            self.current = next.controls;
+            self.preload_attempted = false;
+
+            self.started_playing = switch_at;
+            self.paused_length = Duration::from_millis(0);
+            self.current_play_started = Some(SystemTime::now() - switch_at.elapsed());
+            return true;
+        }
+
+        if !self.playing_sink.empty() {
+            self.preload_next();
+            return false;
+        }
+
+        self.record_play(true);
+        match self.skip_with_fade(SkipDirection::Forward, Duration::ZERO) {
+            Ok(()) => true,
+            Err(_) => {
+                self.pause();
+                false
+            }
+        }
+    }
+
+    /// Open the next track in the queue ahead of time, so it starts without a gap
+    ///
+    /// Tracks from the same album as the current one are appended to its sink to play
+    /// gapless; others crossfade if that is turned on. If the next track can't be opened
+    /// it is left to `skip` to step over it once the current track ends.
+    fn preload_next(&mut self) {
+        if self.preload_attempted {
+            return;
+        }
+        self.preload_attempted = true;
+
+        let position = self.queue_position + 1;
+        let item = match self.queue.get(position) {
+            Some(item) => item.clone(),
+            None => return,
+        };
+
+        let album_sequence =
+            !item.album.is_empty() && item.album == self.currently_playing.album;
+        let crossfade_ms = match album_sequence {
+            true => 0,
+            false => self.crossfade_settings.crossfade_ms,
+        };
+        let gain = self.gain_for(position, &item);
+
+        let track = if crossfade_ms == 0 {
+            open_track(&item, Duration::ZERO, gain, 1.0, None, &self.dsp, &self.speed)
+        } else {
+            open_track(
+                &item,
+                Duration::ZERO,
+                gain,
+                0.0,
+                Some(&self.current.tail_started),
+                &self.dsp,
+                &self.speed,
+            )
+        };
+        let track = match track {
+            Ok(track) => track,
+            Err(err) => {
+                warn!("Could not preload '{}': {:?}", item.path, err);
+                return;
+            }
+        };
+
+        let sink = if crossfade_ms == 0 {
+            self.playing_sink.append(track.source);
+            None
+        } else {
+            let sink = self.new_sink().unwrap();
+            sink.append(track.source);
+            track
+                .controls
+                .fader
+                .fade_to(1.0, Duration::from_millis(crossfade_ms));
+            Some(sink)
+        };
+        self.current.tail_ms.store(crossfade_ms, Ordering::Relaxed);
+
+        self.preloaded = Some(PreloadedTrack {
+            queue_position: position,
+            item,
+            track_length: track.track_length,
+            controls: track.controls,
+            sink,
+        });
+    }
+
+    /// Change how ReplayGain is applied, takes effect on the playing track right away
+    pub fn set_replaygain_settings(&mut self, settings: ReplayGainSettings) {
+        self.replaygain_settings = settings;
+        self.update_gain();
+    }
+
+    /// Change the volume of the player, takes effect right away
+    pub fn set_volume(&mut self, volume: f32) -> Result<(), MusicPlayerError> {
+        if !(0.0..=1.0).contains(&volume) {
+            return Err(MusicPlayerError::InvalidVolume);
+        }
+        self.volume = volume;
+        self.playing_sink.set_volume(volume);
+        if let Some(sink) = self.preloaded.as_ref().and_then(|preloaded| preloaded.sink.as_ref()) {
+            sink.set_volume(volume);
+        }
+        for (sink, _) in &self.fading_out {
+            sink.set_volume(volume);
+        }
+        Ok(())
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    /// A new sink on the output, at the volume of the player
+    fn new_sink(&self) -> Result<Sink, OutputError> {
+        let sink = self.output.new_sink()?;
+        sink.set_volume(self.volume);
+        Ok(sink)
+    }
+
+    /// Change the fade lengths; a new crossfade length applies from the next track on
+    pub fn set_crossfade_settings(&mut self, settings: CrossfadeSettings) {
+        self.crossfade_settings = settings;
+    }
+
+    /// Change the playback speed, takes effect on the playing track right away
+    pub fn set_speed(&mut self, settings: SpeedSettings) -> Result<(), MusicPlayerError> {
+        if !settings.is_valid() {
+            return Err(MusicPlayerError::InvalidSpeed);
+        }
+        // The time played so far was at the old speed
+        if !self.is_paused() {
+            self.paused_length += self.played_since_started();
+            self.started_playing = Instant::now();
+        }
+        self.speed_settings = settings;
+        self.speed.set(settings);
+        Ok(())
+    }
+
+    pub fn speed_settings(&self) -> SpeedSettings {
+        self.speed_settings
+    }
+
+    /// How much of the track played since `started_playing`, in media time
+    fn played_since_started(&self) -> Duration {
+        self.started_playing
+            .elapsed()
+            .mul_f32(self.speed_settings.speed)
+    }
+
+    /// Pick from the EQ presets shared between the zones, starting out with `active_preset`
+    pub fn set_equalizer(&mut self, presets: EqPresets, active_preset: Option<String>) {
+        self.eq_presets = presets;
+        self.eq_preset = active_preset;
+        self.refresh_eq_preset();
+    }
+
+    pub fn eq_presets(&self) -> &EqPresets {
+        &self.eq_presets
+    }
+
+    /// The shared EQ presets along with the one this player uses
+    pub fn equalizer_settings(&self) -> EqualizerSettings {
+        EqualizerSettings {
+            active_preset: self.eq_preset.clone(),
+            presets: self.eq_presets.borrow().clone(),
+        }
+    }
+
+    /// Switch to an EQ preset by name while playing, or turn the EQ off with `None`
+    pub fn select_eq_preset(&mut self, name: Option<String>) -> Result<(), MusicPlayerError> {
+        if let Some(name) = &name {
+            if !self.eq_presets.borrow().iter().any(|preset| &preset.name == name) {
+                return Err(MusicPlayerError::NoSuchPreset);
+            }
+        }
+        self.eq_preset = name;
+        self.refresh_eq_preset();
+        Ok(())
+    }
+
+    /// Hear the changes to the shared EQ presets, the EQ is turned off if its preset is gone
+    pub fn refresh_eq_preset(&mut self) {
+        let preset = self.eq_preset.as_ref().and_then(|name| {
+            self.eq_presets
+                .borrow()
+                .iter()
+                .find(|preset| &preset.name == name)
+                .cloned()
+        });
+        if preset.is_none() {
+            self.eq_preset = None;
+        }
+        self.dsp.set_preset(preset);
+    }
+
+    /// Work out the ReplayGain factors for the current and the preloaded track
+    fn update_gain(&mut self) {
+        let factor = self.gain_for(self.queue_position, &self.currently_playing);
+        self.current
+            .gain_factor
+            .store(factor.to_bits(), Ordering::Relaxed);
+
+        if let Some(next) = &self.preloaded {
+            let factor = self.gain_for(next.queue_position, &next.item);
+            next.controls
+                .gain_factor
+                .store(factor.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// The ReplayGain factor for `item` at `position` in the queue
+    ///
+    /// The queue counts as playing an album when a neighbouring track is from the same album.
+    fn gain_for(&self, position: usize, item: &ItemTag) -> f32 {
+        let album = &item.album;
+        let in_album = !album.is_empty()
+            && [position.checked_sub(1), position.checked_add(1)]
+                .iter()
+                .flatten()
+                .filter_map(|neighbour| self.queue.get(*neighbour))
+                .any(|neighbour| neighbour.album == *album);
+
+        self.replaygain_settings
+            .gain_factor(&item.replaygain, in_album)
+    }
+
+    /// Finish the listen of the current track and queue it up for `take_play_records`
+    ///
+    /// Does nothing if the listen was already recorded, or if the track never played.
+    fn record_play(&mut self, completed: bool) {
+        let started_at = match self.current_play_started.take() {
+            Some(started_at) => started_at,
+            None => return,
+        };
+
+        let played = self.get_played_time();
+        if played.is_zero() && !completed {
+            return;
+        }
+
+        self.play_records.push(PlayRecord {
+            path: self.currently_playing.path.clone(),
+            started_at,
+            played,
+            completed,
+            track_length: self.current_track_length,
+        });
+    }
+
+    /// Take the listens that finished since the last call, oldest first
+    pub fn take_play_records(&mut self) -> Vec<PlayRecord> {
+        std::mem::take(&mut self.play_records)
+    }
+
+    /// Get the tag of the track that is currently playing
+    pub fn get_currently_playing(&self) -> &ItemTag {
+        &self.currently_playing
+    }
+
+    /// Get the play queue and the position of the current track in it
+    pub fn get_queue(&self) -> (&[ItemTag], usize) {
+        (&self.queue, self.queue_position)
     }
 
     /// Get the song's current position (time wise)
+    ///
+    /// This is the position in the track, which runs faster or slower than the clock when the
+    /// playback speed isn't 1.
     pub fn get_played_time(&self) -> Duration {
         if self.is_paused() {return self.paused_length;}
-        else {return self.started_playing.elapsed();}
+        else {return self.paused_length + self.played_since_started();}
+    }
+
+    /// How long until the current track ends at the current speed, `None` if its length is
+    /// unknown
+    pub fn remaining_time(&self) -> Option<Duration> {
+        if self.current_track_length.is_zero() {
+            return None;
+        }
+        let remaining = self
+            .current_track_length
+            .saturating_sub(self.get_played_time());
+        Some(remaining.div_f32(self.speed_settings.speed))
     }
 
-    /// Get the song's length
+    /// Get the song's length, as it would play at normal speed
     pub fn get_track_length(&self) -> Duration {
         return self.current_track_length;
     }
 }
+
+/// Amplify a source by the factor in `gain_factor`, following changes to it as it plays
+fn with_gain_stage<S>(source: S, gain_factor: &Arc<AtomicU32>) -> impl Source<Item = S::Item>
+where
+    S: Source,
+    S::Item: rodio::Sample,
+{
+    let gain_factor = Arc::clone(gain_factor);
+    let factor = f32::from_bits(gain_factor.load(Ordering::Relaxed));
+    source
+        .amplify(factor)
+        .periodic_access(GAIN_UPDATE_PERIOD, move |amplified| {
+            amplified.set_factor(f32::from_bits(gain_factor.load(Ordering::Relaxed)))
+        })
+}
+
+#[test]
+fn test_resolve_queue() {
+    let state = PlayerState {
+        queue: ["one", "gone", "two", "also gone", "three"]
+            .iter()
+            .map(|path| path.to_string())
+            .collect(),
+        queue_position: 2,
+        position_ms: 61_000,
+        speed: SpeedSettings::default(),
+        volume: 1.0,
+        eq_preset: None,
+    };
+    let lookup = |path: &str| {
+        (!path.contains("gone")).then(|| ItemTag {
+            path: path.to_string(),
+            ..ItemTag::default()
+        })
+    };
+    let paths = |items: &[ItemTag]| -> Vec<String> {
+        items.iter().map(|item| item.path.clone()).collect()
+    };
+
+    let (items, position, start_at) = resolve_queue(&state, lookup).unwrap();
+    assert_eq!(paths(&items), vec!["one", "two", "three"]);
+    assert_eq!((position, start_at), (1, Duration::from_secs(61)));
+
+    // The current track is gone, so the next one starts from the top
+    let (items, position, start_at) = resolve_queue(
+        &PlayerState {
+            queue_position: 3,
+            ..state.clone()
+        },
+        lookup,
+    )
+    .unwrap();
+    assert_eq!(items[position].path, "three");
+    assert_eq!(start_at, Duration::ZERO);
+
+    // Nothing after it, so the last track that is left
+    let ends_gone = PlayerState {
+        queue: vec!["one".to_string(), "gone".to_string()],
+        queue_position: 1,
+        ..state.clone()
+    };
+    let (items, position, _) = resolve_queue(&ends_gone, lookup).unwrap();
+    assert_eq!(items[position].path, "one");
+
+    let all_gone = PlayerState {
+        queue: vec!["gone".to_string()],
+        queue_position: 0,
+        ..state
+    };
+    assert!(resolve_queue(&all_gone, lookup).is_none());
+}
+
+#[test]
+fn test_headless_playback() {
+    let path = std::env::temp_dir().join(format!("sousa-player-{}.wav", std::process::id()));
+    crate::output::write_test_wav(&path, 0.5);
+
+    let item = ItemTag {
+        path: path.to_string_lossy().into_owned(),
+        ..ItemTag::default()
+    };
+    let mut player = MusicPlayer::new(item, Rc::new(AudioOutput::null()));
+    player.set_crossfade_settings(CrossfadeSettings {
+        pause_fade_ms: 0,
+        ..CrossfadeSettings::default()
+    });
+    assert!(player.is_paused());
+    assert_eq!(player.get_track_length(), Duration::from_millis(500));
+
+    player.play();
+    std::thread::sleep(Duration::from_millis(200));
+    let played = player.get_played_time();
+    assert!(played >= Duration::from_millis(200) && played < Duration::from_millis(400));
+
+    // The end of the only track in the queue pauses the player
+    let started = Instant::now();
+    while !player.is_paused() && started.elapsed() < Duration::from_secs(2) {
+        player.advance_if_finished();
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    let records = player.take_play_records();
+    std::fs::remove_file(&path).unwrap();
+    assert!(player.is_paused());
+    assert_eq!(records.len(), 1);
+    assert!(records[0].completed);
+}
@@ -0,0 +1,509 @@
+use derive_more::From;
+use log::{error, info};
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+use rodio::dynamic_mixer::{mixer, DynamicMixer, DynamicMixerController};
+use rodio::{OutputStream, Sink, Source};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// The format everything is mixed to before it is played
+pub const MIX_CHANNELS: u16 = 2;
+pub const MIX_SAMPLE_RATE: u32 = 44100;
+/// The mix is pulled in chunks this long, then the thread waits until they would have played
+const CHUNK_LENGTH: Duration = Duration::from_millis(10);
+/// How often the WAV header is brought up to date, so the file is playable while it's written
+const WAV_FLUSH_PERIOD: Duration = Duration::from_secs(1);
+
+/// Catch all Error for opening an audio output
+#[derive(From, Debug)]
+pub enum OutputError {
+    StreamError(rodio::StreamError),
+    PlayError(rodio::PlayError),
+    WavError(hound::Error),
+    DevicesError(rodio::cpal::DevicesError),
+    DeviceNameError(rodio::cpal::DeviceNameError),
+    /// No output device has that name or index
+    NoSuchDevice,
+}
+
+/// Where the audio goes, part of the configuration file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default, JsonSchema)]
+pub enum OutputBackend {
+    /// A sound device, the one picked by `output_device` or the default one
+    #[default]
+    Device,
+    /// Nowhere, the samples are thrown away at the pace they would play at
+    Null,
+    /// Recorded to a 16 bit WAV file, at the pace it would play at
+    Wav(PathBuf),
+}
+
+/// Picks a sound device by its position in `list_output_devices` or by its name
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(untagged)]
+pub enum DeviceSelector {
+    Index(usize),
+    Name(String),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = std::convert::Infallible;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        Ok(match selector.parse() {
+            Ok(index) => DeviceSelector::Index(index),
+            Err(_) => DeviceSelector::Name(selector.to_string()),
+        })
+    }
+}
+
+/// A sound device that can be played to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct OutputDevice {
+    pub index: usize,
+    pub name: String,
+    pub is_default: bool,
+}
+
+/// The sound devices of the default host, in the order `DeviceSelector::Index` counts them
+pub fn list_output_devices() -> Result<Vec<OutputDevice>, OutputError> {
+    let host = rodio::cpal::default_host();
+    let default_name = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+
+    let mut devices = vec![];
+    for (index, device) in host.output_devices()?.enumerate() {
+        let name = device.name()?;
+        devices.push(OutputDevice {
+            index,
+            is_default: Some(&name) == default_name.as_ref(),
+            name,
+        });
+    }
+    Ok(devices)
+}
+
+/// The sound devices as a `DeviceWatcher` last listed them
+struct DeviceListing {
+    /// `None` if the devices couldn't be listed
+    names: Option<Vec<String>>,
+    listed_at: Instant,
+}
+
+/// Lists the sound devices on a thread of its own every so often, so checking that a device
+/// is still there doesn't hold up the server loop
+///
+/// Listing the devices can take hundreds of milliseconds on ALSA and PulseAudio.
+pub struct DeviceWatcher {
+    listing: Arc<Mutex<DeviceListing>>,
+}
+
+impl DeviceWatcher {
+    /// List the devices every `period`, until the watcher is dropped
+    pub fn spawn(period: Duration) -> Self {
+        let listing = Arc::new(Mutex::new(DeviceListing {
+            names: None,
+            listed_at: Instant::now(),
+        }));
+        let shared = Arc::downgrade(&listing);
+        std::thread::spawn(move || loop {
+            let started = Instant::now();
+            let names = list_output_devices()
+                .ok()
+                .map(|devices| devices.into_iter().map(|device| device.name).collect());
+            match shared.upgrade() {
+                Some(listing) => {
+                    *listing.lock().unwrap() = DeviceListing {
+                        names,
+                        listed_at: started,
+                    }
+                }
+                None => return,
+            }
+            std::thread::sleep(period);
+        });
+        DeviceWatcher { listing }
+    }
+}
+
+fn find_device(selector: &DeviceSelector) -> Result<rodio::Device, OutputError> {
+    let mut devices = rodio::cpal::default_host().output_devices()?;
+    let device = match selector {
+        DeviceSelector::Index(index) => devices.nth(*index),
+        DeviceSelector::Name(name) => {
+            devices.find(|device| device.name().is_ok_and(|device_name| device_name == *name))
+        }
+    };
+    device.ok_or(OutputError::NoSuchDevice)
+}
+
+/// Gets a copy of the mix an output plays, in chunks of interleaved samples
+///
+/// Chunks are dropped rather than waited for if the receiver falls behind, so it can never
+/// hold up the playback.
+pub type Monitor = Arc<Mutex<Option<SyncSender<Vec<f32>>>>>;
+
+fn send_to_monitor(monitor: &Monitor, chunk: &[f32]) {
+    // Skipped rather than waited for while the monitor is being changed
+    if let Ok(monitor) = monitor.try_lock() {
+        if let Some(sender) = monitor.as_ref() {
+            let _ = sender.try_send(chunk.to_vec());
+        }
+    }
+}
+
+/// The audio output the sinks of a `MusicPlayer` play to
+///
+/// The output can be switched while sinks are playing to it; those sinks go quiet, and new
+/// ones play to the new output.
+pub struct AudioOutput {
+    backend: RefCell<Backend>,
+    monitor: Monitor,
+    opened_at: Cell<Instant>,
+}
+
+/// Every backend mixes its sinks itself, so the mix can be monitored
+enum Backend {
+    Device {
+        name: String,
+        controller: Arc<DynamicMixerController<f32>>,
+        // Playback stops when the stream is dropped
+        _stream: OutputStream,
+    },
+    /// Mixed on a thread of our own, that consumes the mix in real time
+    Mixed {
+        controller: Arc<DynamicMixerController<f32>>,
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    },
+}
+
+/// Plays the mix to a sound device, copying it to the monitor on the way
+///
+/// Silence is played while nothing is mixed, as the device would stop at the end of the
+/// source.
+struct MonitoredMix {
+    mix: DynamicMixer<f32>,
+    monitor: Monitor,
+    chunk: Vec<f32>,
+}
+
+impl Iterator for MonitoredMix {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.mix.next().unwrap_or(0.0);
+        self.chunk.push(sample);
+        if self.chunk.len() == self.chunk.capacity() {
+            send_to_monitor(&self.monitor, &self.chunk);
+            self.chunk.clear();
+        }
+        Some(sample)
+    }
+}
+
+impl Source for MonitoredMix {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        MIX_CHANNELS
+    }
+
+    fn sample_rate(&self) -> u32 {
+        MIX_SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// The number of samples in a chunk of `CHUNK_LENGTH`
+fn chunk_samples() -> usize {
+    (MIX_SAMPLE_RATE as u128 * CHUNK_LENGTH.as_millis() / 1000) as usize * MIX_CHANNELS as usize
+}
+
+impl Backend {
+    fn open(
+        backend: &OutputBackend,
+        device: Option<&DeviceSelector>,
+        monitor: &Monitor,
+    ) -> Result<Self, OutputError> {
+        match backend {
+            OutputBackend::Device => {
+                let device = match device {
+                    Some(selector) => find_device(selector)?,
+                    None => rodio::cpal::default_host()
+                        .default_output_device()
+                        .ok_or(OutputError::NoSuchDevice)?,
+                };
+                let name = device.name()?;
+                let (stream, handle) = OutputStream::try_from_device(&device)?;
+                let (controller, mix) = mixer::<f32>(MIX_CHANNELS, MIX_SAMPLE_RATE);
+                handle.play_raw(MonitoredMix {
+                    mix,
+                    monitor: Arc::clone(monitor),
+                    chunk: Vec::with_capacity(chunk_samples()),
+                })?;
+                info!("Playing to the sound device '{}'", name);
+                Ok(Backend::Device {
+                    name,
+                    controller,
+                    _stream: stream,
+                })
+            }
+            OutputBackend::Null => Ok(Self::mixed(|_: &[f32]| {}, monitor)),
+            OutputBackend::Wav(path) => {
+                let spec = hound::WavSpec {
+                    channels: MIX_CHANNELS,
+                    sample_rate: MIX_SAMPLE_RATE,
+                    bits_per_sample: 16,
+                    sample_format: hound::SampleFormat::Int,
+                };
+                let mut writer = hound::WavWriter::create(path, spec)?;
+                info!("Recording the audio output to {}", path.to_string_lossy());
+
+                let mut last_flush = Instant::now();
+                Ok(Self::mixed(move |chunk: &[f32]| {
+                    for sample in chunk {
+                        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                        if let Err(err) = writer.write_sample(sample) {
+                            error!("Could not write to the WAV output: {}", err);
+                        }
+                    }
+                    if last_flush.elapsed() >= WAV_FLUSH_PERIOD {
+                        last_flush = Instant::now();
+                        if let Err(err) = writer.flush() {
+                            error!("Could not write to the WAV output: {}", err);
+                        }
+                    }
+                }, monitor))
+            }
+        }
+    }
+
+    /// Mix the sinks on a thread that hands the mix to `consume` in real time
+    fn mixed<F>(mut consume: F, monitor: &Monitor) -> Self
+    where
+        F: FnMut(&[f32]) + Send + 'static,
+    {
+        let (controller, mut mix) = mixer::<f32>(MIX_CHANNELS, MIX_SAMPLE_RATE);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_stop = Arc::clone(&stop);
+        let monitor = Arc::clone(monitor);
+        let thread = std::thread::spawn(move || {
+            let mut chunk = vec![0.0; chunk_samples()];
+            let started = Instant::now();
+            let mut consumed = Duration::ZERO;
+
+            while !thread_stop.load(Ordering::Relaxed) {
+                fill_chunk(&mut mix, &mut chunk);
+                consume(&chunk);
+                send_to_monitor(&monitor, &chunk);
+                consumed += CHUNK_LENGTH;
+                // Keep to the pace of a sound card, so playback takes as long as it would
+                if let Some(ahead) = consumed.checked_sub(started.elapsed()) {
+                    std::thread::sleep(ahead);
+                }
+            }
+        });
+
+        Backend::Mixed {
+            controller,
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        if let Backend::Mixed { stop, thread, .. } = self {
+            stop.store(true, Ordering::Relaxed);
+            if let Some(thread) = thread.take() {
+                // Dropping the WAV writer on the thread finishes the file
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl AudioOutput {
+    /// Open an output, `device` picks the sound device for `OutputBackend::Device`
+    pub fn open(
+        backend: &OutputBackend,
+        device: Option<&DeviceSelector>,
+    ) -> Result<Self, OutputError> {
+        let monitor = Monitor::default();
+        Ok(AudioOutput {
+            backend: RefCell::new(Backend::open(backend, device, &monitor)?),
+            monitor,
+            opened_at: Cell::new(Instant::now()),
+        })
+    }
+
+    /// An output that plays to nowhere, for headless servers and tests
+    pub fn null() -> Self {
+        let monitor = Monitor::default();
+        AudioOutput {
+            backend: RefCell::new(Backend::mixed(|_: &[f32]| {}, &monitor)),
+            monitor,
+            opened_at: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Send a copy of the mix to `sender` from now on, or stop with `None`
+    ///
+    /// The monitor is kept when the output is switched.
+    pub fn set_monitor(&self, sender: Option<SyncSender<Vec<f32>>>) {
+        *self.monitor.lock().unwrap() = sender;
+    }
+
+    /// Play to another output from now on, the current one is kept if the new one can't be
+    /// opened
+    pub fn switch(
+        &self,
+        backend: &OutputBackend,
+        device: Option<&DeviceSelector>,
+    ) -> Result<(), OutputError> {
+        let backend = Backend::open(backend, device, &self.monitor)?;
+        // The old output is closed once the new one is in place
+        drop(self.backend.replace(backend));
+        self.opened_at.set(Instant::now());
+        Ok(())
+    }
+
+    /// The name of the sound device being played to, `None` for the null and WAV outputs
+    pub fn device_name(&self) -> Option<String> {
+        match &*self.backend.borrow() {
+            Backend::Device { name, .. } => Some(name.clone()),
+            Backend::Mixed { .. } => None,
+        }
+    }
+
+    /// Whether the sound device is still there, as far as `devices` last saw; if the
+    /// devices couldn't be listed it is assumed to be
+    pub fn is_available(&self, devices: &DeviceWatcher) -> bool {
+        let name = match self.device_name() {
+            Some(name) => name,
+            None => return true,
+        };
+        let listing = devices.listing.lock().unwrap();
+        // A device plugged in since the last listing isn't in it yet
+        if listing.listed_at < self.opened_at.get() {
+            return true;
+        }
+        match &listing.names {
+            Some(names) => names.contains(&name),
+            None => true,
+        }
+    }
+
+    /// A new sink that plays to this output
+    pub fn new_sink(&self) -> Result<Sink, OutputError> {
+        let (sink, source) = Sink::new_idle();
+        match &*self.backend.borrow() {
+            Backend::Device { controller, .. } | Backend::Mixed { controller, .. } => {
+                controller.add(source)
+            }
+        }
+        Ok(sink)
+    }
+}
+
+/// Pull the next chunk of the mix, silence while nothing is playing
+fn fill_chunk(mix: &mut DynamicMixer<f32>, chunk: &mut [f32]) {
+    for sample in chunk.iter_mut() {
+        *sample = mix.next().unwrap_or(0.0);
+    }
+}
+
+/// Write a WAV file of 16 bit samples, for tests that need an audio file on disk
+#[cfg(test)]
+pub fn write_wav(
+    path: &std::path::Path,
+    channels: u16,
+    sample_rate: u32,
+    samples: impl IntoIterator<Item = i16>,
+) {
+    let spec = hound::WavSpec {
+        channels,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec).unwrap();
+    for sample in samples {
+        writer.write_sample(sample).unwrap();
+    }
+    writer.finalize().unwrap();
+}
+
+/// Write a quiet mono 8 kHz tone `seconds` long, a track for the tests to play
+#[cfg(test)]
+pub fn write_test_wav(path: &std::path::Path, seconds: f32) {
+    let samples = (0..(8000.0 * seconds) as i32).map(|n| ((n % 80) * 100) as i16);
+    write_wav(path, 1, 8000, samples);
+}
+
+#[test]
+fn test_wav_output() {
+    let path = std::env::temp_dir().join(format!("sousa-output-{}.wav", std::process::id()));
+    let output = AudioOutput::open(&OutputBackend::Wav(path.clone()), None).unwrap();
+
+    let sink = output.new_sink().unwrap();
+    let tone: Vec<f32> = (0..MIX_SAMPLE_RATE / 5)
+        .flat_map(|n| {
+            let sample =
+                0.5 * (n as f32 * 440.0 * std::f32::consts::TAU / MIX_SAMPLE_RATE as f32).sin();
+            [sample, sample]
+        })
+        .collect();
+    let started = Instant::now();
+    sink.append(rodio::buffer::SamplesBuffer::new(
+        MIX_CHANNELS,
+        MIX_SAMPLE_RATE,
+        tone,
+    ));
+    sink.sleep_until_end();
+    // Played in real time, not as fast as it could be written
+    assert!(started.elapsed() >= Duration::from_millis(150));
+    drop(sink);
+    drop(output);
+
+    let mut reader = hound::WavReader::open(&path).unwrap();
+    assert_eq!(reader.spec().channels, MIX_CHANNELS);
+    let samples: Vec<i16> = reader.samples::<i16>().map(Result::unwrap).collect();
+    std::fs::remove_file(&path).unwrap();
+
+    let loud = samples.iter().filter(|sample| sample.abs() > 8000).count();
+    assert!(loud > MIX_SAMPLE_RATE as usize / 10, "{}", loud);
+    assert!(samples
+        .iter()
+        .all(|sample| sample.abs() <= i16::MAX / 2 + 1));
+}
+
+#[test]
+fn test_device_selector() {
+    assert_eq!("2".parse(), Ok(DeviceSelector::Index(2)));
+    assert_eq!(
+        "USB DAC".parse(),
+        Ok(DeviceSelector::Name("USB DAC".to_string()))
+    );
+    assert_eq!(
+        serde_json::from_str::<DeviceSelector>("\"hdmi\"").unwrap(),
+        DeviceSelector::Name("hdmi".to_string())
+    );
+}
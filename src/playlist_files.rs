@@ -0,0 +1,512 @@
+use derive_more::From;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+
+use crate::db_operations::DBObject;
+use crate::message_types::ItemTag;
+
+pub const SUPPORTED_PLAYLIST_FILETYPES: [&str; 4] = ["m3u", "m3u8", "pls", "xspf"];
+
+/// Characters that are left alone when a path is written as a `file://` URL
+const PATH_URL_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Catch all Error for reading and writing playlist files
+#[derive(From, Debug)]
+pub enum PlaylistFileError {
+    IoError(std::io::Error),
+    XmlError(quick_xml::Error),
+    RusqliteError(rusqlite::Error),
+    UnknownFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    /// Both `.m3u` and `.m3u8`, the latter is always written as UTF-8
+    M3u,
+    Pls,
+    Xspf,
+}
+
+impl PlaylistFormat {
+    /// Guess the format of a playlist file from its extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension().and_then(OsStr::to_str)?.to_lowercase();
+        match extension.as_str() {
+            "m3u" | "m3u8" => Some(PlaylistFormat::M3u),
+            "pls" => Some(PlaylistFormat::Pls),
+            "xspf" => Some(PlaylistFormat::Xspf),
+            _ => None,
+        }
+    }
+}
+
+/// An entry as it is written in a playlist file, before it is matched against the library
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PlaylistFileEntry {
+    pub location: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+/// The outcome of importing a playlist file into the database
+#[derive(Debug)]
+pub struct PlaylistImport {
+    pub name: String,
+    /// The library paths of the entries that were found, in playlist order
+    pub resolved: Vec<String>,
+    /// The locations of the entries that didn't match anything in the library
+    pub unresolved: Vec<String>,
+}
+
+/// Parse an M3U or extended M3U playlist
+///
+/// `#EXTINF` lines are used for the title and artist of the entry that follows them,
+/// every other comment line is ignored.
+pub fn parse_m3u(contents: &str) -> Vec<PlaylistFileEntry> {
+    let mut entries = Vec::<PlaylistFileEntry>::new();
+    let mut pending_info: Option<(Option<String>, Option<String>)> = None;
+
+    for line in contents.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>,<artist> - <title>
+            let display = info.split_once(',').map(|(_, display)| display.trim());
+            pending_info = display.map(|display| match display.split_once(" - ") {
+                Some((artist, title)) => (Some(artist.to_string()), Some(title.to_string())),
+                None => (None, Some(display.to_string())),
+            });
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let (artist, title) = pending_info.take().unwrap_or((None, None));
+        entries.push(PlaylistFileEntry {
+            location: line.to_string(),
+            title,
+            artist,
+        });
+    }
+
+    entries
+}
+
+/// Parse a PLS playlist
+pub fn parse_pls(contents: &str) -> Vec<PlaylistFileEntry> {
+    let mut entries = Vec::<(usize, PlaylistFileEntry)>::new();
+
+    for line in contents.trim_start_matches('\u{feff}').lines() {
+        let (key, value) = match line.trim().split_once('=') {
+            Some(pair) => pair,
+            None => continue,
+        };
+
+        let key = key.trim().to_lowercase();
+        let (field, index) = match key.find(|c: char| c.is_ascii_digit()) {
+            Some(split) => (&key[..split], &key[split..]),
+            None => continue,
+        };
+        let index: usize = match index.parse() {
+            Ok(index) => index,
+            Err(_) => continue,
+        };
+
+        let position = match entries.iter().position(|(i, _)| *i == index) {
+            Some(position) => position,
+            None => {
+                entries.push((index, PlaylistFileEntry::default()));
+                entries.len() - 1
+            }
+        };
+
+        let value = value.trim().to_string();
+        match field {
+            "file" => entries[position].1.location = value,
+            "title" => entries[position].1.title = Some(value),
+            _ => {}
+        }
+    }
+
+    entries.sort_by_key(|(index, _)| *index);
+    entries
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .filter(|entry| !entry.location.is_empty())
+        .collect()
+}
+
+/// Parse an XSPF (XML Shareable Playlist Format) playlist
+pub fn parse_xspf(contents: &str) -> Result<Vec<PlaylistFileEntry>, PlaylistFileError> {
+    let mut reader = Reader::from_str(contents);
+    reader.trim_text(true);
+
+    let mut entries = Vec::<PlaylistFileEntry>::new();
+    let mut current_entry: Option<PlaylistFileEntry> = None;
+    let mut current_element = Vec::<u8>::new();
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => {
+                if element.name().as_ref() == b"track" {
+                    current_entry = Some(PlaylistFileEntry::default());
+                }
+                current_element = element.name().as_ref().to_vec();
+            }
+            Event::Text(text) => {
+                if let Some(entry) = current_entry.as_mut() {
+                    let text = text.unescape()?.into_owned();
+                    match current_element.as_slice() {
+                        b"location" if entry.location.is_empty() => entry.location = text,
+                        b"title" => entry.title = Some(text),
+                        b"creator" => entry.artist = Some(text),
+                        _ => {}
+                    }
+                }
+            }
+            Event::End(element) => {
+                if element.name().as_ref() == b"track" {
+                    if let Some(entry) = current_entry.take() {
+                        if !entry.location.is_empty() {
+                            entries.push(entry);
+                        }
+                    }
+                }
+                current_element.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Read the entries of a playlist file
+///
+/// Files that aren't valid UTF-8 (common for plain `.m3u`) are read as Latin-1.
+pub fn read_playlist_file(path: &Path) -> Result<Vec<PlaylistFileEntry>, PlaylistFileError> {
+    let format = PlaylistFormat::from_path(path).ok_or(PlaylistFileError::UnknownFormat)?;
+
+    let bytes = std::fs::read(path)?;
+    let contents = match String::from_utf8(bytes) {
+        Ok(contents) => contents,
+        Err(err) => err.into_bytes().iter().map(|byte| *byte as char).collect(),
+    };
+
+    match format {
+        PlaylistFormat::M3u => Ok(parse_m3u(&contents)),
+        PlaylistFormat::Pls => Ok(parse_pls(&contents)),
+        PlaylistFormat::Xspf => parse_xspf(&contents),
+    }
+}
+
+/// Resolve `.` and `..` components without touching the filesystem
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut output = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                output.pop();
+            }
+            other => output.push(other),
+        }
+    }
+    output
+}
+
+/// Returns the paths an entry location could refer to, most likely first
+///
+/// Handles `file://` URLs, percent encoded paths, Windows separators and paths that are
+/// relative to the playlist file.
+pub fn candidate_paths(location: &str, playlist_dir: &Path) -> Vec<PathBuf> {
+    let location = location.trim();
+    if location.contains("://") && !location.starts_with("file://") {
+        // Remote streams can't be in the library
+        return vec![];
+    }
+
+    let mut raw_locations = Vec::<String>::new();
+    match location.strip_prefix("file://") {
+        Some(url_path) => {
+            let url_path = url_path.strip_prefix("localhost").unwrap_or(url_path);
+            raw_locations.push(
+                percent_decode_str(url_path)
+                    .decode_utf8_lossy()
+                    .into_owned(),
+            );
+        }
+        None => {
+            raw_locations.push(location.to_string());
+            let decoded = percent_decode_str(location)
+                .decode_utf8_lossy()
+                .into_owned();
+            if decoded != location {
+                raw_locations.push(decoded);
+            }
+        }
+    }
+
+    raw_locations
+        .into_iter()
+        .map(|raw| {
+            let path = PathBuf::from(raw.replace('\\', "/"));
+            if path.is_absolute() {
+                normalize_path(&path)
+            } else {
+                normalize_path(&playlist_dir.join(path))
+            }
+        })
+        .collect()
+}
+
+/// Match a playlist entry against the library, returning the library path
+pub fn resolve_entry(
+    dbo: &DBObject,
+    entry: &PlaylistFileEntry,
+    playlist_dir: &Path,
+) -> Result<Option<String>, rusqlite::Error> {
+    for candidate in candidate_paths(&entry.location, playlist_dir) {
+        if let Some(path) = dbo.find_track_path(&candidate.to_string_lossy())? {
+            return Ok(Some(path));
+        }
+    }
+
+    match &entry.title {
+        Some(title) => dbo.find_track_by_title(entry.artist.as_deref(), title),
+        None => Ok(None),
+    }
+}
+
+/// Import a playlist file into the database
+///
+/// The playlist is named after the file, and an existing playlist with that name has its
+/// contents replaced so rescanning the same file doesn't duplicate entries.
+pub fn import_playlist(dbo: &DBObject, path: &Path) -> Result<PlaylistImport, PlaylistFileError> {
+    let entries = read_playlist_file(path)?;
+    let playlist_dir = path.parent().unwrap_or_else(|| Path::new("/"));
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string_lossy().into_owned());
+
+    let mut resolved = Vec::<String>::new();
+    let mut unresolved = Vec::<String>::new();
+    for entry in entries.iter() {
+        match resolve_entry(dbo, entry, playlist_dir)? {
+            Some(library_path) => resolved.push(library_path),
+            None => unresolved.push(entry.location.clone()),
+        }
+    }
+
+    dbo.set_playlist_paths(&name, &resolved)?;
+
+    Ok(PlaylistImport {
+        name,
+        resolved,
+        unresolved,
+    })
+}
+
+/// Returns how a track is referenced from a playlist written to `playlist_dir`
+///
+/// Tracks below the playlist's directory are written relative to it so the playlist keeps
+/// working when the whole library is moved.
+fn entry_location(tag: &ItemTag, playlist_dir: &Path) -> String {
+    match Path::new(&tag.path).strip_prefix(playlist_dir) {
+        Ok(relative) => relative.to_string_lossy().into_owned(),
+        Err(_) => tag.path.clone(),
+    }
+}
+
+fn entry_display_name(tag: &ItemTag) -> String {
+    if tag.artist.is_empty() {
+        tag.title.clone()
+    } else {
+        format!("{} - {}", tag.artist, tag.title)
+    }
+}
+
+/// Write tags as the contents of a playlist file in `format`
+pub fn write_playlist(tags: &[ItemTag], format: PlaylistFormat, playlist_dir: &Path) -> String {
+    let mut output = String::new();
+
+    match format {
+        PlaylistFormat::M3u => {
+            output.push_str("#EXTM3U\n");
+            for tag in tags {
+                output.push_str(&format!("#EXTINF:-1,{}\n", entry_display_name(tag)));
+                output.push_str(&entry_location(tag, playlist_dir));
+                output.push('\n');
+            }
+        }
+        PlaylistFormat::Pls => {
+            output.push_str("[playlist]\n");
+            for (index, tag) in tags.iter().enumerate() {
+                let number = index + 1;
+                output.push_str(&format!(
+                    "File{}={}\n",
+                    number,
+                    entry_location(tag, playlist_dir)
+                ));
+                output.push_str(&format!("Title{}={}\n", number, entry_display_name(tag)));
+                output.push_str(&format!("Length{}=-1\n", number));
+            }
+            output.push_str(&format!("NumberOfEntries={}\nVersion=2\n", tags.len()));
+        }
+        PlaylistFormat::Xspf => {
+            output.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+            output.push_str("<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n");
+            output.push_str("  <trackList>\n");
+            for tag in tags {
+                let location = format!(
+                    "file://{}",
+                    utf8_percent_encode(&tag.path, PATH_URL_ENCODE_SET)
+                );
+                output.push_str("    <track>\n");
+                output.push_str(&format!(
+                    "      <location>{}</location>\n",
+                    quick_xml::escape::escape(&location)
+                ));
+                output.push_str(&format!(
+                    "      <title>{}</title>\n",
+                    quick_xml::escape::escape(&tag.title)
+                ));
+                if !tag.artist.is_empty() {
+                    output.push_str(&format!(
+                        "      <creator>{}</creator>\n",
+                        quick_xml::escape::escape(&tag.artist)
+                    ));
+                }
+                if !tag.album.is_empty() {
+                    output.push_str(&format!(
+                        "      <album>{}</album>\n",
+                        quick_xml::escape::escape(&tag.album)
+                    ));
+                }
+                output.push_str("    </track>\n");
+            }
+            output.push_str("  </trackList>\n</playlist>\n");
+        }
+    }
+
+    output
+}
+
+/// Export a stored playlist to a file, the format is picked from the file's extension
+///
+/// Entries whose tracks are missing from the library are left out.
+pub fn export_playlist(dbo: &DBObject, name: &str, path: &Path) -> Result<(), PlaylistFileError> {
+    let format = PlaylistFormat::from_path(path).ok_or(PlaylistFileError::UnknownFormat)?;
+    let tags = dbo.get_playlist_tracks(name)?;
+    let playlist_dir = path.parent().unwrap_or_else(|| Path::new("/"));
+
+    std::fs::write(path, write_playlist(&tags, format, playlist_dir))?;
+    Ok(())
+}
+
+#[test]
+fn test_parse_m3u() {
+    let entries = parse_m3u(
+        "#EXTM3U\n#EXTINF:123,An example artist - An example song title\nmusic/one.mp3\n\n# a comment\n/abs/two.mp3\n",
+    );
+
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].location, "music/one.mp3".to_string());
+    assert_eq!(entries[0].artist, Some("An example artist".to_string()));
+    assert_eq!(entries[0].title, Some("An example song title".to_string()));
+    assert_eq!(entries[1].title, None);
+}
+
+#[test]
+fn test_parse_pls_and_xspf() {
+    let pls =
+        parse_pls("[playlist]\nFile2=two.mp3\nFile1=one.mp3\nTitle1=One\nNumberOfEntries=2\n");
+    assert_eq!(pls[0].location, "one.mp3".to_string());
+    assert_eq!(pls[0].title, Some("One".to_string()));
+    assert_eq!(pls[1].location, "two.mp3".to_string());
+
+    let xspf = parse_xspf(
+        r#"<?xml version="1.0"?><playlist><trackList>
+        <track><location>file:///music/a%20b.mp3</location><title>A &amp; B</title></track>
+        </trackList></playlist>"#,
+    )
+    .unwrap();
+    assert_eq!(xspf[0].location, "file:///music/a%20b.mp3".to_string());
+    assert_eq!(xspf[0].title, Some("A & B".to_string()));
+}
+
+#[test]
+fn test_candidate_paths() {
+    let playlist_dir = Path::new("/music/playlists");
+
+    assert_eq!(
+        candidate_paths("../Artist/song.mp3", playlist_dir),
+        vec![PathBuf::from("/music/Artist/song.mp3")]
+    );
+    assert_eq!(
+        candidate_paths("file:///music/a%20b.mp3", playlist_dir),
+        vec![PathBuf::from("/music/a b.mp3")]
+    );
+    assert_eq!(
+        candidate_paths("..\\Artist\\song%231.mp3", playlist_dir),
+        vec![
+            PathBuf::from("/music/Artist/song%231.mp3"),
+            PathBuf::from("/music/Artist/song#1.mp3")
+        ]
+    );
+    assert!(candidate_paths("http://radio.example/stream", playlist_dir).is_empty());
+}
+
+#[test]
+fn test_import_and_export_playlist() {
+    let dbo = DBObject::new(&PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+    let dir = std::env::temp_dir().join(format!("sousa-playlists-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    for (path, title) in [("/music/One.mp3", "One"), ("/music/two.mp3", "Two")] {
+        dbo.save_tag(&ItemTag {
+            path: path.to_string(),
+            title: title.to_string(),
+            artist: "An example artist".to_string(),
+            ..ItemTag::default()
+        })
+        .unwrap();
+    }
+
+    let playlist_path = dir.join("Example.m3u8");
+    std::fs::write(
+        &playlist_path,
+        "#EXTM3U\nfile:///music/one.mp3\n#EXTINF:-1,An example artist - Two\nmoved/two.mp3\nmissing.mp3\n",
+    )
+    .unwrap();
+
+    let import = import_playlist(&dbo, &playlist_path).unwrap();
+    assert_eq!(import.name, "Example".to_string());
+    assert_eq!(
+        import.resolved,
+        vec!["/music/One.mp3".to_string(), "/music/two.mp3".to_string()]
+    );
+    assert_eq!(import.unresolved, vec!["missing.mp3".to_string()]);
+
+    let export_path = dir.join("Example.xspf");
+    export_playlist(&dbo, "Example", &export_path).unwrap();
+    let exported = read_playlist_file(&export_path).unwrap();
+    assert_eq!(exported[0].location, "file:///music/One.mp3".to_string());
+    assert_eq!(exported[1].title, Some("Two".to_string()));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
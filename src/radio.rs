@@ -0,0 +1,514 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::http_stream::{read_request_head, write_status};
+use crate::message_types::ItemTag;
+use crate::output::{AudioOutput, MIX_CHANNELS, MIX_SAMPLE_RATE};
+use crate::server_handling::is_authorized;
+
+/// How many bytes of audio go between two ICY metadata blocks
+const ICY_METAINT: usize = 16000;
+/// How many chunks of the mix can wait to be encoded before new ones are dropped
+const MONITOR_QUEUE: usize = 100;
+/// How many blocks can wait to be sent to a listener before it is dropped as too slow
+const LISTENER_QUEUE: usize = 256;
+/// The size of the blocks read from the encoder
+const ENCODED_BLOCK_SIZE: usize = 4096;
+/// How often the threads check whether the radio was stopped
+const STOP_CHECK_PERIOD: Duration = Duration::from_millis(50);
+/// How long a listener has to send its request before it is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the radio stream is encoded as
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RadioFormat {
+    /// Encoded by ffmpeg, the radio doesn't start if it isn't installed
+    Mp3,
+    /// Uncompressed 16 bit PCM, for a local network or when there is no ffmpeg
+    Wav,
+}
+
+/// Mirroring the playback of the default zone as an HTTP radio stream, part of the
+/// configuration file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RadioSettings {
+    pub enabled: bool,
+    /// The address listeners tune in on
+    pub address: String,
+    pub format: RadioFormat,
+    /// The bitrate of the MP3 stream, in kbit/s
+    pub bitrate: u32,
+    /// The station name sent to listeners
+    pub name: String,
+}
+
+impl Default for RadioSettings {
+    fn default() -> Self {
+        RadioSettings {
+            enabled: false,
+            address: "127.0.0.1:9003".to_string(),
+            format: RadioFormat::Mp3,
+            bitrate: 128,
+            name: "Sousa".to_string(),
+        }
+    }
+}
+
+/// A tuned in listener, fed blocks of the encoded stream by its own thread
+struct Listener {
+    blocks: SyncSender<Arc<Vec<u8>>>,
+}
+
+/// What every thread of the radio shares
+struct Station {
+    listeners: Mutex<Vec<Listener>>,
+    /// The `StreamTitle` of the ICY metadata
+    title: Mutex<String>,
+    stop: AtomicBool,
+}
+
+impl Station {
+    /// Hand a block to every listener, dropping the ones that fell too far behind so they
+    /// can't hold the others up
+    fn broadcast(&self, block: Vec<u8>) {
+        let block = Arc::new(block);
+        self.listeners.lock().unwrap().retain(|listener| {
+            match listener.blocks.try_send(Arc::clone(&block)) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Dropping a radio listener that can't keep up");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+/// The playback of an output as a continuous stream that any number of listeners can tune
+/// into over HTTP, in sync
+///
+/// Listeners that send `Icy-MetaData: 1` get the artist and title of the playing track as
+/// ICY metadata. The stream runs until the radio is dropped.
+pub struct Radio {
+    station: Arc<Station>,
+    encoder: Option<Child>,
+    address: std::net::SocketAddr,
+}
+
+impl Radio {
+    /// Start encoding the playback of `output` and listening for listeners
+    ///
+    /// `ffmpeg` is the binary that encodes MP3 streams.
+    pub fn start(
+        settings: &RadioSettings,
+        ffmpeg: &str,
+        access_token: Option<String>,
+        output: &AudioOutput,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(&settings.address)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+
+        let station = Arc::new(Station {
+            listeners: Mutex::new(vec![]),
+            title: Mutex::new(String::new()),
+            stop: AtomicBool::new(false),
+        });
+        let (monitor, mix) = sync_channel(MONITOR_QUEUE);
+
+        let encoder = match settings.format {
+            RadioFormat::Mp3 => {
+                let mut child = Command::new(ffmpeg)
+                    .args(["-v", "error", "-nostdin", "-f", "f32le"])
+                    .args(["-ar", &MIX_SAMPLE_RATE.to_string()])
+                    .args(["-ac", &MIX_CHANNELS.to_string()])
+                    .args(["-i", "pipe:0", "-c:a", "libmp3lame"])
+                    .args(["-b:a", &format!("{}k", settings.bitrate), "-f", "mp3"])
+                    .arg("pipe:1")
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::null())
+                    .spawn()?;
+                let mut stdin = child.stdin.take().unwrap();
+                let mut stdout = child.stdout.take().unwrap();
+
+                let pump_station = Arc::clone(&station);
+                std::thread::spawn(move || {
+                    pump_mix(&pump_station, &mix, |chunk| {
+                        let bytes: Vec<u8> = chunk
+                            .iter()
+                            .flat_map(|sample| sample.to_le_bytes())
+                            .collect();
+                        stdin.write_all(&bytes).is_ok()
+                    })
+                });
+                let read_station = Arc::clone(&station);
+                std::thread::spawn(move || {
+                    let mut block = vec![0; ENCODED_BLOCK_SIZE];
+                    while let Ok(read) = stdout.read(&mut block) {
+                        if read == 0 {
+                            break;
+                        }
+                        read_station.broadcast(block[..read].to_vec());
+                    }
+                });
+                Some(child)
+            }
+            RadioFormat::Wav => {
+                let pump_station = Arc::clone(&station);
+                std::thread::spawn(move || {
+                    pump_mix(&pump_station, &mix, |chunk| {
+                        pump_station.broadcast(pcm_bytes(chunk));
+                        true
+                    })
+                });
+                None
+            }
+        };
+
+        let accept_station = Arc::clone(&station);
+        let settings = settings.clone();
+        std::thread::spawn(move || {
+            accept_listeners(
+                &listener,
+                &accept_station,
+                &settings,
+                access_token.as_deref(),
+            )
+        });
+
+        output.set_monitor(Some(monitor));
+        info!("Radio on the air at: {}", address);
+        Ok(Radio {
+            station,
+            encoder,
+            address,
+        })
+    }
+
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.address
+    }
+
+    /// Announce the playing track to the listeners, with their next metadata block
+    pub fn set_now_playing(&self, item: &ItemTag) {
+        let title = if item.artist.is_empty() {
+            item.title.clone()
+        } else {
+            format!("{} - {}", item.artist, item.title)
+        };
+        *self.station.title.lock().unwrap() = title;
+    }
+
+    pub fn listener_count(&self) -> usize {
+        self.station.listeners.lock().unwrap().len()
+    }
+}
+
+impl Drop for Radio {
+    fn drop(&mut self) {
+        self.station.stop.store(true, Ordering::Relaxed);
+        if let Some(encoder) = self.encoder.as_mut() {
+            let _ = encoder.kill();
+            let _ = encoder.wait();
+        }
+    }
+}
+
+/// Hand the chunks of the mix to `encode` until the radio stops or `encode` fails
+fn pump_mix(station: &Station, mix: &Receiver<Vec<f32>>, mut encode: impl FnMut(&[f32]) -> bool) {
+    while !station.stop.load(Ordering::Relaxed) {
+        if let Ok(chunk) = mix.recv_timeout(STOP_CHECK_PERIOD) {
+            if !encode(&chunk) {
+                warn!("The radio encoder stopped");
+                break;
+            }
+        }
+    }
+}
+
+fn pcm_bytes(chunk: &[f32]) -> Vec<u8> {
+    chunk
+        .iter()
+        .flat_map(|sample| ((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())
+        .collect()
+}
+
+/// The header of a WAV file with its lengths left unset, for a stream that doesn't end
+fn endless_wav_header() -> Vec<u8> {
+    hound::WavSpec {
+        channels: MIX_CHANNELS,
+        sample_rate: MIX_SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    }
+    .into_header_for_infinite_file()
+}
+
+/// An ICY metadata block: its length in 16 byte units, then the padded text
+///
+/// A title that didn't change since the last block is sent as an empty block.
+fn icy_metadata(title: Option<&str>) -> Vec<u8> {
+    let text = match title {
+        Some(title) => format!("StreamTitle='{}';", title),
+        None => return vec![0],
+    };
+    let mut text = text.into_bytes();
+    text.truncate(255 * 16);
+    let units = text.len().div_ceil(16);
+    let mut block = vec![units as u8];
+    block.extend_from_slice(&text);
+    block.resize(1 + units * 16, 0);
+    block
+}
+
+fn accept_listeners(
+    listener: &TcpListener,
+    station: &Arc<Station>,
+    settings: &RadioSettings,
+    access_token: Option<&str>,
+) {
+    while !station.stop.load(Ordering::Relaxed) {
+        let (stream, addr) = match listener.accept() {
+            Ok(connection) => connection,
+            Err(_) => {
+                std::thread::sleep(STOP_CHECK_PERIOD);
+                continue;
+            }
+        };
+        info!("Radio listener tuned in from: {}", addr);
+        let station = Arc::clone(station);
+        let settings = settings.clone();
+        let access_token = access_token.map(str::to_string);
+        std::thread::spawn(move || {
+            if let Err(err) = serve_listener(stream, &station, &settings, access_token.as_deref()) {
+                info!("Radio listener from {} left: {}", addr, err);
+            }
+        });
+    }
+}
+
+fn serve_listener(
+    mut stream: TcpStream,
+    station: &Station,
+    settings: &RadioSettings,
+    access_token: Option<&str>,
+) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let head = read_request_head(&mut stream)?;
+
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut request = httparse::Request::new(&mut headers);
+    if !matches!(request.parse(&head), Ok(httparse::Status::Complete(_))) {
+        return write_status(&mut stream, "400 Bad Request", "");
+    }
+    let header = |name: &str| {
+        request
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| String::from_utf8_lossy(header.value).into_owned())
+    };
+    let target = request.path.unwrap_or_default();
+    let query = target.split_once('?').map(|(_, query)| query);
+    if !is_authorized(access_token, header("Authorization").as_deref(), query) {
+        return write_status(
+            &mut stream,
+            "401 Unauthorized",
+            "WWW-Authenticate: Bearer\r\n",
+        );
+    }
+    let wants_metadata = header("Icy-MetaData").is_some_and(|value| value.trim() == "1");
+
+    let content_type = match settings.format {
+        RadioFormat::Mp3 => "audio/mpeg",
+        RadioFormat::Wav => "audio/wav",
+    };
+    let mut head = format!(
+        "HTTP/1.0 200 OK\r\nContent-Type: {}\r\nicy-name: {}\r\nCache-Control: no-cache\r\n",
+        content_type, settings.name
+    );
+    if wants_metadata {
+        head.push_str(&format!("icy-metaint: {}\r\n", ICY_METAINT));
+    }
+    head.push_str("\r\n");
+
+    let (blocks, received) = sync_channel(LISTENER_QUEUE);
+    station.listeners.lock().unwrap().push(Listener { blocks });
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.write_all(head.as_bytes())?;
+
+    let mut writer = IcyWriter {
+        stream,
+        metadata: wants_metadata,
+        until_metadata: ICY_METAINT,
+        sent_title: None,
+    };
+    if settings.format == RadioFormat::Wav {
+        writer.write(&endless_wav_header(), station)?;
+    }
+    while !station.stop.load(Ordering::Relaxed) {
+        match received.recv_timeout(STOP_CHECK_PERIOD) {
+            Ok(block) => writer.write(&block, station)?,
+            Err(RecvTimeoutError::Timeout) => {}
+            // Dropped by `Station::broadcast` for falling behind, hang up on it
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Ok(())
+}
+
+/// Writes the stream to a listener, with a metadata block every `ICY_METAINT` bytes if it
+/// asked for them
+struct IcyWriter {
+    stream: TcpStream,
+    metadata: bool,
+    until_metadata: usize,
+    sent_title: Option<String>,
+}
+
+impl IcyWriter {
+    fn write(&mut self, mut bytes: &[u8], station: &Station) -> std::io::Result<()> {
+        if !self.metadata {
+            return self.stream.write_all(bytes);
+        }
+        while !bytes.is_empty() {
+            let part = bytes.len().min(self.until_metadata);
+            self.stream.write_all(&bytes[..part])?;
+            bytes = &bytes[part..];
+            self.until_metadata -= part;
+
+            if self.until_metadata == 0 {
+                let title = station.title.lock().unwrap().clone();
+                let changed = self.sent_title.as_ref() != Some(&title);
+                self.stream
+                    .write_all(&icy_metadata(changed.then_some(title.as_str())))?;
+                self.sent_title = Some(title);
+                self.until_metadata = ICY_METAINT;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_icy_metadata() {
+    assert_eq!(icy_metadata(None), vec![0]);
+    let block = icy_metadata(Some("Artist - Title"));
+    // "StreamTitle='Artist - Title';" is 29 bytes, padded to 32
+    assert_eq!(block[0], 2);
+    assert_eq!(block.len(), 33);
+    assert_eq!(&block[1..30], b"StreamTitle='Artist - Title';");
+    assert!(block[30..].iter().all(|byte| *byte == 0));
+}
+
+#[test]
+fn test_radio_listeners() {
+    let output = AudioOutput::null();
+    let settings = RadioSettings {
+        address: "127.0.0.1:0".to_string(),
+        format: RadioFormat::Wav,
+        ..RadioSettings::default()
+    };
+    let radio = Radio::start(&settings, "ffmpeg", None, &output).unwrap();
+    radio.set_now_playing(&ItemTag {
+        title: "Title".to_string(),
+        artist: "Artist".to_string(),
+        ..ItemTag::default()
+    });
+
+    let tune_in = |headers: &str| {
+        let mut stream = TcpStream::connect(radio.local_addr()).unwrap();
+        write!(stream, "GET / HTTP/1.0\r\n{}\r\n", headers).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    };
+    let read_head = |stream: &mut TcpStream| {
+        let mut head = vec![];
+        let mut byte = [0];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    };
+
+    let mut with_metadata = tune_in("Icy-MetaData: 1\r\n");
+    let mut plain = tune_in("");
+    let head = read_head(&mut with_metadata);
+    assert!(head.contains("icy-metaint: 16000"), "{}", head);
+    assert!(head.contains("Content-Type: audio/wav"));
+    assert!(!read_head(&mut plain).contains("icy-metaint"));
+
+    // The stream keeps going while nothing plays, starting with the WAV header
+    let mut audio = vec![0; ICY_METAINT];
+    with_metadata.read_exact(&mut audio).unwrap();
+    assert_eq!(&audio[..4], b"RIFF");
+    let mut length = [0];
+    with_metadata.read_exact(&mut length).unwrap();
+    let mut metadata = vec![0; length[0] as usize * 16];
+    with_metadata.read_exact(&mut metadata).unwrap();
+    assert!(metadata.starts_with(b"StreamTitle='Artist - Title';"));
+
+    // Both listeners hear the same stream
+    let mut plain_audio = vec![0; ICY_METAINT];
+    plain.read_exact(&mut plain_audio).unwrap();
+    assert_eq!(&plain_audio[..44], &audio[..44]);
+    assert_eq!(radio.listener_count(), 2);
+
+    drop(plain);
+    drop(with_metadata);
+    drop(radio);
+}
+
+#[test]
+fn test_radio_slow_listener() {
+    let station = Arc::new(Station {
+        listeners: Mutex::new(vec![]),
+        title: Mutex::new(String::new()),
+        stop: AtomicBool::new(false),
+    });
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+    let (accepted, _) = server.accept().unwrap();
+    let serve_station = Arc::clone(&station);
+    let serving = std::thread::spawn(move || {
+        serve_listener(accepted, &serve_station, &RadioSettings::default(), None)
+    });
+    write!(stream, "GET / HTTP/1.0\r\n\r\n").unwrap();
+    while station.listeners.lock().unwrap().is_empty() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    // The listener doesn't read, so it falls behind until the station drops it
+    let block = vec![0; 64 * 1024];
+    for _ in 0..10_000 {
+        if station.listeners.lock().unwrap().is_empty() {
+            break;
+        }
+        station.broadcast(block.clone());
+    }
+    assert!(station.listeners.lock().unwrap().is_empty());
+
+    // What was queued for it still arrives, then the connection is closed
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut received = vec![];
+    stream.read_to_end(&mut received).unwrap();
+    assert!(received.starts_with(b"HTTP/1.0 200 OK"));
+    serving.join().unwrap().unwrap();
+    assert!(!station.stop.load(Ordering::Relaxed));
+}
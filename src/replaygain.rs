@@ -0,0 +1,152 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The ReplayGain values of a track, gains are in dB and peaks are linear sample values
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default, JsonSchema)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+/// Which of the ReplayGain values are applied during playback
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+    /// Album gain while the queue plays through an album, track gain otherwise
+    #[default]
+    Auto,
+}
+
+/// How loudness normalization is applied, part of the configuration file
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct ReplayGainSettings {
+    pub mode: ReplayGainMode,
+    /// Added to the gain of every track that has ReplayGain values, in dB
+    pub preamp_db: f32,
+    /// Lower the gain where needed so the track's peak doesn't clip
+    pub prevent_clipping: bool,
+}
+
+impl Default for ReplayGainSettings {
+    fn default() -> Self {
+        ReplayGainSettings {
+            mode: ReplayGainMode::Auto,
+            preamp_db: 0.0,
+            prevent_clipping: true,
+        }
+    }
+}
+
+impl ReplayGainSettings {
+    /// The factor a track's samples are multiplied by
+    ///
+    /// `in_album` picks album gain in `Auto` mode. When the preferred gain is missing the
+    /// other one is used, and tracks without any ReplayGain values are left as they are.
+    pub fn gain_factor(&self, replaygain: &ReplayGain, in_album: bool) -> f32 {
+        let use_album = match self.mode {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Track => false,
+            ReplayGainMode::Album => true,
+            ReplayGainMode::Auto => in_album,
+        };
+
+        let track = (replaygain.track_gain, replaygain.track_peak);
+        let album = (replaygain.album_gain, replaygain.album_peak);
+        let (preferred, fallback) = if use_album {
+            (album, track)
+        } else {
+            (track, album)
+        };
+        let (gain, peak) = match (preferred, fallback) {
+            ((Some(gain), peak), _) | (_, (Some(gain), peak)) => (gain, peak),
+            _ => return 1.0,
+        };
+
+        let factor = 10f32.powf((gain + self.preamp_db) / 20.0);
+        match peak {
+            Some(peak) if self.prevent_clipping && peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        }
+    }
+}
+
+/// Parse a gain like "-6.48 dB"
+pub fn parse_gain(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|gain: &f32| gain.is_finite())
+}
+
+/// Parse a peak like "0.988312"
+pub fn parse_peak(value: &str) -> Option<f32> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|peak: &f32| peak.is_finite() && *peak >= 0.0)
+}
+
+/// Build the ReplayGain values from `REPLAYGAIN_*` fields, looked up by `get_field`
+///
+/// The field names are passed in upper case; callers should match them regardless of case.
+pub fn from_fields<'a>(get_field: impl Fn(&str) -> Option<&'a str>) -> ReplayGain {
+    ReplayGain {
+        track_gain: get_field("REPLAYGAIN_TRACK_GAIN").and_then(parse_gain),
+        track_peak: get_field("REPLAYGAIN_TRACK_PEAK").and_then(parse_peak),
+        album_gain: get_field("REPLAYGAIN_ALBUM_GAIN").and_then(parse_gain),
+        album_peak: get_field("REPLAYGAIN_ALBUM_PEAK").and_then(parse_peak),
+    }
+}
+
+#[test]
+fn test_replaygain_factor() {
+    let replaygain = from_fields(|field| match field {
+        "REPLAYGAIN_TRACK_GAIN" => Some("-6.02 dB"),
+        "REPLAYGAIN_TRACK_PEAK" => Some("0.5"),
+        "REPLAYGAIN_ALBUM_GAIN" => Some("+6.02dB"),
+        "REPLAYGAIN_ALBUM_PEAK" => Some("0.8"),
+        _ => None,
+    });
+    assert_eq!(replaygain.album_gain, Some(6.02));
+
+    let close = |a: f32, b: f32| (a - b).abs() < 0.01;
+    let mut settings = ReplayGainSettings::default();
+    assert!(close(settings.gain_factor(&replaygain, false), 0.5));
+    // +6 dB would double the volume, but the album peak only allows 1.25x
+    assert!(close(settings.gain_factor(&replaygain, true), 1.25));
+
+    settings.prevent_clipping = false;
+    settings.mode = ReplayGainMode::Album;
+    assert!(close(settings.gain_factor(&replaygain, false), 2.0));
+
+    settings.mode = ReplayGainMode::Track;
+    settings.preamp_db = 6.02;
+    assert!(close(settings.gain_factor(&replaygain, true), 1.0));
+
+    // Album mode falls back to the track gain
+    settings.mode = ReplayGainMode::Album;
+    let track_only = ReplayGain {
+        track_gain: Some(-6.02),
+        ..ReplayGain::default()
+    };
+    assert!(close(settings.gain_factor(&track_only, true), 1.0));
+    assert!(close(
+        settings.gain_factor(&ReplayGain::default(), true),
+        1.0
+    ));
+
+    settings.mode = ReplayGainMode::Off;
+    assert!(close(settings.gain_factor(&replaygain, true), 1.0));
+}
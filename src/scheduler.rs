@@ -0,0 +1,404 @@
+use log::{info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::db_operations::DBObject;
+use crate::music_player::MusicPlayer;
+
+/// How long the fade out before a sleep timer pauses is
+const SLEEP_FADE: Duration = Duration::from_secs(10);
+/// How long after its time a schedule still runs, in case the server was busy or starting up
+pub const SCHEDULE_GRACE_MINUTES: u32 = 10;
+/// How often the database is checked for schedules that are due
+const SCHEDULE_CHECK_PERIOD: Duration = Duration::from_secs(5);
+
+/// When a sleep timer pauses the playback
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum SleepTimer {
+    /// After this many seconds
+    After(u64),
+    EndOfTrack,
+    EndOfQueue,
+}
+
+/// Days of the week, numbered from Sunday like SQLite's `strftime('%w')`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum Weekday {
+    Sunday,
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+}
+
+impl Weekday {
+    pub const ALL: [Weekday; 7] = [
+        Weekday::Sunday,
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+        Weekday::Saturday,
+    ];
+    pub const WEEKDAYS: [Weekday; 5] = [
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+    ];
+}
+
+/// What a schedule does when its time comes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub enum ScheduledAction {
+    Play,
+    Pause,
+    /// Replace the queue with the playlist and start playing it
+    LoadPlaylist(String),
+    SleepTimer(SleepTimer),
+}
+
+/// An action run at a time of day, stored in the database
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct Schedule {
+    pub name: String,
+    /// The local time, 0-23
+    pub hour: u8,
+    pub minute: u8,
+    /// The days it runs on, every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub action: ScheduledAction,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl Schedule {
+    pub fn is_valid(&self) -> bool {
+        !self.name.is_empty() && self.hour < 24 && self.minute < 60
+    }
+
+    pub(crate) fn minute_of_day(&self) -> u32 {
+        self.hour as u32 * 60 + self.minute as u32
+    }
+
+    /// The days as a bitmask with Sunday as bit 0, 0 for every day
+    pub(crate) fn days_mask(&self) -> u8 {
+        self.days.iter().fold(0, |mask, day| mask | 1 << *day as u8)
+    }
+
+    pub(crate) fn days_from_mask(mask: u8) -> Vec<Weekday> {
+        Weekday::ALL
+            .into_iter()
+            .filter(|day| mask & 1 << *day as u8 != 0)
+            .collect()
+    }
+}
+
+/// Announced to every client when the sleep timer or a schedule does something
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub enum SchedulerEvent {
+    /// The sleep timer was set, or cancelled with `None`
+    SleepTimerSet(Option<SleepTimer>),
+    /// The sleep timer started fading out, the playback pauses in this many seconds
+    SleepTimerFired(SleepTimer, u64),
+    ScheduleRan(String, ScheduledAction),
+    /// (schedule, reason)
+    ScheduleFailed(String, String),
+}
+
+struct ActiveSleepTimer {
+    timer: SleepTimer,
+    /// When an `After` timer runs out
+    deadline: Option<Instant>,
+}
+
+/// Runs the sleep timer and the schedules from the server loop
+pub struct Scheduler {
+    sleep_timer: Option<ActiveSleepTimer>,
+    /// Only one player runs the schedules, otherwise they would run in every zone
+    runs_schedules: bool,
+    last_schedule_check: Option<Instant>,
+    /// Waiting to be handed out by the next `poll`
+    events: Vec<SchedulerEvent>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            sleep_timer: None,
+            runs_schedules: true,
+            last_schedule_check: None,
+            events: vec![],
+        }
+    }
+
+    /// A scheduler that runs the sleep timer alone, for the players of the other zones
+    pub fn without_schedules() -> Self {
+        Scheduler {
+            runs_schedules: false,
+            ..Self::new()
+        }
+    }
+
+    /// Set the sleep timer, replacing the one running, or cancel it with `None`
+    pub fn set_sleep_timer(&mut self, timer: Option<SleepTimer>) {
+        self.sleep_timer = timer.map(|timer| ActiveSleepTimer {
+            timer,
+            deadline: match timer {
+                SleepTimer::After(seconds) => Some(Instant::now() + Duration::from_secs(seconds)),
+                _ => None,
+            },
+        });
+        self.events.push(SchedulerEvent::SleepTimerSet(timer));
+    }
+
+    pub fn sleep_timer(&self) -> Option<SleepTimer> {
+        self.sleep_timer.as_ref().map(|active| active.timer)
+    }
+
+    /// Start the sleep timer's fade out and run the schedules that are due
+    ///
+    /// `track_changed` is whether the player just moved on to another track. Returns what
+    /// happened, for the clients to be told about.
+    pub fn poll(
+        &mut self,
+        dbo: &DBObject,
+        music_player: &mut MusicPlayer,
+        track_changed: bool,
+    ) -> Vec<SchedulerEvent> {
+        self.poll_sleep_timer(music_player, track_changed);
+
+        let check_due = self.runs_schedules
+            && self
+                .last_schedule_check
+                .is_none_or(|checked| checked.elapsed() >= SCHEDULE_CHECK_PERIOD);
+        if check_due {
+            self.last_schedule_check = Some(Instant::now());
+            self.run_due_schedules(dbo, music_player);
+        }
+
+        std::mem::take(&mut self.events)
+    }
+
+    fn poll_sleep_timer(&mut self, music_player: &mut MusicPlayer, track_changed: bool) {
+        let active = match &self.sleep_timer {
+            Some(active) => active,
+            None => return,
+        };
+        let (queue, position) = music_player.get_queue();
+        let last_in_queue = position + 1 >= queue.len();
+        let fade = sleep_fade_due(
+            active.timer,
+            active.deadline,
+            Instant::now(),
+            music_player.remaining_time(),
+            last_in_queue,
+            track_changed,
+        );
+
+        if let Some(fade) = fade {
+            let timer = active.timer;
+            self.sleep_timer = None;
+            if music_player.is_paused() {
+                self.events.push(SchedulerEvent::SleepTimerSet(None));
+                return;
+            }
+            info!("Sleep timer {:?} is pausing in {:?}", timer, fade);
+            music_player.fade_out_and_pause(fade);
+            self.events
+                .push(SchedulerEvent::SleepTimerFired(timer, fade.as_secs()));
+        }
+    }
+
+    fn run_due_schedules(&mut self, dbo: &DBObject, music_player: &mut MusicPlayer) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let schedules = match dbo.due_schedules(now) {
+            Ok(schedules) => schedules,
+            Err(err) => {
+                warn!("Could not check the schedules: {}", err);
+                return;
+            }
+        };
+
+        for schedule in schedules {
+            if let Err(err) = dbo.mark_schedule_run(&schedule.name, now) {
+                warn!("Could not save when '{}' ran: {}", schedule.name, err);
+            }
+            info!("Running schedule '{}'", schedule.name);
+            let event = match self.run_action(&schedule.action, dbo, music_player) {
+                Ok(()) => SchedulerEvent::ScheduleRan(schedule.name, schedule.action),
+                Err(reason) => {
+                    warn!("Schedule '{}' failed: {}", schedule.name, reason);
+                    SchedulerEvent::ScheduleFailed(schedule.name, reason)
+                }
+            };
+            self.events.push(event);
+        }
+    }
+
+    fn run_action(
+        &mut self,
+        action: &ScheduledAction,
+        dbo: &DBObject,
+        music_player: &mut MusicPlayer,
+    ) -> Result<(), String> {
+        match action {
+            ScheduledAction::Play => music_player.play(),
+            ScheduledAction::Pause => music_player.pause(),
+            ScheduledAction::LoadPlaylist(name) => {
+                let tracks = dbo
+                    .get_playlist_tracks(name)
+                    .map_err(|err| format!("Could not load the playlist: {}", err))?;
+                music_player
+                    .set_queue(tracks)
+                    .map_err(|err| format!("Could not play the playlist: {:?}", err))?;
+                music_player.play();
+            }
+            ScheduledAction::SleepTimer(timer) => self.set_sleep_timer(Some(*timer)),
+        }
+        Ok(())
+    }
+}
+
+/// How long the fade out should be if a sleep timer should start it now, `None` if not yet
+///
+/// `remaining` is the wall time left in the current track, `None` if its length is unknown;
+/// then the timer goes off when the track changes instead.
+fn sleep_fade_due(
+    timer: SleepTimer,
+    deadline: Option<Instant>,
+    now: Instant,
+    remaining: Option<Duration>,
+    last_in_queue: bool,
+    track_changed: bool,
+) -> Option<Duration> {
+    let until_end_of_track = || match remaining {
+        Some(remaining) if remaining <= SLEEP_FADE => Some(remaining),
+        Some(_) => None,
+        None => track_changed.then_some(Duration::ZERO),
+    };
+
+    match timer {
+        SleepTimer::After(_) => {
+            let left = deadline?.saturating_duration_since(now);
+            (left <= SLEEP_FADE).then_some(left)
+        }
+        SleepTimer::EndOfTrack => until_end_of_track(),
+        // The player pauses by itself at the end of the queue, this just fades it out
+        SleepTimer::EndOfQueue if last_in_queue => until_end_of_track(),
+        SleepTimer::EndOfQueue => None,
+    }
+}
+
+#[test]
+fn test_sleep_fade_due() {
+    let now = Instant::now();
+    let minutes = |minutes: u64| Duration::from_secs(minutes * 60);
+    let after = SleepTimer::After(30 * 60);
+
+    assert_eq!(
+        sleep_fade_due(after, Some(now + minutes(20)), now, None, false, false),
+        None
+    );
+    assert_eq!(
+        sleep_fade_due(
+            after,
+            Some(now + Duration::from_secs(4)),
+            now,
+            None,
+            false,
+            true
+        ),
+        Some(Duration::from_secs(4))
+    );
+    assert_eq!(
+        sleep_fade_due(after, Some(now), now + minutes(1), None, false, false),
+        Some(Duration::ZERO)
+    );
+
+    let end_of_track = SleepTimer::EndOfTrack;
+    assert_eq!(
+        sleep_fade_due(end_of_track, None, now, Some(minutes(2)), false, false),
+        None
+    );
+    assert_eq!(
+        sleep_fade_due(
+            end_of_track,
+            None,
+            now,
+            Some(Duration::from_secs(6)),
+            false,
+            false
+        ),
+        Some(Duration::from_secs(6))
+    );
+    assert_eq!(
+        sleep_fade_due(end_of_track, None, now, None, false, false),
+        None
+    );
+    assert_eq!(
+        sleep_fade_due(end_of_track, None, now, None, false, true),
+        Some(Duration::ZERO)
+    );
+
+    let end_of_queue = SleepTimer::EndOfQueue;
+    assert_eq!(
+        sleep_fade_due(
+            end_of_queue,
+            None,
+            now,
+            Some(Duration::from_secs(6)),
+            false,
+            false
+        ),
+        None
+    );
+    assert_eq!(
+        sleep_fade_due(
+            end_of_queue,
+            None,
+            now,
+            Some(Duration::from_secs(6)),
+            true,
+            false
+        ),
+        Some(Duration::from_secs(6))
+    );
+}
+
+#[test]
+fn test_schedule_days() {
+    let schedule = Schedule {
+        name: "Wake up".to_string(),
+        hour: 7,
+        minute: 0,
+        days: Weekday::WEEKDAYS.to_vec(),
+        action: ScheduledAction::LoadPlaylist("Morning".to_string()),
+        enabled: true,
+    };
+    assert_eq!(schedule.days_mask(), 0b0111110);
+    assert_eq!(
+        Schedule::days_from_mask(schedule.days_mask()),
+        schedule.days
+    );
+    assert_eq!(Schedule::days_from_mask(0), vec![]);
+}
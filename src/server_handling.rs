@@ -1,5 +1,6 @@
-use crate::message_types::{UIRequest, ItemTag, ServerResponse, PartialTag};
+use crate::message_types::{UIRequest, ItemTag, ServerResponse, PartialTag, ResponsePayload};
 use log::info;
+use percent_encoding::percent_decode_str;
 use tungstenite::protocol::WebSocket;
 use std::net::TcpStream;
 
@@ -41,21 +42,105 @@ pub fn sanitize_partialtag(input: PartialTag) -> PartialTag {
     if input.album.is_some() {output.album = Some(input.album.unwrap().replace("'", "''"));};
     if input.artist.is_some() {output.artist = Some(input.artist.unwrap().replace("'", "''"));};
     if input.album_artist.is_some() {output.album_artist = Some(input.album_artist.unwrap().replace("'", "''"));};
+    if input.genre.is_some() {output.genre = Some(input.genre.unwrap().replace("'", "''"));};
+    output.year = input.year;
+    if input.label.is_some() {output.label = Some(input.label.unwrap().replace("'", "''"));};
+    if input.lyrics.is_some() {output.lyrics = Some(input.lyrics.unwrap().replace("'", "''"));};
+    output.favorite = input.favorite;
+    output.min_rating = input.min_rating;
     println!("output tag {:?}", output);
     return output;
 }
 
+/// Whether a request carries the access token, as a `token` query parameter or a bearer
+/// `Authorization` header; no token is needed if none is set
+///
+/// `query` is the part of the request target after the `?`.
+pub fn is_authorized(
+    access_token: Option<&str>,
+    authorization: Option<&str>,
+    query: Option<&str>,
+) -> bool {
+    let access_token = match access_token {
+        Some(access_token) => access_token,
+        None => return true,
+    };
+    if authorization.and_then(|value| value.strip_prefix("Bearer ")) == Some(access_token) {
+        return true;
+    }
+    query_parameter(query.unwrap_or_default(), "token").as_deref() == Some(access_token)
+}
+
+/// The percent-decoded value of a parameter in a URL query string
+pub fn query_parameter(query: &str, name: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (key == name).then(|| {
+            percent_decode_str(&value.replace('+', " "))
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+    })
+}
+
+/// Something responses can be sent to, a websocket or a collector for an HTTP reply
+pub trait ClientConnection {
+    // The error type is the one tungstenite's websockets return
+    #[allow(clippy::result_large_err)]
+    fn send_message(&mut self, message: String) -> Result<(), tungstenite::Error>;
+}
+
+impl ClientConnection for WebSocket<TcpStream> {
+    fn send_message(&mut self, message: String) -> Result<(), tungstenite::Error> {
+        self.write_message(message.into())
+    }
+}
+
+/// A connected UI, along with what it wants to be sent without asking
+pub struct Client<C = WebSocket<TcpStream>> {
+    pub socket: C,
+    /// The zone whose lyrics the client follows
+    pub lyrics_zone: Option<String>,
+    pub analysis_subscribed: bool,
+}
+
+impl<C: ClientConnection> Client<C> {
+    pub fn new(socket: C) -> Self {
+        Client {
+            socket,
+            lyrics_zone: None,
+            analysis_subscribed: false,
+        }
+    }
+}
+
 pub fn write_to_socket(
-    socket: &mut WebSocket<TcpStream>,
+    socket: &mut impl ClientConnection,
     message: String,
     results: Vec<ItemTag>,
 ) -> Result<(), tungstenite::Error> {
-    socket.write_message(
+    socket.send_message(
         serde_json::to_string(&ServerResponse {
             message,
             search_results: results,
+            payload: None,
+        })
+        .unwrap(),
+    )
+}
+
+/// Send a message along with structured data that isn't a list of tags
+pub fn write_payload_to_socket(
+    socket: &mut impl ClientConnection,
+    message: String,
+    payload: ResponsePayload,
+) -> Result<(), tungstenite::Error> {
+    socket.send_message(
+        serde_json::to_string(&ServerResponse {
+            message,
+            search_results: vec![],
+            payload: Some(payload),
         })
-        .unwrap()
-        .into(),
+        .unwrap(),
     )
 }
@@ -0,0 +1,277 @@
+use rusqlite::types::Value;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::db_operations::MUSICINFO_COLUMNS;
+
+/// The text columns of `musicinfo` a rule can match against
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum TextField {
+    Path,
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Genre,
+}
+
+impl TextField {
+    pub fn column(&self) -> &'static str {
+        match self {
+            TextField::Path => "path",
+            TextField::Title => "title",
+            TextField::Artist => "artist",
+            TextField::Album => "album",
+            TextField::AlbumArtist => "album_artist",
+            TextField::Genre => "genre",
+        }
+    }
+}
+
+/// Text comparisons, all of them ignore case
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum TextOperator {
+    Is,
+    IsNot,
+    Contains,
+    DoesNotContain,
+    StartsWith,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum NumberOperator {
+    Is,
+    IsNot,
+    LessThan,
+    GreaterThan,
+}
+
+impl NumberOperator {
+    fn sql(&self) -> &'static str {
+        match self {
+            NumberOperator::Is => "=",
+            NumberOperator::IsNot => "!=",
+            NumberOperator::LessThan => "<",
+            NumberOperator::GreaterThan => ">",
+        }
+    }
+}
+
+/// A single condition a track has to fulfil to be part of a smart playlist
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub enum SmartRule {
+    Text {
+        field: TextField,
+        operator: TextOperator,
+        value: String,
+    },
+    Year {
+        operator: NumberOperator,
+        value: i32,
+    },
+    /// The track was added to the library in the last `days` days
+    AddedInLast { days: u32 },
+    /// Unrated tracks never match
+    Rating {
+        operator: NumberOperator,
+        value: u8,
+    },
+    Favorite(bool),
+    HasLabel(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum SmartSort {
+    Random,
+    Title,
+    Artist,
+    Album,
+    Year,
+    DateAdded,
+    Rating,
+    PlayCount,
+}
+
+impl SmartSort {
+    /// Returns the `ORDER BY` clause sorting `musicinfo` rows, without the keywords
+    pub fn order_by(&self, descending: bool) -> String {
+        let direction = if descending { "DESC" } else { "ASC" };
+        match self {
+            SmartSort::Random => "RANDOM()".to_string(),
+            SmartSort::Title => format!("title COLLATE NOCASE {}", direction),
+            SmartSort::Artist => format!("artist COLLATE NOCASE {}, album, title", direction),
+            SmartSort::Album => format!("album COLLATE NOCASE {}, title", direction),
+            SmartSort::Year => format!("year {}, album, title", direction),
+            SmartSort::DateAdded => format!("date_added {}, path", direction),
+            SmartSort::Rating => format!("COALESCE(rating, -1) {}, title", direction),
+            SmartSort::PlayCount => format!("play_count {}, title", direction),
+        }
+    }
+}
+
+/// The definition of a smart playlist
+///
+/// # Examples
+/// ```rust
+/// // genre is jazz AND year < 1970, sorted by random, limit 100
+/// let rules = SmartPlaylistRules {
+///     match_all: true,
+///     rules: vec![
+///         SmartRule::Text { field: TextField::Genre, operator: TextOperator::Is, value: "Jazz".to_string() },
+///         SmartRule::Year { operator: NumberOperator::LessThan, value: 1970 },
+///     ],
+///     sort_by: SmartSort::Random,
+///     descending: false,
+///     limit: Some(100),
+/// };
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct SmartPlaylistRules {
+    /// Tracks have to match every rule when true, any rule when false
+    pub match_all: bool,
+    pub rules: Vec<SmartRule>,
+    pub sort_by: SmartSort,
+    #[serde(default)]
+    pub descending: bool,
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+/// Escape the LIKE wildcards in a value, `\` is used as the escape character
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+impl SmartRule {
+    fn to_sql(&self, parameters: &mut Vec<Value>) -> String {
+        match self {
+            SmartRule::Text {
+                field,
+                operator,
+                value,
+            } => {
+                let column = field.column();
+                let (condition, parameter) = match operator {
+                    TextOperator::Is => (format!("{} = ? COLLATE NOCASE", column), value.clone()),
+                    TextOperator::IsNot => {
+                        (format!("{} != ? COLLATE NOCASE", column), value.clone())
+                    }
+                    TextOperator::Contains => (
+                        format!("{} LIKE ? ESCAPE '\\'", column),
+                        format!("%{}%", escape_like(value)),
+                    ),
+                    TextOperator::DoesNotContain => (
+                        format!("{} NOT LIKE ? ESCAPE '\\'", column),
+                        format!("%{}%", escape_like(value)),
+                    ),
+                    TextOperator::StartsWith => (
+                        format!("{} LIKE ? ESCAPE '\\'", column),
+                        format!("{}%", escape_like(value)),
+                    ),
+                };
+                parameters.push(Value::Text(parameter));
+                condition
+            }
+            SmartRule::Year { operator, value } => {
+                parameters.push(Value::Integer(*value as i64));
+                format!("year {} ?", operator.sql())
+            }
+            SmartRule::AddedInLast { days } => {
+                parameters.push(Value::Integer(*days as i64 * 24 * 60 * 60));
+                "date_added >= CAST(strftime('%s', 'now') AS INTEGER) - ?".to_string()
+            }
+            SmartRule::Rating { operator, value } => {
+                parameters.push(Value::Integer(*value as i64));
+                format!("rating {} ?", operator.sql())
+            }
+            SmartRule::Favorite(favorite) => {
+                parameters.push(Value::Integer(*favorite as i64));
+                "favorite = ?".to_string()
+            }
+            SmartRule::HasLabel(label) => {
+                parameters.push(Value::Text(label.clone()));
+                "path IN (SELECT tracks.path FROM tracks
+                    JOIN track_labels ON track_labels.track_id = tracks.id
+                    JOIN labels ON labels.id = track_labels.label_id
+                    WHERE labels.name = ? COLLATE NOCASE)"
+                    .to_string()
+            }
+        }
+    }
+}
+
+impl SmartPlaylistRules {
+    /// Returns the query selecting the tracks of the playlist from `musicinfo`, and its parameters
+    ///
+    /// The query selects `MUSICINFO_COLUMNS`, so rows can be read with `item_tag_from_row`.
+    pub fn to_sql(&self) -> (String, Vec<Value>) {
+        let mut parameters = Vec::<Value>::new();
+        let mut query = format!("SELECT {} FROM musicinfo", MUSICINFO_COLUMNS);
+
+        if !self.rules.is_empty() {
+            let conditions: Vec<String> = self
+                .rules
+                .iter()
+                .map(|rule| format!("({})", rule.to_sql(&mut parameters)))
+                .collect();
+            let joiner = if self.match_all { " AND " } else { " OR " };
+            query.push_str(" WHERE ");
+            query.push_str(&conditions.join(joiner));
+        }
+
+        query.push_str(" ORDER BY ");
+        query.push_str(&self.sort_by.order_by(self.descending));
+
+        if let Some(limit) = self.limit {
+            parameters.push(Value::Integer(limit as i64));
+            query.push_str(" LIMIT ?");
+        }
+
+        (query, parameters)
+    }
+
+    /// Whether the playlist depends on the current time, and has to be refreshed every time
+    /// it is read rather than only when the library changes
+    pub fn is_time_relative(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| matches!(rule, SmartRule::AddedInLast { .. }))
+    }
+}
+
+#[test]
+fn test_smart_playlist_sql() {
+    let rules = SmartPlaylistRules {
+        match_all: true,
+        rules: vec![
+            SmartRule::Text {
+                field: TextField::Genre,
+                operator: TextOperator::Contains,
+                value: "100%".to_string(),
+            },
+            SmartRule::Year {
+                operator: NumberOperator::LessThan,
+                value: 1970,
+            },
+        ],
+        sort_by: SmartSort::Random,
+        descending: false,
+        limit: Some(100),
+    };
+
+    let (query, parameters) = rules.to_sql();
+    assert!(query
+        .ends_with("WHERE (genre LIKE ? ESCAPE '\\') AND (year < ?) ORDER BY RANDOM() LIMIT ?"));
+    assert_eq!(
+        parameters,
+        vec![
+            Value::Text("%100\\%%".to_string()),
+            Value::Integer(1970),
+            Value::Integer(100)
+        ]
+    );
+    assert!(!rules.is_time_relative());
+}
@@ -0,0 +1,531 @@
+use rodio::cpal::Sample as CpalSample;
+use rodio::{Sample, Source};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 3.0;
+
+/// The length of the segments that are overlapped when time-stretching
+const STRETCH_WINDOW_MS: u32 = 40;
+/// How far from its nominal position a segment can be moved to line up with the previous one
+const STRETCH_SEEK_MS: u32 = 10;
+
+/// How fast tracks play back
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct SpeedSettings {
+    /// From `MIN_SPEED` to `MAX_SPEED`, 1 is normal speed
+    pub speed: f32,
+    /// Time-stretch so voices keep their pitch, instead of speeding up like a tape would
+    pub keep_pitch: bool,
+}
+
+impl Default for SpeedSettings {
+    fn default() -> Self {
+        SpeedSettings {
+            speed: 1.0,
+            keep_pitch: true,
+        }
+    }
+}
+
+impl SpeedSettings {
+    pub fn is_valid(&self) -> bool {
+        (MIN_SPEED..=MAX_SPEED).contains(&self.speed)
+    }
+}
+
+/// Controls the speed of `SpeedChanged` sources from another thread
+pub struct SpeedControl {
+    /// Stored as `f32` bits
+    speed: AtomicU32,
+    keep_pitch: AtomicBool,
+}
+
+impl SpeedControl {
+    pub fn new(settings: SpeedSettings) -> Arc<Self> {
+        let control = Arc::new(SpeedControl {
+            speed: AtomicU32::new(0),
+            keep_pitch: AtomicBool::new(false),
+        });
+        control.set(settings);
+        control
+    }
+
+    pub fn set(&self, settings: SpeedSettings) {
+        self.keep_pitch
+            .store(settings.keep_pitch, Ordering::Relaxed);
+        self.speed.store(
+            settings.speed.clamp(MIN_SPEED, MAX_SPEED).to_bits(),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn get(&self) -> (f32, bool) {
+        (
+            f32::from_bits(self.speed.load(Ordering::Relaxed)),
+            self.keep_pitch.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// Normal speed, the samples are passed on as they are
+    Unchanged,
+    /// Linear interpolation, the pitch changes along with the speed
+    Resampled,
+    /// WSOLA time-stretching, the pitch stays the same
+    Stretched,
+}
+
+/// A source played faster or slower following a `SpeedControl`
+///
+/// The speed and mode are picked up between frames, and every mode carries on from the exact
+/// position the last one stopped at, so changes are heard without a gap or a click.
+pub struct SpeedChanged<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    inner: S,
+    control: Arc<SpeedControl>,
+    channels: usize,
+    sample_rate: u32,
+
+    mode: Mode,
+    speed: f32,
+    /// Decoded frames waiting to be used, interleaved
+    input: Vec<f32>,
+    /// Set once `inner` has run out
+    exhausted: bool,
+    /// The number of frames of silence added after the end of `inner`
+    padding: usize,
+    /// The position of the next frame to use in `input`, in frames
+    position: f64,
+    output: VecDeque<S::Item>,
+
+    /// The start of the last segment that was overlapped, in frames of `input`
+    segment_start: usize,
+    /// The fading out second half of the last segment, to be added to the next one
+    overlap: Vec<f32>,
+    window: Vec<f32>,
+    seek_frames: usize,
+}
+
+impl<S> SpeedChanged<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    pub fn new(inner: S, control: &Arc<SpeedControl>) -> Self {
+        let channels = inner.channels().max(1) as usize;
+        let sample_rate = inner.sample_rate();
+        // An even length, so the two halves of the periodic Hann window add up to 1
+        let window_frames = (sample_rate * STRETCH_WINDOW_MS / 1000 / 2 * 2).max(4) as usize;
+        let window = (0..window_frames)
+            .map(|n| 0.5 - 0.5 * (2.0 * PI * n as f32 / window_frames as f32).cos())
+            .collect();
+
+        SpeedChanged {
+            inner,
+            control: Arc::clone(control),
+            channels,
+            sample_rate,
+            mode: Mode::Unchanged,
+            speed: 1.0,
+            input: vec![],
+            exhausted: false,
+            padding: 0,
+            position: 0.0,
+            output: VecDeque::new(),
+            segment_start: 0,
+            overlap: vec![],
+            window,
+            seek_frames: (sample_rate * STRETCH_SEEK_MS / 1000) as usize,
+        }
+    }
+
+    fn hop_frames(&self) -> usize {
+        self.window.len() / 2
+    }
+
+    fn buffered_frames(&self) -> usize {
+        self.input.len() / self.channels
+    }
+
+    /// Decode until there are `frames` frames in `input`, false if `inner` ran out first
+    fn fill_input(&mut self, frames: usize) -> bool {
+        while self.buffered_frames() < frames {
+            if self.exhausted {
+                return false;
+            }
+            for _ in 0..self.channels {
+                match self.inner.next() {
+                    Some(sample) => self.input.push(sample.to_f32()),
+                    None => {
+                        self.exhausted = true;
+                        // Drop the partial frame
+                        let frames = self.buffered_frames();
+                        self.input.truncate(frames * self.channels);
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    fn push_frame(&mut self, frame: impl Iterator<Item = f32>) {
+        self.output
+            .extend(frame.map(|value| <S::Item as CpalSample>::from(&value.clamp(-1.0, 1.0))));
+    }
+
+    fn update_mode(&mut self) {
+        let (speed, keep_pitch) = self.control.get();
+        let mode = if speed == 1.0 {
+            Mode::Unchanged
+        } else if keep_pitch {
+            Mode::Stretched
+        } else {
+            Mode::Resampled
+        };
+        self.speed = speed;
+        if mode == self.mode {
+            return;
+        }
+
+        let hop = self.hop_frames();
+        if self.mode == Mode::Stretched {
+            // Everything up to the start of the overlap has been played
+            self.position = self.segment_start.wrapping_add(hop) as f64;
+            self.overlap.clear();
+            let frames = self.buffered_frames().saturating_sub(self.padding);
+            self.input.truncate(frames * self.channels);
+            self.padding = 0;
+        }
+        self.position = match mode {
+            Mode::Resampled => self.position,
+            _ => self.position.round(),
+        };
+        if mode == Mode::Stretched {
+            // Play on from `position` as if it was the middle of a segment, so the first
+            // stretched segment fades in as the rest of that one fades out
+            self.fill_input(self.position as usize + hop);
+            let start = (self.position as usize).min(self.buffered_frames());
+            let end = (start + hop).min(self.buffered_frames());
+            self.overlap = self.input[start * self.channels..end * self.channels].to_vec();
+            for (index, value) in self.overlap.iter_mut().enumerate() {
+                *value *= self.window[hop + index / self.channels];
+            }
+            self.overlap.resize(hop * self.channels, 0.0);
+            // Out of range of the input until the first segment is picked
+            self.segment_start = start.wrapping_sub(hop);
+            self.position = start as f64 - hop as f64;
+        }
+        self.mode = mode;
+    }
+
+    /// Add at least one frame to `output`, false at the end of the source
+    fn produce(&mut self) -> bool {
+        let produced = match self.mode {
+            Mode::Unchanged => self.produce_unchanged(),
+            Mode::Resampled => self.produce_resampled(),
+            Mode::Stretched => self.produce_stretched(),
+        };
+        self.drop_used_input();
+        produced
+    }
+
+    fn produce_unchanged(&mut self) -> bool {
+        let frame = self.position as usize;
+        if !self.fill_input(frame + 1) {
+            return false;
+        }
+        let start = frame * self.channels;
+        let samples = self.input[start..start + self.channels].to_vec();
+        self.push_frame(samples.into_iter());
+        self.position += 1.0;
+        true
+    }
+
+    fn produce_resampled(&mut self) -> bool {
+        let frame = self.position.floor() as usize;
+        if !self.fill_input(frame + 1) {
+            return false;
+        }
+        let fraction = (self.position - frame as f64) as f32;
+        let next_frame = if self.fill_input(frame + 2) {
+            frame + 1
+        } else {
+            frame
+        };
+
+        let channels = self.channels;
+        let samples: Vec<f32> = (0..channels)
+            .map(|channel| {
+                let current = self.input[frame * channels + channel];
+                let next = self.input[next_frame * channels + channel];
+                current + (next - current) * fraction
+            })
+            .collect();
+        self.push_frame(samples.into_iter());
+        self.position += self.speed as f64;
+        true
+    }
+
+    /// Overlap-add the next segment, moved within `seek_frames` of its nominal position to
+    /// where it best lines up with how the previous segment carries on
+    fn produce_stretched(&mut self) -> bool {
+        let hop = self.hop_frames();
+        let window_frames = self.window.len();
+        let channels = self.channels;
+        let nominal = self.position.round().max(0.0) as usize;
+        let continuation = self.segment_start.wrapping_add(hop);
+        let lowest = nominal.saturating_sub(self.seek_frames);
+        let highest = nominal + self.seek_frames;
+
+        if !self.fill_input(highest.max(continuation) + window_frames)
+            && nominal.max(continuation) >= self.buffered_frames().saturating_sub(self.padding)
+        {
+            // Nothing left but the end of the last segment
+            let overlap = std::mem::take(&mut self.overlap);
+            self.push_frame(overlap.into_iter());
+            return !self.output.is_empty();
+        }
+        // Pad the end of the source with silence, so the last segments are whole
+        let padded = (highest.max(continuation) + window_frames) * channels;
+        if self.input.len() < padded {
+            self.padding += padded / channels - self.buffered_frames();
+            self.input.resize(padded, 0.0);
+        }
+
+        let mono = |input: &[f32], frame: usize| -> f32 {
+            input[frame * channels..(frame + 1) * channels].iter().sum()
+        };
+        let mut best = (nominal, f32::MIN);
+        for candidate in lowest..=highest {
+            let (mut correlation, mut energy) = (0.0, 1e-9);
+            for offset in (0..hop).step_by(4) {
+                let value = mono(&self.input, candidate + offset);
+                correlation += value * mono(&self.input, continuation + offset);
+                energy += value * value;
+            }
+            let score = correlation / energy.sqrt();
+            if score > best.1 {
+                best = (candidate, score);
+            }
+        }
+        let start = best.0;
+
+        for offset in 0..hop {
+            for channel in 0..channels {
+                let index = offset * channels + channel;
+                let value = self.overlap[index]
+                    + self.input[(start + offset) * channels + channel] * self.window[offset];
+                self.output
+                    .push_back(<S::Item as CpalSample>::from(&value.clamp(-1.0, 1.0)));
+                self.overlap[index] = self.input[(start + hop + offset) * channels + channel]
+                    * self.window[hop + offset];
+            }
+        }
+
+        self.segment_start = start;
+        self.position += hop as f64 * self.speed as f64;
+        true
+    }
+
+    /// Forget the input that is behind every position still in use
+    fn drop_used_input(&mut self) {
+        let mut used = self.position.floor().max(0.0) as usize;
+        if self.mode == Mode::Stretched {
+            used = used
+                .saturating_sub(self.seek_frames)
+                .min(self.segment_start.wrapping_add(self.hop_frames()));
+        }
+        // Only every so often, so the buffer isn't shifted for every frame
+        if used < self.window.len() * 4 || used > self.buffered_frames() {
+            return;
+        }
+        self.input.drain(..used * self.channels);
+        // What was drained may have reached into the silence after the end of `inner`
+        self.padding = self.padding.min(self.buffered_frames());
+        self.position -= used as f64;
+        self.segment_start = self.segment_start.wrapping_sub(used);
+    }
+}
+
+impl<S> Iterator for SpeedChanged<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<S::Item> {
+        loop {
+            if let Some(sample) = self.output.pop_front() {
+                return Some(sample);
+            }
+            // Only whole frames are ever added, so this is a frame boundary
+            self.update_mode();
+            if !self.produce() {
+                return None;
+            }
+        }
+    }
+}
+
+impl<S> Source for SpeedChanged<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// How many times a signal crosses zero on the way up
+#[cfg(test)]
+fn count_rising_crossings(samples: &[f32]) -> usize {
+    samples
+        .windows(2)
+        .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+        .count()
+}
+
+#[test]
+fn test_speed_changes() {
+    let sample_rate = 44100;
+    let sine: Vec<f32> = (0..sample_rate * 2)
+        .map(|n| 0.5 * (2.0 * PI * 440.0 * n as f32 / sample_rate as f32).sin())
+        .collect();
+    let play = |settings: SpeedSettings| -> Vec<f32> {
+        let source = rodio::buffer::SamplesBuffer::new(1, sample_rate, sine.clone());
+        SpeedChanged::new(source, &SpeedControl::new(settings)).collect()
+    };
+    // Rising zero crossings per second is the frequency
+    let frequency = |samples: &[f32]| {
+        count_rising_crossings(samples) as f32 * sample_rate as f32 / samples.len() as f32
+    };
+
+    let unchanged = play(SpeedSettings::default());
+    assert_eq!(unchanged, sine);
+
+    let tape = play(SpeedSettings {
+        speed: 2.0,
+        keep_pitch: false,
+    });
+    assert!(tape.len().abs_diff(sine.len() / 2) <= 1, "{}", tape.len());
+    assert!(
+        (frequency(&tape) - 880.0).abs() < 5.0,
+        "{}",
+        frequency(&tape)
+    );
+
+    for speed in [0.5, 1.5, 3.0] {
+        let stretched = play(SpeedSettings {
+            speed,
+            keep_pitch: true,
+        });
+        let expected = sine.len() as f32 / speed;
+        assert!(
+            (stretched.len() as f32 - expected).abs() < sample_rate as f32 * 0.05,
+            "{} at {}",
+            stretched.len(),
+            speed
+        );
+        let middle = &stretched[stretched.len() / 4..stretched.len() * 3 / 4];
+        assert!(
+            (frequency(middle) - 440.0).abs() < 5.0,
+            "{} at {}",
+            frequency(middle),
+            speed
+        );
+        // No dips where the segments overlap
+        let peak = middle
+            .chunks(sample_rate as usize / 440 + 1)
+            .map(|cycle| cycle.iter().fold(0.0f32, |peak, s| peak.max(s.abs())))
+            .fold(1.0f32, f32::min);
+        assert!(peak > 0.45, "{} at {}", peak, speed);
+    }
+}
+
+#[test]
+fn test_speed_change_while_playing() {
+    let sample_rate = 8000;
+    let ramp: Vec<f32> = (0..sample_rate)
+        .map(|n| n as f32 / sample_rate as f32)
+        .collect();
+    let control = SpeedControl::new(SpeedSettings::default());
+    let mut source = SpeedChanged::new(
+        rodio::buffer::SamplesBuffer::new(1, sample_rate, ramp.clone()),
+        &control,
+    );
+
+    let mut played: Vec<f32> = source.by_ref().take(1000).collect();
+    control.set(SpeedSettings {
+        speed: 2.0,
+        keep_pitch: false,
+    });
+    played.extend(source.by_ref().take(1000));
+    control.set(SpeedSettings::default());
+    played.extend(source);
+
+    // Picks up exactly where it was, without skipping or repeating any of the ramp
+    assert_eq!(played[999], ramp[999]);
+    assert_eq!(played[1000], ramp[1000]);
+    assert_eq!(played[1999], ramp[2998]);
+    assert_eq!(played[2000], ramp[3000]);
+    assert_eq!(played.len(), ramp.len() - 1000);
+}
+
+#[test]
+fn test_stretched_playback_ends() {
+    let sample_rate = 44100;
+    let sine: Vec<f32> = (0..24000)
+        .map(|n| 0.5 * (2.0 * PI * 440.0 * n as f32 / sample_rate as f32).sin())
+        .collect();
+    // Whether the end of the source falls in the input that is dropped depends on its
+    // length, some of these used to end up playing the padding forever
+    for frames in (22000..24000).step_by(101) {
+        for speed in [1.5, 3.0] {
+            let control = SpeedControl::new(SpeedSettings {
+                speed,
+                keep_pitch: true,
+            });
+            let source = rodio::buffer::SamplesBuffer::new(1, sample_rate, &sine[..frames]);
+            let expected = frames as f32 / speed;
+            // Capped, in case it never ends
+            let played = SpeedChanged::new(source, &control)
+                .take(expected as usize * 2)
+                .count() as f32;
+            assert!(
+                played >= expected && played < expected + sample_rate as f32 * 0.1,
+                "{} frames at {} played {}",
+                frames,
+                speed,
+                played
+            );
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use derive_more::From;
+use std::path::{Path, PathBuf};
+
+use crate::db_operations::DBObject;
+use crate::file_operations::{get_tag, is_in_music_roots, write_tags, TagError};
+use crate::message_types::{ItemTag, PartialTag};
+
+/// Longest value accepted for a text field
+const MAX_FIELD_LENGTH: usize = 1024;
+
+/// Catch all Error for editing the tags of audio files
+#[derive(From, Debug)]
+pub enum TagEditError {
+    TagError(TagError),
+    RusqliteError(rusqlite::Error),
+    #[from(ignore)]
+    InvalidValue(String),
+    OutsideMusicRoots,
+    NotInLibrary,
+    NothingToUndo,
+}
+
+/// Check that `changes` only touches editable fields, with values that can be written
+pub fn validate_changes(changes: &PartialTag) -> Result<(), TagEditError> {
+    if changes.path.is_some() {
+        return Err(TagEditError::InvalidValue(
+            "The path of a track can't be edited".to_string(),
+        ));
+    }
+    if changes.favorite.is_some() || changes.min_rating.is_some() || changes.label.is_some() {
+        return Err(TagEditError::InvalidValue(
+            "Favorites, ratings and labels aren't tag fields".to_string(),
+        ));
+    }
+    if changes.lyrics.is_some() {
+        return Err(TagEditError::InvalidValue(
+            "Lyrics are read from the tags and .lrc files, they can't be edited".to_string(),
+        ));
+    }
+
+    let text_fields = [
+        ("title", &changes.title),
+        ("artist", &changes.artist),
+        ("album", &changes.album),
+        ("album artist", &changes.album_artist),
+        ("genre", &changes.genre),
+    ];
+    if text_fields.iter().all(|(_, value)| value.is_none()) && changes.year.is_none() {
+        return Err(TagEditError::InvalidValue(
+            "There is nothing to change".to_string(),
+        ));
+    }
+
+    for (name, value) in text_fields.iter() {
+        if let Some(value) = value {
+            if value.len() > MAX_FIELD_LENGTH {
+                return Err(TagEditError::InvalidValue(format!(
+                    "The {} is too long",
+                    name
+                )));
+            }
+            if value.chars().any(char::is_control) {
+                return Err(TagEditError::InvalidValue(format!(
+                    "The {} can't contain control characters",
+                    name
+                )));
+            }
+        }
+    }
+
+    if changes
+        .title
+        .as_ref()
+        .is_some_and(|title| title.trim().is_empty())
+    {
+        return Err(TagEditError::InvalidValue(
+            "A track needs a title".to_string(),
+        ));
+    }
+    if changes.year.is_some_and(|year| !(0..=9999).contains(&year)) {
+        return Err(TagEditError::InvalidValue(
+            "The year has to be between 0 and 9999".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the current values of the fields `changes` is going to overwrite
+fn overwritten_values(current: &ItemTag, changes: &PartialTag) -> PartialTag {
+    PartialTag {
+        title: changes.title.as_ref().map(|_| current.title.clone()),
+        artist: changes.artist.as_ref().map(|_| current.artist.clone()),
+        album: changes.album.as_ref().map(|_| current.album.clone()),
+        album_artist: changes
+            .album_artist
+            .as_ref()
+            .map(|_| current.album_artist.clone()),
+        genre: changes.genre.as_ref().map(|_| current.genre.clone()),
+        year: changes.year.map(|_| current.year.unwrap_or(0)),
+        ..PartialTag::default()
+    }
+}
+
+/// Write changes to a file and bring its library entry up to date
+fn apply_changes(
+    dbo: &DBObject,
+    path: &Path,
+    changes: &PartialTag,
+) -> Result<ItemTag, TagEditError> {
+    write_tags(path, changes)?;
+
+    let written = get_tag(path)?;
+    dbo.update_tag(&written)?;
+
+    dbo.get_tag_by_path(&written.path)?
+        .ok_or(TagEditError::NotInLibrary)
+}
+
+/// Check that a file can be edited: it is inside the music roots and in the library
+fn check_editable(dbo: &DBObject, path: &str, music_roots: &[PathBuf]) -> Result<(), TagEditError> {
+    if !is_in_music_roots(Path::new(path), music_roots) {
+        return Err(TagEditError::OutsideMusicRoots);
+    }
+    if dbo.get_tag_by_path(path)?.is_none() {
+        return Err(TagEditError::NotInLibrary);
+    }
+    Ok(())
+}
+
+/// Change the tags of a file and update its library entry
+///
+/// The values that get overwritten are written to the edit log so the change can be
+/// undone with `undo_last_edit`. Returns the updated library entry.
+pub fn edit_tags(
+    dbo: &DBObject,
+    path: &str,
+    changes: &PartialTag,
+    music_roots: &[PathBuf],
+) -> Result<ItemTag, TagEditError> {
+    validate_changes(changes)?;
+    check_editable(dbo, path, music_roots)?;
+
+    let current = get_tag(Path::new(path))?;
+    let previous = overwritten_values(&current, changes);
+
+    let updated = apply_changes(dbo, Path::new(path), changes)?;
+    dbo.log_tag_edit(path, &previous, changes)?;
+
+    Ok(updated)
+}
+
+/// Apply the same changes to several files
+///
+/// Every file is attempted; the results are returned in the same order as `paths`.
+pub fn edit_tags_batch(
+    dbo: &DBObject,
+    paths: &[String],
+    changes: &PartialTag,
+    music_roots: &[PathBuf],
+) -> Vec<Result<ItemTag, TagEditError>> {
+    if let Err(err) = validate_changes(changes) {
+        return paths
+            .iter()
+            .map(|_| Err(TagEditError::InvalidValue(format!("{:?}", err))))
+            .collect();
+    }
+
+    paths
+        .iter()
+        .map(|path| edit_tags(dbo, path, changes, music_roots))
+        .collect()
+}
+
+/// Restore the values the last edit of a file overwrote
+pub fn undo_last_edit(
+    dbo: &DBObject,
+    path: &str,
+    music_roots: &[PathBuf],
+) -> Result<ItemTag, TagEditError> {
+    check_editable(dbo, path, music_roots)?;
+
+    let (edit_id, previous) = dbo
+        .last_tag_edit(path)?
+        .ok_or(TagEditError::NothingToUndo)?;
+
+    let restored = apply_changes(dbo, Path::new(path), &previous)?;
+    dbo.delete_tag_edit(edit_id)?;
+
+    Ok(restored)
+}
+
+#[test]
+fn test_validate_changes() {
+    let valid = PartialTag {
+        title: Some("An example song title".to_string()),
+        year: Some(1999),
+        ..PartialTag::default()
+    };
+    assert!(validate_changes(&valid).is_ok());
+
+    for invalid in [
+        PartialTag::default(),
+        PartialTag {
+            path: Some("/somewhere/else.mp3".to_string()),
+            ..PartialTag::default()
+        },
+        PartialTag {
+            title: Some("   ".to_string()),
+            ..PartialTag::default()
+        },
+        PartialTag {
+            album: Some("line\nbreak".to_string()),
+            ..PartialTag::default()
+        },
+        PartialTag {
+            year: Some(-4),
+            ..PartialTag::default()
+        },
+    ] {
+        assert!(validate_changes(&invalid).is_err());
+    }
+}
+
+#[test]
+fn test_edit_and_undo_tags() {
+    let dir = std::env::temp_dir().join(format!("sousa-tag-edit-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let music_roots = vec![dir.clone()];
+
+    // An mp3 file with only an ID3 tag is enough for the tag code
+    let path = dir.join("song.mp3");
+    std::fs::write(&path, []).unwrap();
+    let mut tag = id3::Tag::new();
+    id3::TagLike::set_title(&mut tag, "An example song title");
+    id3::TagLike::set_artist(&mut tag, "An example artist");
+    tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
+    let path_string = path.to_string_lossy().into_owned();
+
+    let dbo = DBObject::new(&PathBuf::from("/there/is/no/file/saved"), true).unwrap();
+    dbo.save_tag(&get_tag(&path).unwrap()).unwrap();
+
+    let changes = PartialTag {
+        artist: Some("A new artist feat. A guest".to_string()),
+        year: Some(1999),
+        ..PartialTag::default()
+    };
+    let edited = edit_tags(&dbo, &path_string, &changes, &music_roots).unwrap();
+    assert_eq!(edited.artist, "A new artist; A guest".to_string());
+    assert_eq!(edited.year, Some(1999));
+    assert_eq!(get_tag(&path).unwrap().year, Some(1999));
+
+    let restored = undo_last_edit(&dbo, &path_string, &music_roots).unwrap();
+    assert_eq!(restored.artist, "An example artist".to_string());
+    assert_eq!(restored.year, None);
+    assert!(matches!(
+        undo_last_edit(&dbo, &path_string, &music_roots),
+        Err(TagEditError::NothingToUndo)
+    ));
+
+    assert!(matches!(
+        edit_tags(&dbo, &path_string, &changes, &[PathBuf::from("/nowhere")]),
+        Err(TagEditError::OutsideMusicRoots)
+    ));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,416 @@
+use derive_more::From;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::config::SousaConfig;
+use crate::equalizer::{EqPreset, EqPresets};
+use crate::lyrics::LyricsFollower;
+use crate::message_types::ItemTag;
+use crate::music_player::{MusicPlayer, MusicPlayerError, PlayerState};
+use crate::output::{AudioOutput, DeviceSelector, OutputBackend, OutputError};
+use crate::scheduler::Scheduler;
+
+/// The zone that plays to the output from the command line and configuration file
+///
+/// Requests that don't name a zone go to it, and it is the one that runs the schedules.
+pub const DEFAULT_ZONE: &str = "Main";
+
+#[derive(From, Debug)]
+pub enum ZoneError {
+    NoSuchZone,
+    /// There already is a zone by that name
+    ZoneExists,
+    /// Zones need a name
+    InvalidName,
+    /// The default zone is always there
+    DefaultZone,
+    /// The queue can't be moved to the zone it is in
+    SameZone,
+    /// Zones made by clients can't record to a WAV file
+    OutputNotAllowed,
+    OutputError(OutputError),
+    MusicPlayerError(MusicPlayerError),
+}
+
+/// What a zone plays to, stored in the database for every zone but the default one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct ZoneSettings {
+    pub name: String,
+    #[serde(default)]
+    pub output: OutputBackend,
+    /// The sound device to play to by name or index, the default device if not set
+    #[serde(default)]
+    pub device: Option<DeviceSelector>,
+}
+
+impl ZoneSettings {
+    /// Zones made by clients play to a sound device or nowhere
+    ///
+    /// A WAV output creates a file at whatever path it is given, so it can only be set in the
+    /// configuration file or on the command line.
+    pub fn check_client_output(&self) -> Result<(), ZoneError> {
+        match self.output {
+            OutputBackend::Device | OutputBackend::Null => Ok(()),
+            OutputBackend::Wav(_) => Err(ZoneError::OutputNotAllowed),
+        }
+    }
+}
+
+/// A zone as listed to clients
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct ZoneInfo {
+    pub name: String,
+    pub output: OutputBackend,
+    /// The name of the sound device being played to, `None` for the null and WAV outputs
+    pub device: Option<String>,
+    pub currently_playing: ItemTag,
+    pub paused: bool,
+    pub queue_length: usize,
+    pub volume: f32,
+}
+
+/// A player with an output of its own, for one room
+pub struct Zone {
+    pub settings: ZoneSettings,
+    pub output: Rc<AudioOutput>,
+    pub player: MusicPlayer,
+    pub scheduler: Scheduler,
+    pub lyrics_follower: LyricsFollower,
+    /// The player state that was last saved to the database, and when it was
+    pub saved_state: PlayerState,
+    pub state_saved_at: Instant,
+}
+
+impl Zone {
+    /// A zone playing to `output`, paused on `starting_item` with the settings from `config`
+    ///
+    /// It picks from `eq_presets` along with the other zones.
+    pub fn new(
+        settings: ZoneSettings,
+        output: AudioOutput,
+        starting_item: ItemTag,
+        config: &SousaConfig,
+        eq_presets: &EqPresets,
+    ) -> Self {
+        let output = Rc::new(output);
+        let mut player = MusicPlayer::new(starting_item, Rc::clone(&output));
+        player.set_replaygain_settings(config.replaygain);
+        player.set_crossfade_settings(config.crossfade);
+        player.set_equalizer(
+            Rc::clone(eq_presets),
+            config.equalizer.active_preset.clone(),
+        );
+
+        let scheduler = if settings.name == DEFAULT_ZONE {
+            Scheduler::new()
+        } else {
+            Scheduler::without_schedules()
+        };
+        Zone {
+            settings,
+            output,
+            saved_state: player.state(),
+            player,
+            scheduler,
+            lyrics_follower: LyricsFollower::new(),
+            state_saved_at: Instant::now(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.settings.name
+    }
+
+    pub fn info(&self) -> ZoneInfo {
+        ZoneInfo {
+            name: self.settings.name.clone(),
+            output: self.settings.output.clone(),
+            device: self.output.device_name(),
+            currently_playing: self.player.get_currently_playing().clone(),
+            paused: self.player.is_paused(),
+            queue_length: self.player.get_queue().0.len(),
+            volume: self.player.volume(),
+        }
+    }
+}
+
+/// Every zone the server plays to, the default one first
+pub struct Zones {
+    zones: Vec<Zone>,
+    /// The EQ presets every zone picks from, those of the default zone
+    eq_presets: EqPresets,
+}
+
+impl Zones {
+    pub fn new(default_zone: Zone) -> Self {
+        Zones {
+            eq_presets: Rc::clone(default_zone.player.eq_presets()),
+            zones: vec![default_zone],
+        }
+    }
+
+    /// A zone by name, the default one for `None`
+    pub fn get_mut(&mut self, name: Option<&str>) -> Option<&mut Zone> {
+        match name {
+            None => self.zones.first_mut(),
+            Some(name) => self.zones.iter_mut().find(|zone| zone.name() == name),
+        }
+    }
+
+    pub fn default_zone(&self) -> &Zone {
+        &self.zones[0]
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Zone> {
+        self.zones.iter_mut()
+    }
+
+    pub fn list(&self) -> Vec<ZoneInfo> {
+        self.zones.iter().map(Zone::info).collect()
+    }
+
+    pub fn eq_presets(&self) -> &EqPresets {
+        &self.eq_presets
+    }
+
+    /// Add an EQ preset, or replace the one with the same name; zones using it hear it right away
+    pub fn save_eq_preset(&mut self, preset: EqPreset) {
+        {
+            let mut presets = self.eq_presets.borrow_mut();
+            match presets.iter_mut().find(|saved| saved.name == preset.name) {
+                Some(saved) => *saved = preset,
+                None => presets.push(preset),
+            }
+        }
+        self.refresh_eq_presets();
+    }
+
+    /// Remove an EQ preset, the zones that were using it turn their EQ off
+    pub fn delete_eq_preset(&mut self, name: &str) -> Result<(), ZoneError> {
+        {
+            let mut presets = self.eq_presets.borrow_mut();
+            let index = presets
+                .iter()
+                .position(|preset| preset.name == name)
+                .ok_or(MusicPlayerError::NoSuchPreset)?;
+            presets.remove(index);
+        }
+        self.refresh_eq_presets();
+        Ok(())
+    }
+
+    fn refresh_eq_presets(&mut self) {
+        for zone in &mut self.zones {
+            zone.player.refresh_eq_preset();
+        }
+    }
+
+    /// Whether a new zone could be added by that name
+    pub fn check_name(&self, name: &str) -> Result<(), ZoneError> {
+        if name.is_empty() {
+            return Err(ZoneError::InvalidName);
+        }
+        if self.position(name).is_ok() {
+            return Err(ZoneError::ZoneExists);
+        }
+        Ok(())
+    }
+
+    /// Add a zone, unless there is one by that name already
+    pub fn add(&mut self, zone: Zone) -> Result<&mut Zone, ZoneError> {
+        self.check_name(zone.name())?;
+        self.zones.push(zone);
+        Ok(self.zones.last_mut().unwrap())
+    }
+
+    /// Take a zone out, its playback stops once it is dropped
+    pub fn remove(&mut self, name: &str) -> Result<Zone, ZoneError> {
+        match self.position(name)? {
+            0 => Err(ZoneError::DefaultZone),
+            index => Ok(self.zones.remove(index)),
+        }
+    }
+
+    /// Hand the queue of one zone over to another, carrying on where it was
+    ///
+    /// The zone it came from is paused, the other keeps its own volume and EQ preset and plays
+    /// if the queue was playing.
+    pub fn move_queue(&mut self, from: &str, to: &str) -> Result<(), ZoneError> {
+        let (from, to) = (self.position(from)?, self.position(to)?);
+        if from == to {
+            return Err(ZoneError::SameZone);
+        }
+
+        let source = &self.zones[from].player;
+        let was_playing = !source.is_paused();
+        let queue = source.get_queue().0.to_vec();
+        let target_state = self.zones[to].player.state();
+        let state = PlayerState {
+            volume: target_state.volume,
+            eq_preset: target_state.eq_preset,
+            ..source.state()
+        };
+
+        let target = &mut self.zones[to].player;
+        target.restore_state(&state, |path| {
+            queue.iter().find(|item| item.path == path).cloned()
+        })?;
+        self.zones[from].player.pause();
+        if was_playing {
+            self.zones[to].player.play();
+        }
+        Ok(())
+    }
+
+    fn position(&self, name: &str) -> Result<usize, ZoneError> {
+        self.zones
+            .iter()
+            .position(|zone| zone.name() == name)
+            .ok_or(ZoneError::NoSuchZone)
+    }
+}
+
+#[test]
+fn test_move_queue() {
+    let path = std::env::temp_dir().join(format!("sousa-zones-{}.wav", std::process::id()));
+    crate::output::write_test_wav(&path, 10.0);
+
+    let item = ItemTag {
+        path: path.to_string_lossy().into_owned(),
+        ..ItemTag::default()
+    };
+    let config = SousaConfig::default();
+    let zone = |name: &str| {
+        Zone::new(
+            ZoneSettings {
+                name: name.to_string(),
+                output: OutputBackend::Null,
+                device: None,
+            },
+            AudioOutput::null(),
+            item.clone(),
+            &config,
+            &EqPresets::default(),
+        )
+    };
+
+    let mut zones = Zones::new(zone(DEFAULT_ZONE));
+    zones.add(zone("Kitchen")).unwrap();
+    assert!(matches!(
+        zones.add(zone("Kitchen")),
+        Err(ZoneError::ZoneExists)
+    ));
+    zones
+        .get_mut(Some("Kitchen"))
+        .unwrap()
+        .player
+        .set_volume(0.5)
+        .unwrap();
+
+    zones.get_mut(None).unwrap().player.play();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    zones.move_queue(DEFAULT_ZONE, "Kitchen").unwrap();
+
+    let main = zones.get_mut(None).unwrap();
+    assert!(main.player.is_paused());
+    let kitchen = zones.get_mut(Some("Kitchen")).unwrap();
+    assert!(!kitchen.player.is_paused());
+    assert_eq!(kitchen.player.volume(), 0.5);
+    assert!(kitchen.player.get_played_time() >= std::time::Duration::from_millis(250));
+    assert!(matches!(
+        zones.move_queue("Kitchen", "Kitchen"),
+        Err(ZoneError::SameZone)
+    ));
+
+    assert!(matches!(
+        zones.remove(DEFAULT_ZONE),
+        Err(ZoneError::DefaultZone)
+    ));
+    zones.remove("Kitchen").unwrap();
+    assert_eq!(zones.list().len(), 1);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_client_output() {
+    let settings = |output| ZoneSettings {
+        name: "Kitchen".to_string(),
+        output,
+        device: None,
+    };
+    assert!(settings(OutputBackend::Device)
+        .check_client_output()
+        .is_ok());
+    assert!(settings(OutputBackend::Null).check_client_output().is_ok());
+    assert!(matches!(
+        settings(OutputBackend::Wav("/etc/passwd".into())).check_client_output(),
+        Err(ZoneError::OutputNotAllowed)
+    ));
+}
+
+#[test]
+fn test_zone_eq_presets() {
+    let path = std::env::temp_dir().join(format!("sousa-zones-eq-{}.wav", std::process::id()));
+    crate::output::write_test_wav(&path, 1.0);
+    let item = ItemTag {
+        path: path.to_string_lossy().into_owned(),
+        ..ItemTag::default()
+    };
+    let mut config = SousaConfig::default();
+    config.equalizer.active_preset = Some("Flat".to_string());
+    config.equalizer.presets = vec![
+        EqPreset::graphic("Flat", [0.0; 10]),
+        EqPreset::graphic("Bass", [6.0, 6.0, 3.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
+    ];
+    let eq_presets = Rc::new(std::cell::RefCell::new(config.equalizer.presets.clone()));
+    let zone = |name: &str| {
+        Zone::new(
+            ZoneSettings {
+                name: name.to_string(),
+                output: OutputBackend::Null,
+                device: None,
+            },
+            AudioOutput::null(),
+            item.clone(),
+            &config,
+            &eq_presets,
+        )
+    };
+    let mut zones = Zones::new(zone(DEFAULT_ZONE));
+    zones.add(zone("Kitchen")).unwrap();
+    let active = |zones: &mut Zones, name| {
+        let zone = zones.get_mut(name).unwrap();
+        zone.player.equalizer_settings().active_preset
+    };
+
+    // Each zone picks its own preset and keeps it in its player state
+    let kitchen = zones.get_mut(Some("Kitchen")).unwrap();
+    kitchen
+        .player
+        .select_eq_preset(Some("Bass".to_string()))
+        .unwrap();
+    assert_eq!(kitchen.player.state().eq_preset.as_deref(), Some("Bass"));
+    assert_eq!(active(&mut zones, None).as_deref(), Some("Flat"));
+
+    // The presets themselves are shared
+    zones.save_eq_preset(EqPreset::graphic(
+        "Treble",
+        [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 3.0, 6.0, 6.0],
+    ));
+    for name in [None, Some("Kitchen")] {
+        let zone = zones.get_mut(name).unwrap();
+        assert_eq!(zone.player.equalizer_settings().presets.len(), 3);
+    }
+
+    zones.delete_eq_preset("Bass").unwrap();
+    assert_eq!(active(&mut zones, Some("Kitchen")), None);
+    assert_eq!(active(&mut zones, None).as_deref(), Some("Flat"));
+    assert_eq!(eq_presets.borrow().len(), 2);
+    assert!(matches!(
+        zones.delete_eq_preset("Bass"),
+        Err(ZoneError::MusicPlayerError(MusicPlayerError::NoSuchPreset))
+    ));
+    std::fs::remove_file(&path).unwrap();
+}